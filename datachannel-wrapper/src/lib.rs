@@ -3,35 +3,113 @@ pub use datachannel::{
     SessionDescription, SignalingState, TransportPolicy,
 };
 
+pub mod loopback;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CandidateType {
+    Host,
+    ServerReflexive,
+    PeerReflexive,
+    Relay,
+}
+
+impl CandidateType {
+    /// Parses the type out of a candidate, e.g. `candidate:1 1 UDP 2122317823 192.168.1.2 50000 typ host`.
+    pub fn parse(candidate: &str) -> Option<Self> {
+        let mut parts = candidate.split_whitespace();
+        parts.find(|part| *part == "typ")?;
+        match parts.next()? {
+            "host" => Some(CandidateType::Host),
+            "srflx" => Some(CandidateType::ServerReflexive),
+            "prflx" => Some(CandidateType::PeerReflexive),
+            "relay" => Some(CandidateType::Relay),
+            _ => None,
+        }
+    }
+}
+
+/// Payload bytes that went through a peer connection's data channels, not counting SCTP, DTLS or UDP overhead.
+#[derive(Default)]
+struct Counters {
+    bytes_sent: std::sync::atomic::AtomicU64,
+    bytes_received: std::sync::atomic::AtomicU64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub local_candidate_type: Option<CandidateType>,
+    pub remote_candidate_type: Option<CandidateType>,
+    pub local_address: Option<String>,
+    pub remote_address: Option<String>,
+    /// Payload bytes only, across all data channels.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// The round-trip time as measured by the transport, if it has a measurement to give.
+    pub rtt: Option<std::time::Duration>,
+}
+
+/// What signaling and the netplay link need from a peer connection.
+///
+/// Implemented by [`PeerConnection`], which goes through libdatachannel, and by [`loopback::PeerConnection`], which
+/// never leaves the process.
+pub trait Peer: Send + 'static {
+    fn new(config: RtcConfig) -> Result<(Self, tokio::sync::mpsc::Receiver<PeerConnectionEvent>), std::io::Error>
+    where
+        Self: Sized;
+
+    fn create_data_channel(&mut self, label: &str, dc_init: DataChannelInit) -> Result<DataChannel, std::io::Error>;
+
+    fn set_local_description(&mut self, sdp_type: SdpType) -> Result<(), std::io::Error>;
+
+    fn set_remote_description(&mut self, sess_desc: SessionDescription) -> Result<(), std::io::Error>;
+
+    fn local_description(&self) -> Option<SessionDescription>;
+
+    fn remote_description(&self) -> Option<SessionDescription>;
+
+    fn add_remote_candidate(&mut self, cand: IceCandidate) -> Result<(), std::io::Error>;
+
+    /// The local and remote candidates ICE settled on, once connected.
+    fn selected_candidate_pair(&self) -> Option<(String, String)>;
+
+    fn stats(&self) -> Stats;
+}
+
 pub struct PeerConnection {
     peer_conn: Box<datachannel::RtcPeerConnection<PeerConnectionHandler>>,
     data_channel_rx: tokio::sync::mpsc::Receiver<DataChannel>,
+    counters: std::sync::Arc<Counters>,
 }
 
 impl PeerConnection {
-    pub fn new(config: RtcConfig) -> Result<(Self, tokio::sync::mpsc::Receiver<PeerConnectionEvent>), std::io::Error> {
+    pub async fn accept(&mut self) -> Option<DataChannel> {
+        self.data_channel_rx.recv().await
+    }
+}
+
+impl Peer for PeerConnection {
+    fn new(config: RtcConfig) -> Result<(Self, tokio::sync::mpsc::Receiver<PeerConnectionEvent>), std::io::Error> {
         let (event_tx, event_rx) = tokio::sync::mpsc::channel(1);
         let (data_channel_tx, data_channel_rx) = tokio::sync::mpsc::channel(1);
+        let counters = std::sync::Arc::new(Counters::default());
         let pch = PeerConnectionHandler {
             event_tx,
             pending_dc_receiver: None,
             data_channel_tx,
+            counters: counters.clone(),
         };
         let peer_conn = datachannel::RtcPeerConnection::new(&config, pch).map_err(datachannel_error_to_io_error)?;
         Ok((
             PeerConnection {
                 peer_conn,
                 data_channel_rx,
+                counters,
             },
             event_rx,
         ))
     }
 
-    pub fn create_data_channel(
-        &mut self,
-        label: &str,
-        dc_init: DataChannelInit,
-    ) -> Result<DataChannel, std::io::Error> {
+    fn create_data_channel(&mut self, label: &str, dc_init: DataChannelInit) -> Result<DataChannel, std::io::Error> {
         let (message_tx, message_rx) = tokio::sync::mpsc::channel(1);
         let (open_tx, open_rx) = tokio::sync::oneshot::channel();
         let state = std::sync::Arc::new(tokio::sync::Mutex::new(DataChannelState {
@@ -42,49 +120,76 @@ impl PeerConnection {
             message_tx: Some(message_tx),
             open_tx: Some(open_tx),
             state: state.clone(),
+            counters: self.counters.clone(),
         };
         let dc = self
             .peer_conn
             .create_data_channel_ex(label, dch, &dc_init)
             .map_err(datachannel_error_to_io_error)?;
         Ok(DataChannel {
-            sender: DataChannelSender { state, dc },
+            sender: DataChannelSender {
+                state,
+                transport: SenderTransport::Rtc(dc),
+                counters: self.counters.clone(),
+            },
             receiver: DataChannelReceiver { message_rx },
         })
     }
 
-    pub async fn accept(&mut self) -> Option<DataChannel> {
-        self.data_channel_rx.recv().await
-    }
-
-    pub fn set_local_description(&mut self, sdp_type: SdpType) -> Result<(), std::io::Error> {
+    fn set_local_description(&mut self, sdp_type: SdpType) -> Result<(), std::io::Error> {
         self.peer_conn
             .set_local_description(sdp_type)
             .map_err(datachannel_error_to_io_error)?;
         Ok(())
     }
 
-    pub fn set_remote_description(&mut self, sess_desc: SessionDescription) -> Result<(), std::io::Error> {
+    fn set_remote_description(&mut self, sess_desc: SessionDescription) -> Result<(), std::io::Error> {
         self.peer_conn
             .set_remote_description(&sess_desc)
             .map_err(datachannel_error_to_io_error)?;
         Ok(())
     }
 
-    pub fn local_description(&self) -> Option<SessionDescription> {
+    fn local_description(&self) -> Option<SessionDescription> {
         self.peer_conn.local_description()
     }
 
-    pub fn remote_description(&self) -> Option<SessionDescription> {
+    fn remote_description(&self) -> Option<SessionDescription> {
         self.peer_conn.remote_description()
     }
 
-    pub fn add_remote_candidate(&mut self, cand: IceCandidate) -> Result<(), std::io::Error> {
+    fn add_remote_candidate(&mut self, cand: IceCandidate) -> Result<(), std::io::Error> {
         self.peer_conn
             .add_remote_candidate(&cand)
             .map_err(datachannel_error_to_io_error)?;
         Ok(())
     }
+
+    fn selected_candidate_pair(&self) -> Option<(String, String)> {
+        self.peer_conn
+            .selected_candidate_pair()
+            .map(|pair| (pair.local, pair.remote))
+    }
+
+    fn stats(&self) -> Stats {
+        let (local_candidate_type, remote_candidate_type) =
+            if let Some((local, remote)) = self.selected_candidate_pair() {
+                (CandidateType::parse(&local), CandidateType::parse(&remote))
+            } else {
+                (None, None)
+            };
+        Stats {
+            local_candidate_type,
+            remote_candidate_type,
+            local_address: self.peer_conn.local_address(),
+            remote_address: self.peer_conn.remote_address(),
+            bytes_sent: self.counters.bytes_sent.load(std::sync::atomic::Ordering::Relaxed),
+            bytes_received: self.counters.bytes_received.load(std::sync::atomic::Ordering::Relaxed),
+            // libdatachannel only exposes SCTP's RTT estimate through its C++ API, and the datachannel crate binds the C
+            // API.
+            rtt: None,
+        }
+    }
 }
 
 struct PeerConnectionHandler {
@@ -94,6 +199,7 @@ struct PeerConnectionHandler {
         std::sync::Arc<tokio::sync::Mutex<DataChannelState>>,
     )>,
     data_channel_tx: tokio::sync::mpsc::Sender<DataChannel>,
+    counters: std::sync::Arc<Counters>,
 }
 
 #[derive(Debug)]
@@ -119,6 +225,7 @@ impl datachannel::PeerConnectionHandler for PeerConnectionHandler {
             message_tx: Some(message_tx),
             open_tx: Some(open_tx),
             state: state.clone(),
+            counters: self.counters.clone(),
         };
         self.pending_dc_receiver = Some((message_rx, state));
         dch
@@ -164,7 +271,11 @@ impl datachannel::PeerConnectionHandler for PeerConnectionHandler {
     fn on_data_channel(&mut self, dc: Box<datachannel::RtcDataChannel<Self::DCH>>) {
         let (message_rx, state) = self.pending_dc_receiver.take().unwrap();
         let _ = self.data_channel_tx.blocking_send(DataChannel {
-            sender: DataChannelSender { state, dc },
+            sender: DataChannelSender {
+                state,
+                transport: SenderTransport::Rtc(dc),
+                counters: self.counters.clone(),
+            },
             receiver: DataChannelReceiver { message_rx },
         });
     }
@@ -185,6 +296,11 @@ impl DataChannel {
         self.sender.send(msg).await
     }
 
+    /// Waits for the data channel to open. Sending does this anyway, but this lets it be waited for by itself.
+    pub async fn wait_open(&mut self) -> Result<(), std::io::Error> {
+        self.sender.wait_open().await
+    }
+
     pub async fn receive(&mut self) -> Option<Vec<u8>> {
         self.receiver.receive().await
     }
@@ -194,13 +310,19 @@ impl DataChannel {
     }
 }
 
+enum SenderTransport {
+    Rtc(Box<datachannel::RtcDataChannel<DataChannelHandler>>),
+    Loopback(std::sync::Arc<std::sync::Mutex<Option<loopback::Target>>>),
+}
+
 pub struct DataChannelSender {
     state: std::sync::Arc<tokio::sync::Mutex<DataChannelState>>,
-    dc: Box<datachannel::RtcDataChannel<DataChannelHandler>>,
+    transport: SenderTransport,
+    counters: std::sync::Arc<Counters>,
 }
 
 impl DataChannelSender {
-    pub async fn send(&mut self, msg: &[u8]) -> Result<(), std::io::Error> {
+    async fn lock_open(&self) -> Result<tokio::sync::MutexGuard<'_, DataChannelState>, std::io::Error> {
         let mut state = self.state.lock().await;
         if let Some(err) = &state.error {
             return Err(std::io::Error::new(std::io::ErrorKind::Other, err.clone()));
@@ -211,11 +333,51 @@ impl DataChannelSender {
                 .await
                 .map_err(|_| std::io::Error::new(std::io::ErrorKind::NotConnected, "not connected"))?;
         }
+        Ok(state)
+    }
 
-        self.dc.send(msg).map_err(datachannel_error_to_io_error)?;
+    pub async fn wait_open(&mut self) -> Result<(), std::io::Error> {
+        self.lock_open().await?;
         Ok(())
     }
 
+    pub async fn send(&mut self, msg: &[u8]) -> Result<(), std::io::Error> {
+        let _state = self.lock_open().await?;
+
+        match &mut self.transport {
+            SenderTransport::Rtc(dc) => {
+                dc.send(msg).map_err(datachannel_error_to_io_error)?;
+            }
+            SenderTransport::Loopback(target) => {
+                let (message_tx, remote_counters) = target
+                    .lock()
+                    .unwrap()
+                    .as_ref()
+                    .map(|target| (target.message_tx.clone(), target.counters.clone()))
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "peer went away"))?;
+                message_tx
+                    .send(msg.to_vec())
+                    .await
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "peer went away"))?;
+                remote_counters
+                    .bytes_received
+                    .fetch_add(msg.len() as u64, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+        self.counters
+            .bytes_sent
+            .fetch_add(msg.len() as u64, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// How many bytes are queued up in SCTP, waiting to be sent.
+    pub fn buffered_amount(&self) -> usize {
+        match &self.transport {
+            SenderTransport::Rtc(dc) => dc.buffered_amount(),
+            SenderTransport::Loopback(_) => 0,
+        }
+    }
+
     pub fn unsplit(self, receiver: DataChannelReceiver) -> DataChannel {
         DataChannel { sender: self, receiver }
     }
@@ -239,6 +401,7 @@ struct DataChannelHandler {
     state: std::sync::Arc<tokio::sync::Mutex<DataChannelState>>,
     open_tx: Option<tokio::sync::oneshot::Sender<()>>,
     message_tx: Option<tokio::sync::mpsc::Sender<Vec<u8>>>,
+    counters: std::sync::Arc<Counters>,
 }
 
 fn datachannel_error_to_io_error(err: datachannel::Error) -> std::io::Error {
@@ -268,6 +431,9 @@ impl datachannel::DataChannelHandler for DataChannelHandler {
     }
 
     fn on_message(&mut self, msg: &[u8]) {
+        self.counters
+            .bytes_received
+            .fetch_add(msg.len() as u64, std::sync::atomic::Ordering::Relaxed);
         let _ = self.message_tx.as_mut().unwrap().blocking_send(msg.to_vec());
     }
 
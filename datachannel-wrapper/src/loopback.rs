@@ -0,0 +1,357 @@
+use crate::{
+    CandidateType, ConnectionState, Counters, DataChannel, DataChannelInit, DataChannelReceiver, DataChannelSender,
+    DataChannelState, GatheringState, IceCandidate, Peer, PeerConnectionEvent, RtcConfig, SdpType, SenderTransport,
+    SessionDescription, Stats,
+};
+
+const EVENT_QUEUE_LENGTH: usize = 16;
+
+/// Where a loopback data channel sends to, once the other side has opened a channel with the same label.
+pub(crate) struct Target {
+    pub(crate) message_tx: tokio::sync::mpsc::Sender<Vec<u8>>,
+    /// The other side's counters, so what it receives is counted too.
+    pub(crate) counters: std::sync::Arc<Counters>,
+}
+
+struct Channel {
+    /// Handed over to the other side when the channel is paired.
+    message_tx: Option<tokio::sync::mpsc::Sender<Vec<u8>>>,
+    open_tx: Option<tokio::sync::oneshot::Sender<()>>,
+    target: std::sync::Arc<std::sync::Mutex<Option<Target>>>,
+}
+
+struct State {
+    event_tx: tokio::sync::mpsc::Sender<PeerConnectionEvent>,
+    counters: std::sync::Arc<Counters>,
+    channels: std::collections::HashMap<String, Channel>,
+    local_description: Option<(SdpType, String)>,
+    remote_description: Option<(SdpType, String)>,
+    /// Only set once connected.
+    remote_id: Option<u64>,
+}
+
+impl State {
+    /// Pretends to gather the one candidate there is.
+    fn gather(&self, id: u64) {
+        for event in [
+            PeerConnectionEvent::GatheringStateChange(GatheringState::InProgress),
+            PeerConnectionEvent::IceCandidate(IceCandidate {
+                candidate: make_candidate(id),
+                mid: "0".to_string(),
+            }),
+            PeerConnectionEvent::GatheringStateChange(GatheringState::Complete),
+        ] {
+            let _ = self.event_tx.try_send(event);
+        }
+    }
+}
+
+type Peers = std::collections::BTreeMap<u64, State>;
+
+/// Every loopback peer connection in the process, keyed by the ID that goes in its descriptions.
+static PEERS: std::sync::Mutex<Peers> = std::sync::Mutex::new(std::collections::BTreeMap::new());
+static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+
+fn make_candidate(id: u64) -> String {
+    format!("candidate:{} 1 UDP 2122317823 127.0.0.1 9 typ host", id)
+}
+
+fn make_sdp(id: u64) -> String {
+    format!(
+        "v=0\r\n\
+         o=- {} 0 IN IP4 127.0.0.1\r\n\
+         s=-\r\n\
+         t=0 0\r\n\
+         a=ice-ufrag:loopback{}\r\n\
+         a=ice-pwd:loopbackloopbackloopback\r\n\
+         m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+         c=IN IP4 127.0.0.1\r\n\
+         a=mid:0\r\n\
+         a=sctp-port:5000\r\n",
+        id, id
+    )
+}
+
+/// Reads the peer ID back out of a description's origin line.
+fn parse_id(sdp: &str) -> Option<u64> {
+    sdp.lines()
+        .find_map(|line| line.strip_prefix("o="))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+fn to_session_description((sdp_type, sdp): &(SdpType, String)) -> Option<SessionDescription> {
+    Some(SessionDescription {
+        sdp_type: sdp_type.clone(),
+        sdp: crate::sdp::parse_sdp(sdp, false).ok()?,
+    })
+}
+
+/// Hooks up both ends of a channel, if both sides have opened it.
+fn pair(peers: &mut Peers, a: u64, b: u64, label: &str) {
+    let is_open = |peers: &Peers, id: u64| {
+        peers
+            .get(&id)
+            .and_then(|state| state.channels.get(label))
+            .map(|channel| channel.message_tx.is_some())
+            .unwrap_or(false)
+    };
+    if !is_open(peers, a) || !is_open(peers, b) {
+        return;
+    }
+
+    for (from, to) in [(a, b), (b, a)] {
+        let to_state = peers.get_mut(&to).unwrap();
+        let target = Target {
+            message_tx: to_state.channels.get_mut(label).unwrap().message_tx.take().unwrap(),
+            counters: to_state.counters.clone(),
+        };
+
+        let from_channel = peers.get_mut(&from).unwrap().channels.get_mut(label).unwrap();
+        *from_channel.target.lock().unwrap() = Some(target);
+        if let Some(open_tx) = from_channel.open_tx.take() {
+            let _ = open_tx.send(());
+        }
+    }
+}
+
+fn connect(peers: &mut Peers, a: u64, b: u64) -> Result<(), std::io::Error> {
+    if !peers.contains_key(&b) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "no loopback peer connection with that description",
+        ));
+    }
+
+    let labels = peers[&a].channels.keys().cloned().collect::<Vec<_>>();
+    for id in [a, b] {
+        peers.get_mut(&id).unwrap().remote_id = Some(if id == a { b } else { a });
+    }
+    for label in labels {
+        pair(peers, a, b, &label);
+    }
+    for id in [a, b] {
+        let _ = peers[&id]
+            .event_tx
+            .try_send(PeerConnectionEvent::ConnectionStateChange(ConnectionState::Connected));
+    }
+    Ok(())
+}
+
+/// A peer connection that only ever connects to another loopback peer connection in the same process.
+///
+/// Descriptions are made up and carry nothing but an ID, but they go through the same offer/answer dance as real ones,
+/// so they can be passed through a signaling server. Data channels are paired up by label and are always reliable and
+/// ordered.
+pub struct PeerConnection {
+    id: u64,
+    counters: std::sync::Arc<Counters>,
+}
+
+impl Peer for PeerConnection {
+    fn new(_config: RtcConfig) -> Result<(Self, tokio::sync::mpsc::Receiver<PeerConnectionEvent>), std::io::Error> {
+        let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let (event_tx, event_rx) = tokio::sync::mpsc::channel(EVENT_QUEUE_LENGTH);
+        let counters = std::sync::Arc::new(Counters::default());
+        PEERS.lock().unwrap().insert(
+            id,
+            State {
+                event_tx,
+                counters: counters.clone(),
+                channels: std::collections::HashMap::new(),
+                local_description: None,
+                remote_description: None,
+                remote_id: None,
+            },
+        );
+        Ok((PeerConnection { id, counters }, event_rx))
+    }
+
+    fn create_data_channel(&mut self, label: &str, _dc_init: DataChannelInit) -> Result<DataChannel, std::io::Error> {
+        let (message_tx, message_rx) = tokio::sync::mpsc::channel(1);
+        let (open_tx, open_rx) = tokio::sync::oneshot::channel();
+        let target = std::sync::Arc::new(std::sync::Mutex::new(None));
+
+        let mut peers = PEERS.lock().unwrap();
+        let state = peers.get_mut(&self.id).unwrap();
+        state.channels.insert(
+            label.to_string(),
+            Channel {
+                message_tx: Some(message_tx),
+                open_tx: Some(open_tx),
+                target: target.clone(),
+            },
+        );
+
+        // Like libdatachannel, the first channel starts negotiation.
+        if state.local_description.is_none() && state.remote_description.is_none() {
+            state.local_description = Some((SdpType::Offer, make_sdp(self.id)));
+            state.gather(self.id);
+        }
+
+        let remote_id = state.remote_id;
+        if let Some(remote_id) = remote_id {
+            pair(&mut peers, self.id, remote_id, label);
+        }
+
+        Ok(DataChannel {
+            sender: DataChannelSender {
+                state: std::sync::Arc::new(tokio::sync::Mutex::new(DataChannelState {
+                    open_rx: Some(open_rx),
+                    error: None,
+                })),
+                transport: SenderTransport::Loopback(target),
+                counters: self.counters.clone(),
+            },
+            receiver: DataChannelReceiver { message_rx },
+        })
+    }
+
+    fn set_local_description(&mut self, sdp_type: SdpType) -> Result<(), std::io::Error> {
+        let mut peers = PEERS.lock().unwrap();
+        let state = peers.get_mut(&self.id).unwrap();
+        match sdp_type {
+            SdpType::Rollback => {
+                state.local_description = None;
+            }
+            SdpType::Offer if state.local_description.is_none() => {
+                state.local_description = Some((SdpType::Offer, make_sdp(self.id)));
+                state.gather(self.id);
+            }
+            SdpType::Answer if state.local_description.is_none() && state.remote_description.is_some() => {
+                state.local_description = Some((SdpType::Answer, make_sdp(self.id)));
+                state.gather(self.id);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn set_remote_description(&mut self, sess_desc: SessionDescription) -> Result<(), std::io::Error> {
+        let sdp = sess_desc.sdp.to_string();
+        let remote_id = parse_id(&sdp)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a loopback description"))?;
+
+        let mut peers = PEERS.lock().unwrap();
+        let state = peers.get_mut(&self.id).unwrap();
+        state.remote_description = Some((sess_desc.sdp_type.clone(), sdp));
+        match sess_desc.sdp_type {
+            SdpType::Offer => {
+                // Like libdatachannel, an offer is answered straight away.
+                state.local_description = Some((SdpType::Answer, make_sdp(self.id)));
+                state.gather(self.id);
+                Ok(())
+            }
+            SdpType::Answer => connect(&mut peers, self.id, remote_id),
+            _ => Ok(()),
+        }
+    }
+
+    fn local_description(&self) -> Option<SessionDescription> {
+        to_session_description(PEERS.lock().unwrap().get(&self.id)?.local_description.as_ref()?)
+    }
+
+    fn remote_description(&self) -> Option<SessionDescription> {
+        to_session_description(PEERS.lock().unwrap().get(&self.id)?.remote_description.as_ref()?)
+    }
+
+    fn add_remote_candidate(&mut self, _cand: IceCandidate) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn selected_candidate_pair(&self) -> Option<(String, String)> {
+        let remote_id = PEERS.lock().unwrap().get(&self.id)?.remote_id?;
+        Some((make_candidate(self.id), make_candidate(remote_id)))
+    }
+
+    fn stats(&self) -> Stats {
+        let candidate_type = self.selected_candidate_pair().map(|_| CandidateType::Host);
+        Stats {
+            local_candidate_type: candidate_type,
+            remote_candidate_type: candidate_type,
+            local_address: None,
+            remote_address: None,
+            bytes_sent: self.counters.bytes_sent.load(std::sync::atomic::Ordering::Relaxed),
+            bytes_received: self.counters.bytes_received.load(std::sync::atomic::Ordering::Relaxed),
+            rtt: None,
+        }
+    }
+}
+
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        let mut peers = PEERS.lock().unwrap();
+        let state = if let Some(state) = peers.remove(&self.id) {
+            state
+        } else {
+            return;
+        };
+
+        // Letting go of what we send with ends the other side's receivers.
+        for channel in state.channels.values() {
+            *channel.target.lock().unwrap() = None;
+        }
+
+        if let Some(remote) = state.remote_id.and_then(|id| peers.get(&id)) {
+            let _ = remote.event_tx.try_send(PeerConnectionEvent::ConnectionStateChange(
+                ConnectionState::Disconnected,
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn wait_for_connected(event_rx: &mut tokio::sync::mpsc::Receiver<PeerConnectionEvent>) {
+        loop {
+            if let Some(PeerConnectionEvent::ConnectionStateChange(ConnectionState::Connected)) = event_rx.recv().await
+            {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offer_answer() {
+        let (mut offerer, mut offerer_event_rx) = PeerConnection::new(RtcConfig::new::<&str>(&[])).unwrap();
+        let (mut answerer, mut answerer_event_rx) = PeerConnection::new(RtcConfig::new::<&str>(&[])).unwrap();
+
+        // Both sides open the channel up front, as signaling does, so the answerer has to roll back its own offer.
+        let mut offerer_dc = offerer
+            .create_data_channel("tango", DataChannelInit::default())
+            .unwrap();
+        let mut answerer_dc = answerer
+            .create_data_channel("tango", DataChannelInit::default())
+            .unwrap();
+
+        let offer = offerer.local_description().unwrap();
+        assert!(matches!(offer.sdp_type, SdpType::Offer));
+
+        answerer.set_local_description(SdpType::Rollback).unwrap();
+        answerer.set_remote_description(offer).unwrap();
+        let answer = answerer.local_description().unwrap();
+        assert!(matches!(answer.sdp_type, SdpType::Answer));
+
+        offerer.set_remote_description(answer).unwrap();
+        wait_for_connected(&mut offerer_event_rx).await;
+        wait_for_connected(&mut answerer_event_rx).await;
+
+        offerer_dc.send(b"hello").await.unwrap();
+        assert_eq!(answerer_dc.receive().await.unwrap(), b"hello");
+        answerer_dc.send(b"hi").await.unwrap();
+        assert_eq!(offerer_dc.receive().await.unwrap(), b"hi");
+
+        assert_eq!(offerer.stats().bytes_sent, 5);
+        assert_eq!(offerer.stats().bytes_received, 2);
+        assert_eq!(answerer.stats().bytes_sent, 2);
+        assert_eq!(answerer.stats().bytes_received, 5);
+
+        drop(offerer_dc);
+        drop(offerer);
+        assert_eq!(answerer_dc.receive().await, None);
+    }
+}
@@ -3,6 +3,7 @@ pub mod game;
 pub mod msg;
 pub mod navicust;
 pub mod rom;
+pub mod rules;
 pub mod save;
 
 #[cfg(target_endian = "big")]
@@ -0,0 +1,582 @@
+use crate::{rom, save};
+
+/// Restrictions on a player's setup, as agreed upon before a match.
+///
+/// All IDs are the same IDs used by [`rom::Assets`] for the game the ruleset is applied to.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Ruleset {
+    pub name: String,
+    pub max_mega_chips: Option<usize>,
+    pub max_giga_chips: Option<usize>,
+    pub max_chip_copies: Option<usize>,
+    pub banned_chips: std::collections::BTreeSet<usize>,
+    pub banned_navicust_parts: std::collections::BTreeSet<usize>,
+    pub banned_patch_cards: std::collections::BTreeSet<usize>,
+    pub max_patch_card_mb: Option<u32>,
+    pub max_patch_card_copies: Option<usize>,
+}
+
+#[derive(thiserror::Error, Clone, Debug, PartialEq)]
+pub enum Violation {
+    #[error("too many mega chips: {count} > {max}")]
+    TooManyMegaChips { count: usize, max: usize },
+
+    #[error("too many giga chips: {count} > {max}")]
+    TooManyGigaChips { count: usize, max: usize },
+
+    #[error("too many copies of chip {id}: {count} > {max}")]
+    TooManyChipCopies { id: usize, count: usize, max: usize },
+
+    #[error("banned chip: {id}")]
+    BannedChip { id: usize },
+
+    #[error("banned navicust part: {id}")]
+    BannedNavicustPart { id: usize },
+
+    #[error("banned patch card: {id}")]
+    BannedPatchCard { id: usize },
+
+    #[error("patch cards use too much MB: {mb} > {max}")]
+    TooMuchPatchCardMb { mb: u32, max: u32 },
+
+    #[error("too many copies of patch card {id}: {count} > {max}")]
+    TooManyPatchCardCopies { id: usize, count: usize, max: usize },
+}
+
+impl Ruleset {
+    /// Checks the equipped folder, navicust and patch cards of a save, returning every rule that was broken.
+    pub fn check(&self, save: &dyn save::Save, assets: &dyn rom::Assets) -> Vec<Violation> {
+        let mut violations = vec![];
+        self.check_chips(save, assets, &mut violations);
+        self.check_navicust(save, &mut violations);
+        self.check_patch_cards(save, assets, &mut violations);
+        violations
+    }
+
+    fn check_chips(&self, save: &dyn save::Save, assets: &dyn rom::Assets, violations: &mut Vec<Violation>) {
+        let chips_view = if let Some(chips_view) = save.view_chips() {
+            chips_view
+        } else {
+            return;
+        };
+
+        let folder_index = chips_view.equipped_folder_index();
+        let mut copies = std::collections::BTreeMap::<usize, usize>::new();
+        let mut mega_chips = 0;
+        let mut giga_chips = 0;
+        for chip in (0..30).flat_map(|i| chips_view.chip(folder_index, i)) {
+            *copies.entry(chip.id).or_default() += 1;
+            match assets.chip(chip.id).map(|info| info.class()) {
+                Some(rom::ChipClass::Mega) => {
+                    mega_chips += 1;
+                }
+                Some(rom::ChipClass::Giga) => {
+                    giga_chips += 1;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(max) = self.max_mega_chips {
+            if mega_chips > max {
+                violations.push(Violation::TooManyMegaChips { count: mega_chips, max });
+            }
+        }
+
+        if let Some(max) = self.max_giga_chips {
+            if giga_chips > max {
+                violations.push(Violation::TooManyGigaChips { count: giga_chips, max });
+            }
+        }
+
+        violations.extend(
+            copies
+                .keys()
+                .filter(|id| self.banned_chips.contains(id))
+                .map(|id| Violation::BannedChip { id: *id }),
+        );
+
+        if let Some(max) = self.max_chip_copies {
+            for (id, count) in copies {
+                if count > max {
+                    violations.push(Violation::TooManyChipCopies { id, count, max });
+                }
+            }
+        }
+    }
+
+    fn check_navicust(&self, save: &dyn save::Save, violations: &mut Vec<Violation>) {
+        let navicust_view = if let Some(save::NaviView::Navicust(navicust_view)) = save.view_navi() {
+            navicust_view
+        } else {
+            return;
+        };
+
+        let ids = (0..navicust_view.count())
+            .flat_map(|i| navicust_view.navicust_part(i))
+            .map(|ncp| ncp.id)
+            .collect::<std::collections::BTreeSet<_>>();
+
+        violations.extend(
+            ids.into_iter()
+                .filter(|id| self.banned_navicust_parts.contains(id))
+                .map(|id| Violation::BannedNavicustPart { id }),
+        );
+    }
+
+    fn check_patch_cards(&self, save: &dyn save::Save, assets: &dyn rom::Assets, violations: &mut Vec<Violation>) {
+        let mut copies = std::collections::BTreeMap::<usize, usize>::new();
+        let mut mb = 0;
+        match save.view_patch_cards() {
+            Some(save::PatchCardsView::PatchCard4s(patch_card4s_view)) => {
+                for patch_card in (0..6).flat_map(|slot| patch_card4s_view.patch_card(slot)) {
+                    if !patch_card.enabled {
+                        continue;
+                    }
+                    *copies.entry(patch_card.id).or_default() += 1;
+                }
+            }
+            Some(save::PatchCardsView::PatchCard56s(patch_card56s_view)) => {
+                for patch_card in (0..patch_card56s_view.count()).flat_map(|slot| patch_card56s_view.patch_card(slot)) {
+                    if !patch_card.enabled {
+                        continue;
+                    }
+                    *copies.entry(patch_card.id).or_default() += 1;
+                    mb += assets
+                        .patch_card56(patch_card.id)
+                        .map(|info| info.mb() as u32)
+                        .unwrap_or(0);
+                }
+            }
+            None => {
+                return;
+            }
+        }
+
+        violations.extend(
+            copies
+                .keys()
+                .filter(|id| self.banned_patch_cards.contains(id))
+                .map(|id| Violation::BannedPatchCard { id: *id }),
+        );
+
+        if let Some(max) = self.max_patch_card_mb {
+            if mb > max {
+                violations.push(Violation::TooMuchPatchCardMb { mb, max });
+            }
+        }
+
+        if let Some(max) = self.max_patch_card_copies {
+            for (id, count) in copies {
+                if count > max {
+                    violations.push(Violation::TooManyPatchCardCopies { id, count, max });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEGA_CHIP: usize = 1;
+    const GIGA_CHIP: usize = 2;
+    const PATCH_CARD_MB: u8 = 10;
+
+    #[derive(Clone)]
+    enum StubPatchCards {
+        PatchCard4s(Vec<save::PatchCard>),
+        PatchCard56s(Vec<save::PatchCard>),
+    }
+
+    #[derive(Clone, Default)]
+    struct StubSave {
+        folder: Vec<usize>,
+        navicust_parts: Option<Vec<usize>>,
+        patch_cards: Option<StubPatchCards>,
+    }
+
+    struct StubChipsView<'a>(&'a [usize]);
+
+    impl<'a> save::ChipsView<'a> for StubChipsView<'a> {
+        fn num_folders(&self) -> usize {
+            1
+        }
+
+        fn equipped_folder_index(&self) -> usize {
+            0
+        }
+
+        fn regular_chip_index(&self, _folder_index: usize) -> Option<usize> {
+            None
+        }
+
+        fn tag_chip_indexes(&self, _folder_index: usize) -> Option<[usize; 2]> {
+            None
+        }
+
+        fn chip(&self, folder_index: usize, chip_index: usize) -> Option<save::Chip> {
+            if folder_index != 0 {
+                return None;
+            }
+            self.0.get(chip_index).map(|id| save::Chip {
+                id: *id,
+                code: save::ChipCode::A,
+            })
+        }
+    }
+
+    struct StubNavicustView<'a>(&'a [usize]);
+
+    impl<'a> save::NavicustView<'a> for StubNavicustView<'a> {
+        fn size(&self) -> [usize; 2] {
+            [7, 7]
+        }
+
+        fn navicust_part(&self, i: usize) -> Option<save::NavicustPart> {
+            self.0.get(i).map(|id| save::NavicustPart {
+                id: *id,
+                col: 0,
+                row: 0,
+                rot: 0,
+                compressed: false,
+            })
+        }
+
+        fn materialized(&self) -> crate::navicust::MaterializedNavicust {
+            unimplemented!()
+        }
+    }
+
+    struct StubPatchCard4sView<'a>(&'a [save::PatchCard]);
+
+    impl<'a> save::PatchCard4sView<'a> for StubPatchCard4sView<'a> {
+        fn patch_card(&self, slot: usize) -> Option<save::PatchCard> {
+            self.0.get(slot).cloned()
+        }
+    }
+
+    struct StubPatchCard56sView<'a>(&'a [save::PatchCard]);
+
+    impl<'a> save::PatchCard56sView<'a> for StubPatchCard56sView<'a> {
+        fn count(&self) -> usize {
+            self.0.len()
+        }
+
+        fn patch_card(&self, slot: usize) -> Option<save::PatchCard> {
+            self.0.get(slot).cloned()
+        }
+    }
+
+    impl save::Save for StubSave {
+        fn as_sram_dump(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn as_raw_wram(&self) -> std::borrow::Cow<[u8]> {
+            std::borrow::Cow::Borrowed(&[])
+        }
+
+        fn rebuild_checksum(&mut self) {}
+
+        fn view_chips(&self) -> Option<Box<dyn save::ChipsView + '_>> {
+            Some(Box::new(StubChipsView(&self.folder)))
+        }
+
+        fn view_navi(&self) -> Option<save::NaviView> {
+            Some(save::NaviView::Navicust(Box::new(StubNavicustView(
+                self.navicust_parts.as_ref()?,
+            ))))
+        }
+
+        fn view_patch_cards(&self) -> Option<save::PatchCardsView> {
+            Some(match self.patch_cards.as_ref()? {
+                StubPatchCards::PatchCard4s(patch_cards) => {
+                    save::PatchCardsView::PatchCard4s(Box::new(StubPatchCard4sView(patch_cards)))
+                }
+                StubPatchCards::PatchCard56s(patch_cards) => {
+                    save::PatchCardsView::PatchCard56s(Box::new(StubPatchCard56sView(patch_cards)))
+                }
+            })
+        }
+    }
+
+    struct StubChip(rom::ChipClass);
+
+    impl rom::Chip for StubChip {
+        fn name(&self) -> Option<String> {
+            None
+        }
+
+        fn description(&self) -> Option<String> {
+            None
+        }
+
+        fn icon(&self) -> image::RgbaImage {
+            unimplemented!()
+        }
+
+        fn image(&self) -> image::RgbaImage {
+            unimplemented!()
+        }
+
+        fn codes(&self) -> Vec<char> {
+            vec![]
+        }
+
+        fn element(&self) -> usize {
+            0
+        }
+
+        fn class(&self) -> rom::ChipClass {
+            self.0
+        }
+
+        fn dark(&self) -> bool {
+            false
+        }
+
+        fn mb(&self) -> u8 {
+            0
+        }
+
+        fn attack_power(&self) -> u32 {
+            0
+        }
+
+        fn library_sort_order(&self) -> Option<usize> {
+            None
+        }
+    }
+
+    struct StubPatchCard56;
+
+    impl rom::PatchCard56 for StubPatchCard56 {
+        fn name(&self) -> Option<String> {
+            None
+        }
+
+        fn mb(&self) -> u8 {
+            PATCH_CARD_MB
+        }
+
+        fn effects(&self) -> Vec<rom::PatchCard56Effect> {
+            vec![]
+        }
+    }
+
+    /// Every chip is standard except for MEGA_CHIP and GIGA_CHIP, and every patch card takes PATCH_CARD_MB.
+    struct StubAssets;
+
+    impl rom::Assets for StubAssets {
+        fn chip(&self, id: usize) -> Option<Box<dyn rom::Chip + '_>> {
+            Some(Box::new(StubChip(match id {
+                MEGA_CHIP => rom::ChipClass::Mega,
+                GIGA_CHIP => rom::ChipClass::Giga,
+                _ => rom::ChipClass::Standard,
+            })))
+        }
+
+        fn num_chips(&self) -> usize {
+            0
+        }
+
+        fn element_icon(&self, _id: usize) -> Option<image::RgbaImage> {
+            None
+        }
+
+        fn patch_card56(&self, _id: usize) -> Option<Box<dyn rom::PatchCard56 + '_>> {
+            Some(Box::new(StubPatchCard56))
+        }
+    }
+
+    fn patch_card(id: usize, enabled: bool) -> save::PatchCard {
+        save::PatchCard { id, enabled }
+    }
+
+    #[test]
+    fn test_default_ruleset_allows_anything() {
+        let save = StubSave {
+            folder: vec![MEGA_CHIP, MEGA_CHIP, GIGA_CHIP, GIGA_CHIP, 10, 10, 10, 10, 10],
+            navicust_parts: Some(vec![1, 2, 3]),
+            patch_cards: Some(StubPatchCards::PatchCard56s(vec![patch_card(1, true); 20])),
+        };
+        assert_eq!(Ruleset::default().check(&save, &StubAssets), vec![]);
+    }
+
+    #[test]
+    fn test_too_many_mega_chips() {
+        let ruleset = Ruleset {
+            max_mega_chips: Some(1),
+            ..Default::default()
+        };
+        let save = StubSave {
+            folder: vec![MEGA_CHIP, 10],
+            ..Default::default()
+        };
+        assert_eq!(ruleset.check(&save, &StubAssets), vec![]);
+        let save = StubSave {
+            folder: vec![MEGA_CHIP, MEGA_CHIP, GIGA_CHIP],
+            ..Default::default()
+        };
+        assert_eq!(
+            ruleset.check(&save, &StubAssets),
+            vec![Violation::TooManyMegaChips { count: 2, max: 1 }]
+        );
+    }
+
+    #[test]
+    fn test_too_many_giga_chips() {
+        let ruleset = Ruleset {
+            max_giga_chips: Some(1),
+            ..Default::default()
+        };
+        let save = StubSave {
+            folder: vec![GIGA_CHIP, MEGA_CHIP, MEGA_CHIP],
+            ..Default::default()
+        };
+        assert_eq!(ruleset.check(&save, &StubAssets), vec![]);
+        let save = StubSave {
+            folder: vec![GIGA_CHIP, GIGA_CHIP],
+            ..Default::default()
+        };
+        assert_eq!(
+            ruleset.check(&save, &StubAssets),
+            vec![Violation::TooManyGigaChips { count: 2, max: 1 }]
+        );
+    }
+
+    #[test]
+    fn test_too_many_chip_copies() {
+        let ruleset = Ruleset {
+            max_chip_copies: Some(2),
+            ..Default::default()
+        };
+        let save = StubSave {
+            folder: vec![10, 10, 11, 11, 11, 12],
+            ..Default::default()
+        };
+        assert_eq!(
+            ruleset.check(&save, &StubAssets),
+            vec![Violation::TooManyChipCopies {
+                id: 11,
+                count: 3,
+                max: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn test_banned_chip() {
+        let ruleset = Ruleset {
+            banned_chips: [11, 13].into_iter().collect(),
+            ..Default::default()
+        };
+        let save = StubSave {
+            folder: vec![10, 11, 11, 12],
+            ..Default::default()
+        };
+        assert_eq!(
+            ruleset.check(&save, &StubAssets),
+            vec![Violation::BannedChip { id: 11 }]
+        );
+    }
+
+    #[test]
+    fn test_banned_navicust_part() {
+        let ruleset = Ruleset {
+            banned_navicust_parts: [2, 4].into_iter().collect(),
+            ..Default::default()
+        };
+        let save = StubSave {
+            navicust_parts: Some(vec![1, 2, 2, 3]),
+            ..Default::default()
+        };
+        assert_eq!(
+            ruleset.check(&save, &StubAssets),
+            vec![Violation::BannedNavicustPart { id: 2 }]
+        );
+    }
+
+    #[test]
+    fn test_banned_patch_card() {
+        let ruleset = Ruleset {
+            banned_patch_cards: [2, 3].into_iter().collect(),
+            ..Default::default()
+        };
+        let patch_cards = vec![patch_card(1, true), patch_card(2, true), patch_card(3, false)];
+        for patch_cards in [
+            StubPatchCards::PatchCard4s(patch_cards.clone()),
+            StubPatchCards::PatchCard56s(patch_cards),
+        ] {
+            let save = StubSave {
+                patch_cards: Some(patch_cards),
+                ..Default::default()
+            };
+            assert_eq!(
+                ruleset.check(&save, &StubAssets),
+                vec![Violation::BannedPatchCard { id: 2 }]
+            );
+        }
+    }
+
+    #[test]
+    fn test_too_much_patch_card_mb() {
+        let ruleset = Ruleset {
+            max_patch_card_mb: Some(PATCH_CARD_MB as u32 * 2),
+            ..Default::default()
+        };
+        let save = StubSave {
+            patch_cards: Some(StubPatchCards::PatchCard56s(vec![
+                patch_card(1, true),
+                patch_card(2, true),
+                patch_card(3, false),
+            ])),
+            ..Default::default()
+        };
+        assert_eq!(ruleset.check(&save, &StubAssets), vec![]);
+        let save = StubSave {
+            patch_cards: Some(StubPatchCards::PatchCard56s(vec![
+                patch_card(1, true),
+                patch_card(2, true),
+                patch_card(3, true),
+            ])),
+            ..Default::default()
+        };
+        assert_eq!(
+            ruleset.check(&save, &StubAssets),
+            vec![Violation::TooMuchPatchCardMb {
+                mb: PATCH_CARD_MB as u32 * 3,
+                max: PATCH_CARD_MB as u32 * 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_too_many_patch_card_copies() {
+        let ruleset = Ruleset {
+            max_patch_card_copies: Some(1),
+            ..Default::default()
+        };
+        let save = StubSave {
+            patch_cards: Some(StubPatchCards::PatchCard56s(vec![
+                patch_card(1, true),
+                patch_card(2, true),
+                patch_card(2, true),
+                patch_card(3, true),
+                patch_card(3, false),
+            ])),
+            ..Default::default()
+        };
+        assert_eq!(
+            ruleset.check(&save, &StubAssets),
+            vec![Violation::TooManyPatchCardCopies {
+                id: 2,
+                count: 2,
+                max: 1
+            }]
+        );
+    }
+}
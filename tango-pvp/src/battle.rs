@@ -8,6 +8,24 @@ pub enum BattleOutcome {
     Win,
 }
 
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Score {
+    pub wins: u8,
+    pub losses: u8,
+}
+
+impl Score {
+    pub fn series_outcome(&self, first_to: u8) -> Option<BattleOutcome> {
+        if self.wins >= first_to {
+            Some(BattleOutcome::Win)
+        } else if self.losses >= first_to {
+            Some(BattleOutcome::Loss)
+        } else {
+            None
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct CommittedState {
     pub state: Box<mgba::state::State>,
@@ -19,6 +37,7 @@ pub struct RoundState {
     pub number: u8,
     pub round: Option<Round>,
     pub last_outcome: Option<BattleOutcome>,
+    pub score: Score,
 }
 
 impl RoundState {
@@ -26,6 +45,13 @@ impl RoundState {
         match self.round.take() {
             Some(round) => {
                 log::info!("round ended at {:x}", round.current_tick);
+                log::info!("round diagnostics: {}", round.diagnostics);
+                match self.last_outcome {
+                    Some(BattleOutcome::Win) => self.score.wins += 1,
+                    Some(BattleOutcome::Loss) => self.score.losses += 1,
+                    None => {}
+                }
+                log::info!("score is now {}-{}", self.score.wins, self.score.losses);
             }
             None => {
                 return Ok(());
@@ -47,7 +73,9 @@ pub struct Match {
     rng: tokio::sync::Mutex<rand_pcg::Mcg128Xsl64>,
     cancellation_token: tokio_util::sync::CancellationToken,
     match_type: (u8, u8),
+    first_to: Option<u8>,
     input_delay: u32,
+    lockstep_remote_inputs: Option<std::sync::Arc<crate::lockstep::RemoteInputQueue>>,
     is_offerer: bool,
     round_state: tokio::sync::Mutex<RoundState>,
     primary_thread_handle: mgba::thread::Handle,
@@ -77,7 +105,9 @@ impl Match {
         remote_rom: &[u8],
         remote_save: &(dyn tango_dataview::save::Save + Send + Sync),
         match_type: (u8, u8),
+        first_to: Option<u8>,
         input_delay: u32,
+        lockstep: bool,
         replay_writer_factory: impl Fn(
                 /* round_number */ u8,
                 /* local_player_index */ u8,
@@ -94,6 +124,13 @@ impl Match {
         } else {
             BattleOutcome::Loss
         };
+        let lockstep_remote_inputs = if lockstep {
+            Some(std::sync::Arc::new(crate::lockstep::RemoteInputQueue::new(
+                cancellation_token.clone(),
+            )))
+        } else {
+            None
+        };
         let match_ = std::sync::Arc::new(Self {
             shadow: std::sync::Arc::new(parking_lot::Mutex::new(crate::shadow::Shadow::new(
                 &remote_rom,
@@ -110,11 +147,14 @@ impl Match {
             rng: tokio::sync::Mutex::new(rng),
             cancellation_token,
             match_type,
+            first_to,
             input_delay,
+            lockstep_remote_inputs,
             round_state: tokio::sync::Mutex::new(RoundState {
                 number: 0,
                 round: None,
                 last_outcome: Some(last_outcome),
+                score: Score::default(),
             }),
             is_offerer,
             primary_thread_handle,
@@ -144,9 +184,25 @@ impl Match {
 
     pub async fn run(&self, mut receiver: Box<dyn crate::net::Receiver + Send + Sync>) -> anyhow::Result<()> {
         let mut last_round_number = 0;
+        let mut last_received = None;
         loop {
             let input = receiver.receive().await?;
 
+            // Over an unreliable transport, inputs may arrive more than once or out of order. Anything at or before the
+            // last input we've received is stale.
+            let key = (input.round_number, input.local_tick);
+            if last_received.map(|last_received| key <= last_received).unwrap_or(false) {
+                log::debug!("dropping stale input: {:?}", input);
+                continue;
+            }
+            last_received = Some(key);
+
+            // In lockstep mode, the round pulls remote inputs itself when it needs them.
+            if let Some(lockstep_remote_inputs) = self.lockstep_remote_inputs.as_ref() {
+                lockstep_remote_inputs.push(input);
+                continue;
+            }
+
             // We need to wait for the next round to start to avoid dropping inputs on the floor.
             if input.round_number != last_round_number {
                 let round_number = if let Some(number) = self.round_started_rx.lock().await.recv().await {
@@ -217,6 +273,12 @@ impl Match {
         self.round_state.blocking_lock()
     }
 
+    /// Like lock_round_state, but gives up if the round state is in use. In lockstep mode, the emulator thread holds it
+    /// for as long as the opponent's inputs take to arrive, so anything that mustn't stall (e.g. the GUI) should use this.
+    pub fn try_lock_round_state(&self) -> Option<tokio::sync::MutexGuard<'_, RoundState>> {
+        self.round_state.try_lock().ok()
+    }
+
     pub fn lock_rng(&self) -> tokio::sync::MutexGuard<'_, rand_pcg::Mcg128Xsl64> {
         self.rng.blocking_lock()
     }
@@ -225,10 +287,19 @@ impl Match {
         self.match_type
     }
 
+    /// The number of round wins needed to take the series, if playing one.
+    pub fn first_to(&self) -> Option<u8> {
+        self.first_to
+    }
+
     pub fn is_offerer(&self) -> bool {
         self.is_offerer
     }
 
+    pub fn is_lockstep(&self) -> bool {
+        self.lockstep_remote_inputs.is_some()
+    }
+
     pub async fn start_round(self: &std::sync::Arc<Self>) -> anyhow::Result<()> {
         let mut round_state = self.round_state.lock().await;
         round_state.number += 1;
@@ -299,8 +370,12 @@ impl Match {
             on_replay_complete: self.on_replay_complete.clone(),
             last_local_input_time: now,
             last_remote_input_time: now,
+            lockstep_remote_inputs: self.lockstep_remote_inputs.clone(),
+            diagnostics: crate::diagnostics::RoundDiagnostics::default(),
         });
-        self.round_started_tx.send(round_state.number).await?;
+        if self.lockstep_remote_inputs.is_none() {
+            self.round_started_tx.send(round_state.number).await?;
+        }
         log::info!("round has started");
         Ok(())
     }
@@ -325,6 +400,8 @@ pub struct Round {
     on_replay_complete: std::sync::Arc<dyn Fn(&mut dyn std::io::Read) -> anyhow::Result<()> + Send + Sync>,
     last_local_input_time: std::time::Instant,
     last_remote_input_time: std::time::Instant,
+    lockstep_remote_inputs: Option<std::sync::Arc<crate::lockstep::RemoteInputQueue>>,
+    diagnostics: crate::diagnostics::RoundDiagnostics,
 }
 
 impl Round {
@@ -340,6 +417,10 @@ impl Round {
         self.local_player_index
     }
 
+    pub fn diagnostics(&self) -> &crate::diagnostics::RoundDiagnostics {
+        &self.diagnostics
+    }
+
     pub fn set_first_committed_state(
         &mut self,
         local_state: Box<mgba::state::State>,
@@ -366,6 +447,7 @@ impl Round {
         mut core: mgba::core::CoreMutRef<'_>,
         joyflags: u16,
     ) -> anyhow::Result<Option<BattleOutcome>> {
+        let start_time = std::time::Instant::now();
         let local_tick = self.current_tick + self.local_delay();
         let remote_tick = self.last_committed_remote_input.local_tick;
 
@@ -391,14 +473,32 @@ impl Round {
             .await?;
 
         let now = std::time::Instant::now();
+        let frame_time = now - self.last_local_input_time;
         self.add_local_input(crate::input::PartialInput {
             local_tick,
             remote_tick,
             joyflags,
-            dt: now - self.last_local_input_time,
+            dt: frame_time,
         });
         self.last_local_input_time = now;
 
+        if let Some(lockstep_remote_inputs) = self.lockstep_remote_inputs.clone() {
+            // Wait until we have every remote input we need, so nothing has to be predicted (and later rolled back).
+            while self.iq.remote_queue_length() + (self.iq.local_delay() as usize) < self.iq.local_queue_length() {
+                let input = lockstep_remote_inputs.pop(self.number).await?;
+                let now = std::time::Instant::now();
+                self.add_remote_input(crate::input::PartialInput {
+                    local_tick: input.local_tick,
+                    remote_tick: (input.local_tick as i64 + input.tick_diff as i64) as u32,
+                    joyflags: input.joyflags as u16,
+                    dt: now - self.last_remote_input_time,
+                });
+                self.last_remote_input_time = now;
+            }
+        }
+
+        let stall_time = start_time.elapsed();
+
         let (committable, predict_required) = self.iq.consume_and_peek_local();
 
         let last_committed_state = self.committed_state.take().expect("committed state");
@@ -433,7 +533,9 @@ impl Round {
             }))
             .collect::<Vec<crate::input::Pair<crate::input::PartialInput, crate::input::PartialInput>>>();
         let last_local_input = input_pairs.last().unwrap().local.clone();
+        let rollback_depth = input_pairs.len() as u32 - 1;
 
+        let ff_start_time = std::time::Instant::now();
         let ff_result = self.stepper.fastforward(
             &last_committed_state.state,
             input_pairs,
@@ -464,6 +566,7 @@ impl Round {
                 }
             }),
         )?;
+        let ff_time = ff_start_time.elapsed();
 
         for ip in &ff_result.output_pairs {
             if ip.local.local_tick >= commit_tick {
@@ -488,6 +591,13 @@ impl Round {
         self.committed_state = Some(ff_result.committed_state);
 
         self.dtick = last_local_input.lag() - self.last_committed_remote_input.lag();
+        self.diagnostics.mark_tick(
+            rollback_depth,
+            ff_time,
+            frame_time,
+            stall_time,
+            self.last_committed_remote_input.lag(),
+        );
 
         core.gba_mut().sync_mut().expect("set fps target").set_fps_target(
             match EXPECTED_FPS as f32 + self.tps_adjustment() {
@@ -0,0 +1,112 @@
+/// Per-round counters for how hard rollback is working.
+#[derive(Clone, Default, Debug)]
+pub struct RoundDiagnostics {
+    /// Number of local ticks processed.
+    pub ticks: u32,
+
+    /// Number of ticks that had to re-simulate at least one earlier tick.
+    pub rollbacks: u32,
+
+    /// Depth of the most recent rollback, in ticks.
+    pub last_rollback_depth: u32,
+
+    /// Deepest rollback seen this round, in ticks.
+    pub max_rollback_depth: u32,
+
+    /// Total number of re-simulated ticks.
+    pub rollback_ticks: u64,
+
+    /// Time spent in the fastforwarder.
+    pub fastforward_time: std::time::Duration,
+
+    /// Longest single fastforward.
+    pub max_fastforward_time: std::time::Duration,
+
+    /// Frames that were skipped because the primary core fell behind real time.
+    pub dropped_frames: u32,
+
+    /// Frames that had to wait on the network for longer than a frame.
+    pub stalled_frames: u32,
+
+    /// How far ahead the remote is, as last reported by the remote.
+    pub remote_lead: i32,
+
+    pub min_remote_lead: i32,
+    pub max_remote_lead: i32,
+}
+
+impl RoundDiagnostics {
+    pub fn mark_tick(
+        &mut self,
+        rollback_depth: u32,
+        fastforward_time: std::time::Duration,
+        frame_time: std::time::Duration,
+        stall_time: std::time::Duration,
+        remote_lead: i32,
+    ) {
+        let expected_frame_time = std::time::Duration::from_secs_f32(1.0 / crate::battle::EXPECTED_FPS);
+
+        if self.ticks == 0 {
+            self.min_remote_lead = remote_lead;
+            self.max_remote_lead = remote_lead;
+        } else {
+            // The first input of the round has no meaningful previous frame to measure against.
+            let frames = (frame_time.as_secs_f32() / expected_frame_time.as_secs_f32()).round() as u32;
+            self.dropped_frames += frames.saturating_sub(1);
+        }
+        self.ticks += 1;
+
+        if rollback_depth > 0 {
+            self.rollbacks += 1;
+        }
+        self.last_rollback_depth = rollback_depth;
+        self.max_rollback_depth = self.max_rollback_depth.max(rollback_depth);
+        self.rollback_ticks += rollback_depth as u64;
+
+        self.fastforward_time += fastforward_time;
+        self.max_fastforward_time = self.max_fastforward_time.max(fastforward_time);
+
+        if stall_time > expected_frame_time {
+            self.stalled_frames += 1;
+        }
+
+        self.remote_lead = remote_lead;
+        self.min_remote_lead = self.min_remote_lead.min(remote_lead);
+        self.max_remote_lead = self.max_remote_lead.max(remote_lead);
+    }
+
+    pub fn mean_rollback_depth(&self) -> f32 {
+        if self.ticks == 0 {
+            return 0.0;
+        }
+        self.rollback_ticks as f32 / self.ticks as f32
+    }
+
+    pub fn mean_fastforward_time(&self) -> std::time::Duration {
+        if self.ticks == 0 {
+            return std::time::Duration::ZERO;
+        }
+        self.fastforward_time / self.ticks
+    }
+}
+
+impl std::fmt::Display for RoundDiagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ticks = {}, rollbacks = {} (mean depth = {:.2}, max depth = {}), fastforward = {:?} (mean = {:?}, max = {:?}), dropped frames = {}, stalled frames = {}, remote lead = {} ({}..={})",
+            self.ticks,
+            self.rollbacks,
+            self.mean_rollback_depth(),
+            self.max_rollback_depth,
+            self.fastforward_time,
+            self.mean_fastforward_time(),
+            self.max_fastforward_time,
+            self.dropped_frames,
+            self.stalled_frames,
+            self.remote_lead,
+            self.min_remote_lead,
+            self.max_remote_lead,
+        )
+    }
+}
@@ -1,8 +1,10 @@
 pub mod battle;
+pub mod diagnostics;
 pub mod eval;
 pub mod game;
 pub mod hooks;
 pub mod input;
+pub mod lockstep;
 pub mod net;
 pub mod replay;
 pub mod shadow;
@@ -0,0 +1,55 @@
+/// Remote inputs waiting to be consumed by a lockstep round.
+///
+/// In lockstep mode we never predict remote input: the primary core blocks on this until the remote input for the
+/// tick it's about to step has arrived. The primary core holds the round state lock while it waits, so inputs are
+/// queued here straight from the receive loop instead of going through the round state.
+pub struct RemoteInputQueue {
+    queue: parking_lot::Mutex<std::collections::VecDeque<crate::net::Input>>,
+    notify: tokio::sync::Notify,
+    cancellation_token: tokio_util::sync::CancellationToken,
+}
+
+impl RemoteInputQueue {
+    pub fn new(cancellation_token: tokio_util::sync::CancellationToken) -> Self {
+        Self {
+            queue: parking_lot::Mutex::new(std::collections::VecDeque::new()),
+            notify: tokio::sync::Notify::new(),
+            cancellation_token,
+        }
+    }
+
+    pub fn push(&self, input: crate::net::Input) {
+        self.queue.lock().push_back(input);
+        self.notify.notify_one();
+    }
+
+    pub async fn pop(&self, round_number: u8) -> anyhow::Result<crate::net::Input> {
+        loop {
+            {
+                let mut queue = self.queue.lock();
+                while let Some(input) = queue.pop_front() {
+                    if input.round_number == round_number {
+                        return Ok(input);
+                    }
+
+                    if input.round_number > round_number {
+                        anyhow::bail!(
+                            "remote is on round {} but we are still on round {}",
+                            input.round_number,
+                            round_number
+                        );
+                    }
+
+                    log::info!("dropping input from old round {}", input.round_number);
+                }
+            }
+
+            tokio::select! {
+                _ = self.notify.notified() => {}
+                _ = self.cancellation_token.cancelled() => {
+                    anyhow::bail!("cancelled while waiting for remote input");
+                }
+            }
+        }
+    }
+}
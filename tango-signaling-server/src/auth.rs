@@ -0,0 +1,114 @@
+use hmac::Mac;
+use jwt::{SignWithKey, VerifyWithKey};
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Claims {
+    /// Who the token was issued to, for logging.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    pub iat: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+    /// If set, only these game families may be played.
+    ///
+    /// This is enforced wherever the server sees the family for itself: queued matches and listed lobbies. A private
+    /// session with a link code is only checked against the family the client says it's playing, so there it's
+    /// advisory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub families: Option<Vec<String>>,
+}
+
+impl Claims {
+    pub fn allows_family(&self, family: Option<&str>) -> bool {
+        let families = if let Some(families) = self.families.as_ref() {
+            families
+        } else {
+            return true;
+        };
+
+        if let Some(family) = family {
+            families.iter().any(|f| f == family)
+        } else {
+            false
+        }
+    }
+}
+
+/// How long a queued match's family is remembered for, which is how long its players have to start signaling.
+const SESSION_FAMILY_TTL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// The game family of each session the server has seen the family of for itself, rather than taking a client's word
+/// for it.
+pub struct SessionFamilies {
+    families: std::sync::Mutex<std::collections::HashMap<String, (String, std::time::Instant)>>,
+}
+
+impl SessionFamilies {
+    pub fn new() -> Self {
+        Self {
+            families: std::sync::Mutex::new(std::collections::HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, session_id: &str, family: &str) {
+        let mut families = self.families.lock().unwrap();
+        families.retain(|_, (_, recorded_at)| recorded_at.elapsed() < SESSION_FAMILY_TTL);
+        families.insert(session_id.to_string(), (family.to_string(), std::time::Instant::now()));
+    }
+
+    pub fn get(&self, session_id: &str) -> Option<String> {
+        self.families
+            .lock()
+            .unwrap()
+            .get(session_id)
+            .filter(|(_, recorded_at)| recorded_at.elapsed() < SESSION_FAMILY_TTL)
+            .map(|(family, _)| family.clone())
+    }
+}
+
+/// Issues and verifies HS256 tokens signed with a shared secret.
+pub struct Authenticator {
+    key: hmac::Hmac<sha2::Sha256>,
+}
+
+impl Authenticator {
+    pub fn new(secret: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            key: hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes())?,
+        })
+    }
+
+    pub fn issue(&self, claims: &Claims) -> anyhow::Result<String> {
+        Ok(claims.sign_with_key(&self.key)?)
+    }
+
+    /// Verifies the token's signature and expiry. Family restrictions are left to the caller, as the family isn't
+    /// always known up front.
+    pub fn verify(&self, token: &str) -> anyhow::Result<Claims> {
+        let claims: Claims = token.verify_with_key(&self.key)?;
+        if let Some(exp) = claims.exp {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            if now >= exp {
+                anyhow::bail!("token expired at {}", exp);
+            }
+        }
+        Ok(claims)
+    }
+}
+
+/// Compares tokens in constant time, so a token can't be worked out a byte at a time from how long checking it takes.
+pub fn tokens_equal(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Pulls the token out of an `Authorization: Bearer <token>` header.
+pub fn get_bearer_token(request: &hyper::Request<hyper::Body>) -> Option<&str> {
+    request
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim())
+}
@@ -1,3 +1,5 @@
+pub mod coturn;
+pub mod file;
 pub mod metered;
 pub mod opentok;
 pub mod twilio;
@@ -0,0 +1,59 @@
+use hmac::Mac;
+
+/// Generates credentials for a self-hosted coturn server using its shared secret REST API scheme, i.e. with
+/// `use-auth-secret` and `static-auth-secret` set.
+pub struct Backend {
+    shared_secret: String,
+    urls: Vec<String>,
+    ttl: std::time::Duration,
+}
+
+impl Backend {
+    pub fn new(shared_secret: String, urls: Vec<String>, ttl: std::time::Duration) -> Self {
+        Self {
+            shared_secret,
+            urls,
+            ttl,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Backend for Backend {
+    async fn get(
+        &self,
+        _remote_ip: &std::net::IpAddr,
+    ) -> anyhow::Result<Vec<tango_signaling::proto::signaling::packet::hello::IceServer>> {
+        let expiry = (std::time::SystemTime::now() + self.ttl).duration_since(std::time::UNIX_EPOCH)?;
+
+        // The username is the expiry timestamp, optionally followed by anything: coturn only cares about the timestamp.
+        let username = format!("{}:tango", expiry.as_secs());
+
+        let mut mac = hmac::Hmac::<sha1::Sha1>::new_from_slice(self.shared_secret.as_bytes())?;
+        mac.update(username.as_bytes());
+        let credential = base64::encode(mac.finalize().into_bytes());
+
+        let (turn_urls, stun_urls): (Vec<_>, Vec<_>) = self
+            .urls
+            .iter()
+            .cloned()
+            .partition(|url| url.starts_with("turn:") || url.starts_with("turns:"));
+
+        let mut ice_servers = vec![];
+        if !stun_urls.is_empty() {
+            ice_servers.push(tango_signaling::proto::signaling::packet::hello::IceServer {
+                credential: None,
+                username: None,
+                urls: stun_urls,
+            });
+        }
+        if !turn_urls.is_empty() {
+            ice_servers.push(tango_signaling::proto::signaling::packet::hello::IceServer {
+                credential: Some(credential),
+                username: Some(username),
+                urls: turn_urls,
+            });
+        }
+        Ok(ice_servers)
+    }
+}
@@ -0,0 +1,153 @@
+#[derive(serde::Deserialize, Clone)]
+struct ICEServer {
+    urls: Vec<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    credential: Option<String>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum IPFamily {
+    IPv4,
+    IPv6,
+}
+
+#[derive(serde::Deserialize)]
+struct RawServerSet {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    networks: Vec<String>,
+    #[serde(default)]
+    ip_family: Option<IPFamily>,
+    ice_servers: Vec<ICEServer>,
+}
+
+#[derive(serde::Deserialize)]
+struct RawConfig {
+    #[serde(default)]
+    server_sets: Vec<RawServerSet>,
+    default: Vec<ICEServer>,
+}
+
+struct ServerSet {
+    name: String,
+    networks: Vec<ipnet::IpNet>,
+    ip_family: Option<IPFamily>,
+    ice_servers: Vec<ICEServer>,
+}
+
+impl ServerSet {
+    fn matches(&self, ip: &std::net::IpAddr) -> bool {
+        if let Some(ip_family) = self.ip_family {
+            if (ip_family == IPFamily::IPv4) != ip.is_ipv4() {
+                return false;
+            }
+        }
+        self.networks.is_empty() || self.networks.iter().any(|network| network.contains(ip))
+    }
+}
+
+struct Config {
+    server_sets: Vec<ServerSet>,
+    default: Vec<ICEServer>,
+}
+
+impl Config {
+    fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        let raw: RawConfig = serde_json::from_reader(std::fs::File::open(path)?)?;
+        Ok(Self {
+            server_sets: raw
+                .server_sets
+                .into_iter()
+                .map(|server_set| {
+                    Ok(ServerSet {
+                        networks: server_set
+                            .networks
+                            .iter()
+                            .map(|network| {
+                                network
+                                    .parse()
+                                    .map_err(|e| anyhow::format_err!("invalid network {}: {}", network, e))
+                            })
+                            .collect::<anyhow::Result<Vec<_>>>()?,
+                        name: server_set.name,
+                        ip_family: server_set.ip_family,
+                        ice_servers: server_set.ice_servers,
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            default: raw.default,
+        })
+    }
+}
+
+/// ICE servers loaded from a JSON file.
+///
+/// Server sets are tried in order, and the first one whose networks (if any) contain the client's IP and whose IP family
+/// (if any) matches is used. If none match, the default servers are used. For example:
+///
+/// ```json
+/// {
+///     "server_sets": [
+///         {
+///             "name": "eu",
+///             "networks": ["2a02::/16", "5.0.0.0/8"],
+///             "ice_servers": [{ "urls": ["turn:eu.example.com:3478"], "username": "tango", "credential": "hunter2" }]
+///         },
+///         { "name": "ipv6", "ip_family": "ipv6", "ice_servers": [{ "urls": ["stun:v6.example.com:3478"] }] }
+///     ],
+///     "default": [{ "urls": ["stun:stun.example.com:3478"] }]
+/// }
+/// ```
+///
+/// Cloning shares the loaded configuration, so a clone can be kept around to reload it.
+#[derive(Clone)]
+pub struct Backend {
+    path: std::path::PathBuf,
+    config: std::sync::Arc<std::sync::RwLock<Config>>,
+}
+
+impl Backend {
+    pub fn new(path: std::path::PathBuf) -> anyhow::Result<Self> {
+        let config = Config::load(&path)?;
+        Ok(Self {
+            path,
+            config: std::sync::Arc::new(std::sync::RwLock::new(config)),
+        })
+    }
+
+    /// Reloads the file. If it can't be loaded, the previous configuration is kept.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let config = Config::load(&self.path)?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Backend for Backend {
+    async fn get(
+        &self,
+        remote_ip: &std::net::IpAddr,
+    ) -> anyhow::Result<Vec<tango_signaling::proto::signaling::packet::hello::IceServer>> {
+        let config = self.config.read().unwrap();
+        let ice_servers = if let Some(server_set) = config.server_sets.iter().find(|s| s.matches(remote_ip)) {
+            log::debug!("using ice server set {} for {}", server_set.name, remote_ip);
+            &server_set.ice_servers
+        } else {
+            &config.default
+        };
+
+        Ok(ice_servers
+            .iter()
+            .map(|ice_server| tango_signaling::proto::signaling::packet::hello::IceServer {
+                credential: ice_server.credential.clone(),
+                username: ice_server.username.clone(),
+                urls: ice_server.urls.clone(),
+            })
+            .collect())
+    }
+}
@@ -1,6 +1,11 @@
+mod auth;
 mod httputil;
 mod iceconfig;
 mod matchmaking;
+mod metrics;
+mod queue;
+mod ratelimit;
+mod relay;
 use envconfig::Envconfig;
 use prost::Message;
 use routerify::ext::RequestExt;
@@ -34,11 +39,206 @@ struct Config {
 
     #[envconfig(from = "METERED_API_KEY", default = "")]
     metered_api_key: String,
+
+    #[envconfig(from = "COTURN_SHARED_SECRET", default = "")]
+    coturn_shared_secret: String,
+
+    // Comma-separated, e.g. "stun:turn.example.com:3478,turn:turn.example.com:3478?transport=udp".
+    #[envconfig(from = "COTURN_URLS", default = "")]
+    coturn_urls: String,
+
+    #[envconfig(from = "COTURN_CREDENTIAL_TTL_SECS", default = "86400")]
+    coturn_credential_ttl_secs: u64,
+
+    // Reloaded on SIGHUP.
+    #[envconfig(from = "ICECONFIG_FILE", default = "")]
+    iceconfig_file: String,
+
+    #[envconfig(from = "QUEUE_MAX_TIMEOUT_SECS", default = "300")]
+    queue_max_timeout_secs: u64,
+
+    // For all of the limits below, 0 means unlimited.
+    #[envconfig(from = "CONNECTIONS_PER_IP_PER_MINUTE", default = "30")]
+    connections_per_ip_per_minute: u32,
+
+    #[envconfig(from = "SESSIONS_PER_IP_PER_MINUTE", default = "10")]
+    sessions_per_ip_per_minute: u32,
+
+    #[envconfig(from = "MAX_PENDING_SESSIONS_PER_IP", default = "4")]
+    max_pending_sessions_per_ip: usize,
+
+    #[envconfig(from = "MAX_PENDING_SESSIONS", default = "10000")]
+    max_pending_sessions: usize,
+
+    // If set, clients must present a token signed with this secret. Tokens are issued with the issue-token subcommand.
+    #[envconfig(from = "AUTH_SECRET", default = "")]
+    auth_secret: String,
+
+    // Relays game data through this server for players who can't connect to each other directly. This can use a lot of
+    // bandwidth, so it's off by default.
+    #[envconfig(from = "RELAY_ENABLED", default = "false")]
+    relay_enabled: bool,
+
+    // Per player, 0 means unlimited.
+    #[envconfig(from = "RELAY_BYTES_PER_SEC", default = "32768")]
+    relay_bytes_per_sec: u32,
+
+    // Counting each player separately, 0 means unlimited.
+    #[envconfig(from = "MAX_RELAY_CONNECTIONS", default = "200")]
+    max_relay_connections: usize,
+
+    #[envconfig(from = "RELAY_WAIT_TIMEOUT_SECS", default = "30")]
+    relay_wait_timeout_secs: u64,
+
+    // Lets hosts list their sessions publicly for anyone to join.
+    #[envconfig(from = "LOBBY_LISTING_ENABLED", default = "false")]
+    lobby_listing_enabled: bool,
+
+    // Enables the admin API under /admin for requests bearing this token. Off if empty.
+    #[envconfig(from = "ADMIN_TOKEN", default = "")]
+    admin_token: String,
+
+    // A JSON object of language tags to notices, e.g. {"en-US": "This server is moving on June 1."}, sent to everyone
+    // connecting.
+    #[envconfig(from = "NOTICE_FILE", default = "")]
+    notice_file: String,
+
+    // Clients older than this are told to update, but can still connect.
+    #[envconfig(from = "MIN_RECOMMENDED_CLIENT_VERSION", default = "")]
+    min_recommended_client_version: String,
 }
 
 struct State {
     real_ip_getter: httputil::RealIPGetter,
     matchmaking_server: std::sync::Arc<matchmaking::Server>,
+    queue_server: std::sync::Arc<queue::Server>,
+    relay_server: Option<std::sync::Arc<relay::Server>>,
+    metrics: std::sync::Arc<metrics::Metrics>,
+    connection_limiter: ratelimit::Limiter,
+    authenticator: Option<auth::Authenticator>,
+    lobby_listing: bool,
+    admin_token: Option<String>,
+    /// IPs that may not create or join sessions, with the message to turn them away with. Only kept until the server
+    /// restarts.
+    bans: std::sync::Arc<std::sync::RwLock<std::collections::BTreeMap<std::net::IpAddr, String>>>,
+}
+
+/// The longest message the admin API will take, whether to broadcast or to kick or ban with.
+const MAX_ADMIN_MESSAGE_BYTES: usize = 1024;
+
+fn make_abort_response(
+    status: hyper::StatusCode,
+    reason: tango_signaling::proto::signaling::packet::abort::Reason,
+) -> hyper::Response<hyper::Body> {
+    make_abort_response_with_message(status, reason, String::new())
+}
+
+fn make_abort_response_with_message(
+    status: hyper::StatusCode,
+    reason: tango_signaling::proto::signaling::packet::abort::Reason,
+    message: String,
+) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(status)
+        .body(hyper::Body::from(
+            tango_signaling::proto::signaling::packet::Abort {
+                reason: reason as i32,
+                message,
+            }
+            .encode_to_vec(),
+        ))
+        .unwrap()
+}
+
+/// Checks the request's token if the server requires one, returning its claims or the response to reject it with.
+fn authenticate(
+    state: &State,
+    request: &hyper::Request<hyper::Body>,
+    remote_ip: &std::net::IpAddr,
+) -> Result<Option<auth::Claims>, hyper::Response<hyper::Body>> {
+    let authenticator = if let Some(authenticator) = state.authenticator.as_ref() {
+        authenticator
+    } else {
+        return Ok(None);
+    };
+
+    let r = if let Some(token) = auth::get_bearer_token(request) {
+        authenticator.verify(token)
+    } else {
+        Err(anyhow::format_err!("no token"))
+    };
+
+    match r {
+        Ok(claims) => Ok(Some(claims)),
+        Err(e) => {
+            log::warn!("rejecting connection from {}: {}", remote_ip, e);
+            state
+                .metrics
+                .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::Unauthorized);
+            Err(make_abort_response(
+                hyper::StatusCode::UNAUTHORIZED,
+                tango_signaling::proto::signaling::packet::abort::Reason::Unauthorized,
+            ))
+        }
+    }
+}
+
+/// Checks whether the remote IP is banned, returning the response to reject it with if so.
+fn check_ban(state: &State, remote_ip: &std::net::IpAddr) -> Result<(), hyper::Response<hyper::Body>> {
+    let ban_message = if let Some(ban_message) = state.bans.read().unwrap().get(remote_ip).cloned() {
+        ban_message
+    } else {
+        return Ok(());
+    };
+
+    log::warn!("rejecting connection from banned {}", remote_ip);
+    state
+        .metrics
+        .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::Banned);
+    Err(make_abort_response_with_message(
+        hyper::StatusCode::FORBIDDEN,
+        tango_signaling::proto::signaling::packet::abort::Reason::Banned,
+        ban_message,
+    ))
+}
+
+/// Checks the request's admin token, returning the response to reject it with if the admin API is off or the token is
+/// wrong.
+fn authenticate_admin(
+    state: &State,
+    request: &hyper::Request<hyper::Body>,
+) -> Result<(), hyper::Response<hyper::Body>> {
+    let admin_token = if let Some(admin_token) = state.admin_token.as_ref() {
+        admin_token
+    } else {
+        return Err(hyper::Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body(hyper::Body::from("admin api not enabled"))
+            .unwrap());
+    };
+
+    if !auth::get_bearer_token(request)
+        .map(|token| auth::tokens_equal(token, admin_token))
+        .unwrap_or(false)
+    {
+        log::warn!(
+            "rejecting admin request from {:?}",
+            state.real_ip_getter.get_remote_real_ip(request)
+        );
+        return Err(hyper::Response::builder()
+            .status(hyper::StatusCode::UNAUTHORIZED)
+            .body(hyper::Body::from("unauthorized"))
+            .unwrap());
+    }
+    Ok(())
+}
+
+fn make_json_response(value: &impl serde::Serialize) -> anyhow::Result<hyper::Response<hyper::Body>> {
+    Ok(hyper::Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(hyper::Body::from(serde_json::to_vec(value)?))
+        .unwrap())
 }
 
 async fn handle_healthcheck_request(
@@ -50,7 +250,44 @@ async fn handle_healthcheck_request(
         .unwrap());
 }
 
-pub const EXPECTED_PROTOCOL_VERSION: u8 = 0x3a;
+async fn handle_metrics_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+
+    let mut out = String::new();
+    metrics::write_gauge(
+        &mut out,
+        "tango_signaling_active_sessions",
+        "Sessions waiting for a second player.",
+        state.matchmaking_server.num_sessions().await,
+    );
+    metrics::write_gauge(
+        &mut out,
+        "tango_signaling_queue_waiting",
+        "Players waiting in the queue for an opponent.",
+        state.queue_server.num_waiting().await,
+    );
+    if let Some(relay_server) = state.relay_server.as_ref() {
+        metrics::write_gauge(
+            &mut out,
+            "tango_signaling_active_relays",
+            "Players whose game data is being relayed.",
+            relay_server.num_active(),
+        );
+    }
+    state.metrics.render(&mut out);
+
+    Ok(hyper::Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(hyper::Body::from(out))
+        .unwrap())
+}
+
+/// The oldest client protocol version we will match. Whether two clients can actually talk to each other is up to the
+/// overlap of their supported versions.
+pub const MIN_PROTOCOL_VERSION: u32 = 0x3c;
 
 async fn handle_matchmaking_request(
     mut request: hyper::Request<hyper::Body>,
@@ -69,6 +306,50 @@ async fn handle_matchmaking_request(
             .unwrap());
     };
 
+    let state = request.data::<State>().unwrap();
+    if let Err(response) = check_ban(state, &remote_ip) {
+        return Ok(response);
+    }
+
+    if !state.connection_limiter.check(&remote_ip) {
+        log::warn!("rate limiting connection from {}", remote_ip);
+        state
+            .metrics
+            .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::RateLimited);
+        return Ok(make_abort_response(
+            hyper::StatusCode::TOO_MANY_REQUESTS,
+            tango_signaling::proto::signaling::packet::abort::Reason::RateLimited,
+        ));
+    }
+
+    let claims = match authenticate(state, &request, &remote_ip) {
+        Ok(claims) => claims,
+        Err(response) => {
+            return Ok(response);
+        }
+    };
+    if let Some(claims) = claims.as_ref() {
+        let family = request
+            .headers()
+            .get("X-Tango-Game-Family")
+            .and_then(|v| v.to_str().ok());
+        if !claims.allows_family(family) {
+            log::warn!(
+                "rejecting connection from {}: {:?} may not play {:?}",
+                remote_ip,
+                claims.sub,
+                family
+            );
+            state
+                .metrics
+                .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::Forbidden);
+            return Ok(make_abort_response(
+                hyper::StatusCode::FORBIDDEN,
+                tango_signaling::proto::signaling::packet::abort::Reason::Forbidden,
+            ));
+        }
+    }
+
     let session_id = if let Some(session_id) = request.uri().query().and_then(|query| {
         url::form_urlencoded::parse(query.as_bytes())
             .into_owned()
@@ -77,11 +358,17 @@ async fn handle_matchmaking_request(
     }) {
         session_id
     } else {
+        request
+            .data::<State>()
+            .unwrap()
+            .metrics
+            .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::MissingSessionId);
         return Ok(hyper::Response::builder()
             .status(hyper::StatusCode::BAD_REQUEST)
             .body(hyper::Body::from(
                 tango_signaling::proto::signaling::packet::Abort {
                     reason: tango_signaling::proto::signaling::packet::abort::Reason::MissingSessionId as i32,
+                    ..Default::default()
                 }
                 .encode_to_vec(),
             ))
@@ -94,16 +381,18 @@ async fn handle_matchmaking_request(
         .and_then(|v| v.to_str().ok())
         .and_then(|v| u32::from_str_radix(v, 16).ok());
     if let Some(protocol_version) = protocol_version {
-        if protocol_version as u8 != EXPECTED_PROTOCOL_VERSION {
+        if protocol_version < MIN_PROTOCOL_VERSION {
+            request
+                .data::<State>()
+                .unwrap()
+                .metrics
+                .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooOld);
             return Ok(hyper::Response::builder()
                 .status(hyper::StatusCode::BAD_REQUEST)
                 .body(hyper::Body::from(
                     tango_signaling::proto::signaling::packet::Abort {
-                        reason: if (protocol_version as u8) < EXPECTED_PROTOCOL_VERSION {
-                            tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooOld
-                        } else {
-                            tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooNew
-                        } as i32,
+                        reason: tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooOld as i32,
+                        ..Default::default()
                     }
                     .encode_to_vec(),
                 ))
@@ -112,11 +401,17 @@ async fn handle_matchmaking_request(
     }
 
     if !hyper_tungstenite::is_upgrade_request(&request) {
+        request
+            .data::<State>()
+            .unwrap()
+            .metrics
+            .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::NotUpgrade);
         return Ok(hyper::Response::builder()
             .status(hyper::StatusCode::BAD_REQUEST)
             .body(hyper::Body::from(
                 tango_signaling::proto::signaling::packet::Abort {
                     reason: tango_signaling::proto::signaling::packet::abort::Reason::NotUpgrade as i32,
+                    ..Default::default()
                 }
                 .encode_to_vec(),
             ))
@@ -133,41 +428,595 @@ async fn handle_matchmaking_request(
     )?;
 
     let matchmaking_server = request.data::<State>().unwrap().matchmaking_server.clone();
+    let metrics = request.data::<State>().unwrap().metrics.clone();
     tokio::spawn(async move {
+        let _guard = metrics.track_connection();
         let websocket = match websocket.await {
             Ok(websocket) => websocket,
             Err(e) => {
                 log::error!("error in websocket connection: {}", e);
+                metrics.record_websocket_error();
                 return;
             }
         };
 
         if let Err(e) = matchmaking_server
-            .handle_stream(websocket, remote_ip, &session_id)
+            .handle_stream(websocket, remote_ip, &session_id, claims)
             .await
         {
             log::error!("error in websocket connection: {}", e);
+            metrics.record_websocket_error();
+        }
+    });
+
+    Ok(response)
+}
+
+async fn handle_queue_request(
+    mut request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+    let remote_ip = if let Some(remote_ip) = state.real_ip_getter.get_remote_real_ip(&request) {
+        remote_ip
+    } else {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(hyper::Body::from("internal error"))
+            .unwrap());
+    };
+
+    if let Err(response) = check_ban(state, &remote_ip) {
+        return Ok(response);
+    }
+
+    if !state.connection_limiter.check(&remote_ip) {
+        log::warn!("rate limiting queue connection from {}", remote_ip);
+        state
+            .metrics
+            .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::RateLimited);
+        return Ok(make_abort_response(
+            hyper::StatusCode::TOO_MANY_REQUESTS,
+            tango_signaling::proto::signaling::packet::abort::Reason::RateLimited,
+        ));
+    }
+
+    // Family restrictions are checked once the client says what it wants to play.
+    let claims = match authenticate(state, &request, &remote_ip) {
+        Ok(claims) => claims,
+        Err(response) => {
+            return Ok(response);
+        }
+    };
+
+    if !hyper_tungstenite::is_upgrade_request(&request) {
+        request
+            .data::<State>()
+            .unwrap()
+            .metrics
+            .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::NotUpgrade);
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(hyper::Body::from(
+                tango_signaling::proto::signaling::packet::Abort {
+                    reason: tango_signaling::proto::signaling::packet::abort::Reason::NotUpgrade as i32,
+                    ..Default::default()
+                }
+                .encode_to_vec(),
+            ))
+            .unwrap());
+    }
+
+    let (response, websocket) = hyper_tungstenite::upgrade(
+        &mut request,
+        Some(tungstenite::protocol::WebSocketConfig {
+            max_message_size: Some(64 * 1024),
+            max_frame_size: Some(64 * 1024),
+            ..Default::default()
+        }),
+    )?;
+
+    let queue_server = request.data::<State>().unwrap().queue_server.clone();
+    let metrics = request.data::<State>().unwrap().metrics.clone();
+    tokio::spawn(async move {
+        let _guard = metrics.track_connection();
+        let websocket = match websocket.await {
+            Ok(websocket) => websocket,
+            Err(e) => {
+                log::error!("error in websocket connection: {}", e);
+                metrics.record_websocket_error();
+                return;
+            }
+        };
+
+        if let Err(e) = queue_server.handle_stream(websocket, claims).await {
+            log::error!("error in queue websocket connection: {}", e);
+            metrics.record_websocket_error();
+        }
+    });
+
+    Ok(response)
+}
+
+async fn handle_lobbies_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+    if !state.lobby_listing {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body(hyper::Body::from("lobby listing not enabled"))
+            .unwrap());
+    }
+
+    let remote_ip = if let Some(remote_ip) = state.real_ip_getter.get_remote_real_ip(&request) {
+        remote_ip
+    } else {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(hyper::Body::from("internal error"))
+            .unwrap());
+    };
+
+    if let Err(response) = check_ban(state, &remote_ip) {
+        return Ok(response);
+    }
+
+    if !state.connection_limiter.check(&remote_ip) {
+        log::warn!("rate limiting lobby list request from {}", remote_ip);
+        state
+            .metrics
+            .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::RateLimited);
+        return Ok(make_abort_response(
+            hyper::StatusCode::TOO_MANY_REQUESTS,
+            tango_signaling::proto::signaling::packet::abort::Reason::RateLimited,
+        ));
+    }
+
+    let claims = match authenticate(state, &request, &remote_ip) {
+        Ok(claims) => claims,
+        Err(response) => {
+            return Ok(response);
+        }
+    };
+
+    let family = request.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .find(|(k, _)| k == "family")
+            .map(|(_, v)| v)
+    });
+
+    let lobby_list = state
+        .matchmaking_server
+        .list_lobbies(|listing| {
+            family.as_ref().map(|family| *family == listing.family).unwrap_or(true)
+                && claims
+                    .as_ref()
+                    .map(|claims| claims.allows_family(Some(&listing.family)))
+                    .unwrap_or(true)
+        })
+        .await;
+
+    Ok(hyper::Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header("Content-Type", "application/x-protobuf")
+        .body(hyper::Body::from(lobby_list.encode_to_vec()))
+        .unwrap())
+}
+
+async fn handle_relay_request(
+    mut request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+    let relay_server = if let Some(relay_server) = state.relay_server.clone() {
+        relay_server
+    } else {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body(hyper::Body::from("relay not enabled"))
+            .unwrap());
+    };
+
+    let remote_ip = if let Some(remote_ip) = state.real_ip_getter.get_remote_real_ip(&request) {
+        remote_ip
+    } else {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            .body(hyper::Body::from("internal error"))
+            .unwrap());
+    };
+
+    if let Err(response) = check_ban(state, &remote_ip) {
+        return Ok(response);
+    }
+
+    if !state.connection_limiter.check(&remote_ip) {
+        log::warn!("rate limiting relay connection from {}", remote_ip);
+        state
+            .metrics
+            .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::RateLimited);
+        return Ok(make_abort_response(
+            hyper::StatusCode::TOO_MANY_REQUESTS,
+            tango_signaling::proto::signaling::packet::abort::Reason::RateLimited,
+        ));
+    }
+
+    if let Err(response) = authenticate(state, &request, &remote_ip) {
+        return Ok(response);
+    }
+
+    let query = request
+        .uri()
+        .query()
+        .map(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect::<std::collections::HashMap<_, _>>()
+        })
+        .unwrap_or_default();
+
+    let session_id = if let Some(session_id) = query.get("session_id").cloned() {
+        session_id
+    } else {
+        state
+            .metrics
+            .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::MissingSessionId);
+        return Ok(make_abort_response(
+            hyper::StatusCode::BAD_REQUEST,
+            tango_signaling::proto::signaling::packet::abort::Reason::MissingSessionId,
+        ));
+    };
+
+    // Only the two sides that signaled each other through this server are given the session's token, so nobody else
+    // can take either side's place.
+    if !relay_server.check_token(
+        &session_id,
+        query.get("relay_token").map(|token| token.as_str()).unwrap_or(""),
+    ) {
+        log::warn!(
+            "refusing relay connection from {} for {}: bad token",
+            remote_ip,
+            session_id
+        );
+        state
+            .metrics
+            .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::Forbidden);
+        return Ok(make_abort_response(
+            hyper::StatusCode::FORBIDDEN,
+            tango_signaling::proto::signaling::packet::abort::Reason::Forbidden,
+        ));
+    }
+
+    if !hyper_tungstenite::is_upgrade_request(&request) {
+        state
+            .metrics
+            .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::NotUpgrade);
+        return Ok(make_abort_response(
+            hyper::StatusCode::BAD_REQUEST,
+            tango_signaling::proto::signaling::packet::abort::Reason::NotUpgrade,
+        ));
+    }
+
+    let (response, websocket) = hyper_tungstenite::upgrade(
+        &mut request,
+        Some(tungstenite::protocol::WebSocketConfig {
+            max_message_size: Some(64 * 1024),
+            max_frame_size: Some(64 * 1024),
+            ..Default::default()
+        }),
+    )?;
+
+    let metrics = request.data::<State>().unwrap().metrics.clone();
+    tokio::spawn(async move {
+        let _guard = metrics.track_connection();
+        let websocket = match websocket.await {
+            Ok(websocket) => websocket,
+            Err(e) => {
+                log::error!("error in websocket connection: {}", e);
+                metrics.record_websocket_error();
+                return;
+            }
+        };
+
+        if let Err(e) = relay_server.handle_stream(websocket, &session_id).await {
+            log::error!("error in relay websocket connection: {}", e);
+            metrics.record_websocket_error();
         }
     });
 
     Ok(response)
 }
 
+/// Reads a message for players out of an admin request's body, returning the response to reject it with if it's too
+/// long or not text.
+async fn read_admin_message(body: hyper::Body) -> Result<String, hyper::Response<hyper::Body>> {
+    let body = if let Ok(body) = hyper::body::to_bytes(body).await {
+        body
+    } else {
+        return Err(hyper::Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(hyper::Body::from("couldn't read body"))
+            .unwrap());
+    };
+
+    if body.len() > MAX_ADMIN_MESSAGE_BYTES {
+        return Err(hyper::Response::builder()
+            .status(hyper::StatusCode::PAYLOAD_TOO_LARGE)
+            .body(hyper::Body::from("message too long"))
+            .unwrap());
+    }
+
+    if let Ok(message) = String::from_utf8(body.to_vec()) {
+        Ok(message.trim().to_string())
+    } else {
+        Err(hyper::Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(hyper::Body::from("message is not utf-8"))
+            .unwrap())
+    }
+}
+
+async fn handle_admin_sessions_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+    if let Err(response) = authenticate_admin(state, &request) {
+        return Ok(response);
+    }
+
+    make_json_response(&state.matchmaking_server.pending_sessions().await)
+}
+
+/// The request body, if any, is shown to the kicked player.
+async fn handle_admin_kick_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+    if let Err(response) = authenticate_admin(state, &request) {
+        return Ok(response);
+    }
+
+    let session_id = request.param("session_id").unwrap().clone();
+    let matchmaking_server = state.matchmaking_server.clone();
+    let message = match read_admin_message(request.into_body()).await {
+        Ok(message) => message,
+        Err(response) => {
+            return Ok(response);
+        }
+    };
+
+    if !matchmaking_server.kick(&session_id, message).await {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body(hyper::Body::from("no such session"))
+            .unwrap());
+    }
+
+    Ok(hyper::Response::builder()
+        .status(hyper::StatusCode::OK)
+        .body(hyper::Body::from("ok"))
+        .unwrap())
+}
+
+async fn handle_admin_bans_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+    if let Err(response) = authenticate_admin(state, &request) {
+        return Ok(response);
+    }
+
+    let bans = state.bans.read().unwrap().clone();
+    make_json_response(&bans)
+}
+
+/// Bans the IP, kicking its pending sessions. The request body, if any, is shown to the banned player.
+async fn handle_admin_ban_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+    if let Err(response) = authenticate_admin(state, &request) {
+        return Ok(response);
+    }
+
+    let ip = if let Ok(ip) = request.param("ip").unwrap().parse::<std::net::IpAddr>() {
+        ip
+    } else {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(hyper::Body::from("invalid ip"))
+            .unwrap());
+    };
+
+    let bans = state.bans.clone();
+    let matchmaking_server = state.matchmaking_server.clone();
+    let message = match read_admin_message(request.into_body()).await {
+        Ok(message) => message,
+        Err(response) => {
+            return Ok(response);
+        }
+    };
+
+    bans.write().unwrap().insert(ip, message.clone());
+    let kicked = matchmaking_server.kick_ip(&ip, message).await;
+    log::info!("banned {}, kicked {} pending sessions", ip, kicked);
+
+    Ok(hyper::Response::builder()
+        .status(hyper::StatusCode::OK)
+        .body(hyper::Body::from(format!("kicked {} pending sessions", kicked)))
+        .unwrap())
+}
+
+async fn handle_admin_unban_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+    if let Err(response) = authenticate_admin(state, &request) {
+        return Ok(response);
+    }
+
+    let ip = if let Ok(ip) = request.param("ip").unwrap().parse::<std::net::IpAddr>() {
+        ip
+    } else {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::BAD_REQUEST)
+            .body(hyper::Body::from("invalid ip"))
+            .unwrap());
+    };
+
+    if state.bans.write().unwrap().remove(&ip).is_none() {
+        return Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::NOT_FOUND)
+            .body(hyper::Body::from("not banned"))
+            .unwrap());
+    }
+    log::info!("unbanned {}", ip);
+
+    Ok(hyper::Response::builder()
+        .status(hyper::StatusCode::OK)
+        .body(hyper::Body::from("ok"))
+        .unwrap())
+}
+
+async fn handle_admin_get_broadcast_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+    if let Err(response) = authenticate_admin(state, &request) {
+        return Ok(response);
+    }
+
+    Ok(hyper::Response::builder()
+        .status(hyper::StatusCode::OK)
+        .body(hyper::Body::from(state.matchmaking_server.broadcast_message()))
+        .unwrap())
+}
+
+/// Sets the broadcast message to the request body. An empty body clears it.
+async fn handle_admin_set_broadcast_request(
+    request: hyper::Request<hyper::Body>,
+) -> Result<hyper::Response<hyper::Body>, anyhow::Error> {
+    let state = request.data::<State>().unwrap();
+    if let Err(response) = authenticate_admin(state, &request) {
+        return Ok(response);
+    }
+
+    let matchmaking_server = state.matchmaking_server.clone();
+    let broadcast_message = match read_admin_message(request.into_body()).await {
+        Ok(broadcast_message) => broadcast_message,
+        Err(response) => {
+            return Ok(response);
+        }
+    };
+    log::info!("broadcast message set to {:?}", broadcast_message);
+    matchmaking_server.set_broadcast_message(broadcast_message);
+
+    Ok(hyper::Response::builder()
+        .status(hyper::StatusCode::OK)
+        .body(hyper::Body::from("ok"))
+        .unwrap())
+}
+
 fn router(
     real_ip_getter: httputil::RealIPGetter,
     iceconfig_backend: Option<Box<dyn iceconfig::Backend + Send + Sync + 'static>>,
+    queue_max_timeout: std::time::Duration,
+    connection_limiter: ratelimit::Limiter,
+    session_limits: ratelimit::SessionLimits,
+    authenticator: Option<auth::Authenticator>,
+    relay_limits: Option<relay::Limits>,
+    lobby_listing: bool,
+    notices: matchmaking::Notices,
+    admin_token: Option<String>,
 ) -> routerify::Router<hyper::Body, anyhow::Error> {
+    let metrics = std::sync::Arc::new(metrics::Metrics::new());
+    let relay_server = relay_limits.map(|limits| std::sync::Arc::new(relay::Server::new(limits, metrics.clone())));
+    let session_families = std::sync::Arc::new(auth::SessionFamilies::new());
     routerify::Router::builder()
         .data(State {
             real_ip_getter,
-            matchmaking_server: std::sync::Arc::new(matchmaking::Server::new(iceconfig_backend)),
+            matchmaking_server: std::sync::Arc::new(matchmaking::Server::new(
+                iceconfig_backend,
+                metrics.clone(),
+                session_limits,
+                relay_server.clone(),
+                lobby_listing,
+                notices,
+                session_families.clone(),
+            )),
+            queue_server: std::sync::Arc::new(queue::Server::new(queue_max_timeout, metrics.clone(), session_families)),
+            relay_server,
+            metrics,
+            connection_limiter,
+            authenticator,
+            lobby_listing,
+            admin_token,
+            bans: std::sync::Arc::new(std::sync::RwLock::new(std::collections::BTreeMap::new())),
         })
         .get("/", handle_matchmaking_request)
+        .get("/queue", handle_queue_request)
+        .get("/relay", handle_relay_request)
+        .get("/lobbies", handle_lobbies_request)
         .get("/ok", handle_healthcheck_request)
+        .get("/metrics", handle_metrics_request)
+        .get("/admin/sessions", handle_admin_sessions_request)
+        .post("/admin/sessions/:session_id/kick", handle_admin_kick_request)
+        .get("/admin/bans", handle_admin_bans_request)
+        .put("/admin/bans/:ip", handle_admin_ban_request)
+        .delete("/admin/bans/:ip", handle_admin_unban_request)
+        .get("/admin/broadcast", handle_admin_get_broadcast_request)
+        .put("/admin/broadcast", handle_admin_set_broadcast_request)
         .build()
         .unwrap()
 }
 
+/// Prints a token signed with AUTH_SECRET.
+///
+/// Usage: issue-token [--subject NAME] [--family FAMILY]... [--ttl-secs SECS]
+///
+/// Families are enforced for queued matches and listed lobbies. For private sessions, the server only has the client's
+/// word for which family is being played.
+fn issue_token(config: &Config, mut args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    if config.auth_secret.is_empty() {
+        anyhow::bail!("AUTH_SECRET must be set to issue tokens");
+    }
+
+    let mut sub = None;
+    let mut families = vec![];
+    let mut ttl_secs = None;
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow::format_err!("missing value for {}", arg))?;
+        match arg.as_str() {
+            "--subject" => {
+                sub = Some(value);
+            }
+            "--family" => {
+                families.push(value);
+            }
+            "--ttl-secs" => {
+                ttl_secs = Some(value.parse::<u64>()?);
+            }
+            _ => {
+                anyhow::bail!("unknown argument: {}", arg);
+            }
+        }
+    }
+
+    let iat = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let token = auth::Authenticator::new(&config.auth_secret)?.issue(&auth::Claims {
+        sub,
+        iat,
+        exp: ttl_secs.map(|ttl_secs| iat + ttl_secs),
+        families: if !families.is_empty() { Some(families) } else { None },
+    })?;
+    println!("{}", token);
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_default_env()
@@ -175,6 +1024,12 @@ async fn main() -> anyhow::Result<()> {
         .init();
     log::info!("welcome to {} {}!", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
     let config = Config::init_from_env().unwrap();
+
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("issue-token") {
+        return issue_token(&config, args);
+    }
+
     let real_ip_getter = httputil::RealIPGetter::new(config.use_x_real_ip);
     let addr = config.listen_addr.parse()?;
 
@@ -195,18 +1050,105 @@ async fn main() -> anyhow::Result<()> {
                 config.opentok_api_key.clone(),
                 config.opentok_api_secret.clone(),
             )))
+        } else if !config.coturn_shared_secret.is_empty() && !config.coturn_urls.is_empty() {
+            log::info!("using coturn iceconfig backend");
+            Some(Box::new(iceconfig::coturn::Backend::new(
+                config.coturn_shared_secret.clone(),
+                config
+                    .coturn_urls
+                    .split(',')
+                    .map(|url| url.trim().to_string())
+                    .filter(|url| !url.is_empty())
+                    .collect(),
+                std::time::Duration::from_secs(config.coturn_credential_ttl_secs),
+            )))
         } else if !config.metered_application_name.is_empty() && !config.metered_api_key.is_empty() {
             log::info!("using metered iceconfig backend");
             Some(Box::new(iceconfig::metered::Backend::new(
                 config.metered_application_name.clone(),
                 config.metered_api_key.clone(),
             )))
+        } else if !config.iceconfig_file.is_empty() {
+            log::info!("using iceconfig file {}", config.iceconfig_file);
+            let backend = iceconfig::file::Backend::new(std::path::PathBuf::from(&config.iceconfig_file))?;
+            tokio::spawn({
+                let backend = backend.clone();
+                async move {
+                    let mut sighup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+                        Ok(sighup) => sighup,
+                        Err(e) => {
+                            log::error!(
+                                "failed to listen for SIGHUP, iceconfig file will not be reloaded: {}",
+                                e
+                            );
+                            return;
+                        }
+                    };
+                    while let Some(()) = sighup.recv().await {
+                        match backend.reload() {
+                            Ok(()) => log::info!("reloaded iceconfig file"),
+                            Err(e) => log::error!("failed to reload iceconfig file, keeping old one: {:?}", e),
+                        }
+                    }
+                }
+            });
+            Some(Box::new(backend))
         } else {
             log::warn!("no iceconfig backend, will not service iceconfig requests");
             None
         };
 
-    let router = router(real_ip_getter, iceconfig_backend);
+    let authenticator = if !config.auth_secret.is_empty() {
+        log::info!("requiring tokens from clients");
+        Some(auth::Authenticator::new(&config.auth_secret)?)
+    } else {
+        None
+    };
+
+    let relay_limits = if config.relay_enabled {
+        log::info!("relaying game data for players who can't connect directly");
+        Some(relay::Limits {
+            max_connections: config.max_relay_connections,
+            bytes_per_sec: config.relay_bytes_per_sec,
+            wait_timeout: std::time::Duration::from_secs(config.relay_wait_timeout_secs),
+        })
+    } else {
+        None
+    };
+
+    let notices = matchmaking::Notices {
+        notice: if !config.notice_file.is_empty() {
+            log::info!("using notice file {}", config.notice_file);
+            serde_json::from_slice(&std::fs::read(&config.notice_file)?)?
+        } else {
+            std::collections::HashMap::new()
+        },
+        min_recommended_client_version: config.min_recommended_client_version.clone(),
+    };
+
+    let admin_token = if !config.admin_token.is_empty() {
+        log::info!("admin api enabled");
+        Some(config.admin_token.clone())
+    } else {
+        None
+    };
+
+    let router = router(
+        real_ip_getter,
+        iceconfig_backend,
+        std::time::Duration::from_secs(config.queue_max_timeout_secs),
+        ratelimit::Limiter::new(config.connections_per_ip_per_minute),
+        ratelimit::SessionLimits {
+            creation_limiter: ratelimit::Limiter::new(config.sessions_per_ip_per_minute),
+            max_pending_per_ip: config.max_pending_sessions_per_ip,
+            max_pending: config.max_pending_sessions,
+        },
+        authenticator,
+        relay_limits,
+        config.lobby_listing_enabled,
+        notices,
+        admin_token,
+    );
 
     let service = routerify::RouterService::new(router).unwrap();
     hyper::Server::bind(&addr).serve(service).await?;
@@ -2,44 +2,269 @@ use byteorder::WriteBytesExt;
 use futures_util::{SinkExt, StreamExt, TryStreamExt};
 use prost::Message;
 
-use crate::iceconfig;
+use crate::{auth, iceconfig, metrics, ratelimit, relay};
 
 const ICECONFIG_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const RX_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+const TX_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How many candidates to hold on to for an answerer that hasn't shown up yet.
+const MAX_PENDING_CANDIDATES: usize = 64;
+
+const MAX_LISTING_NICKNAME_CHARS: usize = 32;
+const MAX_LISTING_COMMENT_CHARS: usize = 140;
+
+/// The most lobbies to return in one list, oldest first.
+const MAX_LISTED_LOBBIES: usize = 200;
+
+type Tx =
+    futures_util::stream::SplitSink<hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>, tungstenite::Message>;
+
+async fn send_abort(
+    tx: &mut Tx,
+    reason: tango_signaling::proto::signaling::packet::abort::Reason,
+) -> Result<(), tungstenite::Error> {
+    send_abort_with_message(tx, reason, String::new()).await
+}
+
+async fn send_abort_with_message(
+    tx: &mut Tx,
+    reason: tango_signaling::proto::signaling::packet::abort::Reason,
+    message: String,
+) -> Result<(), tungstenite::Error> {
+    tx.send(tungstenite::Message::Binary(
+        tango_signaling::proto::signaling::Packet {
+            which: Some(tango_signaling::proto::signaling::packet::Which::Abort(
+                tango_signaling::proto::signaling::packet::Abort {
+                    reason: reason as i32,
+                    message,
+                },
+            )),
+        }
+        .encode_to_vec(),
+    ))
+    .await
+}
+
+async fn send_packet(
+    tx: &tokio::sync::Mutex<Tx>,
+    which: tango_signaling::proto::signaling::packet::Which,
+) -> anyhow::Result<()> {
+    tokio::time::timeout(
+        TX_TIMEOUT,
+        tx.lock().await.send(tungstenite::Message::Binary(
+            tango_signaling::proto::signaling::Packet { which: Some(which) }.encode_to_vec(),
+        )),
+    )
+    .await??;
+    Ok(())
+}
+
+/// Checks the family the server has seen for a session against the token's claims. Without one, only the family the
+/// client says it's playing was checked, when it connected.
+fn allows_family(claims: Option<&auth::Claims>, family: Option<&str>) -> bool {
+    match (claims, family) {
+        (Some(claims), Some(family)) => claims.allows_family(Some(family)),
+        _ => true,
+    }
+}
+
+/// Where a trickling offerer's candidates go: straight to the answerer if there is one yet, otherwise held until there
+/// is.
+struct Relay {
+    answerer_tx: Option<std::sync::Arc<tokio::sync::Mutex<Tx>>>,
+    pending_candidates: Vec<tango_signaling::proto::signaling::packet::Candidate>,
+}
 
 struct Session {
+    /// The offerer's connection, so it only removes the session if it's still its own.
+    id: u64,
+    remote_ip: std::net::IpAddr,
     offer_sdp: String,
-    offerer_tx: std::sync::Arc<
-        tokio::sync::Mutex<
-            futures_util::stream::SplitSink<
-                hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
-                tungstenite::Message,
-            >,
-        >,
-    >,
+    offerer_trickles: bool,
+    /// The offer with every candidate in it, for answerers that don't trickle. Only set once the offerer has finished
+    /// gathering.
+    full_offer_sdp: tokio::sync::watch::Receiver<Option<String>>,
+    protocol_versions: std::ops::RangeInclusive<u32>,
+    offerer_tx: std::sync::Arc<tokio::sync::Mutex<Tx>>,
+    relay: std::sync::Arc<tokio::sync::Mutex<Relay>>,
+    /// Set if the offerer asked for the session to be listed publicly.
+    listing: Option<tango_signaling::proto::signaling::Listing>,
+    /// The game family, if the server has seen it for itself: from the queue, or from the listing.
+    family: Option<String>,
+    created_at: std::time::Instant,
+    /// Makes the offerer's connection abort, with a message from the operator.
+    kick_tx: tokio::sync::mpsc::Sender<String>,
+}
+
+/// A session waiting for a second player, as shown to admins.
+#[derive(serde::Serialize)]
+pub struct PendingSession {
+    pub session_id: String,
+    pub remote_ip: std::net::IpAddr,
+    pub age_secs: u64,
+    pub listed: bool,
+}
+
+/// What the server's operator has to say to everyone connecting, besides the broadcast message.
+pub struct Notices {
+    /// Keyed by language tag.
+    pub notice: std::collections::HashMap<String, String>,
+    /// Empty if there's no recommendation.
+    pub min_recommended_client_version: String,
+}
+
+enum Role {
+    Offerer {
+        relay: std::sync::Arc<tokio::sync::Mutex<Relay>>,
+        full_offer_sdp_tx: tokio::sync::watch::Sender<Option<String>>,
+    },
+    Answerer {
+        session: Session,
+        trickle_ice: bool,
+        answered: bool,
+        /// Empty if the server doesn't relay.
+        relay_token: String,
+    },
 }
 
 pub struct Server {
     sessions: tokio::sync::Mutex<std::collections::HashMap<String, Session>>,
+    next_id: std::sync::atomic::AtomicU64,
     iceconfig_backend: Option<Box<dyn iceconfig::Backend + Send + Sync + 'static>>,
+    metrics: std::sync::Arc<metrics::Metrics>,
+    session_limits: ratelimit::SessionLimits,
+    relay_server: Option<std::sync::Arc<relay::Server>>,
+    lobby_listing: bool,
+    notices: Notices,
+    broadcast_message: std::sync::RwLock<String>,
+    session_families: std::sync::Arc<auth::SessionFamilies>,
 }
 
 impl Server {
-    pub fn new(iceconfig_backend: Option<Box<dyn iceconfig::Backend + Send + Sync + 'static>>) -> Server {
+    pub fn new(
+        iceconfig_backend: Option<Box<dyn iceconfig::Backend + Send + Sync + 'static>>,
+        metrics: std::sync::Arc<metrics::Metrics>,
+        session_limits: ratelimit::SessionLimits,
+        relay_server: Option<std::sync::Arc<relay::Server>>,
+        lobby_listing: bool,
+        notices: Notices,
+        session_families: std::sync::Arc<auth::SessionFamilies>,
+    ) -> Server {
         Server {
             sessions: tokio::sync::Mutex::new(std::collections::HashMap::new()),
+            next_id: std::sync::atomic::AtomicU64::new(0),
             iceconfig_backend,
+            metrics,
+            session_limits,
+            relay_server,
+            lobby_listing,
+            notices,
+            broadcast_message: std::sync::RwLock::new(String::new()),
+            session_families,
         }
     }
 
+    pub fn broadcast_message(&self) -> String {
+        self.broadcast_message.read().unwrap().clone()
+    }
+
+    /// Sets the message sent to everyone who connects from now on. An empty message means no message.
+    pub fn set_broadcast_message(&self, broadcast_message: String) {
+        *self.broadcast_message.write().unwrap() = broadcast_message;
+    }
+
+    /// Lists the sessions waiting for a second player, oldest first.
+    pub async fn pending_sessions(&self) -> Vec<PendingSession> {
+        let sessions = self.sessions.lock().await;
+        let mut pending_sessions = sessions
+            .iter()
+            .map(|(session_id, session)| PendingSession {
+                session_id: session_id.clone(),
+                remote_ip: session.remote_ip,
+                age_secs: session.created_at.elapsed().as_secs(),
+                listed: session.listing.is_some(),
+            })
+            .collect::<Vec<_>>();
+        pending_sessions.sort_by_key(|session| std::cmp::Reverse(session.age_secs));
+        pending_sessions
+    }
+
+    /// Aborts a session that's waiting for a second player. Returns whether there was such a session.
+    pub async fn kick(&self, session_id: &str, message: String) -> bool {
+        let session = if let Some(session) = self.sessions.lock().await.remove(session_id) {
+            session
+        } else {
+            return false;
+        };
+        log::info!("kicking session {} from {}", session_id, session.remote_ip);
+        let _ = session.kick_tx.try_send(message);
+        true
+    }
+
+    /// Aborts every session from the IP that's waiting for a second player, returning how many there were.
+    pub async fn kick_ip(&self, remote_ip: &std::net::IpAddr, message: String) -> usize {
+        let mut n = 0;
+        self.sessions.lock().await.retain(|session_id, session| {
+            if session.remote_ip != *remote_ip {
+                return true;
+            }
+            log::info!("kicking session {} from {}", session_id, session.remote_ip);
+            let _ = session.kick_tx.try_send(message.clone());
+            n += 1;
+            false
+        });
+        n
+    }
+
+    /// The number of sessions waiting for a second player.
+    pub async fn num_sessions(&self) -> usize {
+        self.sessions.lock().await.len()
+    }
+
+    /// Lists the sessions that are waiting for a second player and asked to be listed, oldest first.
+    pub async fn list_lobbies(
+        &self,
+        filter: impl Fn(&tango_signaling::proto::signaling::Listing) -> bool,
+    ) -> tango_signaling::proto::signaling::LobbyList {
+        let sessions = self.sessions.lock().await;
+        let mut lobbies = sessions
+            .iter()
+            .filter_map(|(session_id, session)| {
+                let listing = session.listing.as_ref()?;
+                if !filter(listing) {
+                    return None;
+                }
+                Some(tango_signaling::proto::signaling::lobby_list::Lobby {
+                    session_id: session_id.clone(),
+                    listing: Some(listing.clone()),
+                    protocol_version: *session.protocol_versions.end(),
+                    min_protocol_version: *session.protocol_versions.start(),
+                    age_secs: session.created_at.elapsed().as_secs() as u32,
+                })
+            })
+            .collect::<Vec<_>>();
+        lobbies.sort_by_key(|lobby| std::cmp::Reverse(lobby.age_secs));
+        lobbies.truncate(MAX_LISTED_LOBBIES);
+        tango_signaling::proto::signaling::LobbyList { lobbies }
+    }
+
     pub async fn handle_stream(
         &self,
         ws: hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
         remote_ip: std::net::IpAddr,
         session_id: &str,
+        claims: Option<auth::Claims>,
     ) -> anyhow::Result<()> {
-        let r = self.handle_stream_inner(ws, remote_ip, session_id).await;
+        let id = self.next_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let r = self
+            .handle_stream_inner(ws, remote_ip, session_id, claims.as_ref(), id)
+            .await;
         let mut sessions = self.sessions.lock().await;
-        sessions.remove(session_id);
+        // An answerer that couldn't talk to the offerer puts the session back, so don't take it away from the offerer.
+        if sessions.get(session_id).map(|s| s.id == id).unwrap_or(false) {
+            sessions.remove(session_id);
+        }
         r
     }
 
@@ -48,15 +273,19 @@ impl Server {
         ws: hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
         remote_ip: std::net::IpAddr,
         session_id: &str,
+        claims: Option<&auth::Claims>,
+        id: u64,
     ) -> anyhow::Result<()> {
         let (mut tx, mut rx) = ws.split();
 
         let ice_servers = if let Some(backend) = self.iceconfig_backend.as_ref() {
-            match tokio::time::timeout(ICECONFIG_TIMEOUT, backend.get(&remote_ip))
+            let start_time = std::time::Instant::now();
+            let r = tokio::time::timeout(ICECONFIG_TIMEOUT, backend.get(&remote_ip))
                 .await
                 .map_err(|e| anyhow::Error::from(e))
-                .and_then(|r| r)
-            {
+                .and_then(|r| r);
+            self.metrics.record_iceconfig_request(start_time.elapsed(), r.is_ok());
+            match r {
                 Ok(ice_servers) => Some(ice_servers),
                 Err(e) => {
                     log::error!("failed to request ICE servers: {:?}", e);
@@ -104,6 +333,11 @@ impl Server {
                                     },
                                 ]
                             },
+                            supports_trickle_ice: true,
+                            supports_relay: self.relay_server.is_some(),
+                            broadcast_message: self.broadcast_message(),
+                            notice: self.notices.notice.clone(),
+                            min_recommended_client_version: self.notices.min_recommended_client_version.clone(),
                         },
                     )),
                 }
@@ -112,9 +346,6 @@ impl Server {
         )
         .await??;
 
-        const RX_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
-        const TX_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
-
         // Wait for start message.
         let start = match tokio::time::timeout(RX_TIMEOUT, rx.try_next())
             .await??
@@ -131,87 +362,256 @@ impl Server {
             }
         };
 
-        if start.protocol_version as u8 != super::EXPECTED_PROTOCOL_VERSION {
+        // Older clients only speak exactly one version and leave the minimum unset.
+        let protocol_versions = if start.min_protocol_version != 0 {
+            start.min_protocol_version
+        } else {
+            start.protocol_version
+        }..=start.protocol_version;
+
+        if *protocol_versions.end() < super::MIN_PROTOCOL_VERSION {
+            self.metrics
+                .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooOld);
             tokio::time::timeout(
                 TX_TIMEOUT,
-                tx.send(tungstenite::Message::Binary(
-                    tango_signaling::proto::signaling::Packet {
-                        which: Some(tango_signaling::proto::signaling::packet::Which::Abort(
-                            tango_signaling::proto::signaling::packet::Abort {
-                                reason: if (start.protocol_version as u8) < super::EXPECTED_PROTOCOL_VERSION {
-                                    tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooOld
-                                } else {
-                                    tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooNew
-                                } as i32,
-                            },
-                        )),
-                    }
-                    .encode_to_vec(),
-                )),
+                send_abort(
+                    &mut tx,
+                    tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooOld,
+                ),
             )
             .await??;
             return Ok(());
         }
 
         let tx = std::sync::Arc::new(tokio::sync::Mutex::new(tx));
+        let (kick_tx, mut kick_rx) = tokio::sync::mpsc::channel(1);
 
-        let offerer_tx = {
+        let mut role = {
             let mut sessions = self.sessions.lock().await;
             if let Some(session) = sessions.remove(session_id) {
-                tokio::time::timeout(
-                    TX_TIMEOUT,
-                    tx.lock().await.send(tungstenite::Message::Binary(
-                        tango_signaling::proto::signaling::Packet {
-                            which: Some(tango_signaling::proto::signaling::packet::Which::Offer(
-                                tango_signaling::proto::signaling::packet::Offer {
-                                    sdp: session.offer_sdp.clone(),
-                                },
-                            )),
-                        }
-                        .encode_to_vec(),
-                    )),
-                )
-                .await??;
+                if protocol_versions.end() < session.protocol_versions.start()
+                    || protocol_versions.start() > session.protocol_versions.end()
+                {
+                    // Leave the session for someone who can actually talk to the offerer.
+                    let reason = if protocol_versions.end() < session.protocol_versions.start() {
+                        tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooOld
+                    } else {
+                        tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooNew
+                    };
+                    sessions.insert(session_id.to_string(), session);
+                    drop(sessions);
+                    self.metrics.record_abort(reason);
+                    tokio::time::timeout(TX_TIMEOUT, send_abort(&mut *tx.lock().await, reason)).await??;
+                    return Ok(());
+                }
+
+                if !allows_family(claims, session.family.as_deref()) {
+                    // Leave the session for someone who may play it.
+                    let family = session.family.clone();
+                    sessions.insert(session_id.to_string(), session);
+                    drop(sessions);
+                    log::warn!(
+                        "{:?} may not join session {} for {:?}",
+                        claims.and_then(|c| c.sub.as_ref()),
+                        session_id,
+                        family
+                    );
+                    self.metrics
+                        .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::Forbidden);
+                    tokio::time::timeout(
+                        TX_TIMEOUT,
+                        send_abort(
+                            &mut *tx.lock().await,
+                            tango_signaling::proto::signaling::packet::abort::Reason::Forbidden,
+                        ),
+                    )
+                    .await??;
+                    return Ok(());
+                }
 
-                Some(session.offerer_tx)
+                Role::Answerer {
+                    session,
+                    trickle_ice: false,
+                    answered: false,
+                    relay_token: if self.relay_server.is_some() {
+                        relay::generate_token()
+                    } else {
+                        String::new()
+                    },
+                }
             } else {
+                let family = self
+                    .session_families
+                    .get(session_id)
+                    .or_else(|| start.listing.as_ref().map(|listing| listing.family.clone()));
+
+                let reason = if !allows_family(claims, family.as_deref()) {
+                    Some(tango_signaling::proto::signaling::packet::abort::Reason::Forbidden)
+                } else if self.session_limits.max_pending != 0 && sessions.len() >= self.session_limits.max_pending {
+                    Some(tango_signaling::proto::signaling::packet::abort::Reason::TooManySessions)
+                } else if self.session_limits.max_pending_per_ip != 0
+                    && sessions.values().filter(|s| s.remote_ip == remote_ip).count()
+                        >= self.session_limits.max_pending_per_ip
+                {
+                    Some(tango_signaling::proto::signaling::packet::abort::Reason::TooManySessions)
+                } else if !self.session_limits.creation_limiter.check(&remote_ip) {
+                    Some(tango_signaling::proto::signaling::packet::abort::Reason::RateLimited)
+                } else {
+                    None
+                };
+
+                if let Some(reason) = reason {
+                    drop(sessions);
+                    log::warn!("refusing to create session for {}: {:?}", remote_ip, reason);
+                    self.metrics.record_abort(reason);
+                    tokio::time::timeout(TX_TIMEOUT, send_abort(&mut *tx.lock().await, reason)).await??;
+                    return Ok(());
+                }
+
+                // Offerers that don't trickle already have every candidate in their offer.
+                let (full_offer_sdp_tx, full_offer_sdp_rx) =
+                    tokio::sync::watch::channel(if start.supports_trickle_ice {
+                        None
+                    } else {
+                        Some(start.offer_sdp.clone())
+                    });
+                let relay = std::sync::Arc::new(tokio::sync::Mutex::new(Relay {
+                    answerer_tx: None,
+                    pending_candidates: vec![],
+                }));
+
+                let listing = start.listing.filter(|_| self.lobby_listing).map(|listing| {
+                    log::info!("listing session {} for {}", session_id, listing.family);
+                    tango_signaling::proto::signaling::Listing {
+                        nickname: listing.nickname.chars().take(MAX_LISTING_NICKNAME_CHARS).collect(),
+                        comment: listing.comment.chars().take(MAX_LISTING_COMMENT_CHARS).collect(),
+                        ..listing
+                    }
+                });
+
                 sessions.insert(
                     session_id.to_string(),
                     Session {
+                        id,
+                        remote_ip,
                         offer_sdp: start.offer_sdp,
+                        offerer_trickles: start.supports_trickle_ice,
+                        full_offer_sdp: full_offer_sdp_rx,
+                        protocol_versions,
                         offerer_tx: std::sync::Arc::clone(&tx),
+                        relay: std::sync::Arc::clone(&relay),
+                        listing,
+                        family,
+                        created_at: std::time::Instant::now(),
+                        kick_tx,
                     },
                 );
-                None
+                Role::Offerer {
+                    relay,
+                    full_offer_sdp_tx,
+                }
             }
         };
 
+        if let Role::Answerer {
+            session,
+            trickle_ice,
+            relay_token,
+            ..
+        } = &mut role
+        {
+            *trickle_ice = session.offerer_trickles && start.supports_trickle_ice;
+            if *trickle_ice {
+                // Hold the relay while flushing, so candidates can't overtake the ones that were buffered.
+                let mut relay = session.relay.lock().await;
+                send_packet(
+                    &tx,
+                    tango_signaling::proto::signaling::packet::Which::Offer(
+                        tango_signaling::proto::signaling::packet::Offer {
+                            sdp: session.offer_sdp.clone(),
+                            trickle_ice: true,
+                            relay_token: relay_token.clone(),
+                        },
+                    ),
+                )
+                .await?;
+                for candidate in relay.pending_candidates.drain(..) {
+                    send_packet(
+                        &tx,
+                        tango_signaling::proto::signaling::packet::Which::Candidate(candidate),
+                    )
+                    .await?;
+                }
+                relay.answerer_tx = Some(std::sync::Arc::clone(&tx));
+            } else {
+                // We can't trickle to this answerer, so wait until the offerer has gathered everything.
+                let mut full_offer_sdp = session.full_offer_sdp.clone();
+                let sdp = tokio::time::timeout(RX_TIMEOUT, async {
+                    loop {
+                        let sdp = full_offer_sdp.borrow().clone();
+                        if let Some(sdp) = sdp {
+                            return Ok(sdp);
+                        }
+                        full_offer_sdp.changed().await?;
+                    }
+                })
+                .await?
+                .map_err(|e: tokio::sync::watch::error::RecvError| anyhow::Error::from(e))?;
+
+                send_packet(
+                    &tx,
+                    tango_signaling::proto::signaling::packet::Which::Offer(
+                        tango_signaling::proto::signaling::packet::Offer {
+                            sdp,
+                            trickle_ice: false,
+                            relay_token: relay_token.clone(),
+                        },
+                    ),
+                )
+                .await?;
+            }
+        }
+
         const PING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
         let mut ping_timer = tokio::time::interval(PING_TIMEOUT);
 
-        let answer = loop {
-            tokio::select! {
+        loop {
+            let which = tokio::select! {
                 _ = ping_timer.tick() => {
                     let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
                     let mut buf = vec![];
                     buf.write_u64::<byteorder::LittleEndian>(now.as_millis() as u64)?;
                     tokio::time::timeout(TX_TIMEOUT, tx.lock().await.send(tungstenite::Message::Ping(buf))).await??;
+                    continue;
+                }
+
+                Some(message) = kick_rx.recv() => {
+                    self.metrics.record_abort(tango_signaling::proto::signaling::packet::abort::Reason::Kicked);
+                    tokio::time::timeout(
+                        TX_TIMEOUT,
+                        send_abort_with_message(
+                            &mut *tx.lock().await,
+                            tango_signaling::proto::signaling::packet::abort::Reason::Kicked,
+                            message,
+                        ),
+                    )
+                    .await??;
+                    return Ok(());
                 }
 
                 msg = tokio::time::timeout(RX_TIMEOUT, rx.try_next()) => {
                     match msg?? {
                         Some(tungstenite::Message::Binary(d)) => {
-                            match tango_signaling::proto::signaling::Packet::decode(d.as_slice())?.which {
-                                Some(tango_signaling::proto::signaling::packet::Which::Answer(answer)) => {
-                                    break answer;
-                                },
-                                m => anyhow::bail!("unexpected message: {:?}", m),
-                            }
+                            tango_signaling::proto::signaling::Packet::decode(d.as_slice())?.which
                         }
                         Some(tungstenite::Message::Pong(_)) => {
                             continue;
                         }
                         Some(tungstenite::Message::Close(_)) | None => {
+                            if let Role::Answerer { session, trickle_ice: true, .. } = &role {
+                                // The offerer was being kept around for candidates: let it go now that we're done.
+                                let _ = tokio::time::timeout(TX_TIMEOUT, session.offerer_tx.lock().await.close()).await;
+                            }
                             return Ok(());
                         }
                         m => {
@@ -219,30 +619,88 @@ impl Server {
                         }
                     }
                 }
-            }
-        };
+            };
 
-        let offerer_tx = if let Some(offerer_tx) = offerer_tx {
-            offerer_tx
-        } else {
-            anyhow::bail!("unexpected answer from offerer");
-        };
-
-        let mut offerer_tx = offerer_tx.lock().await;
-        tokio::time::timeout(
-            TX_TIMEOUT,
-            offerer_tx.send(tungstenite::Message::Binary(
-                tango_signaling::proto::signaling::Packet {
-                    which: Some(tango_signaling::proto::signaling::packet::Which::Answer(
-                        tango_signaling::proto::signaling::packet::Answer { sdp: answer.sdp },
-                    )),
+            match (&mut role, which) {
+                (
+                    Role::Offerer { relay, .. },
+                    Some(tango_signaling::proto::signaling::packet::Which::Candidate(candidate)),
+                ) => {
+                    let mut relay = relay.lock().await;
+                    if let Some(answerer_tx) = relay.answerer_tx.as_ref() {
+                        // If the answerer has gone away, there's nobody left to care about this.
+                        let _ = send_packet(
+                            answerer_tx,
+                            tango_signaling::proto::signaling::packet::Which::Candidate(candidate),
+                        )
+                        .await;
+                    } else if relay.pending_candidates.len() < MAX_PENDING_CANDIDATES {
+                        relay.pending_candidates.push(candidate);
+                    }
                 }
-                .encode_to_vec(),
-            )),
-        )
-        .await??;
-        tokio::time::timeout(TX_TIMEOUT, offerer_tx.close()).await??;
+                (
+                    Role::Offerer { full_offer_sdp_tx, .. },
+                    Some(tango_signaling::proto::signaling::packet::Which::GatheringComplete(gathering_complete)),
+                ) => {
+                    let _ = full_offer_sdp_tx.send(Some(gathering_complete.sdp));
+                }
+                (
+                    Role::Answerer {
+                        session,
+                        trickle_ice,
+                        answered,
+                        relay_token,
+                    },
+                    Some(tango_signaling::proto::signaling::packet::Which::Answer(answer)),
+                ) if !*answered => {
+                    *answered = true;
+                    // Only these two have signaled each other, so only they get onto the relay for this session.
+                    if let Some(relay_server) = self.relay_server.as_ref() {
+                        relay_server.allow(session_id, relay_token.clone());
+                    }
+                    send_packet(
+                        &session.offerer_tx,
+                        tango_signaling::proto::signaling::packet::Which::Answer(
+                            tango_signaling::proto::signaling::packet::Answer {
+                                sdp: answer.sdp,
+                                relay_token: relay_token.clone(),
+                            },
+                        ),
+                    )
+                    .await?;
+                    self.metrics.record_handshake_completed();
 
-        Ok(())
+                    if !*trickle_ice {
+                        tokio::time::timeout(TX_TIMEOUT, session.offerer_tx.lock().await.close()).await??;
+                        return Ok(());
+                    }
+                }
+                (
+                    Role::Answerer {
+                        session,
+                        trickle_ice: true,
+                        answered: true,
+                        ..
+                    },
+                    Some(tango_signaling::proto::signaling::packet::Which::Candidate(candidate)),
+                ) => {
+                    send_packet(
+                        &session.offerer_tx,
+                        tango_signaling::proto::signaling::packet::Which::Candidate(candidate),
+                    )
+                    .await?;
+                }
+                (
+                    Role::Answerer { .. },
+                    Some(tango_signaling::proto::signaling::packet::Which::Candidate(_))
+                    | Some(tango_signaling::proto::signaling::packet::Which::GatheringComplete(_)),
+                ) => {
+                    // Anything sent before the answer is left over from the offer the answerer rolled back.
+                }
+                (_, m) => {
+                    anyhow::bail!("unexpected message: {:?}", m);
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,146 @@
+use std::fmt::Write;
+
+#[derive(Default)]
+pub struct Metrics {
+    active_connections: std::sync::atomic::AtomicI64,
+    handshakes_completed: std::sync::atomic::AtomicU64,
+    queue_matches: std::sync::atomic::AtomicU64,
+    aborts: std::sync::Mutex<std::collections::BTreeMap<String, u64>>,
+    iceconfig_requests: std::sync::atomic::AtomicU64,
+    iceconfig_request_micros: std::sync::atomic::AtomicU64,
+    iceconfig_errors: std::sync::atomic::AtomicU64,
+    websocket_errors: std::sync::atomic::AtomicU64,
+    relayed_bytes: std::sync::atomic::AtomicU64,
+}
+
+/// Decrements the active connection gauge when dropped.
+pub struct ConnectionGuard<'a>(&'a Metrics);
+
+impl<'a> Drop for ConnectionGuard<'a> {
+    fn drop(&mut self) {
+        self.0
+            .active_connections
+            .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+pub fn write_gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn track_connection(&self) -> ConnectionGuard<'_> {
+        self.active_connections
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        ConnectionGuard(self)
+    }
+
+    pub fn record_handshake_completed(&self) {
+        self.handshakes_completed
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn record_queue_match(&self) {
+        self.queue_matches.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn record_abort(&self, reason: tango_signaling::proto::signaling::packet::abort::Reason) {
+        *self.aborts.lock().unwrap().entry(format!("{:?}", reason)).or_default() += 1;
+    }
+
+    pub fn record_iceconfig_request(&self, duration: std::time::Duration, ok: bool) {
+        self.iceconfig_requests
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.iceconfig_request_micros
+            .fetch_add(duration.as_micros() as u64, std::sync::atomic::Ordering::Relaxed);
+        if !ok {
+            self.iceconfig_errors.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_websocket_error(&self) {
+        self.websocket_errors.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn record_relayed_bytes(&self, n: usize) {
+        self.relayed_bytes
+            .fetch_add(n as u64, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Renders everything in the Prometheus text exposition format.
+    pub fn render(&self, out: &mut String) {
+        write_gauge(
+            out,
+            "tango_signaling_active_connections",
+            "WebSocket connections currently open.",
+            self.active_connections.load(std::sync::atomic::Ordering::Relaxed),
+        );
+        write_counter(
+            out,
+            "tango_signaling_handshakes_completed_total",
+            "Offer/answer exchanges relayed to completion.",
+            self.handshakes_completed.load(std::sync::atomic::Ordering::Relaxed),
+        );
+        write_counter(
+            out,
+            "tango_signaling_queue_matches_total",
+            "Pairs of players matched from the queue.",
+            self.queue_matches.load(std::sync::atomic::Ordering::Relaxed),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP tango_signaling_aborts_total Aborts sent to clients, by reason."
+        );
+        let _ = writeln!(out, "# TYPE tango_signaling_aborts_total counter");
+        for (reason, count) in self.aborts.lock().unwrap().iter() {
+            let _ = writeln!(out, "tango_signaling_aborts_total{{reason=\"{}\"}} {}", reason, count);
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP tango_signaling_iceconfig_request_duration_seconds Time taken by the ICE config backend."
+        );
+        let _ = writeln!(out, "# TYPE tango_signaling_iceconfig_request_duration_seconds summary");
+        let _ = writeln!(
+            out,
+            "tango_signaling_iceconfig_request_duration_seconds_sum {}",
+            self.iceconfig_request_micros.load(std::sync::atomic::Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(
+            out,
+            "tango_signaling_iceconfig_request_duration_seconds_count {}",
+            self.iceconfig_requests.load(std::sync::atomic::Ordering::Relaxed)
+        );
+        write_counter(
+            out,
+            "tango_signaling_iceconfig_errors_total",
+            "ICE config backend requests that failed or timed out.",
+            self.iceconfig_errors.load(std::sync::atomic::Ordering::Relaxed),
+        );
+        write_counter(
+            out,
+            "tango_signaling_websocket_errors_total",
+            "WebSocket connections that ended in an error.",
+            self.websocket_errors.load(std::sync::atomic::Ordering::Relaxed),
+        );
+        write_counter(
+            out,
+            "tango_signaling_relayed_bytes_total",
+            "Game data relayed between players who couldn't connect directly.",
+            self.relayed_bytes.load(std::sync::atomic::Ordering::Relaxed),
+        );
+    }
+}
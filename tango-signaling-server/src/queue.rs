@@ -0,0 +1,272 @@
+use byteorder::WriteBytesExt;
+use futures_util::{SinkExt, StreamExt, TryStreamExt};
+use prost::Message;
+use rand::Rng;
+
+use crate::{auth, metrics};
+
+const RX_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+const TX_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+const SESSION_ID_LENGTH: usize = 24;
+
+/// Players are only paired if these are all the same. Variants of the same game family can play each other, so the
+/// variant isn't part of this.
+#[derive(PartialEq, Eq, Debug, Clone)]
+struct Key {
+    family: String,
+    patch: Option<(String, String)>,
+    match_type: (u32, u32),
+}
+
+struct Waiting {
+    id: u64,
+    key: Key,
+    protocol_versions: std::ops::RangeInclusive<u32>,
+    matched_tx: tokio::sync::oneshot::Sender<String>,
+}
+
+pub struct Server {
+    waiting: tokio::sync::Mutex<Vec<Waiting>>,
+    next_id: std::sync::atomic::AtomicU64,
+    max_timeout: std::time::Duration,
+    metrics: std::sync::Arc<metrics::Metrics>,
+    session_families: std::sync::Arc<auth::SessionFamilies>,
+}
+
+fn make_packet(which: tango_signaling::proto::signaling::packet::Which) -> tungstenite::Message {
+    tungstenite::Message::Binary(tango_signaling::proto::signaling::Packet { which: Some(which) }.encode_to_vec())
+}
+
+fn make_abort(reason: tango_signaling::proto::signaling::packet::abort::Reason) -> tungstenite::Message {
+    make_packet(tango_signaling::proto::signaling::packet::Which::Abort(
+        tango_signaling::proto::signaling::packet::Abort {
+            reason: reason as i32,
+            ..Default::default()
+        },
+    ))
+}
+
+fn generate_session_id() -> String {
+    format!(
+        "queue-{}",
+        rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(SESSION_ID_LENGTH)
+            .map(char::from)
+            .collect::<String>()
+    )
+}
+
+impl Server {
+    pub fn new(
+        max_timeout: std::time::Duration,
+        metrics: std::sync::Arc<metrics::Metrics>,
+        session_families: std::sync::Arc<auth::SessionFamilies>,
+    ) -> Server {
+        Server {
+            waiting: tokio::sync::Mutex::new(vec![]),
+            next_id: std::sync::atomic::AtomicU64::new(0),
+            max_timeout,
+            metrics,
+            session_families,
+        }
+    }
+
+    /// The number of players waiting for an opponent.
+    pub async fn num_waiting(&self) -> usize {
+        self.waiting.lock().await.len()
+    }
+
+    pub async fn handle_stream(
+        &self,
+        ws: hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
+        claims: Option<auth::Claims>,
+    ) -> anyhow::Result<()> {
+        let id = self.next_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let r = self.handle_stream_inner(ws, claims, id).await;
+        let mut waiting = self.waiting.lock().await;
+        waiting.retain(|w| w.id != id);
+        r
+    }
+
+    async fn handle_stream_inner(
+        &self,
+        ws: hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
+        claims: Option<auth::Claims>,
+        id: u64,
+    ) -> anyhow::Result<()> {
+        let (mut tx, mut rx) = ws.split();
+
+        let enqueue = match tokio::time::timeout(RX_TIMEOUT, rx.try_next())
+            .await??
+            .ok_or_else(|| anyhow::format_err!("unexpected end of stream"))?
+        {
+            tungstenite::Message::Binary(d) => {
+                match tango_signaling::proto::signaling::Packet::decode(d.as_slice())?.which {
+                    Some(tango_signaling::proto::signaling::packet::Which::Enqueue(enqueue)) => enqueue,
+                    m => anyhow::bail!("unexpected message: {:?}", m),
+                }
+            }
+            m => {
+                anyhow::bail!("unexpected message: {:?}", m);
+            }
+        };
+
+        let protocol_versions = if enqueue.min_protocol_version != 0 {
+            enqueue.min_protocol_version
+        } else {
+            enqueue.protocol_version
+        }..=enqueue.protocol_version;
+
+        if *protocol_versions.end() < super::MIN_PROTOCOL_VERSION {
+            self.metrics
+                .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooOld);
+            tokio::time::timeout(
+                TX_TIMEOUT,
+                tx.send(make_abort(
+                    tango_signaling::proto::signaling::packet::abort::Reason::ProtocolVersionTooOld,
+                )),
+            )
+            .await??;
+            return Ok(());
+        }
+
+        if let Some(claims) = claims.as_ref() {
+            if !claims.allows_family(Some(&enqueue.family)) {
+                log::warn!("queue: {:?} may not play {:?}", claims.sub, enqueue.family);
+                self.metrics
+                    .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::Forbidden);
+                tokio::time::timeout(
+                    TX_TIMEOUT,
+                    tx.send(make_abort(
+                        tango_signaling::proto::signaling::packet::abort::Reason::Forbidden,
+                    )),
+                )
+                .await??;
+                return Ok(());
+            }
+        }
+
+        let key = Key {
+            family: enqueue.family,
+            patch: if !enqueue.patch_name.is_empty() {
+                Some((enqueue.patch_name, enqueue.patch_version))
+            } else {
+                None
+            },
+            match_type: (enqueue.match_type, enqueue.match_subtype),
+        };
+
+        let timeout = if enqueue.timeout_secs != 0 {
+            std::time::Duration::from_secs(enqueue.timeout_secs as u64).min(self.max_timeout)
+        } else {
+            self.max_timeout
+        };
+
+        let matched_rx = {
+            let mut waiting = self.waiting.lock().await;
+            let mut session_id = None;
+            while let Some(i) = waiting.iter().position(|w| {
+                w.key == key
+                    && protocol_versions.start() <= w.protocol_versions.end()
+                    && protocol_versions.end() >= w.protocol_versions.start()
+            }) {
+                let other = waiting.remove(i);
+                let candidate_session_id = generate_session_id();
+
+                // Recorded before either side hears of the session, so it's there by the time they start signaling.
+                self.session_families.record(&candidate_session_id, &key.family);
+
+                // If this fails, the other side went away and just hasn't been cleaned up yet.
+                if other.matched_tx.send(candidate_session_id.clone()).is_ok() {
+                    session_id = Some(candidate_session_id);
+                    break;
+                }
+            }
+
+            if let Some(session_id) = session_id {
+                drop(waiting);
+                log::info!("queue: matched {:?}", key);
+                self.metrics.record_queue_match();
+                tokio::time::timeout(
+                    TX_TIMEOUT,
+                    tx.send(make_packet(tango_signaling::proto::signaling::packet::Which::Matched(
+                        tango_signaling::proto::signaling::packet::Matched { session_id },
+                    ))),
+                )
+                .await??;
+                tokio::time::timeout(TX_TIMEOUT, tx.close()).await??;
+                return Ok(());
+            }
+
+            let (matched_tx, matched_rx) = tokio::sync::oneshot::channel();
+            waiting.push(Waiting {
+                id,
+                key: key.clone(),
+                protocol_versions,
+                matched_tx,
+            });
+            log::info!("queue: waiting for {:?} ({} in queue)", key, waiting.len());
+            matched_rx
+        };
+
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        tokio::pin!(matched_rx);
+
+        let mut ping_timer = tokio::time::interval(PING_INTERVAL);
+
+        let session_id = loop {
+            tokio::select! {
+                session_id = &mut matched_rx => {
+                    break session_id?;
+                }
+
+                _ = &mut deadline => {
+                    self.metrics.record_abort(tango_signaling::proto::signaling::packet::abort::Reason::QueueTimeout);
+                    tokio::time::timeout(
+                        TX_TIMEOUT,
+                        tx.send(make_abort(tango_signaling::proto::signaling::packet::abort::Reason::QueueTimeout)),
+                    )
+                    .await??;
+                    return Ok(());
+                }
+
+                _ = ping_timer.tick() => {
+                    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+                    let mut buf = vec![];
+                    buf.write_u64::<byteorder::LittleEndian>(now.as_millis() as u64)?;
+                    tokio::time::timeout(TX_TIMEOUT, tx.send(tungstenite::Message::Ping(buf))).await??;
+                }
+
+                msg = tokio::time::timeout(RX_TIMEOUT, rx.try_next()) => {
+                    match msg?? {
+                        Some(tungstenite::Message::Pong(_)) => {
+                            continue;
+                        }
+                        Some(tungstenite::Message::Close(_)) | None => {
+                            // Cancelled: we'll be removed from the queue on the way out.
+                            return Ok(());
+                        }
+                        m => {
+                            anyhow::bail!("unexpected message: {:?}", m);
+                        }
+                    }
+                }
+            }
+        };
+
+        tokio::time::timeout(
+            TX_TIMEOUT,
+            tx.send(make_packet(tango_signaling::proto::signaling::packet::Which::Matched(
+                tango_signaling::proto::signaling::packet::Matched { session_id },
+            ))),
+        )
+        .await??;
+        tokio::time::timeout(TX_TIMEOUT, tx.close()).await??;
+
+        Ok(())
+    }
+}
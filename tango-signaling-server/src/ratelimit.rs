@@ -0,0 +1,64 @@
+/// Above this many tracked IPs, buckets that have fully refilled are dropped to keep memory bounded.
+const PRUNE_THRESHOLD: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: std::time::Instant,
+}
+
+/// A per-IP token bucket: each IP may burst up to the limit, then is refilled at the limit per minute.
+pub struct Limiter {
+    per_minute: u32,
+    buckets: std::sync::Mutex<std::collections::HashMap<std::net::IpAddr, Bucket>>,
+}
+
+impl Limiter {
+    /// Creates a new limiter. A limit of 0 means unlimited.
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: std::sync::Mutex::new(std::collections::HashMap::new()),
+        }
+    }
+
+    /// Takes a token for the IP, returning false if it has none left.
+    pub fn check(&self, ip: &std::net::IpAddr) -> bool {
+        if self.per_minute == 0 {
+            return true;
+        }
+
+        let capacity = self.per_minute as f64;
+        let refill_per_sec = capacity / 60.0;
+        let now = std::time::Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.last_refill).as_secs_f64() * refill_per_sec < capacity
+            });
+        }
+
+        let bucket = buckets.entry(*ip).or_insert(Bucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.last_refill).as_secs_f64() * refill_per_sec).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// Limits on how many sessions may be waiting for a second player at once.
+pub struct SessionLimits {
+    pub creation_limiter: Limiter,
+    /// 0 means unlimited.
+    pub max_pending_per_ip: usize,
+    /// 0 means unlimited.
+    pub max_pending: usize,
+}
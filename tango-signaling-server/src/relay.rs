@@ -0,0 +1,372 @@
+use byteorder::WriteBytesExt;
+use futures_util::{SinkExt, StreamExt, TryStreamExt};
+use prost::Message;
+use rand::Rng;
+
+use crate::{auth, metrics};
+
+const RX_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+const TX_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How many packets may be queued up for one side before the other side has to wait.
+const PEER_QUEUE_LENGTH: usize = 64;
+
+const TOKEN_LENGTH: usize = 32;
+
+/// How long the sides of a session have to get to the relay after signaling.
+const TOKEN_TTL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+pub struct Limits {
+    /// Counting each side separately, including sides still waiting for the other. 0 means unlimited.
+    pub max_connections: usize,
+    /// How much each side may send. 0 means unlimited.
+    pub bytes_per_sec: u32,
+    /// How long the first side waits for the second to show up.
+    pub wait_timeout: std::time::Duration,
+}
+
+struct Waiting {
+    id: u64,
+    to_first_tx: tokio::sync::mpsc::Sender<Vec<u8>>,
+    paired_tx: tokio::sync::oneshot::Sender<tokio::sync::mpsc::Sender<Vec<u8>>>,
+}
+
+struct Token {
+    token: String,
+    issued_at: std::time::Instant,
+}
+
+enum Pairing {
+    Waiting(tokio::sync::oneshot::Receiver<tokio::sync::mpsc::Sender<Vec<u8>>>),
+    Paired(tokio::sync::mpsc::Sender<Vec<u8>>),
+    Full,
+}
+
+/// Holds a side to its byte rate by making it wait once it has used up its allowance.
+struct Throttle {
+    bytes_per_sec: u32,
+    allowance: f64,
+    last_refill: std::time::Instant,
+}
+
+impl Throttle {
+    fn new(bytes_per_sec: u32) -> Self {
+        Self {
+            bytes_per_sec,
+            allowance: bytes_per_sec as f64,
+            last_refill: std::time::Instant::now(),
+        }
+    }
+
+    async fn take(&mut self, n: usize) {
+        if self.bytes_per_sec == 0 {
+            return;
+        }
+
+        let rate = self.bytes_per_sec as f64;
+        let now = std::time::Instant::now();
+        self.allowance = (self.allowance + now.duration_since(self.last_refill).as_secs_f64() * rate).min(rate);
+        self.last_refill = now;
+        self.allowance -= n as f64;
+        if self.allowance < 0.0 {
+            tokio::time::sleep(std::time::Duration::from_secs_f64(-self.allowance / rate)).await;
+        }
+    }
+}
+
+/// Decrements the active connection count when dropped.
+struct ActiveGuard<'a>(&'a std::sync::atomic::AtomicUsize);
+
+impl<'a> Drop for ActiveGuard<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Relays game data between two players over their WebSockets, for when they can't connect to each other directly.
+///
+/// Both players connect with the same session ID and the token the matchmaking server gave them both once they'd
+/// signaled: the first one waits for the second, then everything one sends is passed on to the other.
+pub struct Server {
+    waiting: tokio::sync::Mutex<std::collections::HashMap<String, Waiting>>,
+    tokens: std::sync::Mutex<std::collections::HashMap<String, Token>>,
+    num_active: std::sync::atomic::AtomicUsize,
+    next_id: std::sync::atomic::AtomicU64,
+    limits: Limits,
+    metrics: std::sync::Arc<metrics::Metrics>,
+}
+
+fn make_packet(which: tango_signaling::proto::signaling::packet::Which) -> tungstenite::Message {
+    tungstenite::Message::Binary(tango_signaling::proto::signaling::Packet { which: Some(which) }.encode_to_vec())
+}
+
+fn make_abort(reason: tango_signaling::proto::signaling::packet::abort::Reason) -> tungstenite::Message {
+    make_packet(tango_signaling::proto::signaling::packet::Which::Abort(
+        tango_signaling::proto::signaling::packet::Abort {
+            reason: reason as i32,
+            ..Default::default()
+        },
+    ))
+}
+
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+fn make_ping() -> anyhow::Result<tungstenite::Message> {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    let mut buf = vec![];
+    buf.write_u64::<byteorder::LittleEndian>(now.as_millis() as u64)?;
+    Ok(tungstenite::Message::Ping(buf))
+}
+
+impl Server {
+    pub fn new(limits: Limits, metrics: std::sync::Arc<metrics::Metrics>) -> Server {
+        Server {
+            waiting: tokio::sync::Mutex::new(std::collections::HashMap::new()),
+            tokens: std::sync::Mutex::new(std::collections::HashMap::new()),
+            num_active: std::sync::atomic::AtomicUsize::new(0),
+            next_id: std::sync::atomic::AtomicU64::new(0),
+            limits,
+            metrics,
+        }
+    }
+
+    /// Lets the sides of a session that have finished signaling onto the relay with the given token.
+    pub fn allow(&self, session_id: &str, token: String) {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, token| token.issued_at.elapsed() < TOKEN_TTL);
+        tokens.insert(
+            session_id.to_string(),
+            Token {
+                token,
+                issued_at: std::time::Instant::now(),
+            },
+        );
+    }
+
+    /// Whether the token is the one issued for the session, and hasn't expired or been used by both sides yet.
+    pub fn check_token(&self, session_id: &str, token: &str) -> bool {
+        self.tokens
+            .lock()
+            .unwrap()
+            .get(session_id)
+            .map(|t| t.issued_at.elapsed() < TOKEN_TTL && auth::tokens_equal(&t.token, token))
+            .unwrap_or(false)
+    }
+
+    /// The number of sides currently being relayed.
+    pub fn num_active(&self) -> usize {
+        self.num_active.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub async fn handle_stream(
+        &self,
+        ws: hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
+        session_id: &str,
+    ) -> anyhow::Result<()> {
+        let id = self.next_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let r = self.handle_stream_inner(ws, session_id, id).await;
+        let mut waiting = self.waiting.lock().await;
+        if waiting.get(session_id).map(|w| w.id == id).unwrap_or(false) {
+            waiting.remove(session_id);
+        }
+        r
+    }
+
+    async fn handle_stream_inner(
+        &self,
+        ws: hyper_tungstenite::WebSocketStream<hyper::upgrade::Upgraded>,
+        session_id: &str,
+        id: u64,
+    ) -> anyhow::Result<()> {
+        let (mut tx, mut rx) = ws.split();
+        let (to_me_tx, mut to_me_rx) = tokio::sync::mpsc::channel(PEER_QUEUE_LENGTH);
+
+        let pairing = {
+            let mut waiting = self.waiting.lock().await;
+            let mut to_me_tx = Some(to_me_tx);
+            let mut peer_tx = None;
+            if let Some(first) = waiting.remove(session_id) {
+                // If this fails, the first side went away and just hasn't been cleaned up yet.
+                match first.paired_tx.send(to_me_tx.take().unwrap()) {
+                    Ok(()) => {
+                        // Both sides are here, so nobody else gets to use the token.
+                        self.tokens.lock().unwrap().remove(session_id);
+                        peer_tx = Some(first.to_first_tx);
+                    }
+                    Err(tx) => {
+                        to_me_tx = Some(tx);
+                    }
+                }
+            }
+
+            if let Some(peer_tx) = peer_tx {
+                Pairing::Paired(peer_tx)
+            } else if self.limits.max_connections != 0
+                && self.num_active() + waiting.len() >= self.limits.max_connections
+            {
+                Pairing::Full
+            } else {
+                let (paired_tx, paired_rx) = tokio::sync::oneshot::channel();
+                waiting.insert(
+                    session_id.to_string(),
+                    Waiting {
+                        id,
+                        to_first_tx: to_me_tx.unwrap(),
+                        paired_tx,
+                    },
+                );
+                Pairing::Waiting(paired_rx)
+            }
+        };
+
+        let (peer_tx, is_first) = match pairing {
+            Pairing::Paired(peer_tx) => (peer_tx, false),
+            Pairing::Full => {
+                log::warn!("relay: too many connections, refusing {}", session_id);
+                self.metrics
+                    .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::TooManySessions);
+                tokio::time::timeout(
+                    TX_TIMEOUT,
+                    tx.send(make_abort(
+                        tango_signaling::proto::signaling::packet::abort::Reason::TooManySessions,
+                    )),
+                )
+                .await??;
+                return Ok(());
+            }
+            Pairing::Waiting(paired_rx) => {
+                let deadline = tokio::time::sleep(self.limits.wait_timeout);
+                tokio::pin!(deadline);
+                tokio::pin!(paired_rx);
+                let mut ping_timer = tokio::time::interval(PING_INTERVAL);
+
+                let peer_tx = loop {
+                    tokio::select! {
+                        peer_tx = &mut paired_rx => {
+                            break peer_tx?;
+                        }
+
+                        _ = &mut deadline => {
+                            self.metrics
+                                .record_abort(tango_signaling::proto::signaling::packet::abort::Reason::RelayTimeout);
+                            tokio::time::timeout(
+                                TX_TIMEOUT,
+                                tx.send(make_abort(
+                                    tango_signaling::proto::signaling::packet::abort::Reason::RelayTimeout,
+                                )),
+                            )
+                            .await??;
+                            return Ok(());
+                        }
+
+                        _ = ping_timer.tick() => {
+                            tokio::time::timeout(TX_TIMEOUT, tx.send(make_ping()?)).await??;
+                        }
+
+                        msg = tokio::time::timeout(RX_TIMEOUT, rx.try_next()) => {
+                            match msg?? {
+                                Some(tungstenite::Message::Pong(_)) => {
+                                    continue;
+                                }
+                                Some(tungstenite::Message::Close(_)) | None => {
+                                    return Ok(());
+                                }
+                                m => {
+                                    anyhow::bail!("unexpected message: {:?}", m);
+                                }
+                            }
+                        }
+                    }
+                };
+                (peer_tx, true)
+            }
+        };
+
+        self.num_active.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let _guard = ActiveGuard(&self.num_active);
+        if is_first {
+            log::info!("relay: relaying {}", session_id);
+        }
+
+        tokio::time::timeout(
+            TX_TIMEOUT,
+            tx.send(make_packet(
+                tango_signaling::proto::signaling::packet::Which::RelayReady(
+                    tango_signaling::proto::signaling::packet::RelayReady { is_first },
+                ),
+            )),
+        )
+        .await??;
+
+        // Sending and receiving are kept apart, so a side that's slow to read can never stop the other from being read.
+        let upstream = async {
+            let mut throttle = Throttle::new(self.limits.bytes_per_sec);
+            loop {
+                match tokio::time::timeout(RX_TIMEOUT, rx.try_next()).await?? {
+                    Some(tungstenite::Message::Binary(d)) => {
+                        match tango_signaling::proto::signaling::Packet::decode(d.as_slice())?.which {
+                            Some(tango_signaling::proto::signaling::packet::Which::RelayData(relay_data)) => {
+                                throttle.take(relay_data.data.len()).await;
+                                self.metrics.record_relayed_bytes(relay_data.data.len());
+                                if peer_tx.send(relay_data.data).await.is_err() {
+                                    return Ok(());
+                                }
+                            }
+                            m => anyhow::bail!("unexpected message: {:?}", m),
+                        }
+                    }
+                    Some(tungstenite::Message::Pong(_)) => {}
+                    Some(tungstenite::Message::Close(_)) | None => {
+                        return Ok(());
+                    }
+                    m => {
+                        anyhow::bail!("unexpected message: {:?}", m);
+                    }
+                }
+            }
+        };
+
+        let downstream = async {
+            let mut ping_timer = tokio::time::interval(PING_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = ping_timer.tick() => {
+                        tokio::time::timeout(TX_TIMEOUT, tx.send(make_ping()?)).await??;
+                    }
+
+                    data = to_me_rx.recv() => {
+                        let data = if let Some(data) = data {
+                            data
+                        } else {
+                            // The other side has gone away.
+                            return Ok(());
+                        };
+                        tokio::time::timeout(
+                            TX_TIMEOUT,
+                            tx.send(make_packet(tango_signaling::proto::signaling::packet::Which::RelayData(
+                                tango_signaling::proto::signaling::packet::RelayData { data },
+                            ))),
+                        )
+                        .await??;
+                    }
+                }
+            }
+        };
+
+        let r: anyhow::Result<()> = tokio::select! {
+            r = upstream => r,
+            r = downstream => r,
+        };
+        r?;
+
+        let _ = tokio::time::timeout(TX_TIMEOUT, tx.close()).await;
+        Ok(())
+    }
+}
@@ -6,17 +6,43 @@ use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 
 pub type AbortReason = crate::proto::signaling::packet::abort::Reason;
 
-async fn create_data_channel(
+pub(crate) async fn create_data_channel<P: datachannel_wrapper::Peer>(
     rtc_config: datachannel_wrapper::RtcConfig,
 ) -> Result<
     (
         datachannel_wrapper::DataChannel,
         tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionEvent>,
-        datachannel_wrapper::PeerConnection,
+        P,
     ),
     std::io::Error,
 > {
-    let (mut peer_conn, mut event_rx) = datachannel_wrapper::PeerConnection::new(rtc_config)?;
+    let (dc, mut event_rx, peer_conn) = open_data_channel(rtc_config)?;
+
+    loop {
+        if let Some(datachannel_wrapper::PeerConnectionEvent::GatheringStateChange(
+            datachannel_wrapper::GatheringState::Complete,
+        )) = event_rx.recv().await
+        {
+            break;
+        }
+    }
+
+    Ok((dc, event_rx, peer_conn))
+}
+
+/// Like [`create_data_channel`], but doesn't wait for gathering to complete: candidates are gathered in the background
+/// and show up as events.
+fn open_data_channel<P: datachannel_wrapper::Peer>(
+    rtc_config: datachannel_wrapper::RtcConfig,
+) -> Result<
+    (
+        datachannel_wrapper::DataChannel,
+        tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionEvent>,
+        P,
+    ),
+    std::io::Error,
+> {
+    let (mut peer_conn, event_rx) = P::new(rtc_config)?;
 
     let dc = peer_conn.create_data_channel(
         "tango",
@@ -32,22 +58,35 @@ async fn create_data_channel(
             .stream(0),
     )?;
 
-    loop {
-        if let Some(datachannel_wrapper::PeerConnectionEvent::GatheringStateChange(
-            datachannel_wrapper::GatheringState::Complete,
-        )) = event_rx.recv().await
-        {
-            break;
-        }
-    }
-
     Ok((dc, event_rx, peer_conn))
 }
 
+/// Opens an unordered, unreliable data channel alongside the main one.
+///
+/// Both sides must call this, as the channel is negotiated out of band on a fixed stream.
+pub fn create_unreliable_data_channel<P: datachannel_wrapper::Peer + ?Sized>(
+    peer_conn: &mut P,
+) -> Result<datachannel_wrapper::DataChannel, std::io::Error> {
+    peer_conn.create_data_channel(
+        "tango-unreliable",
+        datachannel_wrapper::DataChannelInit::default()
+            .reliability(datachannel_wrapper::Reliability {
+                unordered: true,
+                unreliable: true,
+                max_packet_life_time: 0,
+                max_retransmits: 0,
+            })
+            .negotiated()
+            .manual_stream()
+            .stream(1),
+    )
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    /// The server's operator may have left a message saying why. It's empty if not.
     #[error("signaling abort: {0:?}")]
-    ServerAbort(AbortReason),
+    ServerAbort(AbortReason, String),
 
     #[error("tungstenite: {0:?}")]
     Tungstenite(#[from] tokio_tungstenite::tungstenite::Error),
@@ -64,9 +103,15 @@ pub enum Error {
     #[error("http error: {0:?}")]
     Http(#[from] tokio_tungstenite::tungstenite::http::Error),
 
+    #[error("http request error: {0:?}")]
+    Request(#[from] reqwest::Error),
+
     #[error("sdp parse error: {0:?}")]
     SdpParse(#[from] datachannel_wrapper::sdp::error::SdpParserError),
 
+    #[error("invalid manual signaling blob")]
+    InvalidBlob,
+
     #[error("invalid packet")]
     InvalidPacket(tokio_tungstenite::tungstenite::Message),
 
@@ -83,19 +128,165 @@ pub enum Error {
     PeerConnectionClosed,
 }
 
-pub struct Connecting {
-    fut: futures_util::future::BoxFuture<
-        'static,
-        Result<(datachannel_wrapper::DataChannel, datachannel_wrapper::PeerConnection), Error>,
-    >,
+impl From<crate::proto::signaling::packet::Abort> for Error {
+    fn from(abort: crate::proto::signaling::packet::Abort) -> Self {
+        Error::ServerAbort(AbortReason::from_i32(abort.reason).unwrap_or_default(), abort.message)
+    }
+}
+
+/// Credentials for signaling servers that only admit clients with a token.
+#[derive(Clone, Debug, Default)]
+pub struct Auth {
+    pub token: Option<String>,
+    /// The game family that will be played, for tokens that are only good for some families.
+    pub family: Option<String>,
+}
+
+impl Auth {
+    pub(crate) fn apply(
+        &self,
+        req: &mut tokio_tungstenite::tungstenite::handshake::client::Request,
+    ) -> Result<(), Error> {
+        if let Some(token) = self.token.as_ref() {
+            req.headers_mut().append(
+                "Authorization",
+                tokio_tungstenite::tungstenite::http::HeaderValue::from_str(&format!("Bearer {}", token))
+                    .map_err(|e| tokio_tungstenite::tungstenite::http::Error::from(e))?,
+            );
+        }
+        if let Some(family) = self.family.as_ref() {
+            req.headers_mut().append(
+                "X-Tango-Game-Family",
+                tokio_tungstenite::tungstenite::http::HeaderValue::from_str(family)
+                    .map_err(|e| tokio_tungstenite::tungstenite::http::Error::from(e))?,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Makes the URL of another endpoint on the same server, e.g. `relay` on `wss://example.com/signaling` is
+/// `wss://example.com/signaling/relay`.
+pub(crate) fn endpoint_url(addr: &str, endpoint: &str) -> Result<url::Url, Error> {
+    let mut url = url::Url::parse(addr)?;
+    url.path_segments_mut()
+        .map_err(|_| url::ParseError::RelativeUrlWithCannotBeABaseBase)?
+        .pop_if_empty()
+        .push(endpoint);
+    Ok(url)
 }
 
-pub async fn connect(
+/// Whether the server will have put an Abort in the body of a rejected upgrade with this status.
+pub(crate) fn is_abort_status(status: http::StatusCode) -> bool {
+    status == http::StatusCode::BAD_REQUEST
+        || status == http::StatusCode::UNAUTHORIZED
+        || status == http::StatusCode::FORBIDDEN
+        || status == http::StatusCode::TOO_MANY_REQUESTS
+}
+
+pub struct Connecting<P = datachannel_wrapper::PeerConnection> {
+    pub(crate) fut: futures_util::future::BoxFuture<'static, Result<(datachannel_wrapper::DataChannel, P), Error>>,
+}
+
+const WEBSOCKET_ATTEMPTS: u32 = 3;
+const WEBSOCKET_INITIAL_BACKOFF: std::time::Duration = std::time::Duration::from_millis(500);
+
+fn is_retryable(e: &tokio_tungstenite::tungstenite::Error) -> bool {
+    match e {
+        tokio_tungstenite::tungstenite::Error::Io(_) => true,
+        tokio_tungstenite::tungstenite::Error::Http(response) => response.status().is_server_error(),
+        _ => false,
+    }
+}
+
+/// Opens a WebSocket to the signaling server, retrying with backoff if the server can't be reached.
+///
+/// The request is rebuilt for every attempt. Aborts from the server aren't retried.
+pub(crate) async fn open_websocket(
+    make_request: impl Fn() -> Result<tokio_tungstenite::tungstenite::handshake::client::Request, Error>,
+    recorder: Option<&crate::report::Recorder>,
+) -> Result<SignalingStream, Error> {
+    let mut backoff = WEBSOCKET_INITIAL_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        if let Some(recorder) = recorder {
+            recorder.record_websocket_attempt();
+        }
+
+        match tokio_tungstenite::connect_async(make_request()?).await {
+            Ok((stream, _)) => {
+                return Ok(stream);
+            }
+            Err(tokio_tungstenite::tungstenite::Error::Http(e)) if is_abort_status(e.status()) => {
+                let abort = crate::proto::signaling::packet::Abort::decode(
+                    e.body().as_ref().map(|b| b.as_bytes()).unwrap_or_default(),
+                )?;
+                return Err(abort.into());
+            }
+            Err(e) if attempt < WEBSOCKET_ATTEMPTS && is_retryable(&e) => {
+                log::warn!(
+                    "failed to open signaling websocket (attempt {}/{}), retrying in {:?}: {}",
+                    attempt,
+                    WEBSOCKET_ATTEMPTS,
+                    backoff,
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        }
+    }
+}
+
+/// Connects to the other side through the signaling server.
+///
+/// If a listing is given and nobody else is waiting on the session yet, the session is listed publicly until someone
+/// joins it. The recorder is filled in as connecting goes along, including if it fails.
+///
+/// Any [`datachannel_wrapper::Peer`] can be connected: [`datachannel_wrapper::loopback::PeerConnection`] connects to
+/// another one in the same process without touching the network, other than to talk to the signaling server.
+pub async fn connect<P: datachannel_wrapper::Peer>(
     addr: &str,
     session_id: &str,
     use_relay: Option<bool>,
-    protocol_version: u32,
-) -> Result<Connecting, Error> {
+    protocol_versions: std::ops::RangeInclusive<u32>,
+    auth: &Auth,
+    listing: Option<&crate::lobbies::Listing>,
+    recorder: &crate::report::Recorder,
+) -> Result<Connecting<P>, Error> {
+    match connect_inner(addr, session_id, use_relay, protocol_versions, auth, listing, recorder).await {
+        Ok(connecting) => {
+            let recorder = recorder.clone();
+            Ok(Connecting {
+                fut: Box::pin(async move {
+                    let r = connecting.fut.await;
+                    if let Err(e) = r.as_ref() {
+                        recorder.fail(e);
+                    }
+                    r
+                }),
+            })
+        }
+        Err(e) => {
+            recorder.fail(&e);
+            Err(e)
+        }
+    }
+}
+
+async fn connect_inner<P: datachannel_wrapper::Peer>(
+    addr: &str,
+    session_id: &str,
+    use_relay: Option<bool>,
+    protocol_versions: std::ops::RangeInclusive<u32>,
+    auth: &Auth,
+    listing: Option<&crate::lobbies::Listing>,
+    recorder: &crate::report::Recorder,
+) -> Result<Connecting<P>, Error> {
     let mut url = url::Url::parse(addr)?;
     url.set_query(Some(
         &url::form_urlencoded::Serializer::new(String::new())
@@ -103,35 +294,40 @@ pub async fn connect(
             .finish(),
     ));
 
-    let mut req = url.to_string().into_client_request()?;
-    req.headers_mut().append(
-        "User-Agent",
-        tokio_tungstenite::tungstenite::http::HeaderValue::from_str(&format!(
-            "tango-signaling/{}",
-            env!("CARGO_PKG_VERSION")
-        ))
-        .map_err(|e| tokio_tungstenite::tungstenite::http::Error::from(e))?,
-    );
-    req.headers_mut().append(
-        "X-Tango-Protocol-Version",
-        tokio_tungstenite::tungstenite::http::HeaderValue::from_str(&format!("{:x}", protocol_version))
-            .map_err(|e| tokio_tungstenite::tungstenite::http::Error::from(e))?,
-    );
-    let mut signaling_stream = match tokio_tungstenite::connect_async(req).await {
-        Ok((signaling_stream, _)) => signaling_stream,
-        Err(tokio_tungstenite::tungstenite::Error::Http(e)) if e.status() == http::StatusCode::BAD_REQUEST => {
-            let abort = crate::proto::signaling::packet::Abort::decode(
-                e.body().as_ref().map(|b| b.as_bytes()).unwrap_or_default(),
-            )?;
-            return Err(Error::ServerAbort(
-                AbortReason::from_i32(abort.reason).unwrap_or_default(),
-            ));
-        }
-        Err(e) => {
-            return Err(e.into());
-        }
-    };
-
+    recorder.begin(crate::report::Phase::WebSocket);
+    let mut signaling_stream = open_websocket(
+        || {
+            let mut req = url.to_string().into_client_request()?;
+            req.headers_mut().append(
+                "User-Agent",
+                tokio_tungstenite::tungstenite::http::HeaderValue::from_str(&format!(
+                    "tango-signaling/{}",
+                    env!("CARGO_PKG_VERSION")
+                ))
+                .map_err(|e| tokio_tungstenite::tungstenite::http::Error::from(e))?,
+            );
+            req.headers_mut().append(
+                "X-Tango-Protocol-Version",
+                tokio_tungstenite::tungstenite::http::HeaderValue::from_str(&format!("{:x}", protocol_versions.end()))
+                    .map_err(|e| tokio_tungstenite::tungstenite::http::Error::from(e))?,
+            );
+            req.headers_mut().append(
+                "X-Tango-Min-Protocol-Version",
+                tokio_tungstenite::tungstenite::http::HeaderValue::from_str(&format!(
+                    "{:x}",
+                    protocol_versions.start()
+                ))
+                .map_err(|e| tokio_tungstenite::tungstenite::http::Error::from(e))?,
+            );
+            auth.apply(&mut req)?;
+            Ok(req)
+        },
+        Some(recorder),
+    )
+    .await?;
+    recorder.end(crate::report::Phase::WebSocket);
+
+    recorder.begin(crate::report::Phase::Hello);
     let raw = if let Some(raw) = signaling_stream.try_next().await? {
         raw
     } else {
@@ -150,7 +346,10 @@ pub async fn connect(
         return Err(Error::UnexpectedPacket(packet));
     };
 
+    recorder.end(crate::report::Phase::Hello);
     log::info!("hello received from signaling stream: {:?}", hello);
+    recorder.record_relay_available(hello.supports_relay);
+    recorder.record_notices(&hello);
 
     let mut rtc_config = datachannel_wrapper::RtcConfig::new(
         &hello
@@ -198,142 +397,404 @@ pub async fn connect(
     if use_relay == Some(true) {
         rtc_config.ice_transport_policy = datachannel_wrapper::TransportPolicy::Relay;
     }
-    let (dc, mut event_rx, mut peer_conn) = create_data_channel(rtc_config).await?;
+    let supports_trickle_ice = hello.supports_trickle_ice;
+    recorder.begin(crate::report::Phase::Gathering);
+    let (mut dc, mut event_rx, mut peer_conn) = if supports_trickle_ice {
+        open_data_channel(rtc_config)?
+    } else {
+        let r = create_data_channel(rtc_config).await?;
+        recorder.end(crate::report::Phase::Gathering);
+        r
+    };
+
+    recorder.begin(crate::report::Phase::OfferAnswer);
+
+    send_packet(
+        &mut signaling_stream,
+        crate::proto::signaling::packet::Which::Start(crate::proto::signaling::packet::Start {
+            protocol_version: *protocol_versions.end(),
+            min_protocol_version: *protocol_versions.start(),
+            offer_sdp: peer_conn.local_description().unwrap().sdp.to_string(),
+            supports_trickle_ice,
+            listing: listing.map(|listing| listing.to_proto()),
+        }),
+    )
+    .await?;
+
+    let recorder = recorder.clone();
+    Ok(Connecting {
+        fut: Box::pin(async move {
+            let connected = if supports_trickle_ice {
+                signal_trickle(&mut signaling_stream, &mut event_rx, &mut peer_conn, &recorder).await?
+            } else {
+                signal(&mut signaling_stream, &mut peer_conn, &recorder).await?;
+                recorder.end(crate::report::Phase::OfferAnswer);
+                recorder.begin(crate::report::Phase::Connection);
+                false
+            };
+
+            // The server may have already closed the stream on its end.
+            let _ = signaling_stream.close(None).await;
+
+            log::debug!(
+                "local sdp (type = {:?}): {}",
+                peer_conn.local_description().expect("local sdp").sdp_type,
+                peer_conn.local_description().expect("local sdp").sdp
+            );
+            log::debug!(
+                "remote sdp (type = {:?}): {}",
+                peer_conn.remote_description().expect("remote sdp").sdp_type,
+                peer_conn.remote_description().expect("remote sdp").sdp
+            );
 
+            if !connected {
+                wait_for_connection(&mut event_rx).await?;
+            }
+            recorder.end(crate::report::Phase::Connection);
+
+            // If we got here before gathering finished, whatever's left of it can't hold anything up anymore.
+            recorder.end(crate::report::Phase::Gathering);
+
+            recorder.begin(crate::report::Phase::DataChannel);
+            dc.wait_open().await?;
+            recorder.end(crate::report::Phase::DataChannel);
+
+            if let Some((local, remote)) = peer_conn.selected_candidate_pair() {
+                log::info!("selected candidate pair: {} <-> {}", local, remote);
+                recorder.record_candidate_pair(&local, &remote);
+            }
+
+            Ok((dc, peer_conn))
+        }),
+    })
+}
+
+pub(crate) type SignalingStream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+async fn send_packet(
+    signaling_stream: &mut SignalingStream,
+    which: crate::proto::signaling::packet::Which,
+) -> Result<(), Error> {
     signaling_stream
         .send(tokio_tungstenite::tungstenite::Message::Binary(
-            crate::proto::signaling::Packet {
-                which: Some(crate::proto::signaling::packet::Which::Start(
-                    crate::proto::signaling::packet::Start {
-                        protocol_version,
-                        offer_sdp: peer_conn.local_description().unwrap().sdp.to_string(),
-                    },
-                )),
-            }
-            .encode_to_vec(),
+            crate::proto::signaling::Packet { which: Some(which) }.encode_to_vec(),
         ))
         .await?;
+    Ok(())
+}
 
-    Ok(Connecting {
-        fut: Box::pin(async move {
-            loop {
-                const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
-                let raw = if let Some(raw) = tokio::time::timeout(TIMEOUT, signaling_stream.try_next())
-                    .await
-                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out"))??
-                {
-                    raw
+/// Receives the next packet from the signaling stream, or None if the stream has ended.
+pub(crate) async fn receive_packet(
+    signaling_stream: &mut SignalingStream,
+) -> Result<Option<crate::proto::signaling::Packet>, Error> {
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+    loop {
+        let raw = if let Some(raw) = tokio::time::timeout(TIMEOUT, signaling_stream.try_next())
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out"))??
+        {
+            raw
+        } else {
+            return Ok(None);
+        };
+
+        match raw {
+            tokio_tungstenite::tungstenite::Message::Binary(d) => {
+                return Ok(Some(crate::proto::signaling::Packet::decode(d.as_slice())?));
+            }
+            tokio_tungstenite::tungstenite::Message::Ping(_) => {
+                // Note that upon receiving a ping message, tungstenite cues a pong reply automatically.
+                // When you call either read_message, write_message or write_pending next it will try to send that pong out if the underlying connection can take more data.
+                // This means you should not respond to ping frames manually.
+                continue;
+            }
+            _ => {
+                return Err(Error::InvalidPacket(raw));
+            }
+        }
+    }
+}
+
+/// Signals without trickle ICE: our offer already has every candidate in it, and so must our answer.
+async fn signal<P: datachannel_wrapper::Peer>(
+    signaling_stream: &mut SignalingStream,
+    peer_conn: &mut P,
+    recorder: &crate::report::Recorder,
+) -> Result<(), Error> {
+    let packet = if let Some(packet) = receive_packet(signaling_stream).await? {
+        packet
+    } else {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "stream ended early").into());
+    };
+
+    match &packet.which {
+        Some(crate::proto::signaling::packet::Which::Abort(abort)) => Err(abort.clone().into()),
+        Some(crate::proto::signaling::packet::Which::Offer(offer)) => {
+            log::info!("received an offer, this is the polite side. rolling back our local description and switching to answer");
+            recorder.record_relay_token(&offer.relay_token);
+
+            peer_conn.set_local_description(datachannel_wrapper::SdpType::Rollback)?;
+            peer_conn.set_remote_description(datachannel_wrapper::SessionDescription {
+                sdp_type: datachannel_wrapper::SdpType::Offer,
+                sdp: datachannel_wrapper::sdp::parse_sdp(&offer.sdp.to_string(), false)?,
+            })?;
+
+            let local_description = peer_conn.local_description().unwrap();
+            send_packet(
+                signaling_stream,
+                crate::proto::signaling::packet::Which::Answer(crate::proto::signaling::packet::Answer {
+                    sdp: local_description.sdp.to_string(),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+            log::info!("sent answer to impolite side");
+            Ok(())
+        }
+        Some(crate::proto::signaling::packet::Which::Answer(answer)) => {
+            log::info!("received an answer, this is the impolite side");
+            recorder.record_relay_token(&answer.relay_token);
+
+            peer_conn.set_remote_description(datachannel_wrapper::SessionDescription {
+                sdp_type: datachannel_wrapper::SdpType::Answer,
+                sdp: datachannel_wrapper::sdp::parse_sdp(&answer.sdp, false)?,
+            })?;
+            Ok(())
+        }
+        _ => Err(Error::UnexpectedPacket(packet)),
+    }
+}
+
+/// Signals with trickle ICE: candidates are exchanged as they're gathered instead of all at once in the descriptions.
+///
+/// Until an offer arrives we might still be the offerer, so our candidates are sent as they come, followed by the full
+/// offer once gathering completes for answerers that don't trickle. If an offer does arrive, the server drops anything we
+/// sent before our answer.
+///
+/// Returns whether the peer connection is already connected.
+async fn signal_trickle<P: datachannel_wrapper::Peer>(
+    signaling_stream: &mut SignalingStream,
+    event_rx: &mut tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionEvent>,
+    peer_conn: &mut P,
+    recorder: &crate::report::Recorder,
+) -> Result<bool, Error> {
+    let mut is_answerer = false;
+    let mut pending_remote_candidates = vec![];
+
+    loop {
+        tokio::select! {
+            packet = receive_packet(signaling_stream) => {
+                let packet = if let Some(packet) = packet? {
+                    packet
+                } else if peer_conn.remote_description().is_some() {
+                    // The other side is done signaling, so we'll make do with the candidates we have.
+                    return Ok(false);
                 } else {
                     return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "stream ended early").into());
                 };
 
-                let packet = match raw {
-                    tokio_tungstenite::tungstenite::Message::Binary(d) => {
-                        crate::proto::signaling::Packet::decode(d.as_slice())?
-                    }
-                    tokio_tungstenite::tungstenite::Message::Ping(_) => {
-                        // Note that upon receiving a ping message, tungstenite cues a pong reply automatically.
-                        // When you call either read_message, write_message or write_pending next it will try to send that pong out if the underlying connection can take more data.
-                        // This means you should not respond to ping frames manually.
-                        continue;
-                    }
-                    _ => {
-                        return Err(Error::InvalidPacket(raw));
-                    }
-                };
-
-                match &packet.which {
+                match packet.which {
                     Some(crate::proto::signaling::packet::Which::Abort(abort)) => {
-                        return Err(Error::ServerAbort(
-                            AbortReason::from_i32(abort.reason).unwrap_or_default(),
-                        ))
+                        return Err(abort.into());
                     }
-                    Some(crate::proto::signaling::packet::Which::Offer(offer)) => {
+                    Some(crate::proto::signaling::packet::Which::Offer(offer)) if !is_answerer => {
                         log::info!("received an offer, this is the polite side. rolling back our local description and switching to answer");
+                        is_answerer = true;
+                        recorder.record_relay_token(&offer.relay_token);
 
                         peer_conn.set_local_description(datachannel_wrapper::SdpType::Rollback)?;
                         peer_conn.set_remote_description(datachannel_wrapper::SessionDescription {
                             sdp_type: datachannel_wrapper::SdpType::Offer,
-                            sdp: datachannel_wrapper::sdp::parse_sdp(&offer.sdp.to_string(), false)?,
+                            sdp: datachannel_wrapper::sdp::parse_sdp(&offer.sdp, false)?,
                         })?;
 
+                        if !offer.trickle_ice {
+                            // The offerer doesn't trickle, so every candidate has to be in our answer.
+                            wait_for_new_gathering(event_rx).await?;
+                            recorder.end(crate::report::Phase::Gathering);
+                        }
+
                         let local_description = peer_conn.local_description().unwrap();
-                        signaling_stream
-                            .send(tokio_tungstenite::tungstenite::Message::Binary(
-                                crate::proto::signaling::Packet {
-                                    which: Some(crate::proto::signaling::packet::Which::Answer(
-                                        crate::proto::signaling::packet::Answer {
-                                            sdp: local_description.sdp.to_string(),
-                                        },
-                                    )),
-                                }
-                                .encode_to_vec(),
-                            ))
-                            .await?;
-                        log::info!("sent answer to impolite side");
-                        break;
+                        send_packet(
+                            signaling_stream,
+                            crate::proto::signaling::packet::Which::Answer(crate::proto::signaling::packet::Answer {
+                                sdp: local_description.sdp.to_string(),
+                                ..Default::default()
+                            }),
+                        )
+                        .await?;
+                        log::info!("sent answer to impolite side (trickle = {})", offer.trickle_ice);
+                        recorder.end(crate::report::Phase::OfferAnswer);
+                        recorder.begin(crate::report::Phase::Connection);
+
+                        if !offer.trickle_ice {
+                            return Ok(false);
+                        }
+
+                        for candidate in pending_remote_candidates.drain(..) {
+                            peer_conn.add_remote_candidate(candidate)?;
+                        }
                     }
-                    Some(crate::proto::signaling::packet::Which::Answer(answer)) => {
+                    Some(crate::proto::signaling::packet::Which::Answer(answer)) if !is_answerer => {
                         log::info!("received an answer, this is the impolite side");
+                        recorder.record_relay_token(&answer.relay_token);
 
                         peer_conn.set_remote_description(datachannel_wrapper::SessionDescription {
                             sdp_type: datachannel_wrapper::SdpType::Answer,
                             sdp: datachannel_wrapper::sdp::parse_sdp(&answer.sdp, false)?,
                         })?;
-                        break;
+                        recorder.end(crate::report::Phase::OfferAnswer);
+                        recorder.begin(crate::report::Phase::Connection);
+
+                        for candidate in pending_remote_candidates.drain(..) {
+                            peer_conn.add_remote_candidate(candidate)?;
+                        }
+                    }
+                    Some(crate::proto::signaling::packet::Which::Candidate(candidate)) => {
+                        let candidate = datachannel_wrapper::IceCandidate {
+                            candidate: candidate.candidate,
+                            mid: candidate.mid,
+                        };
+                        if peer_conn.remote_description().is_some() {
+                            peer_conn.add_remote_candidate(candidate)?;
+                        } else {
+                            pending_remote_candidates.push(candidate);
+                        }
                     }
-                    _ => {
-                        return Err(Error::UnexpectedPacket(packet));
+                    which => {
+                        return Err(Error::UnexpectedPacket(crate::proto::signaling::Packet { which }));
                     }
                 }
             }
 
-            signaling_stream.close(None).await?;
-
-            log::debug!(
-                "local sdp (type = {:?}): {}",
-                peer_conn.local_description().expect("local sdp").sdp_type,
-                peer_conn.local_description().expect("local sdp").sdp
-            );
-            log::debug!(
-                "remote sdp (type = {:?}): {}",
-                peer_conn.remote_description().expect("remote sdp").sdp_type,
-                peer_conn.remote_description().expect("remote sdp").sdp
-            );
-
-            loop {
-                match event_rx.recv().await {
-                    Some(signal) => match signal {
-                        datachannel_wrapper::PeerConnectionEvent::ConnectionStateChange(c) => match c {
-                            datachannel_wrapper::ConnectionState::Connected => {
-                                break;
-                            }
-                            datachannel_wrapper::ConnectionState::Disconnected => {
-                                return Err(Error::PeerConnectionDisconnected);
-                            }
-                            datachannel_wrapper::ConnectionState::Failed => {
-                                return Err(Error::PeerConnectionFailed);
-                            }
-                            datachannel_wrapper::ConnectionState::Closed => {
-                                return Err(Error::PeerConnectionClosed);
-                            }
-                            _ => {}
-                        },
+            event = event_rx.recv() => {
+                match event {
+                    Some(datachannel_wrapper::PeerConnectionEvent::IceCandidate(candidate)) => {
+                        send_packet(
+                            signaling_stream,
+                            crate::proto::signaling::packet::Which::Candidate(crate::proto::signaling::packet::Candidate {
+                                candidate: candidate.candidate,
+                                mid: candidate.mid,
+                            }),
+                        )
+                        .await?;
+                    }
+                    Some(datachannel_wrapper::PeerConnectionEvent::GatheringStateChange(
+                        datachannel_wrapper::GatheringState::Complete,
+                    )) => {
+                        recorder.end(crate::report::Phase::Gathering);
+                        if !is_answerer {
+                            send_packet(
+                                signaling_stream,
+                                crate::proto::signaling::packet::Which::GatheringComplete(
+                                    crate::proto::signaling::packet::GatheringComplete {
+                                        sdp: peer_conn.local_description().unwrap().sdp.to_string(),
+                                    },
+                                ),
+                            )
+                            .await?;
+                        }
+                    }
+                    Some(datachannel_wrapper::PeerConnectionEvent::ConnectionStateChange(c)) => match c {
+                        datachannel_wrapper::ConnectionState::Connected => {
+                            return Ok(true);
+                        }
+                        datachannel_wrapper::ConnectionState::Disconnected => {
+                            return Err(Error::PeerConnectionDisconnected);
+                        }
+                        datachannel_wrapper::ConnectionState::Failed => {
+                            return Err(Error::PeerConnectionFailed);
+                        }
+                        datachannel_wrapper::ConnectionState::Closed => {
+                            return Err(Error::PeerConnectionClosed);
+                        }
                         _ => {}
                     },
-                    None => unreachable!(),
+                    Some(_) => {}
+                    None => {
+                        return Err(Error::PeerConnectionClosed);
+                    }
                 }
             }
+        }
+    }
+}
 
-            Ok((dc, peer_conn))
-        }),
-    })
+/// Waits for a new round of gathering to complete, skipping anything left over from the one before it.
+async fn wait_for_new_gathering(
+    event_rx: &mut tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionEvent>,
+) -> Result<(), Error> {
+    let mut started = false;
+    loop {
+        match event_rx.recv().await {
+            Some(datachannel_wrapper::PeerConnectionEvent::GatheringStateChange(
+                datachannel_wrapper::GatheringState::InProgress,
+            )) => {
+                started = true;
+            }
+            Some(datachannel_wrapper::PeerConnectionEvent::GatheringStateChange(
+                datachannel_wrapper::GatheringState::Complete,
+            )) if started => {
+                return Ok(());
+            }
+            Some(_) => {}
+            None => {
+                return Err(Error::PeerConnectionClosed);
+            }
+        }
+    }
 }
 
-impl std::future::Future for Connecting {
-    type Output = Result<(datachannel_wrapper::DataChannel, datachannel_wrapper::PeerConnection), Error>;
+pub(crate) async fn wait_for_connection(
+    event_rx: &mut tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionEvent>,
+) -> Result<(), Error> {
+    loop {
+        match event_rx.recv().await {
+            Some(signal) => match signal {
+                datachannel_wrapper::PeerConnectionEvent::ConnectionStateChange(c) => match c {
+                    datachannel_wrapper::ConnectionState::Connected => {
+                        return Ok(());
+                    }
+                    datachannel_wrapper::ConnectionState::Disconnected => {
+                        return Err(Error::PeerConnectionDisconnected);
+                    }
+                    datachannel_wrapper::ConnectionState::Failed => {
+                        return Err(Error::PeerConnectionFailed);
+                    }
+                    datachannel_wrapper::ConnectionState::Closed => {
+                        return Err(Error::PeerConnectionClosed);
+                    }
+                    _ => {}
+                },
+                _ => {}
+            },
+            None => unreachable!(),
+        }
+    }
+}
+
+impl<P> std::future::Future for Connecting<P> {
+    type Output = Result<(datachannel_wrapper::DataChannel, P), Error>;
 
     fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
         self.fut.poll_unpin(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_url() {
+        for (addr, expected) in [
+            ("wss://example.com", "wss://example.com/relay"),
+            ("wss://example.com/", "wss://example.com/relay"),
+            ("wss://example.com/signaling", "wss://example.com/signaling/relay"),
+            ("wss://example.com/signaling/", "wss://example.com/signaling/relay"),
+        ] {
+            assert_eq!(endpoint_url(addr, "relay").unwrap().as_str(), expected);
+        }
+    }
+}
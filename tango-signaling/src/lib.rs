@@ -4,6 +4,21 @@ mod client;
 #[cfg(feature = "client")]
 pub use client::*;
 
+#[cfg(feature = "client")]
+pub mod lobbies;
+
+#[cfg(feature = "client")]
+pub mod manual;
+
+#[cfg(feature = "client")]
+pub mod queue;
+
+#[cfg(feature = "client")]
+pub mod relay;
+
+#[cfg(feature = "client")]
+pub mod report;
+
 #[cfg(feature = "proto")]
 pub mod proto;
 
@@ -0,0 +1,109 @@
+use prost::Message;
+
+use crate::client::{endpoint_url, is_abort_status, Auth, Error};
+
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// What a publicly listed lobby is for.
+#[derive(Clone, Debug)]
+pub struct Listing {
+    pub family: String,
+    pub variant: u8,
+    pub patch: Option<(String, String)>,
+    pub match_type: (u8, u8),
+    pub nickname: String,
+    pub comment: String,
+}
+
+impl Listing {
+    pub(crate) fn to_proto(&self) -> crate::proto::signaling::Listing {
+        let (patch_name, patch_version) = self.patch.clone().unwrap_or_default();
+        crate::proto::signaling::Listing {
+            family: self.family.clone(),
+            variant: self.variant as u32,
+            patch_name,
+            patch_version,
+            match_type: self.match_type.0 as u32,
+            match_subtype: self.match_type.1 as u32,
+            nickname: self.nickname.clone(),
+            comment: self.comment.clone(),
+        }
+    }
+
+    fn from_proto(listing: crate::proto::signaling::Listing) -> Self {
+        Self {
+            family: listing.family,
+            variant: listing.variant as u8,
+            patch: if !listing.patch_name.is_empty() {
+                Some((listing.patch_name, listing.patch_version))
+            } else {
+                None
+            },
+            match_type: (listing.match_type as u8, listing.match_subtype as u8),
+            nickname: listing.nickname,
+            comment: listing.comment,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Lobby {
+    /// Joined like any other link code, with [`crate::connect`].
+    pub session_id: String,
+    pub listing: Listing,
+    pub protocol_versions: std::ops::RangeInclusive<u32>,
+    /// How long the lobby has been listed.
+    pub age: std::time::Duration,
+}
+
+/// Fetches the lobbies listed on the signaling server, oldest first. If a family is given, only lobbies for that family
+/// are returned.
+pub async fn list(addr: &str, family: Option<&str>, auth: &Auth) -> Result<Vec<Lobby>, Error> {
+    let mut url = endpoint_url(addr, "lobbies")?;
+    let scheme = match url.scheme() {
+        "ws" => "http",
+        "wss" => "https",
+        scheme => scheme,
+    }
+    .to_string();
+    let _ = url.set_scheme(&scheme);
+    if let Some(family) = family {
+        url.set_query(Some(
+            &url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("family", family)
+                .finish(),
+        ));
+    }
+
+    let mut req = reqwest::Client::new()
+        .get(url)
+        .timeout(TIMEOUT)
+        .header("User-Agent", format!("tango-signaling/{}", env!("CARGO_PKG_VERSION")));
+    if let Some(token) = auth.token.as_ref() {
+        req = req.bearer_auth(token);
+    }
+
+    let resp = req.send().await?;
+    if is_abort_status(resp.status()) {
+        let abort = crate::proto::signaling::packet::Abort::decode(resp.bytes().await?)?;
+        return Err(abort.into());
+    }
+
+    let lobby_list = crate::proto::signaling::LobbyList::decode(resp.error_for_status()?.bytes().await?)?;
+    Ok(lobby_list
+        .lobbies
+        .into_iter()
+        .filter_map(|lobby| {
+            Some(Lobby {
+                session_id: lobby.session_id,
+                listing: Listing::from_proto(lobby.listing?),
+                protocol_versions: if lobby.min_protocol_version != 0 {
+                    lobby.min_protocol_version
+                } else {
+                    lobby.protocol_version
+                }..=lobby.protocol_version,
+                age: std::time::Duration::from_secs(lobby.age_secs as u64),
+            })
+        })
+        .collect())
+}
@@ -0,0 +1,113 @@
+use std::io::{Read, Write};
+
+use datachannel_wrapper::Peer;
+
+use crate::client::{create_data_channel, wait_for_connection, Connecting, Error};
+
+const OFFER_TAG: u8 = b'o';
+const ANSWER_TAG: u8 = b'a';
+
+fn encode_blob(tag: u8, sdp: &str) -> Result<String, Error> {
+    let mut encoder = flate2::write::DeflateEncoder::new(vec![tag], flate2::Compression::best());
+    encoder.write_all(sdp.as_bytes())?;
+    Ok(base64::encode_config(encoder.finish()?, base64::URL_SAFE_NO_PAD))
+}
+
+fn decode_blob(expected_tag: u8, blob: &str) -> Result<String, Error> {
+    let raw = base64::decode_config(
+        blob.chars().filter(|c| !c.is_whitespace()).collect::<String>(),
+        base64::URL_SAFE_NO_PAD,
+    )
+    .map_err(|_| Error::InvalidBlob)?;
+
+    let (tag, compressed) = if let Some((tag, compressed)) = raw.split_first() {
+        (*tag, compressed)
+    } else {
+        return Err(Error::InvalidBlob);
+    };
+
+    if tag != expected_tag {
+        return Err(Error::InvalidBlob);
+    }
+
+    let mut sdp = String::new();
+    flate2::read::DeflateDecoder::new(compressed)
+        .read_to_string(&mut sdp)
+        .map_err(|_| Error::InvalidBlob)?;
+    Ok(sdp)
+}
+
+/// An offer waiting for the remote to send back their answer.
+pub struct PendingOffer {
+    dc: datachannel_wrapper::DataChannel,
+    event_rx: tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionEvent>,
+    peer_conn: datachannel_wrapper::PeerConnection,
+}
+
+/// Creates an offer, returning it alongside the blob to give to the remote.
+///
+/// This is for when there's no signaling server: players copy and paste the blobs to each other instead. Blobs are only
+/// made once ICE gathering is complete, so they contain every candidate.
+pub async fn offer(rtc_config: datachannel_wrapper::RtcConfig) -> Result<(PendingOffer, String), Error> {
+    let (dc, event_rx, peer_conn) = create_data_channel::<datachannel_wrapper::PeerConnection>(rtc_config).await?;
+    let blob = encode_blob(OFFER_TAG, &peer_conn.local_description().unwrap().sdp.to_string())?;
+    Ok((
+        PendingOffer {
+            dc,
+            event_rx,
+            peer_conn,
+        },
+        blob,
+    ))
+}
+
+impl PendingOffer {
+    /// Accepts the answer blob from the remote.
+    pub fn accept(self, answer_blob: &str) -> Result<Connecting, Error> {
+        let PendingOffer {
+            dc,
+            mut event_rx,
+            mut peer_conn,
+        } = self;
+
+        peer_conn.set_remote_description(datachannel_wrapper::SessionDescription {
+            sdp_type: datachannel_wrapper::SdpType::Answer,
+            sdp: datachannel_wrapper::sdp::parse_sdp(&decode_blob(ANSWER_TAG, answer_blob)?, false)?,
+        })?;
+
+        Ok(Connecting {
+            fut: Box::pin(async move {
+                wait_for_connection(&mut event_rx).await?;
+                Ok((dc, peer_conn))
+            }),
+        })
+    }
+}
+
+/// Answers an offer blob from the remote, returning the connection alongside the blob to send back.
+pub async fn answer(
+    rtc_config: datachannel_wrapper::RtcConfig,
+    offer_blob: &str,
+) -> Result<(Connecting, String), Error> {
+    let offer_sdp = decode_blob(OFFER_TAG, offer_blob)?;
+
+    let (dc, mut event_rx, mut peer_conn) =
+        create_data_channel::<datachannel_wrapper::PeerConnection>(rtc_config).await?;
+    peer_conn.set_local_description(datachannel_wrapper::SdpType::Rollback)?;
+    peer_conn.set_remote_description(datachannel_wrapper::SessionDescription {
+        sdp_type: datachannel_wrapper::SdpType::Offer,
+        sdp: datachannel_wrapper::sdp::parse_sdp(&offer_sdp, false)?,
+    })?;
+
+    let blob = encode_blob(ANSWER_TAG, &peer_conn.local_description().unwrap().sdp.to_string())?;
+
+    Ok((
+        Connecting {
+            fut: Box::pin(async move {
+                wait_for_connection(&mut event_rx).await?;
+                Ok((dc, peer_conn))
+            }),
+        },
+        blob,
+    ))
+}
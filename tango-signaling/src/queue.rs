@@ -0,0 +1,103 @@
+use futures_util::SinkExt;
+use futures_util::TryStreamExt;
+use prost::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+use crate::client::{endpoint_url, open_websocket, Auth, Error};
+
+/// What the player wants to play. Only players who want the same thing are paired.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub family: String,
+    pub variant: u8,
+    pub patch: Option<(String, String)>,
+    pub match_type: (u8, u8),
+}
+
+/// Waits in the random matchmaking queue until an opponent is found, returning the session ID to connect with.
+///
+/// Both players are given the same session ID, which is then used with [`crate::connect`] as with any other link code.
+/// Dropping the future leaves the queue. If no timeout is given, the server's default is used.
+pub async fn enqueue(
+    addr: &str,
+    request: Request,
+    protocol_versions: std::ops::RangeInclusive<u32>,
+    timeout: Option<std::time::Duration>,
+    auth: &Auth,
+) -> Result<String, Error> {
+    let url = endpoint_url(addr, "queue")?;
+
+    let mut stream = open_websocket(
+        || {
+            let mut req = url.to_string().into_client_request()?;
+            req.headers_mut().append(
+                "User-Agent",
+                tokio_tungstenite::tungstenite::http::HeaderValue::from_str(&format!(
+                    "tango-signaling/{}",
+                    env!("CARGO_PKG_VERSION")
+                ))
+                .map_err(|e| tokio_tungstenite::tungstenite::http::Error::from(e))?,
+            );
+            auth.apply(&mut req)?;
+            Ok(req)
+        },
+        None,
+    )
+    .await?;
+
+    let (patch_name, patch_version) = request.patch.unwrap_or_default();
+    stream
+        .send(tokio_tungstenite::tungstenite::Message::Binary(
+            crate::proto::signaling::Packet {
+                which: Some(crate::proto::signaling::packet::Which::Enqueue(
+                    crate::proto::signaling::packet::Enqueue {
+                        family: request.family,
+                        variant: request.variant as u32,
+                        patch_name,
+                        patch_version,
+                        match_type: request.match_type.0 as u32,
+                        match_subtype: request.match_type.1 as u32,
+                        protocol_version: *protocol_versions.end(),
+                        min_protocol_version: *protocol_versions.start(),
+                        timeout_secs: timeout.map(|t| t.as_secs() as u32).unwrap_or(0),
+                    },
+                )),
+            }
+            .encode_to_vec(),
+        ))
+        .await?;
+
+    loop {
+        let raw = if let Some(raw) = stream.try_next().await? {
+            raw
+        } else {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "stream ended early").into());
+        };
+
+        let packet = match raw {
+            tokio_tungstenite::tungstenite::Message::Binary(d) => {
+                crate::proto::signaling::Packet::decode(d.as_slice())?
+            }
+            tokio_tungstenite::tungstenite::Message::Ping(_) => {
+                continue;
+            }
+            _ => {
+                return Err(Error::InvalidPacket(raw));
+            }
+        };
+
+        match packet.which {
+            Some(crate::proto::signaling::packet::Which::Matched(matched)) => {
+                log::info!("matched from queue: {}", matched.session_id);
+                let _ = stream.close(None).await;
+                return Ok(matched.session_id);
+            }
+            Some(crate::proto::signaling::packet::Which::Abort(abort)) => {
+                return Err(abort.into());
+            }
+            _ => {
+                return Err(Error::UnexpectedPacket(packet));
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use futures_util::SinkExt;
+use futures_util::StreamExt;
+use futures_util::TryStreamExt;
+use prost::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+use crate::client::{endpoint_url, open_websocket, receive_packet, Auth, Error, SignalingStream};
+
+pub struct Sender(futures_util::stream::SplitSink<SignalingStream, tokio_tungstenite::tungstenite::Message>);
+
+impl Sender {
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.0
+            .send(tokio_tungstenite::tungstenite::Message::Binary(
+                crate::proto::signaling::Packet {
+                    which: Some(crate::proto::signaling::packet::Which::RelayData(
+                        crate::proto::signaling::packet::RelayData { data: data.to_vec() },
+                    )),
+                }
+                .encode_to_vec(),
+            ))
+            .await?;
+        Ok(())
+    }
+}
+
+pub struct Receiver(futures_util::stream::SplitStream<SignalingStream>);
+
+impl Receiver {
+    /// Receives the next thing the other side sent, or None if the relay has closed.
+    pub async fn receive(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            let raw = if let Some(raw) = self.0.try_next().await? {
+                raw
+            } else {
+                return Ok(None);
+            };
+
+            let packet = match raw {
+                tokio_tungstenite::tungstenite::Message::Binary(d) => {
+                    crate::proto::signaling::Packet::decode(d.as_slice())?
+                }
+                tokio_tungstenite::tungstenite::Message::Ping(_) => {
+                    continue;
+                }
+                tokio_tungstenite::tungstenite::Message::Close(_) => {
+                    return Ok(None);
+                }
+                _ => {
+                    return Err(Error::InvalidPacket(raw));
+                }
+            };
+
+            match packet.which {
+                Some(crate::proto::signaling::packet::Which::RelayData(relay_data)) => {
+                    return Ok(Some(relay_data.data));
+                }
+                Some(crate::proto::signaling::packet::Which::Abort(abort)) => {
+                    return Err(abort.into());
+                }
+                _ => {
+                    return Err(Error::UnexpectedPacket(packet));
+                }
+            }
+        }
+    }
+}
+
+/// Connects to the other side through the signaling server's relay, for when the peer connection couldn't be made.
+///
+/// Both sides must connect with the same session ID and the relay token the server gave them while signaling. Also
+/// returns whether this side got to the relay first, which both sides will agree on.
+pub async fn connect(
+    addr: &str,
+    session_id: &str,
+    relay_token: &str,
+    auth: &Auth,
+) -> Result<(Sender, Receiver, bool), Error> {
+    let mut url = endpoint_url(addr, "relay")?;
+    url.set_query(Some(
+        &url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("session_id", session_id)
+            .append_pair("relay_token", relay_token)
+            .finish(),
+    ));
+
+    let mut stream = open_websocket(
+        || {
+            let mut req = url.to_string().into_client_request()?;
+            req.headers_mut().append(
+                "User-Agent",
+                tokio_tungstenite::tungstenite::http::HeaderValue::from_str(&format!(
+                    "tango-signaling/{}",
+                    env!("CARGO_PKG_VERSION")
+                ))
+                .map_err(|e| tokio_tungstenite::tungstenite::http::Error::from(e))?,
+            );
+            auth.apply(&mut req)?;
+            Ok(req)
+        },
+        None,
+    )
+    .await?;
+
+    let packet = if let Some(packet) = receive_packet(&mut stream).await? {
+        packet
+    } else {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "stream ended early").into());
+    };
+
+    let is_first = match packet.which {
+        Some(crate::proto::signaling::packet::Which::RelayReady(relay_ready)) => relay_ready.is_first,
+        Some(crate::proto::signaling::packet::Which::Abort(abort)) => {
+            return Err(abort.into());
+        }
+        _ => {
+            return Err(Error::UnexpectedPacket(packet));
+        }
+    };
+    log::info!("relay ready for {} (first = {})", session_id, is_first);
+
+    let (tx, rx) = stream.split();
+    Ok((Sender(tx), Receiver(rx), is_first))
+}
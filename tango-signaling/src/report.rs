@@ -0,0 +1,148 @@
+pub use datachannel_wrapper::CandidateType;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Opening the WebSocket to the signaling server.
+    WebSocket,
+    /// Waiting for the server to send ICE servers.
+    Hello,
+    /// Gathering local ICE candidates. When trickling, this overlaps with the offer/answer exchange.
+    Gathering,
+    /// Exchanging the offer and answer through the signaling server.
+    OfferAnswer,
+    /// ICE connectivity checks and the DTLS handshake, until the peer connection is up.
+    Connection,
+    /// Setting up SCTP over the peer connection and opening the data channel on it.
+    DataChannel,
+}
+
+/// What happened while connecting, for working out why a connection failed (or why it's slow).
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub websocket_attempts: u32,
+    pub websocket: Option<std::time::Duration>,
+    pub hello: Option<std::time::Duration>,
+    pub gathering: Option<std::time::Duration>,
+    pub offer_answer: Option<std::time::Duration>,
+    pub connection: Option<std::time::Duration>,
+    pub data_channel: Option<std::time::Duration>,
+    /// The types of the candidate pair ICE settled on, if it got that far.
+    pub local_candidate_type: Option<CandidateType>,
+    pub remote_candidate_type: Option<CandidateType>,
+    pub failed_phase: Option<Phase>,
+    pub failure: Option<String>,
+    /// Whether the signaling server offered to relay game data, should the peer connection fail.
+    pub relay_available: bool,
+    /// What the signaling server's operator had to say, if anything.
+    pub broadcast_message: Option<String>,
+    /// The operator's standing notice, keyed by language tag.
+    pub notice: std::collections::HashMap<String, String>,
+    /// The oldest client version the operator recommends, unparsed.
+    pub min_recommended_client_version: Option<String>,
+}
+
+impl Report {
+    pub fn duration(&self, phase: Phase) -> Option<std::time::Duration> {
+        match phase {
+            Phase::WebSocket => self.websocket,
+            Phase::Hello => self.hello,
+            Phase::Gathering => self.gathering,
+            Phase::OfferAnswer => self.offer_answer,
+            Phase::Connection => self.connection,
+            Phase::DataChannel => self.data_channel,
+        }
+    }
+
+    fn duration_mut(&mut self, phase: Phase) -> &mut Option<std::time::Duration> {
+        match phase {
+            Phase::WebSocket => &mut self.websocket,
+            Phase::Hello => &mut self.hello,
+            Phase::Gathering => &mut self.gathering,
+            Phase::OfferAnswer => &mut self.offer_answer,
+            Phase::Connection => &mut self.connection,
+            Phase::DataChannel => &mut self.data_channel,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    report: Report,
+    in_progress: Vec<(Phase, std::time::Instant)>,
+    /// Kept out of the report, since it lets whoever has it onto the relay.
+    relay_token: Option<String>,
+}
+
+/// Fills in a report as a connection goes along.
+///
+/// Clones share the same report, so the caller can hold on to one to look at the report once connecting has finished
+/// or failed.
+#[derive(Clone, Default)]
+pub struct Recorder(std::sync::Arc<std::sync::Mutex<State>>);
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn report(&self) -> Report {
+        self.0.lock().unwrap().report.clone()
+    }
+
+    /// What to connect to the relay with, if the server gave us one once signaling was done.
+    pub fn relay_token(&self) -> Option<String> {
+        self.0.lock().unwrap().relay_token.clone()
+    }
+
+    pub(crate) fn begin(&self, phase: Phase) {
+        self.0
+            .lock()
+            .unwrap()
+            .in_progress
+            .push((phase, std::time::Instant::now()));
+    }
+
+    pub(crate) fn end(&self, phase: Phase) {
+        let mut state = self.0.lock().unwrap();
+        let i = if let Some(i) = state.in_progress.iter().position(|(p, _)| *p == phase) {
+            i
+        } else {
+            return;
+        };
+        let (_, started_at) = state.in_progress.remove(i);
+        *state.report.duration_mut(phase) = Some(started_at.elapsed());
+    }
+
+    pub(crate) fn record_websocket_attempt(&self) {
+        self.0.lock().unwrap().report.websocket_attempts += 1;
+    }
+
+    pub(crate) fn record_candidate_pair(&self, local: &str, remote: &str) {
+        let mut state = self.0.lock().unwrap();
+        state.report.local_candidate_type = CandidateType::parse(local);
+        state.report.remote_candidate_type = CandidateType::parse(remote);
+    }
+
+    pub(crate) fn record_notices(&self, hello: &crate::proto::signaling::packet::Hello) {
+        let mut state = self.0.lock().unwrap();
+        state.report.broadcast_message = Some(hello.broadcast_message.clone()).filter(|m| !m.is_empty());
+        state.report.notice = hello.notice.clone();
+        state.report.min_recommended_client_version =
+            Some(hello.min_recommended_client_version.clone()).filter(|v| !v.is_empty());
+    }
+
+    pub(crate) fn record_relay_available(&self, relay_available: bool) {
+        self.0.lock().unwrap().report.relay_available = relay_available;
+    }
+
+    pub(crate) fn record_relay_token(&self, relay_token: &str) {
+        self.0.lock().unwrap().relay_token = Some(relay_token.to_string()).filter(|t| !t.is_empty());
+    }
+
+    /// Records the failure against the most recently started phase that hasn't finished.
+    pub(crate) fn fail(&self, error: &impl std::fmt::Display) {
+        let mut state = self.0.lock().unwrap();
+        state.report.failed_phase = state.in_progress.last().map(|(phase, _)| *phase);
+        state.report.failure = Some(error.to_string());
+    }
+}
@@ -15,12 +15,53 @@ play-details-game = Game
     .unrecognized = Unknown
 play-details-match-type = Match type
 play-details-reveal-setup = Reveal setup
+play-details-series = Series
+    .unlimited = Unlimited
+    .first-to = First to { $n }
+play-details-ruleset = Ruleset
+    .none = None
+play-details-lockstep = Lockstep
+    .description = Wait for the opponent's inputs instead of predicting them. Never rolls back, but input delay has to cover the full round trip.
 play-details-input-delay = Input delay
     .suggest = Suggest
 
 play-connection-task-starting = Starting connection...
+play-connection-task-queued = Looking for an opponent...
 play-connection-task-signaling = Connecting to matchmaking server...
 play-connection-task-waiting = Waiting for opponent...
+play-connection-task-relaying = Couldn't connect directly, connecting through matchmaking server...
+
+play-queue = Find a random opponent for the selected game
+play-manual-offer = Connect without a server: create an invite
+play-manual-answer = Connect without a server: paste an invite
+play-manual-gathering = Gathering connection info...
+play-manual-offer-instructions = Send this invite to your opponent, then paste their reply below.
+play-manual-answer-instructions = Paste your opponent's invite below.
+play-manual-reply-instructions = Send this reply to your opponent. Waiting for them to connect...
+play-manual-copy = Copy
+play-manual-paste = Paste here
+
+play-lobby-list = Browse public lobbies
+play-lobby-list-refresh = Refresh
+play-lobby-list-empty = Nobody is hosting a public lobby right now.
+play-lobby-list-error = Couldn't fetch public lobbies: { $error }
+play-lobby-list-game = Game
+play-lobby-list-patch = Patch
+play-lobby-list-host = Host
+play-lobby-list-comment = Comment
+play-lobby-list-age = Waiting
+    .minutes = { $minutes }m
+play-lobby-list-join = Join
+play-lobby-list-incompatible = This lobby's version of Tango is incompatible with yours.
+
+play-public-lobby = Host a public lobby for the selected game
+play-public-lobby-comment = Comment (optional)
+play-public-lobby-host = Host
+
+play-direct = Connect directly over LAN
+play-direct-host = Host on port { $port }
+play-direct-address = Address
+play-direct-connect = Connect
 
 select-save = Select save
     .select = Select
@@ -44,8 +85,27 @@ lobby-issue-no-remote-patches = The opponent does not have any copy of the patch
 lobby-issue-unrecognized-game = The opponent selected an unrecognized game.
 lobby-issue-incompatible = Game is not compatible with the opponent's.
 lobby-issue-match-type-mismatch = Match type does not match the opponent's.
+lobby-issue-series-mismatch = Series length does not match the opponent's.
+lobby-issue-lockstep-mismatch = Lockstep setting does not match the opponent's.
+lobby-issue-ruleset-mismatch = Ruleset does not match the opponent's.
+lobby-issue-ruleset-violations = Your setup does not follow the ruleset:
 lobby-issue-no-local-selection = You have not selected a game.
 lobby-issue-no-remote-selection = The opponent has not selected a game.
+lobby-transfer-progress = Exchanging save data: sent { $sent }/{ $to_send }, received { $received }/{ $to_receive }
+
+chat-you = You
+chat-opponent = Opponent
+chat-hint = Say something...
+chat-send = Send
+
+ruleset-violation-too-many-mega-chips = Too many MegaChips in folder: { $count } (max { $max })
+ruleset-violation-too-many-giga-chips = Too many GigaChips in folder: { $count } (max { $max })
+ruleset-violation-too-many-chip-copies = Too many copies of { $name } in folder: { $count } (max { $max })
+ruleset-violation-banned-chip = Banned chip in folder: { $name }
+ruleset-violation-banned-navicust-part = Banned NaviCust part installed: { $name }
+ruleset-violation-banned-patch-card = Banned patch card enabled: { $name }
+ruleset-violation-too-much-patch-card-mb = Patch cards use too much MB: { $mb } (max { $max })
+ruleset-violation-too-many-patch-card-copies = Too many copies of patch card { $name }: { $count } (max { $max })
 
 opponent-setup = Opponent's setup
 own-setup = Own setup
@@ -55,7 +115,45 @@ connection-error-remote-protocol-version-too-old = Unable to connect to the othe
 connection-error-remote-protocol-version-too-new = The other player is using a newer version of Tango. Please update.
 connection-error-protocol-version-too-old = Your version of Tango is too old to connect to the matchmaking server. Please update.
 connection-error-eof = The other player disconnected.
+connection-error-queue-timeout = No opponent was found. Try again later.
+connection-error-server-busy = The matchmaking server is too busy right now. Try again in a minute.
+connection-error-unauthorized = The matchmaking server didn't accept your token. Check the matchmaking token in your settings.
+connection-error-forbidden = Your matchmaking token doesn't allow playing this game.
+connection-error-kicked = The matchmaking server's operator closed your session.
+connection-error-banned = You have been banned from the matchmaking server.
 connection-error-other = A connection error has occurred: { $error }
+connection-error-server-message = Message from the matchmaking server: { $message }
 connection-error-confirm = Damn!
+connection-error-details = Details
+
+connection-report-websocket = Reaching the matchmaking server
+connection-report-hello = Getting ICE servers
+connection-report-gathering = Gathering candidates
+connection-report-offer-answer = Exchanging offer and answer
+connection-report-connection = Connecting to the other player
+connection-report-data-channel = Opening the data channel
+connection-report-unfinished = Not finished
+connection-report-websocket-attempts = Attempts to reach the server
+connection-report-candidate-pair = Connection type
+connection-report-candidate-type-host = Direct (local)
+connection-report-candidate-type-srflx = Direct (via STUN)
+connection-report-candidate-type-prflx = Direct (peer reflexive)
+connection-report-candidate-type-relay = Relayed (TURN)
+connection-report-candidate-type-unknown = Unknown
+connection-report-failure = Failure
+
+server-notice-outdated = The matchmaking server recommends updating to Tango { $version } or newer.
 
 play-show-link-code = Show link code
+
+session-series-score = { $wins } - { $losses } (first to { $first_to })
+session-chat = Chat
+session-diagnostics = Netplay diagnostics
+    .no-round = No round in progress.
+    .ticks = Ticks
+    .rollbacks = Rollbacks
+    .rollback-depth = Rollback depth (last / mean / max)
+    .fastforward-time = Fastforward time (mean / max)
+    .dropped-frames = Dropped frames
+    .stalled-frames = Stalled frames
+    .remote-lead = Remote lead (min..=max)
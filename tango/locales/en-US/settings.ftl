@@ -32,6 +32,8 @@ settings-input-delay = Input delay
 settings-ui-scale = UI scale
 settings-max-queue-length = Max queue length
 settings-matchmaking-endpoint = Matchmaking endpoint
+settings-matchmaking-token = Matchmaking token
+settings-matchmaking-token.description = Only needed for private matchmaking servers. Ask whoever runs the server for one.
 settings-replaycollector-endpoint = Replay collector endpoint
 settings-patch-repo = Patches repository
 settings-enable-patch-autoupdate = Enable autoupdate
@@ -60,4 +62,6 @@ settings-use-relay = Use relay
     .auto = Automatic
     .always = Always
     .never = Never
+settings-unreliable-inputs = Send inputs unreliably
+    .description = Send inputs over an unordered channel, resending recent ones with each packet. This can feel smoother on lossy connections. Only used if your opponent has it on too.
 settings-speed-change = Speed change
@@ -1,5 +1,6 @@
 #[cfg(feature = "cpal")]
 pub mod cpal;
+pub mod null;
 #[cfg(feature = "sdl2-audio")]
 pub mod sdl2;
 
@@ -0,0 +1,52 @@
+use crate::audio;
+
+const SAMPLE_RATE: u32 = 48000;
+
+/// A backend that drains the stream in real time and discards it.
+///
+/// mGBA runs with audio sync enabled, so without an output device something still has to consume samples for the core
+/// to make progress.
+pub struct Backend {
+    running: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Backend {
+    pub fn new(mut stream: impl audio::Stream + Send + 'static) -> Self {
+        let running = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let thread = std::thread::spawn({
+            let running = running.clone();
+            move || {
+                let mut buf = vec![[0i16; audio::NUM_CHANNELS]; audio::SAMPLES];
+                let period = std::time::Duration::from_secs_f64(audio::SAMPLES as f64 / SAMPLE_RATE as f64);
+                let mut deadline = std::time::Instant::now();
+                while running.load(std::sync::atomic::Ordering::Relaxed) {
+                    stream.fill(&mut buf);
+                    deadline += period;
+                    if let Some(d) = deadline.checked_duration_since(std::time::Instant::now()) {
+                        std::thread::sleep(d);
+                    }
+                }
+            }
+        });
+        Self {
+            running,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        self.running.store(false, std::sync::atomic::Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl audio::Backend for Backend {
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+}
@@ -0,0 +1,556 @@
+use datachannel_wrapper::Peer;
+
+use crate::{audio, config, game, net, patch, save, session, stats};
+
+/// Scripted inputs for the sparring bot.
+///
+/// Each line is a frame count followed by the keys to hold for that many frames, e.g. `30 a left`. Blank lines and
+/// lines starting with `#` are ignored. The script loops once it reaches the end.
+pub struct Script {
+    steps: Vec<(u32, u32)>,
+    total_frames: u64,
+}
+
+impl Script {
+    pub fn idle() -> Self {
+        Self {
+            steps: vec![(1, 0)],
+            total_frames: 1,
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self, anyhow::Error> {
+        let mut steps = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let frames = parts
+                .next()
+                .unwrap()
+                .parse::<u32>()
+                .map_err(|e| anyhow::anyhow!("line {}: {}", i + 1, e))?;
+
+            let mut joyflags = 0;
+            for key in parts {
+                joyflags |= match key.to_lowercase().as_str() {
+                    "a" => mgba::input::keys::A,
+                    "b" => mgba::input::keys::B,
+                    "select" => mgba::input::keys::SELECT,
+                    "start" => mgba::input::keys::START,
+                    "right" => mgba::input::keys::RIGHT,
+                    "left" => mgba::input::keys::LEFT,
+                    "up" => mgba::input::keys::UP,
+                    "down" => mgba::input::keys::DOWN,
+                    "r" => mgba::input::keys::R,
+                    "l" => mgba::input::keys::L,
+                    key => anyhow::bail!("line {}: unknown key: {}", i + 1, key),
+                };
+            }
+            steps.push((frames, joyflags));
+        }
+
+        let total_frames = steps.iter().map(|(frames, _)| *frames as u64).sum::<u64>();
+        if total_frames == 0 {
+            anyhow::bail!("script has no frames");
+        }
+
+        Ok(Self { steps, total_frames })
+    }
+
+    pub fn joyflags(&self, frame: u64) -> u32 {
+        let mut frame = frame % self.total_frames;
+        for (frames, joyflags) in self.steps.iter() {
+            if frame < *frames as u64 {
+                return *joyflags;
+            }
+            frame -= *frames as u64;
+        }
+        0
+    }
+}
+
+struct Selection {
+    game: &'static (dyn game::Game + Send + Sync),
+    patch: Option<(String, semver::Version, patch::Version)>,
+    rom: Vec<u8>,
+    save: Box<dyn tango_dataview::save::Save + Send + Sync>,
+}
+
+struct Inventory {
+    roms: std::collections::HashMap<&'static (dyn game::Game + Send + Sync), Vec<u8>>,
+    saves: std::collections::HashMap<&'static (dyn game::Game + Send + Sync), Vec<save::ScannedSave>>,
+    patches: std::collections::BTreeMap<String, patch::Patch>,
+    patches_path: std::path::PathBuf,
+    save_path: Option<std::path::PathBuf>,
+    /// If set, only games of this family are played, as that's what we authenticated for.
+    family: Option<String>,
+}
+
+impl Inventory {
+    fn allows(&self, game: &'static (dyn game::Game + Send + Sync)) -> bool {
+        self.family
+            .as_ref()
+            .map(|family| game.gamedb_entry().family_and_variant.0 == family)
+            .unwrap_or(true)
+    }
+
+    fn select(&self, game_info: &net::protocol::GameInfo) -> Result<Selection, anyhow::Error> {
+        let game = if let Some(game) =
+            game::find_by_family_and_variant(&game_info.family_and_variant.0, game_info.family_and_variant.1)
+        {
+            game
+        } else {
+            anyhow::bail!("unknown game: {:?}", game_info.family_and_variant);
+        };
+
+        if !self.allows(game) {
+            anyhow::bail!("not playing {:?}", game_info.family_and_variant);
+        }
+
+        let rom = if let Some(rom) = self.roms.get(&game) {
+            rom
+        } else {
+            anyhow::bail!("no rom for {:?}", game_info.family_and_variant);
+        };
+
+        let (rom, patch) = if let Some(pi) = game_info.patch.as_ref() {
+            let version_meta = if let Some(version_meta) = self
+                .patches
+                .get(&pi.name)
+                .and_then(|p| p.versions.get(&pi.version))
+                .cloned()
+            {
+                version_meta
+            } else {
+                anyhow::bail!("no patch {} v{}", pi.name, pi.version);
+            };
+            (
+                patch::apply_patch_from_disk(rom, game, &self.patches_path, &pi.name, &pi.version)?,
+                Some((pi.name.clone(), pi.version.clone(), version_meta)),
+            )
+        } else {
+            (rom.clone(), None)
+        };
+
+        let save = if let Some(save_path) = self.save_path.as_ref() {
+            game.parse_save(&std::fs::read(save_path)?)?
+        } else if let Some(scanned_save) = self.saves.get(&game).and_then(|saves| saves.first()) {
+            log::info!("using save: {}", scanned_save.path.display());
+            scanned_save.save.clone()
+        } else {
+            anyhow::bail!("no save for {:?}", game_info.family_and_variant);
+        };
+
+        Ok(Selection { game, patch, rom, save })
+    }
+
+    fn make_settings(
+        &self,
+        nickname: &str,
+        match_type: (u8, u8),
+        lockstep: bool,
+        first_to: Option<u8>,
+        ruleset: Option<tango_dataview::rules::Ruleset>,
+        selection: Option<&Selection>,
+    ) -> net::protocol::Settings {
+        net::protocol::Settings {
+            nickname: nickname.to_string(),
+            match_type,
+            lockstep,
+            first_to,
+            ruleset,
+            // Always willing: it's only used if the opponent opts in too.
+            unreliable_inputs: true,
+            game_info: selection.map(|selection| {
+                let (family, variant) = selection.game.gamedb_entry().family_and_variant;
+                net::protocol::GameInfo {
+                    family_and_variant: (family.to_string(), variant),
+                    patch: selection
+                        .patch
+                        .as_ref()
+                        .map(|(name, version, _)| net::protocol::PatchInfo {
+                            name: name.clone(),
+                            version: version.clone(),
+                        }),
+                }
+            }),
+            available_games: self
+                .roms
+                .keys()
+                .filter(|g| self.allows(**g))
+                .map(|g| {
+                    let (family, variant) = g.gamedb_entry().family_and_variant;
+                    (family.to_string(), variant)
+                })
+                .collect(),
+            available_patches: self
+                .patches
+                .iter()
+                .map(|(p, info)| (p.clone(), info.versions.keys().cloned().collect()))
+                .collect(),
+            reveal_setup: true,
+        }
+    }
+}
+
+/// Plays whatever the opponent picks, committing as soon as it can.
+struct MirrorPolicy {
+    inventory: Inventory,
+    nickname: String,
+    sender: Option<net::Sender>,
+    selection: Option<Selection>,
+    local_settings: net::protocol::Settings,
+    remote_settings: net::protocol::Settings,
+    remote_commitment: Option<[u8; 16]>,
+    local_negotiated_state: Option<(net::protocol::NegotiatedState, Vec<u8>)>,
+}
+
+impl MirrorPolicy {
+    fn sender(&mut self) -> Result<&mut net::Sender, anyhow::Error> {
+        if let Some(sender) = self.sender.as_mut() {
+            Ok(sender)
+        } else {
+            anyhow::bail!("no sender?")
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl net::lobby::Policy for MirrorPolicy {
+    async fn send_ping(&mut self) -> Result<(), anyhow::Error> {
+        self.sender()?.send_ping(std::time::SystemTime::now()).await?;
+        Ok(())
+    }
+
+    async fn send_pong(&mut self, ts: std::time::SystemTime) -> Result<(), anyhow::Error> {
+        self.sender()?.send_pong(ts).await?;
+        Ok(())
+    }
+
+    async fn set_remote_settings(&mut self, settings: net::protocol::Settings) -> Result<(), anyhow::Error> {
+        // Mirror whatever the opponent picks, recommitting if it changed.
+        if (
+            &settings.game_info,
+            settings.match_type,
+            settings.lockstep,
+            settings.first_to,
+            &settings.ruleset,
+        ) != (
+            &self.local_settings.game_info,
+            self.local_settings.match_type,
+            self.local_settings.lockstep,
+            self.local_settings.first_to,
+            &self.local_settings.ruleset,
+        ) {
+            if self.local_negotiated_state.take().is_some() {
+                self.sender()?.send_uncommit().await?;
+            }
+
+            self.selection = settings
+                .game_info
+                .as_ref()
+                .and_then(|gi| match self.inventory.select(gi) {
+                    Ok(selection) => Some(selection),
+                    Err(e) => {
+                        log::error!("cannot select {:?}: {:?}", gi.family_and_variant, e);
+                        None
+                    }
+                });
+            if self.selection.is_none() {
+                self.remote_commitment = None;
+            }
+
+            self.local_settings = self.inventory.make_settings(
+                &self.nickname,
+                settings.match_type,
+                settings.lockstep,
+                settings.first_to,
+                settings.ruleset.clone(),
+                self.selection.as_ref(),
+            );
+            let local_settings = self.local_settings.clone();
+            self.sender()?.send_settings(local_settings).await?;
+        }
+        self.remote_settings = settings;
+
+        if self.local_negotiated_state.is_none() {
+            if let Some(selection) = self.selection.as_ref() {
+                let (negotiated_state, buf, commitment) = net::transfer::prepare(selection.save.as_raw_wram())?;
+                self.sender()?.send_commit(commitment).await?;
+                self.local_negotiated_state = Some((negotiated_state, buf));
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_remote_commitment(&mut self, commitment: Option<[u8; 16]>) {
+        self.remote_commitment = commitment;
+    }
+
+    async fn local_negotiated_state(&mut self) -> Option<(net::protocol::NegotiatedState, Vec<u8>)> {
+        self.local_negotiated_state.clone()
+    }
+
+    async fn remote_commitment(&mut self) -> Option<[u8; 16]> {
+        self.remote_commitment
+    }
+
+    async fn take_sender(&mut self) -> Option<net::Sender> {
+        self.sender.take()
+    }
+}
+
+pub fn main(
+    config: config::Config,
+    link_code: String,
+    script_path: Option<std::path::PathBuf>,
+    save_path: Option<std::path::PathBuf>,
+    family: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let script = if let Some(script_path) = script_path {
+        Script::parse(&std::fs::read_to_string(&script_path)?)?
+    } else {
+        Script::idle()
+    };
+
+    let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    rt.block_on(run(config, link_code, script, save_path, family))
+}
+
+async fn run(
+    config: config::Config,
+    link_code: String,
+    script: Script,
+    save_path: Option<std::path::PathBuf>,
+    family: Option<String>,
+) -> Result<(), anyhow::Error> {
+    let family = if let Some(family) = family {
+        Some(family)
+    } else if let Some(save_path) = save_path.as_ref() {
+        let buf = std::fs::read(save_path)?;
+        let game = if let Some(game) = tango_gamedb::GAMES
+            .iter()
+            .flat_map(|g| game::game_from_gamedb_entry(*g))
+            .find(|game| game.parse_save(&buf).is_ok())
+        {
+            game
+        } else {
+            anyhow::bail!("{}: not a save for any known game", save_path.display());
+        };
+        Some(game.gamedb_entry().family_and_variant.0.to_string())
+    } else {
+        None
+    };
+    if let Some(family) = family.as_ref() {
+        log::info!("playing {}", family);
+    }
+
+    let inventory = Inventory {
+        roms: game::scan_roms(&config.roms_path(), config.allow_detached_roms()),
+        saves: save::scan_saves(&config.saves_path()),
+        patches: patch::scan(&config.patches_path()).unwrap_or_default(),
+        patches_path: config.patches_path(),
+        save_path,
+        family: family.clone(),
+    };
+
+    let matchmaking_endpoint = if !config.matchmaking_endpoint.is_empty() {
+        config.matchmaking_endpoint.clone()
+    } else {
+        config::DEFAULT_MATCHMAKING_ENDPOINT.to_string()
+    };
+    let nickname = config.nickname.clone().unwrap_or_else(|| "".to_string());
+
+    log::info!("connecting to {} with link code {}", matchmaking_endpoint, link_code);
+    const OPEN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+    let pending_conn = tokio::time::timeout(
+        OPEN_TIMEOUT,
+        tango_signaling::connect::<datachannel_wrapper::PeerConnection>(
+            &matchmaking_endpoint,
+            &link_code,
+            config.use_relay,
+            net::protocol::MIN_VERSION as u32..=net::protocol::VERSION as u32,
+            &tango_signaling::Auth {
+                token: if !config.matchmaking_token.is_empty() {
+                    Some(config.matchmaking_token.clone())
+                } else {
+                    None
+                },
+                family,
+            },
+            None,
+            &tango_signaling::report::Recorder::new(),
+        ),
+    )
+    .await??;
+
+    let (dc, peer_conn) = pending_conn.await?;
+    let (dc_tx, dc_rx) = dc.split();
+    let mut sender = net::Sender::new(dc_tx);
+    let mut receiver = net::Receiver::new(dc_rx);
+    net::negotiate(&mut sender, &mut receiver).await?;
+    log::info!("in lobby");
+
+    let local_settings = inventory.make_settings(&nickname, (0, 0), false, None, None, None);
+    sender.send_settings(local_settings.clone()).await?;
+
+    let mut policy = MirrorPolicy {
+        inventory,
+        nickname,
+        sender: Some(sender),
+        selection: None,
+        local_settings,
+        remote_settings: net::protocol::Settings::default(),
+        remote_commitment: None,
+        local_negotiated_state: None,
+    };
+    let started = net::lobby::run(&mut policy, &mut receiver, |_| {}).await?;
+
+    let MirrorPolicy {
+        selection,
+        local_settings,
+        remote_settings,
+        ..
+    } = policy;
+    let selection = if let Some(selection) = selection {
+        selection
+    } else {
+        anyhow::bail!("attempted to start match in invalid state");
+    };
+
+    let mut audio_binder = audio::LateBinder::new();
+    let audio_backend: Box<dyn audio::Backend> = Box::new(audio::null::Backend::new(audio_binder.clone()));
+    audio_binder.set_sample_rate(audio_backend.sample_rate());
+
+    // Both sides are playing the same game and patch, so the remote ROM is the same as ours.
+    let patch_overrides = selection
+        .patch
+        .as_ref()
+        .map(|(_, _, meta)| meta.rom_overrides.clone())
+        .unwrap_or_default();
+
+    log::info!("starting session");
+    let is_offerer = peer_conn
+        .local_description()
+        .ok_or_else(|| anyhow::anyhow!("no local description"))?
+        .sdp_type
+        == datachannel_wrapper::SdpType::Offer;
+    let replays_path = config.replays_path();
+    let match_type = local_settings.match_type;
+    let session = session::Session::new_pvp(
+        std::sync::Arc::new(parking_lot::RwLock::new(config)),
+        audio_binder,
+        link_code,
+        selection
+            .patch
+            .as_ref()
+            .map(|(_, _, metadata)| metadata.netplay_compatibility.clone())
+            .unwrap_or(selection.game.gamedb_entry().family_and_variant.0.to_owned()),
+        local_settings,
+        selection.game,
+        selection
+            .patch
+            .as_ref()
+            .map(|(name, version, _)| (name.clone(), version.clone())),
+        &patch_overrides,
+        &selection.rom,
+        selection
+            .game
+            .save_from_wram(&started.local_negotiated_state.save_data)?,
+        remote_settings,
+        selection.game,
+        &patch_overrides,
+        &selection.rom,
+        selection
+            .game
+            .save_from_wram(&started.remote_negotiated_state.save_data)?,
+        std::sync::Arc::new(parking_lot::Mutex::new(stats::Counter::new(10))),
+        started.sender,
+        receiver,
+        net::Link::PeerConnection(Box::new(peer_conn)),
+        None,
+        is_offerer,
+        replays_path,
+        match_type,
+        started.rng_seed,
+    )?;
+
+    let match_ = if let session::Mode::PvP(pvp) = session.mode() {
+        pvp.match_.clone()
+    } else {
+        unreachable!();
+    };
+
+    let mut frame = 0u64;
+    let mut frame_timer = tokio::time::interval(std::time::Duration::from_secs_f32(1.0 / session::EXPECTED_FPS));
+    loop {
+        frame_timer.tick().await;
+
+        if session.has_crashed().is_some() {
+            anyhow::bail!("core crashed");
+        }
+
+        if session.completed() || match_.lock().await.is_none() {
+            break;
+        }
+
+        session.set_joyflags(script.joyflags(frame));
+        frame += 1;
+    }
+
+    log::info!("match ended");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_parse() {
+        let script = Script::parse("# dash forward\n\n30 a right\n  10   B  \n5\n").unwrap();
+        assert_eq!(
+            script.steps,
+            vec![
+                (30, mgba::input::keys::A | mgba::input::keys::RIGHT),
+                (10, mgba::input::keys::B),
+                (5, 0),
+            ]
+        );
+        assert_eq!(script.total_frames, 45);
+    }
+
+    #[test]
+    fn test_script_repeats() {
+        let script = Script::parse("2 a\n1 b").unwrap();
+        let joyflags = (0..7).map(|frame| script.joyflags(frame)).collect::<Vec<_>>();
+        assert_eq!(
+            joyflags,
+            vec![
+                mgba::input::keys::A,
+                mgba::input::keys::A,
+                mgba::input::keys::B,
+                mgba::input::keys::A,
+                mgba::input::keys::A,
+                mgba::input::keys::B,
+                mgba::input::keys::A,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_script_parse_malformed() {
+        let e = Script::parse("10 a\nten b").unwrap_err();
+        assert!(e.to_string().starts_with("line 2:"), "{}", e);
+        let e = Script::parse("10 a\n\n10 x").unwrap_err();
+        assert_eq!(e.to_string(), "line 3: unknown key: x");
+        let e = Script::parse("-1 a").unwrap_err();
+        assert!(e.to_string().starts_with("line 1:"), "{}", e);
+        assert!(Script::parse("").is_err());
+        assert!(Script::parse("# nothing\n0 a").is_err());
+    }
+}
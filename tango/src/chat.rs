@@ -0,0 +1,72 @@
+pub const MAX_MESSAGE_LENGTH: usize = 200;
+const MAX_MESSAGES: usize = 100;
+
+/// How long emotes and messages stay on screen during a match.
+pub const OVERLAY_DURATION: std::time::Duration = std::time::Duration::from_secs(4);
+
+/// Emotes are sent by index into this list, so only ever append to it.
+pub const EMOTES: &[&str] = &["👋", "👍", "👏", "😂", "😮", "😭", "🔥", "🤝", "GG", "🔁"];
+
+#[derive(Clone, Debug)]
+pub enum Entry {
+    Message(String),
+    Emote(usize),
+}
+
+#[derive(Clone, Debug)]
+pub struct Line {
+    pub is_local: bool,
+    pub entry: Entry,
+    pub ts: std::time::Instant,
+}
+
+#[derive(Default)]
+pub struct Log {
+    lines: std::collections::VecDeque<Line>,
+}
+
+impl Log {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, is_local: bool, entry: Entry) {
+        self.lines.push_back(Line {
+            is_local,
+            entry,
+            ts: std::time::Instant::now(),
+        });
+        while self.lines.len() > MAX_MESSAGES {
+            self.lines.pop_front();
+        }
+    }
+
+    pub fn push_message(&mut self, is_local: bool, text: &str) {
+        let text = text.trim().chars().take(MAX_MESSAGE_LENGTH).collect::<String>();
+        if text.is_empty() {
+            return;
+        }
+        self.push(is_local, Entry::Message(text));
+    }
+
+    pub fn push_emote(&mut self, is_local: bool, id: u8) {
+        let id = id as usize;
+        if id >= EMOTES.len() {
+            // Probably from a newer version with more emotes.
+            return;
+        }
+        self.push(is_local, Entry::Emote(id));
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = &Line> {
+        self.lines.iter()
+    }
+
+    /// Lines that are recent enough to still be shown in the overlay.
+    pub fn recent_lines(&self) -> impl Iterator<Item = &Line> {
+        let now = std::time::Instant::now();
+        self.lines
+            .iter()
+            .filter(move |line| now.duration_since(line.ts) < OVERLAY_DURATION)
+    }
+}
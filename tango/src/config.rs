@@ -99,6 +99,7 @@ pub struct Config {
     pub max_scale: u32,
     pub input_mapping: input::Mapping,
     pub matchmaking_endpoint: String,
+    pub matchmaking_token: String,
     pub replaycollector_endpoint: String,
     pub patch_repo: String,
     pub enable_patch_autoupdate: bool,
@@ -121,6 +122,7 @@ pub struct Config {
     pub window_size: winit::dpi::LogicalSize<u32>,
     pub last_version: semver::Version,
     pub use_relay: Option<bool>,
+    pub unreliable_inputs: bool,
     pub speed_change_percent: u32,
     pub starred_patches: std::collections::HashSet<String>,
     #[serde(skip_serializing_if = "is_false")]
@@ -141,6 +143,7 @@ impl Default for Config {
             max_scale: 0,
             input_mapping: Default::default(),
             matchmaking_endpoint: "".to_string(),
+            matchmaking_token: "".to_string(),
             replaycollector_endpoint: "https://replaycollector.tango.n1gp.net".to_string(),
             patch_repo: "".to_string(),
             enable_patch_autoupdate: true,
@@ -161,6 +164,7 @@ impl Default for Config {
             window_size: winit::dpi::LogicalSize::new(mgba::gba::SCREEN_WIDTH * 3, mgba::gba::SCREEN_HEIGHT * 3),
             last_version: version,
             use_relay: None,
+            unreliable_inputs: false,
             speed_change_percent: 300,
             either_i_am_one_of_five_people_who_actually_dumped_their_carts_or_i_am_pirating_this_game_and_i_am_a_huge_loser: false,
             starred_patches: Default::default(),
@@ -272,6 +276,10 @@ impl Config {
         self.data_path.join("patches")
     }
 
+    pub fn rulesets_path(&self) -> std::path::PathBuf {
+        self.data_path.join("rulesets")
+    }
+
     pub fn logs_path(&self) -> std::path::PathBuf {
         self.data_path.join("logs")
     }
@@ -285,6 +293,7 @@ impl Config {
         std::fs::create_dir_all(&self.replays_path())?;
         std::fs::create_dir_all(&self.patches_path())?;
         std::fs::create_dir_all(&self.roms_path())?;
+        std::fs::create_dir_all(&self.rulesets_path())?;
         std::fs::create_dir_all(&self.logs_path())?;
         std::fs::create_dir_all(&self.crashstates_path())?;
         Ok(())
@@ -297,3 +306,7 @@ impl Config {
 
 pub const DEFAULT_MATCHMAKING_ENDPOINT: &str = "wss://matchmaking.tango.n1gp.net";
 pub const DEFAULT_PATCH_REPO: &str = "https://patches.tango.n1gp.net";
+
+/// Without a matchmaking server there's nothing to tell us which ICE servers to use, so fall back to a public STUN
+/// server. On a LAN, host candidates are enough anyway.
+pub const MANUAL_SIGNALING_ICE_SERVERS: &[&str] = &["stun:stun.l.google.com:19302"];
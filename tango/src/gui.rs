@@ -3,9 +3,12 @@ use fluent_templates::Loader;
 use crate::{audio, config, discord, game, i18n, input, patch, rom, save, session, stats, updater};
 use std::str::FromStr;
 
+mod chat_view;
+mod connection_report_view;
 mod debug_window;
 mod escape_window;
 mod language_select;
+mod lobby_list_window;
 mod main_view;
 mod patches_pane;
 mod play_pane;
@@ -13,6 +16,7 @@ mod replay_dump_windows;
 mod replays_pane;
 mod save_select_view;
 mod save_view;
+mod server_notices_view;
 mod session_view;
 mod settings_window;
 mod steal_input_window;
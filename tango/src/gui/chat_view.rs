@@ -0,0 +1,106 @@
+use fluent_templates::Loader;
+
+use crate::{chat, i18n};
+
+pub enum Action {
+    Message(String),
+    Emote(u8),
+}
+
+fn line_text(language: &unic_langid::LanguageIdentifier, line: &chat::Line) -> egui::RichText {
+    let who = i18n::LOCALES
+        .lookup(language, if line.is_local { "chat-you" } else { "chat-opponent" })
+        .unwrap();
+    let text = egui::RichText::new(match &line.entry {
+        chat::Entry::Message(text) => format!("{}: {}", who, text),
+        chat::Entry::Emote(id) => format!("{}: {}", who, chat::EMOTES[*id]),
+    });
+    if line.is_local {
+        text.weak()
+    } else {
+        text.strong()
+    }
+}
+
+/// Shows the chat log with an input box and emote buttons underneath. Returns what the user wants to send, if anything.
+pub fn show(
+    ui: &mut egui::Ui,
+    language: &unic_langid::LanguageIdentifier,
+    log: &chat::Log,
+    input: &mut String,
+) -> Option<Action> {
+    let mut action = None;
+
+    egui::ScrollArea::vertical()
+        .id_source("chat-log")
+        .max_height(120.0)
+        .auto_shrink([false, true])
+        .stick_to_bottom(true)
+        .show(ui, |ui| {
+            for line in log.lines() {
+                ui.label(line_text(language, line));
+            }
+        });
+
+    ui.horizontal(|ui| {
+        for (id, emote) in chat::EMOTES.iter().enumerate() {
+            if ui.small_button(*emote).clicked() {
+                action = Some(Action::Emote(id as u8));
+            }
+        }
+    });
+
+    ui.horizontal(|ui| {
+        let submit_button = ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+            let submit_button = ui.add_enabled(
+                !input.trim().is_empty(),
+                egui::Button::new(i18n::LOCALES.lookup(language, "chat-send").unwrap()),
+            );
+            let input_resp = ui.add(
+                egui::TextEdit::singleline(input)
+                    .char_limit(chat::MAX_MESSAGE_LENGTH)
+                    .hint_text(i18n::LOCALES.lookup(language, "chat-hint").unwrap())
+                    .desired_width(f32::INFINITY),
+            );
+            let submitted = input_resp.lost_focus() && ui.ctx().input(|i| i.key_pressed(egui::Key::Enter));
+            if submitted {
+                input_resp.request_focus();
+            }
+            submit_button.clicked() || submitted
+        });
+
+        if submit_button.inner && !input.trim().is_empty() {
+            action = Some(Action::Message(std::mem::take(input).trim().to_string()));
+        }
+    });
+
+    action
+}
+
+/// Shows recent messages and emotes over the top of whatever is on screen.
+pub fn show_overlay(ctx: &egui::Context, language: &unic_langid::LanguageIdentifier, log: &chat::Log) {
+    let lines = log.recent_lines().collect::<Vec<_>>();
+    if lines.is_empty() {
+        return;
+    }
+
+    egui::Area::new("chat-overlay")
+        .interactable(false)
+        .anchor(egui::Align2::RIGHT_TOP, egui::Vec2::new(-10.0, 10.0))
+        .show(ctx, |ui| {
+            for line in lines {
+                let text = line_text(language, line);
+                let text = if let chat::Entry::Emote(_) = line.entry {
+                    text.size(24.0)
+                } else {
+                    text
+                };
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.label(text);
+                });
+            }
+        });
+
+    // Keep repainting so lines disappear once they're old enough.
+    ctx.request_repaint_after(std::time::Duration::from_millis(250));
+}
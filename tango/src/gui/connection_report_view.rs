@@ -0,0 +1,86 @@
+use fluent_templates::Loader;
+
+use crate::i18n;
+
+fn phase_label_key(phase: tango_signaling::report::Phase) -> &'static str {
+    match phase {
+        tango_signaling::report::Phase::WebSocket => "connection-report-websocket",
+        tango_signaling::report::Phase::Hello => "connection-report-hello",
+        tango_signaling::report::Phase::Gathering => "connection-report-gathering",
+        tango_signaling::report::Phase::OfferAnswer => "connection-report-offer-answer",
+        tango_signaling::report::Phase::Connection => "connection-report-connection",
+        tango_signaling::report::Phase::DataChannel => "connection-report-data-channel",
+    }
+}
+
+fn candidate_type_label_key(candidate_type: Option<tango_signaling::report::CandidateType>) -> &'static str {
+    match candidate_type {
+        Some(tango_signaling::report::CandidateType::Host) => "connection-report-candidate-type-host",
+        Some(tango_signaling::report::CandidateType::ServerReflexive) => "connection-report-candidate-type-srflx",
+        Some(tango_signaling::report::CandidateType::PeerReflexive) => "connection-report-candidate-type-prflx",
+        Some(tango_signaling::report::CandidateType::Relay) => "connection-report-candidate-type-relay",
+        None => "connection-report-candidate-type-unknown",
+    }
+}
+
+/// Shows how long each phase of connecting took, what kind of connection was made and, if it failed, where and why.
+pub fn show(ui: &mut egui::Ui, language: &unic_langid::LanguageIdentifier, report: &tango_signaling::report::Report) {
+    egui::Grid::new("connection-report-grid").num_columns(2).show(ui, |ui| {
+        for phase in [
+            tango_signaling::report::Phase::WebSocket,
+            tango_signaling::report::Phase::Hello,
+            tango_signaling::report::Phase::Gathering,
+            tango_signaling::report::Phase::OfferAnswer,
+            tango_signaling::report::Phase::Connection,
+            tango_signaling::report::Phase::DataChannel,
+        ] {
+            ui.strong(i18n::LOCALES.lookup(language, phase_label_key(phase)).unwrap());
+            if let Some(duration) = report.duration(phase) {
+                ui.label(format!("{}ms", duration.as_millis()));
+            } else {
+                ui.weak(i18n::LOCALES.lookup(language, "connection-report-unfinished").unwrap());
+            }
+            ui.end_row();
+        }
+
+        ui.strong(
+            i18n::LOCALES
+                .lookup(language, "connection-report-websocket-attempts")
+                .unwrap(),
+        );
+        ui.label(format!("{}", report.websocket_attempts));
+        ui.end_row();
+
+        if report.local_candidate_type.is_some() || report.remote_candidate_type.is_some() {
+            ui.strong(
+                i18n::LOCALES
+                    .lookup(language, "connection-report-candidate-pair")
+                    .unwrap(),
+            );
+            ui.label(format!(
+                "{} ↔ {}",
+                i18n::LOCALES
+                    .lookup(language, candidate_type_label_key(report.local_candidate_type))
+                    .unwrap(),
+                i18n::LOCALES
+                    .lookup(language, candidate_type_label_key(report.remote_candidate_type))
+                    .unwrap()
+            ));
+            ui.end_row();
+        }
+
+        if let Some(failure) = report.failure.as_ref() {
+            ui.strong(i18n::LOCALES.lookup(language, "connection-report-failure").unwrap());
+            ui.label(if let Some(phase) = report.failed_phase {
+                format!(
+                    "{}: {}",
+                    i18n::LOCALES.lookup(language, phase_label_key(phase)).unwrap(),
+                    failure
+                )
+            } else {
+                failure.clone()
+            });
+            ui.end_row();
+        }
+    });
+}
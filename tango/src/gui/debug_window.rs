@@ -1,19 +1,98 @@
 use fluent_templates::Loader;
 
-use crate::{i18n, session};
+use crate::{i18n, net, session, sync};
+
+#[derive(PartialEq, Eq)]
+enum Tab {
+    Memory,
+    Connection,
+}
 
 pub struct State {
+    tab: Tab,
     jump_to: String,
 }
 
 impl State {
     pub fn new() -> Self {
         Self {
+            tab: Tab::Memory,
             jump_to: "".to_string(),
         }
     }
 }
 
+fn candidate_type_name(candidate_type: Option<datachannel_wrapper::CandidateType>) -> &'static str {
+    match candidate_type {
+        Some(datachannel_wrapper::CandidateType::Host) => "host",
+        Some(datachannel_wrapper::CandidateType::ServerReflexive) => "srflx",
+        Some(datachannel_wrapper::CandidateType::PeerReflexive) => "prflx",
+        Some(datachannel_wrapper::CandidateType::Relay) => "relay",
+        None => "?",
+    }
+}
+
+fn show_connection(ui: &mut egui::Ui, session: &session::Session) {
+    let pvp = if let session::Mode::PvP(pvp) = session.mode() {
+        pvp
+    } else {
+        ui.weak("Not a netplay session.");
+        return;
+    };
+
+    let connection_stats = sync::block_on(pvp.connection_stats());
+    egui::Grid::new("debug-connection-grid").num_columns(2).show(ui, |ui| {
+        ui.strong("Route");
+        ui.monospace(connection_stats.link.route_name());
+        ui.end_row();
+
+        ui.strong("Ping");
+        ui.monospace(format!("{}ms", connection_stats.ping.as_millis()));
+        ui.end_row();
+
+        ui.strong("Transport RTT");
+        ui.monospace(if let Some(rtt) = connection_stats.link.transport_rtt() {
+            format!("{}ms", rtt.as_millis())
+        } else {
+            "?".to_string()
+        });
+        ui.end_row();
+
+        if let net::LinkStats::PeerConnection(stats) = &connection_stats.link {
+            ui.strong("Candidate pair");
+            ui.monospace(format!(
+                "{} ⇄ {}",
+                candidate_type_name(stats.local_candidate_type),
+                candidate_type_name(stats.remote_candidate_type)
+            ));
+            ui.end_row();
+
+            ui.strong("Local address");
+            ui.monospace(stats.local_address.as_deref().unwrap_or("?"));
+            ui.end_row();
+
+            ui.strong("Remote address");
+            ui.monospace(stats.remote_address.as_deref().unwrap_or("?"));
+            ui.end_row();
+
+            ui.strong("Bytes sent");
+            ui.monospace(format!("{}", stats.bytes_sent));
+            ui.end_row();
+
+            ui.strong("Bytes received");
+            ui.monospace(format!("{}", stats.bytes_received));
+            ui.end_row();
+        }
+
+        if let Some(buffered_amount) = connection_stats.buffered_amount {
+            ui.strong("Buffered");
+            ui.monospace(format!("{}", buffered_amount));
+            ui.end_row();
+        }
+    });
+    ui.ctx().request_repaint_after(std::time::Duration::from_secs(1));
+}
+
 pub fn show(
     ctx: &egui::Context,
     language: &unic_langid::LanguageIdentifier,
@@ -25,13 +104,19 @@ pub fn show(
         .id(egui::Id::new("debug"))
         .open(&mut open)
         .show(ctx, |ui| {
+            let state = state.as_mut().unwrap();
+
             ui.horizontal(|ui| {
-                let _ = ui.selectable_label(true, "Memory");
+                ui.selectable_value(&mut state.tab, Tab::Memory, "Memory");
+                ui.selectable_value(&mut state.tab, Tab::Connection, "Connection");
             });
 
             ui.separator();
 
-            let state = state.as_mut().unwrap();
+            if state.tab == Tab::Connection {
+                show_connection(ui, session);
+                return;
+            }
 
             let mut jumping = false;
             ui.horizontal(|ui| {
@@ -0,0 +1,184 @@
+use fluent_templates::Loader;
+
+use crate::{i18n, net};
+
+pub struct State {
+    matchmaking_addr: String,
+    family: Option<String>,
+    auth: tango_signaling::Auth,
+    /// None while fetching.
+    lobbies: std::sync::Arc<parking_lot::Mutex<Option<Result<Vec<tango_signaling::lobbies::Lobby>, String>>>>,
+}
+
+impl State {
+    pub fn new(matchmaking_addr: String, family: Option<String>, auth: tango_signaling::Auth) -> Self {
+        Self {
+            matchmaking_addr,
+            family,
+            auth,
+            lobbies: std::sync::Arc::new(parking_lot::Mutex::new(None)),
+        }
+    }
+
+    /// Fetches the list again in the background.
+    pub fn refresh(&self, egui_ctx: &egui::Context) {
+        *self.lobbies.lock() = None;
+        let matchmaking_addr = self.matchmaking_addr.clone();
+        let family = self.family.clone();
+        let auth = self.auth.clone();
+        let lobbies = self.lobbies.clone();
+        let egui_ctx = egui_ctx.clone();
+        tokio::task::spawn(async move {
+            let r = tango_signaling::lobbies::list(&matchmaking_addr, family.as_deref(), &auth).await;
+            if let Err(e) = r.as_ref() {
+                log::error!("failed to list lobbies: {:?}", e);
+            }
+            *lobbies.lock() = Some(r.map_err(|e| e.to_string()));
+            egui_ctx.request_repaint();
+        });
+    }
+}
+
+fn game_label(language: &unic_langid::LanguageIdentifier, listing: &tango_signaling::lobbies::Listing) -> String {
+    i18n::LOCALES
+        .lookup(
+            language,
+            &format!("game-{}.variant-{}", listing.family, listing.variant),
+        )
+        .unwrap_or_else(|| format!("{} ({})", listing.family, listing.variant))
+}
+
+fn match_type_label(language: &unic_langid::LanguageIdentifier, listing: &tango_signaling::lobbies::Listing) -> String {
+    i18n::LOCALES
+        .lookup(
+            language,
+            &format!(
+                "game-{}.match-type-{}-{}",
+                listing.family, listing.match_type.0, listing.match_type.1
+            ),
+        )
+        .unwrap_or_else(|| format!("{}-{}", listing.match_type.0, listing.match_type.1))
+}
+
+/// Shows the list of public lobbies. Returns the session ID of the lobby to join, if one was picked.
+pub fn show(
+    ctx: &egui::Context,
+    language: &unic_langid::LanguageIdentifier,
+    state: &mut Option<State>,
+    can_join: bool,
+) -> Option<String> {
+    let mut open = state.is_some();
+    let mut joined = None;
+    egui::Window::new(format!(
+        "🌐 {}",
+        i18n::LOCALES.lookup(language, "play-lobby-list").unwrap()
+    ))
+    .id(egui::Id::new("lobby-list-window"))
+    .open(&mut open)
+    .default_width(600.0)
+    .show(ctx, |ui| {
+        let state = if let Some(state) = state.as_ref() {
+            state
+        } else {
+            return;
+        };
+
+        if ui
+            .button(format!(
+                "🔄 {}",
+                i18n::LOCALES.lookup(language, "play-lobby-list-refresh").unwrap()
+            ))
+            .clicked()
+        {
+            state.refresh(ui.ctx());
+        }
+        ui.separator();
+
+        let lobbies = state.lobbies.lock();
+        let lobbies = match lobbies.as_ref() {
+            None => {
+                ui.spinner();
+                return;
+            }
+            Some(Err(e)) => {
+                ui.label(
+                    i18n::LOCALES
+                        .lookup_with_args(
+                            language,
+                            "play-lobby-list-error",
+                            &std::collections::HashMap::from([("error", e.clone().into())]),
+                        )
+                        .unwrap(),
+                );
+                return;
+            }
+            Some(Ok(lobbies)) => lobbies,
+        };
+
+        if lobbies.is_empty() {
+            ui.weak(i18n::LOCALES.lookup(language, "play-lobby-list-empty").unwrap());
+            return;
+        }
+
+        let our_protocol_versions = net::protocol::MIN_VERSION as u32..=net::protocol::VERSION as u32;
+        egui::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+            egui::Grid::new("lobby-list-grid")
+                .num_columns(7)
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.strong(i18n::LOCALES.lookup(language, "play-lobby-list-game").unwrap());
+                    ui.strong(i18n::LOCALES.lookup(language, "play-lobby-list-patch").unwrap());
+                    ui.strong(i18n::LOCALES.lookup(language, "play-details-match-type").unwrap());
+                    ui.strong(i18n::LOCALES.lookup(language, "play-lobby-list-host").unwrap());
+                    ui.strong(i18n::LOCALES.lookup(language, "play-lobby-list-comment").unwrap());
+                    ui.strong(i18n::LOCALES.lookup(language, "play-lobby-list-age").unwrap());
+                    ui.label("");
+                    ui.end_row();
+
+                    for lobby in lobbies.iter() {
+                        ui.label(game_label(language, &lobby.listing));
+                        if let Some((name, version)) = lobby.listing.patch.as_ref() {
+                            ui.label(format!("{} v{}", name, version));
+                        } else {
+                            ui.weak(i18n::LOCALES.lookup(language, "play-no-patch").unwrap());
+                        }
+                        ui.label(match_type_label(language, &lobby.listing));
+                        ui.label(lobby.listing.nickname.as_str());
+                        ui.label(lobby.listing.comment.as_str());
+                        ui.label(
+                            i18n::LOCALES
+                                .lookup_with_args(
+                                    language,
+                                    "play-lobby-list-age.minutes",
+                                    &std::collections::HashMap::from([("minutes", (lobby.age.as_secs() / 60).into())]),
+                                )
+                                .unwrap(),
+                        );
+
+                        let compatible = lobby.protocol_versions.start() <= our_protocol_versions.end()
+                            && our_protocol_versions.start() <= lobby.protocol_versions.end();
+                        let resp = ui.add_enabled(
+                            can_join && compatible,
+                            egui::Button::new(i18n::LOCALES.lookup(language, "play-lobby-list-join").unwrap()),
+                        );
+                        let resp = if !compatible {
+                            resp.on_disabled_hover_text(
+                                i18n::LOCALES.lookup(language, "play-lobby-list-incompatible").unwrap(),
+                            )
+                        } else {
+                            resp
+                        };
+                        if resp.clicked() {
+                            joined = Some(lobby.session_id.clone());
+                        }
+                        ui.end_row();
+                    }
+                });
+        });
+    });
+
+    if !open || joined.is_some() {
+        *state = None;
+    }
+    joined
+}
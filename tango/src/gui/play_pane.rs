@@ -1,9 +1,8 @@
 use fluent_templates::Loader;
-use rand::RngCore;
-use sha3::digest::{ExtendableOutput, Update};
-use subtle::ConstantTimeEq;
 
-use crate::{audio, config, discord, game, gui, i18n, net, patch, randomcode, rom, save, session, stats, sync};
+use crate::{
+    audio, chat, config, discord, game, gui, i18n, net, patch, randomcode, rom, ruleset, save, session, stats, sync,
+};
 
 pub enum Warning {
     Incompatible,
@@ -202,10 +201,20 @@ struct Lobby {
     nickname: String,
     match_type: (u8, u8),
     reveal_setup: bool,
+    lockstep: bool,
+    first_to: Option<u8>,
+    ruleset: Option<ruleset::Ruleset>,
+    available_rulesets: Vec<ruleset::Ruleset>,
+    local_violations: Vec<(ruleset::Violation, Option<String>)>,
+    unreliable_inputs: bool,
+    negotiated: net::Negotiated,
     remote_settings: net::protocol::Settings,
     remote_commitment: Option<[u8; 16]>,
     latencies: crate::stats::LatencyCounter,
     local_negotiated_state: Option<(net::protocol::NegotiatedState, Vec<u8>)>,
+    transfer_progress: std::sync::Arc<parking_lot::Mutex<Option<net::transfer::Progress>>>,
+    chat: std::sync::Arc<parking_lot::Mutex<chat::Log>>,
+    connection_report: Option<tango_signaling::report::Report>,
     roms_scanner: rom::Scanner,
     patches_scanner: patch::Scanner,
 }
@@ -296,6 +305,9 @@ fn are_settings_compatible(
     struct SimplifiedSettings {
         netplay_compatibility: Option<String>,
         match_type: (u8, u8),
+        lockstep: bool,
+        first_to: Option<u8>,
+        ruleset: Option<ruleset::Ruleset>,
     }
 
     impl SimplifiedSettings {
@@ -306,6 +318,9 @@ fn are_settings_compatible(
                     .as_ref()
                     .and_then(|gi| get_netplay_compatibility_from_game_info(gi, patches)),
                 match_type: settings.match_type,
+                lockstep: settings.lockstep,
+                first_to: settings.first_to,
+                ruleset: settings.ruleset.clone(),
             }
         }
     }
@@ -318,15 +333,6 @@ fn are_settings_compatible(
         && local_simplified_settings == remote_simplified_settings
 }
 
-fn make_commitment(buf: &[u8]) -> [u8; 16] {
-    let mut shake128 = sha3::Shake128::default();
-    shake128.update(b"tango:lobby:");
-    shake128.update(buf);
-    let mut commitment = [0u8; 16];
-    shake128.finalize_xof_into(&mut commitment);
-    commitment
-}
-
 impl Lobby {
     async fn uncommit(&mut self) -> Result<(), anyhow::Error> {
         let sender = if let Some(sender) = self.sender.as_mut() {
@@ -341,19 +347,7 @@ impl Lobby {
     }
 
     async fn commit(&mut self, save_data: &[u8]) -> Result<(), anyhow::Error> {
-        let mut nonce = [0u8; 16];
-        rand::thread_rng().fill_bytes(&mut nonce);
-        let negotiated_state = net::protocol::NegotiatedState {
-            nonce: nonce.clone(),
-            save_data: save_data.to_vec(),
-        };
-        let buf = zstd::stream::encode_all(
-            &net::protocol::NegotiatedState::serialize(&negotiated_state).unwrap()[..],
-            0,
-        )?;
-        let commitment = make_commitment(&buf);
-
-        log::info!("nonce = {:02x?}, commitment = {:02x?}", nonce, commitment);
+        let (negotiated_state, buf, commitment) = net::transfer::prepare(save_data)?;
 
         let sender = if let Some(sender) = self.sender.as_mut() {
             sender
@@ -397,6 +391,10 @@ impl Lobby {
                 .map(|(p, info)| (p.clone(), info.versions.keys().cloned().collect()))
                 .collect(),
             reveal_setup: self.reveal_setup,
+            lockstep: self.lockstep,
+            first_to: self.first_to,
+            unreliable_inputs: self.unreliable_inputs,
+            ruleset: self.ruleset.clone(),
         }
     }
 
@@ -426,6 +424,78 @@ impl Lobby {
         Ok(())
     }
 
+    async fn set_lockstep(&mut self, lockstep: bool) -> Result<(), anyhow::Error> {
+        if lockstep == self.lockstep {
+            return Ok(());
+        }
+        self.send_settings(net::protocol::Settings {
+            lockstep,
+            ..self.make_local_settings()
+        })
+        .await?;
+        self.lockstep = lockstep;
+        if !self.can_ready() {
+            self.remote_commitment = None;
+        }
+        Ok(())
+    }
+
+    async fn set_first_to(&mut self, first_to: Option<u8>) -> Result<(), anyhow::Error> {
+        if first_to == self.first_to {
+            return Ok(());
+        }
+        self.send_settings(net::protocol::Settings {
+            first_to,
+            ..self.make_local_settings()
+        })
+        .await?;
+        self.first_to = first_to;
+        if !self.can_ready() {
+            self.remote_commitment = None;
+        }
+        Ok(())
+    }
+
+    async fn set_ruleset(&mut self, ruleset: Option<ruleset::Ruleset>) -> Result<(), anyhow::Error> {
+        if ruleset == self.ruleset {
+            return Ok(());
+        }
+        self.send_settings(net::protocol::Settings {
+            ruleset: ruleset.clone(),
+            ..self.make_local_settings()
+        })
+        .await?;
+        self.ruleset = ruleset;
+        self.update_local_violations();
+        if !self.can_ready() {
+            self.remote_commitment = None;
+        }
+        Ok(())
+    }
+
+    fn update_local_violations(&mut self) {
+        self.local_violations = match (self.ruleset.as_ref(), self.local_selection.as_ref()) {
+            (Some(ruleset), Some(local_selection)) => match ruleset::check(
+                ruleset,
+                local_selection.game,
+                &local_selection.rom,
+                &local_selection
+                    .patch
+                    .as_ref()
+                    .map(|(_, _, metadata)| metadata.rom_overrides.clone())
+                    .unwrap_or_default(),
+                local_selection.save.as_ref(),
+            ) {
+                Ok(violations) => violations,
+                Err(e) => {
+                    log::error!("failed to check save against ruleset: {:?}", e);
+                    vec![]
+                }
+            },
+            _ => vec![],
+        };
+    }
+
     async fn set_match_type(&mut self, match_type: (u8, u8)) -> Result<(), anyhow::Error> {
         if match_type == self.match_type {
             return Ok(());
@@ -504,6 +574,7 @@ impl Lobby {
             None
         };
         self.match_type = match_type;
+        self.update_local_violations();
         if !self.can_ready() {
             self.remote_commitment = None;
         }
@@ -590,9 +661,143 @@ impl Lobby {
         sender.send_ping(std::time::SystemTime::now()).await?;
         Ok(())
     }
+
+    async fn send_chat(&mut self, text: String) -> Result<(), anyhow::Error> {
+        let sender = if let Some(sender) = self.sender.as_mut() {
+            sender
+        } else {
+            anyhow::bail!("no sender?")
+        };
+        sender.send_chat(text.clone()).await?;
+        self.chat.lock().push_message(true, &text);
+        Ok(())
+    }
+
+    async fn send_emote(&mut self, id: u8) -> Result<(), anyhow::Error> {
+        let sender = if let Some(sender) = self.sender.as_mut() {
+            sender
+        } else {
+            anyhow::bail!("no sender?")
+        };
+        sender.send_emote(id).await?;
+        self.chat.lock().push_emote(true, id);
+        Ok(())
+    }
+}
+
+/// Leaves the lobby up to the user, showing them whatever the remote does.
+struct LobbyPolicy {
+    lobby: std::sync::Arc<tokio::sync::Mutex<Lobby>>,
+    egui_ctx: egui::Context,
+    patches_path: std::path::PathBuf,
+}
+
+#[async_trait::async_trait]
+impl net::lobby::Policy for LobbyPolicy {
+    async fn send_ping(&mut self) -> Result<(), anyhow::Error> {
+        self.lobby.lock().await.send_ping().await
+    }
+
+    async fn send_pong(&mut self, ts: std::time::SystemTime) -> Result<(), anyhow::Error> {
+        self.lobby.lock().await.send_pong(ts).await
+    }
+
+    async fn mark_latency(&mut self, latency: std::time::Duration) {
+        self.lobby.lock().await.latencies.mark(latency);
+        self.egui_ctx.request_repaint();
+    }
+
+    async fn set_remote_settings(&mut self, settings: net::protocol::Settings) -> Result<(), anyhow::Error> {
+        self.lobby
+            .lock()
+            .await
+            .set_remote_settings(settings, &self.patches_path);
+        self.egui_ctx.request_repaint();
+        Ok(())
+    }
+
+    async fn set_remote_commitment(&mut self, commitment: Option<[u8; 16]>) {
+        self.lobby.lock().await.remote_commitment = commitment;
+        self.egui_ctx.request_repaint();
+    }
+
+    async fn receive_chat(&mut self, chat: net::protocol::Chat) {
+        self.lobby.lock().await.chat.lock().push_message(false, &chat.text);
+        self.egui_ctx.request_repaint();
+    }
+
+    async fn receive_emote(&mut self, emote: net::protocol::Emote) {
+        self.lobby.lock().await.chat.lock().push_emote(false, emote.id);
+        self.egui_ctx.request_repaint();
+    }
+
+    async fn local_negotiated_state(&mut self) -> Option<(net::protocol::NegotiatedState, Vec<u8>)> {
+        self.lobby.lock().await.local_negotiated_state.clone()
+    }
+
+    async fn remote_commitment(&mut self) -> Option<[u8; 16]> {
+        self.lobby.lock().await.remote_commitment
+    }
+
+    async fn take_sender(&mut self) -> Option<net::Sender> {
+        self.lobby.lock().await.sender.take()
+    }
+
+    async fn check_remote_negotiated_state(
+        &mut self,
+        remote_negotiated_state: &net::protocol::NegotiatedState,
+    ) -> Result<(), anyhow::Error> {
+        let lobby = self.lobby.lock().await;
+        let ruleset = if let Some(ruleset) = lobby.ruleset.as_ref() {
+            ruleset
+        } else {
+            return Ok(());
+        };
+
+        let remote_selection = if let Some(remote_selection) = lobby.remote_selection.as_ref() {
+            remote_selection
+        } else {
+            anyhow::bail!("missing remote selection?");
+        };
+
+        let violations = ruleset::check(
+            ruleset,
+            remote_selection.game,
+            &remote_selection.rom,
+            &remote_selection
+                .patch
+                .as_ref()
+                .map(|(_, _, version_meta)| version_meta.rom_overrides.clone())
+                .unwrap_or_default(),
+            remote_selection
+                .game
+                .save_from_wram(&remote_negotiated_state.save_data)?
+                .as_ref(),
+        )?;
+        if !violations.is_empty() {
+            anyhow::bail!(
+                "opponent's setup does not follow {}: {}",
+                ruleset.name,
+                violations
+                    .iter()
+                    .map(|(violation, name)| if let Some(name) = name {
+                        format!("{} ({})", violation, name)
+                    } else {
+                        violation.to_string()
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        Ok(())
+    }
 }
 
-async fn run_connection_task(
+/// Connects to the opponent and runs the lobby until the match starts.
+///
+/// Peer connections made through the signaling server are `P`, so this can be run against
+/// [`datachannel_wrapper::loopback::PeerConnection`] without a network.
+async fn run_connection_task<P: datachannel_wrapper::Peer>(
     config: std::sync::Arc<parking_lot::RwLock<config::Config>>,
     egui_ctx: egui::Context,
     audio_binder: audio::LateBinder,
@@ -600,14 +805,16 @@ async fn run_connection_task(
     session: std::sync::Arc<parking_lot::Mutex<Option<session::Session>>>,
     roms_scanner: rom::Scanner,
     patches_scanner: patch::Scanner,
-    matchmaking_addr: String,
+    signaling: Signaling,
     link_code: String,
+    game_family: Option<String>,
     nickname: String,
     patches_path: std::path::PathBuf,
     replays_path: std::path::PathBuf,
     connection_task: std::sync::Arc<tokio::sync::Mutex<Option<ConnectionTask>>>,
     cancellation_token: tokio_util::sync::CancellationToken,
 ) {
+    let recorder = tango_signaling::report::Recorder::new();
     if let Err(e) = {
         let connection_task = connection_task.clone();
 
@@ -615,46 +822,178 @@ async fn run_connection_task(
             r = {
                 let connection_task = connection_task.clone();
                 let cancellation_token = cancellation_token.clone();
+                let recorder = recorder.clone();
                 async move {
-                    *connection_task.lock().await =
-                        Some(ConnectionTask::InProgress {
-                            state: ConnectionState::Signaling,
-                            cancellation_token:
-                                cancellation_token.clone(),
-                        });
-                    const OPEN_TIMEOUT: std::time::Duration =
-                        std::time::Duration::from_secs(30);
-                    let use_relay = {
+                    let mut link_code = link_code;
+                    let auth = {
                         let config = config.read();
-                        config.use_relay
+                        tango_signaling::Auth {
+                            token: if !config.matchmaking_token.is_empty() {
+                                Some(config.matchmaking_token.clone())
+                            } else {
+                                None
+                            },
+                            family: game_family,
+                        }
                     };
-                    let pending_conn = tokio::time::timeout(
-                        OPEN_TIMEOUT,
-                        tango_signaling::connect(
+                    let signaling = if let Signaling::Queue(matchmaking_addr, request) = signaling {
+                        *connection_task.lock().await =
+                            Some(ConnectionTask::InProgress {
+                                state: ConnectionState::Queued,
+                                cancellation_token:
+                                    cancellation_token.clone(),
+                            });
+                        link_code = tango_signaling::queue::enqueue(
                             &matchmaking_addr,
-                            &link_code,
-                            use_relay,
-                            crate::net::protocol::VERSION as u32,
-                        ),
-                    )
-                    .await.map_err(|e| std::io::Error::new(std::io::ErrorKind::TimedOut, e))??;
+                            request,
+                            crate::net::protocol::MIN_VERSION as u32..=crate::net::protocol::VERSION as u32,
+                            None,
+                            &auth,
+                        ).await?;
+                        Signaling::Matchmaking(matchmaking_addr)
+                    } else {
+                        signaling
+                    };
 
-                    *connection_task.lock().await =
-                        Some(ConnectionTask::InProgress {
-                            state: ConnectionState::Waiting,
-                            cancellation_token:
-                                cancellation_token.clone(),
-                        });
+                    let (signaling, listing) = if let Signaling::PublicLobby(matchmaking_addr, listing) = signaling {
+                        (Signaling::Matchmaking(matchmaking_addr), Some(listing))
+                    } else {
+                        (signaling, None)
+                    };
+
+                    let (mut sender, mut receiver, link, is_offerer) = match signaling {
+                        Signaling::Matchmaking(matchmaking_addr) => {
+                            *connection_task.lock().await =
+                                Some(ConnectionTask::InProgress {
+                                    state: ConnectionState::Signaling,
+                                    cancellation_token:
+                                        cancellation_token.clone(),
+                                });
+                            const OPEN_TIMEOUT: std::time::Duration =
+                                std::time::Duration::from_secs(30);
+                            let use_relay = {
+                                let config = config.read();
+                                config.use_relay
+                            };
+                            let pending_conn = tokio::time::timeout(
+                                OPEN_TIMEOUT,
+                                tango_signaling::connect::<P>(
+                                    &matchmaking_addr,
+                                    &link_code,
+                                    use_relay,
+                                    crate::net::protocol::MIN_VERSION as u32..=crate::net::protocol::VERSION as u32,
+                                    &auth,
+                                    listing.as_ref(),
+                                    &recorder,
+                                ),
+                            )
+                            .await.map_err(|e| std::io::Error::new(std::io::ErrorKind::TimedOut, e))??;
+
+                            *connection_task.lock().await =
+                                Some(ConnectionTask::InProgress {
+                                    state: ConnectionState::Waiting(Some(recorder.report())),
+                                    cancellation_token:
+                                        cancellation_token.clone(),
+                                });
+
+                            match pending_conn.await {
+                                Ok((dc, peer_conn)) => connect_data_channel(dc, peer_conn),
+                                Err(
+                                    e @ (tango_signaling::Error::PeerConnectionFailed
+                                    | tango_signaling::Error::PeerConnectionDisconnected),
+                                ) if recorder.relay_token().is_some() => {
+                                    log::warn!("{}, falling back to relay", e);
+                                    let relay_token = recorder.relay_token().unwrap();
+                                    *connection_task.lock().await =
+                                        Some(ConnectionTask::InProgress {
+                                            state: ConnectionState::Relaying,
+                                            cancellation_token:
+                                                cancellation_token.clone(),
+                                        });
+                                    connect_relay(
+                                        tango_signaling::relay::connect(&matchmaking_addr, &link_code, &relay_token, &auth).await?,
+                                    )
+                                }
+                                Err(e) => {
+                                    return Err(ConnectionError::Signaling(e));
+                                }
+                            }
+                        }
+                        Signaling::DirectHost(port) => {
+                            *connection_task.lock().await =
+                                Some(ConnectionTask::InProgress {
+                                    state: ConnectionState::Waiting(None),
+                                    cancellation_token:
+                                        cancellation_token.clone(),
+                                });
+                            connect_direct(net::direct::host(port).await?)
+                        }
+                        Signaling::DirectConnect(addr) => {
+                            *connection_task.lock().await =
+                                Some(ConnectionTask::InProgress {
+                                    state: ConnectionState::Signaling,
+                                    cancellation_token:
+                                        cancellation_token.clone(),
+                                });
+                            const CONNECT_TIMEOUT: std::time::Duration =
+                                std::time::Duration::from_secs(10);
+                            connect_direct(
+                                tokio::time::timeout(CONNECT_TIMEOUT, net::direct::connect(&addr))
+                                    .await
+                                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::TimedOut, e))??,
+                            )
+                        }
+                        Signaling::Manual { is_offerer } => {
+                            let manual = std::sync::Arc::new(parking_lot::Mutex::new(ManualSignaling {
+                                is_offerer,
+                                local_blob: None,
+                                remote_blob: String::new(),
+                                remote_blob_tx: None,
+                            }));
+                            *connection_task.lock().await =
+                                Some(ConnectionTask::InProgress {
+                                    state: ConnectionState::Manual(manual.clone()),
+                                    cancellation_token:
+                                        cancellation_token.clone(),
+                                });
+
+                            let rtc_config = datachannel_wrapper::RtcConfig::new(config::MANUAL_SIGNALING_ICE_SERVERS);
+                            let (remote_blob_tx, remote_blob_rx) = tokio::sync::oneshot::channel();
+                            if is_offerer {
+                                let (pending_offer, offer_blob) = tango_signaling::manual::offer(rtc_config).await?;
+                                {
+                                    let mut manual = manual.lock();
+                                    manual.local_blob = Some(offer_blob);
+                                    manual.remote_blob_tx = Some(remote_blob_tx);
+                                }
+                                egui_ctx.request_repaint();
+
+                                let answer_blob = remote_blob_rx.await.map_err(|_| anyhow::anyhow!("no answer?"))?;
+                                let (dc, peer_conn) = pending_offer.accept(&answer_blob)?.await?;
+                                connect_data_channel(dc, peer_conn)
+                            } else {
+                                manual.lock().remote_blob_tx = Some(remote_blob_tx);
+                                egui_ctx.request_repaint();
+
+                                let offer_blob = remote_blob_rx.await.map_err(|_| anyhow::anyhow!("no offer?"))?;
+                                let (connecting, answer_blob) = tango_signaling::manual::answer(rtc_config, &offer_blob).await?;
+                                manual.lock().local_blob = Some(answer_blob);
+                                egui_ctx.request_repaint();
 
-                    let (dc, peer_conn) = pending_conn.await?;
-                    let (dc_tx, dc_rx) = dc.split();
-                    let mut sender = net::Sender::new(dc_tx);
-                    let mut receiver = net::Receiver::new(dc_rx);
-                    net::negotiate(&mut sender, &mut receiver).await?;
+                                let (dc, peer_conn) = connecting.await?;
+                                connect_data_channel(dc, peer_conn)
+                            }
+                        }
+                        Signaling::Queue(_, _) | Signaling::PublicLobby(_, _) => {
+                            // Both were turned into matchmaking above.
+                            unreachable!();
+                        }
+                    };
+                    let negotiated = net::negotiate(&mut sender, &mut receiver).await?;
 
-                    let default_match_type = {
+                    let (default_match_type, unreliable_inputs, rulesets_path) = {
                         let config = config.read();
-                        config.default_match_type
+                        (config.default_match_type, config.unreliable_inputs, config.rulesets_path())
                     };
 
                     let lobby = std::sync::Arc::new(tokio::sync::Mutex::new(Lobby{
@@ -666,10 +1005,22 @@ async fn run_connection_task(
                         link_code,
                         match_type: (default_match_type, 0),
                         reveal_setup: false,
+                        lockstep: false,
+                        first_to: None,
+                        ruleset: None,
+                        available_rulesets: ruleset::scan(&rulesets_path),
+                        local_violations: vec![],
+                        unreliable_inputs: unreliable_inputs
+                            && negotiated.has_capability(net::protocol::capability::UNRELIABLE_INPUTS)
+                            && link.supports_unreliable_inputs(),
+                        negotiated,
                         remote_settings: net::protocol::Settings::default(),
                         remote_commitment: None,
                         latencies: crate::stats::LatencyCounter::new(5),
                         local_negotiated_state: None,
+                        transfer_progress: std::sync::Arc::new(parking_lot::Mutex::new(None)),
+                        chat: std::sync::Arc::new(parking_lot::Mutex::new(chat::Log::new())),
+                        connection_report: Some(recorder.report()).filter(|report| report.websocket_attempts > 0),
                         roms_scanner: roms_scanner.clone(),
                         patches_scanner: patches_scanner.clone(),
                     }));
@@ -686,66 +1037,26 @@ async fn run_connection_task(
                                 cancellation_token.clone(),
                         });
 
-                    let mut remote_chunks = vec![];
-                    let mut ping_timer = tokio::time::interval(net::PING_INTERVAL);
-                    'l: loop {
-                        tokio::select! {
-                            _ = ping_timer.tick() => {
-                                lobby.lock().await.send_ping().await?;
+                    let started = net::lobby::run(
+                        &mut LobbyPolicy {
+                            lobby: lobby.clone(),
+                            egui_ctx: egui_ctx.clone(),
+                            patches_path,
+                        },
+                        &mut receiver,
+                        {
+                            let transfer_progress = lobby.lock().await.transfer_progress.clone();
+                            let egui_ctx = egui_ctx.clone();
+                            move |progress| {
+                                *transfer_progress.lock() = Some(progress);
+                                egui_ctx.request_repaint();
                             }
-                            p = receiver.receive() => {
-                                match p? {
-                                    net::protocol::Packet::Ping(ping) => {
-                                        lobby.lock().await.send_pong(ping.ts).await?;
-                                    },
-                                    net::protocol::Packet::Pong(pong) => {
-                                        let mut lobby = lobby.lock().await;
-                                        if let Ok(d) = std::time::SystemTime::now().duration_since(pong.ts) {
-                                            lobby.latencies.mark(d);
-                                            egui_ctx.request_repaint();
-                                        }
-                                    },
-                                    net::protocol::Packet::Settings(settings) => {
-                                        let mut lobby = lobby.lock().await;
-                                        lobby.set_remote_settings(settings, &patches_path);
-                                        egui_ctx.request_repaint();
-                                    },
-                                    net::protocol::Packet::Commit(commit) => {
-                                        let mut lobby = lobby.lock().await;
-                                        lobby.remote_commitment = Some(commit.commitment);
-                                        egui_ctx.request_repaint();
+                        },
+                    ).await?;
 
-                                        if lobby.local_negotiated_state.is_some() {
-                                            break 'l;
-                                        }
-                                    },
-                                    net::protocol::Packet::Uncommit(_) => {
-                                        lobby.lock().await.remote_commitment = None;
-                                        egui_ctx.request_repaint();
-                                    },
-                                    net::protocol::Packet::Chunk(chunk) => {
-                                        remote_chunks.push(chunk.chunk);
-                                        break 'l;
-                                    },
-                                    p => {
-                                        return Err(ConnectionError::Other(anyhow::anyhow!("unexpected packet: {:?}", p)));
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    log::info!("ending lobby");
-
-                    let (mut sender, match_type, local_settings, remote_selection, remote_settings, remote_commitment, local_negotiated_state, local_selection, link_code) = {
-                        let mut lobby = lobby.lock().await;
-                        let local_settings = lobby.make_local_settings();
-                        let sender = if let Some(sender) = lobby.sender.take() {
-                            sender
-                        } else {
-                            return Err(ConnectionError::Other(anyhow::anyhow!("no sender?")));
-                        };
-                        (sender, lobby.match_type, local_settings, lobby.remote_selection.clone(), lobby.remote_settings.clone(), lobby.remote_commitment.clone(), lobby.local_negotiated_state.clone(), lobby.local_selection.clone(), lobby.link_code.clone())
+                    let (match_type, local_settings, remote_selection, remote_settings, local_selection, link_code, chat) = {
+                        let lobby = lobby.lock().await;
+                        (lobby.match_type, lobby.make_local_settings(), lobby.remote_selection.clone(), lobby.remote_settings.clone(), lobby.local_selection.clone(), lobby.link_code.clone(), if lobby.negotiated.has_capability(net::protocol::capability::CHAT) { Some(lobby.chat.clone()) } else { None })
                     };
 
                     let remote_selection = if let Some(remote_selection) = remote_selection {
@@ -755,60 +1066,7 @@ async fn run_connection_task(
                     };
 
                     let remote_patch_overrides = remote_selection.patch.as_ref().map(|(_, _, version_meta)| version_meta.rom_overrides.clone()).unwrap_or_default();
-
-                    let (local_negotiated_state, raw_local_state) = if let Some((negotiated_state, raw_local_state)) = local_negotiated_state {
-                        (negotiated_state, raw_local_state)
-                    } else {
-                        return Err(ConnectionError::Other(anyhow::anyhow!("missing local state?")));
-                    };
-
-                    const CHUNK_SIZE: usize = 32 * 1024;
-                    const CHUNKS_REQUIRED: usize = 5;
-                    for (_, chunk) in std::iter::zip(
-                        0..CHUNKS_REQUIRED,
-                        raw_local_state.chunks(CHUNK_SIZE).chain(std::iter::repeat(&[][..]))
-                     ) {
-                        sender.send_chunk(chunk.to_vec()).await?;
-
-                        if remote_chunks.len() < CHUNKS_REQUIRED {
-                            loop {
-                                match receiver.receive().await? {
-                                    net::protocol::Packet::Ping(ping) => {
-                                        sender.send_pong(ping.ts).await?;
-                                    },
-                                    net::protocol::Packet::Pong(_) => { },
-                                    net::protocol::Packet::Chunk(chunk) => {
-                                        remote_chunks.push(chunk.chunk);
-                                        break;
-                                    },
-                                    p => {
-                                        return Err(ConnectionError::Other(anyhow::format_err!("unexpected packet: {:?}", p)));
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    let raw_remote_negotiated_state = remote_chunks.into_iter().flatten().collect::<Vec<_>>();
-
-                    let received_remote_commitment = if let Some(commitment) = remote_commitment {
-                        commitment
-                    } else {
-                        return Err(ConnectionError::Other(anyhow::anyhow!("no remote commitment?")));
-                    };
-
-                    log::info!("remote commitment = {:02x?}", received_remote_commitment);
-
-                    if !bool::from(make_commitment(&raw_remote_negotiated_state).ct_eq(&received_remote_commitment)) {
-                        return Err(ConnectionError::Other(anyhow::anyhow!("commitment mismatch?")));
-                    }
-
-                    let raw_remote_negotiated_state = zstd::stream::decode_all(&raw_remote_negotiated_state[..])?;
-                    let remote_negotiated_state = net::protocol::NegotiatedState::deserialize(&raw_remote_negotiated_state)
-                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-
-                    let rng_seed = std::iter::zip(local_negotiated_state.nonce, remote_negotiated_state.nonce).map(|(x, y)| x ^ y).collect::<Vec<_>>().try_into().unwrap();
-                    log::info!("session verified! rng seed = {:02x?}", rng_seed);
+                    let remote_save = remote_selection.game.save_from_wram(&started.remote_negotiated_state.save_data)?;
 
                     let local_selection = if let Some(local_selection) = local_selection {
                         local_selection
@@ -816,14 +1074,7 @@ async fn run_connection_task(
                         return Err(ConnectionError::Other(anyhow::anyhow!("attempted to start match in invalid state")));
                     };
 
-                    sender.send_start_match().await?;
-                    match receiver.receive().await? {
-                        net::protocol::Packet::StartMatch(_) => {},
-                        p => return Err(ConnectionError::Other(anyhow::anyhow!("unexpected packet when expecting start match: {:?}", p))),
-                    }
-
                     log::info!("starting session");
-                    let is_offerer = peer_conn.local_description().unwrap().sdp_type == datachannel_wrapper::SdpType::Offer;
                     {
                         *session.lock() = Some(session::Session::new_pvp(
                             config.clone(),
@@ -841,20 +1092,21 @@ async fn run_connection_task(
                                 meta.rom_overrides.clone()
                             }).unwrap_or_default(),
                             &local_selection.rom,
-                            local_selection.game.save_from_wram(&local_negotiated_state.save_data)?,
+                            local_selection.game.save_from_wram(&started.local_negotiated_state.save_data)?,
                             remote_settings,
                             remote_selection.game,
                             &remote_patch_overrides,
                             &remote_selection.rom,
-                            remote_selection.game.save_from_wram(&remote_negotiated_state.save_data)?,
+                            remote_save,
                             emu_tps_counter.clone(),
-                            sender,
+                            started.sender,
                             receiver,
-                            peer_conn,
+                            link,
+                            chat,
                             is_offerer,
                             replays_path,
                             match_type,
-                            rng_seed,
+                            started.rng_seed,
                         )?);
                     }
                     egui_ctx.request_repaint();
@@ -872,7 +1124,9 @@ async fn run_connection_task(
         }
     } {
         log::info!("connection task failed: {:?}", e);
-        *connection_task.lock().await = Some(ConnectionTask::Failed(e));
+        // Only connections through the signaling server have a report.
+        let report = Some(recorder.report()).filter(|report| report.websocket_attempts > 0);
+        *connection_task.lock().await = Some(ConnectionTask::Failed(e, report));
     } else {
         *connection_task.lock().await = None;
     }
@@ -898,21 +1152,83 @@ enum ConnectionTask {
         state: ConnectionState,
         cancellation_token: tokio_util::sync::CancellationToken,
     },
-    Failed(ConnectionError),
+    Failed(ConnectionError, Option<tango_signaling::report::Report>),
+}
+
+/// Stands in for the link code when connecting without a matchmaking server, e.g. in replay filenames.
+const MANUAL_LINK_CODE: &str = "manual";
+
+enum Signaling {
+    Matchmaking(String),
+    /// Like matchmaking, but the session is listed publicly while waiting for an opponent.
+    PublicLobby(String, tango_signaling::lobbies::Listing),
+    Queue(String, tango_signaling::queue::Request),
+    Manual {
+        is_offerer: bool,
+    },
+    DirectHost(u16),
+    DirectConnect(String),
+}
+
+fn connect_data_channel<P: datachannel_wrapper::Peer>(
+    dc: datachannel_wrapper::DataChannel,
+    peer_conn: P,
+) -> (net::Sender, net::Receiver, net::Link, bool) {
+    let is_offerer = peer_conn.local_description().unwrap().sdp_type == datachannel_wrapper::SdpType::Offer;
+    let (dc_tx, dc_rx) = dc.split();
+    (
+        net::Sender::new(dc_tx),
+        net::Receiver::new(dc_rx),
+        net::Link::PeerConnection(Box::new(peer_conn)),
+        is_offerer,
+    )
+}
+
+fn connect_relay(
+    (relay_tx, relay_rx, is_first): (tango_signaling::relay::Sender, tango_signaling::relay::Receiver, bool),
+) -> (net::Sender, net::Receiver, net::Link, bool) {
+    (
+        net::Sender::new_relay(relay_tx),
+        net::Receiver::new_relay(relay_rx),
+        net::Link::Relay,
+        is_first,
+    )
+}
+
+fn connect_direct(conn: net::direct::Connection) -> (net::Sender, net::Receiver, net::Link, bool) {
+    let is_host = conn.is_host();
+    let (sender, receiver, link) = conn.split();
+    (sender, receiver, link, is_host)
+}
+
+struct ManualSignaling {
+    is_offerer: bool,
+    local_blob: Option<String>,
+    remote_blob: String,
+    remote_blob_tx: Option<tokio::sync::oneshot::Sender<String>>,
 }
 
 enum ConnectionState {
     Starting,
+    Queued,
     Signaling,
-    Waiting,
+    /// Carries the report if connecting through the signaling server, for what its operator had to say.
+    Waiting(Option<tango_signaling::report::Report>),
+    /// The peer connection failed, so the signaling server is relaying instead.
+    Relaying,
+    Manual(std::sync::Arc<parking_lot::Mutex<ManualSignaling>>),
     InLobby(std::sync::Arc<tokio::sync::Mutex<Lobby>>),
 }
 
 pub struct State {
     link_code: String,
     show_link_code: bool,
+    direct_addr: String,
+    chat_input: String,
     connection_task: std::sync::Arc<tokio::sync::Mutex<Option<ConnectionTask>>>,
     show_save_select: Option<gui::save_select_view::State>,
+    lobby_list: Option<gui::lobby_list_window::State>,
+    public_lobby_comment: String,
 }
 
 impl State {
@@ -920,12 +1236,74 @@ impl State {
         Self {
             link_code: String::new(),
             show_link_code: false,
+            direct_addr: String::new(),
+            chat_input: String::new(),
             connection_task: std::sync::Arc::new(tokio::sync::Mutex::new(None)),
             show_save_select: None,
+            lobby_list: None,
+            public_lobby_comment: String::new(),
         }
     }
 }
 
+const MAX_FIRST_TO: u8 = 5;
+
+fn series_label(language: &unic_langid::LanguageIdentifier, first_to: Option<u8>) -> String {
+    if let Some(first_to) = first_to {
+        i18n::LOCALES
+            .lookup_with_args(
+                language,
+                "play-details-series.first-to",
+                &std::collections::HashMap::from([("n", first_to.into())]),
+            )
+            .unwrap()
+    } else {
+        i18n::LOCALES.lookup(language, "play-details-series.unlimited").unwrap()
+    }
+}
+
+fn ruleset_label(language: &unic_langid::LanguageIdentifier, ruleset: Option<&ruleset::Ruleset>) -> String {
+    if let Some(ruleset) = ruleset {
+        ruleset.name.clone()
+    } else {
+        i18n::LOCALES.lookup(language, "play-details-ruleset.none").unwrap()
+    }
+}
+
+fn describe_violation(
+    language: &unic_langid::LanguageIdentifier,
+    violation: &ruleset::Violation,
+    name: Option<&str>,
+) -> String {
+    let mut args = std::collections::HashMap::from([("name", name.unwrap_or("?").into())]);
+    let key = match violation {
+        ruleset::Violation::TooManyMegaChips { count, max } => {
+            args.extend([("count", (*count).into()), ("max", (*max).into())]);
+            "ruleset-violation-too-many-mega-chips"
+        }
+        ruleset::Violation::TooManyGigaChips { count, max } => {
+            args.extend([("count", (*count).into()), ("max", (*max).into())]);
+            "ruleset-violation-too-many-giga-chips"
+        }
+        ruleset::Violation::TooManyChipCopies { count, max, .. } => {
+            args.extend([("count", (*count).into()), ("max", (*max).into())]);
+            "ruleset-violation-too-many-chip-copies"
+        }
+        ruleset::Violation::BannedChip { .. } => "ruleset-violation-banned-chip",
+        ruleset::Violation::BannedNavicustPart { .. } => "ruleset-violation-banned-navicust-part",
+        ruleset::Violation::BannedPatchCard { .. } => "ruleset-violation-banned-patch-card",
+        ruleset::Violation::TooMuchPatchCardMb { mb, max } => {
+            args.extend([("mb", (*mb).into()), ("max", (*max).into())]);
+            "ruleset-violation-too-much-patch-card-mb"
+        }
+        ruleset::Violation::TooManyPatchCardCopies { count, max, .. } => {
+            args.extend([("count", (*count).into()), ("max", (*max).into())]);
+            "ruleset-violation-too-many-patch-card-copies"
+        }
+    };
+    i18n::LOCALES.lookup_with_args(language, key, &args).unwrap()
+}
+
 fn show_lobby_table(
     ui: &mut egui::Ui,
     cancellation_token: &tokio_util::sync::CancellationToken,
@@ -961,6 +1339,7 @@ fn show_lobby_table(
         .size(egui_extras::Size::exact(row_height + spacing_y))
         .size(egui_extras::Size::exact(row_height + spacing_y))
         .size(egui_extras::Size::exact(row_height + spacing_y))
+        .size(egui_extras::Size::exact(row_height + spacing_y))
         .vertical(|mut outer_strip| {
             const CELL_WIDTH: f32 = 200.0;
             outer_strip.strip(|sb| {
@@ -1000,7 +1379,12 @@ fn show_lobby_table(
                         strip.cell(|ui| {
                             ui.horizontal(|ui| {
                                 ui.strong(lobby.remote_settings.nickname.clone());
-                                ui.small(format!("{}ms", lobby.latencies.median().as_millis()));
+                                let latency_label = ui.small(format!("{}ms", lobby.latencies.median().as_millis()));
+                                if let Some(report) = lobby.connection_report.as_ref() {
+                                    latency_label.on_hover_ui(|ui| {
+                                        gui::connection_report_view::show(ui, &config.language, report);
+                                    });
+                                }
                                 if lobby.remote_commitment.is_some() {
                                     ui.label(
                                         egui::RichText::new("✅").color(egui::Color32::from_rgb(0x4c, 0xaf, 0x50)),
@@ -1150,18 +1534,139 @@ fn show_lobby_table(
                             });
                         });
                         strip.cell(|ui| {
-                            ui.label(if let Some(game_info) = lobby.remote_settings.game_info.as_ref() {
-                                i18n::LOCALES
-                                    .lookup(
-                                        &config.language,
-                                        &format!(
-                                            "game-{}.match-type-{}-{}",
-                                            game_info.family_and_variant.0,
-                                            lobby.remote_settings.match_type.0,
-                                            lobby.remote_settings.match_type.1,
-                                        ),
-                                    )
-                                    .unwrap()
+                            ui.label(if let Some(game_info) = lobby.remote_settings.game_info.as_ref() {
+                                i18n::LOCALES
+                                    .lookup(
+                                        &config.language,
+                                        &format!(
+                                            "game-{}.match-type-{}-{}",
+                                            game_info.family_and_variant.0,
+                                            lobby.remote_settings.match_type.0,
+                                            lobby.remote_settings.match_type.1,
+                                        ),
+                                    )
+                                    .unwrap()
+                            } else {
+                                "".to_string()
+                            });
+                        });
+                    });
+            });
+
+            outer_strip.strip(|sb| {
+                sb.size(egui_extras::Size::remainder())
+                    .size(egui_extras::Size::exact(CELL_WIDTH))
+                    .size(egui_extras::Size::exact(CELL_WIDTH))
+                    .horizontal(|mut strip| {
+                        strip.cell(|ui| {
+                            ui.horizontal(|ui| {
+                                ui.strong(i18n::LOCALES.lookup(&config.language, "play-details-series").unwrap());
+                                if lobby.local_selection.is_some()
+                                    && lobby.remote_settings.game_info.is_some()
+                                    && lobby.first_to != lobby.remote_settings.first_to
+                                {
+                                    gui::warning::show(
+                                        ui,
+                                        i18n::LOCALES
+                                            .lookup(&config.language, "lobby-issue-series-mismatch")
+                                            .unwrap(),
+                                    );
+                                }
+                            });
+                        });
+                        strip.cell(|ui| {
+                            let mut first_to = lobby.first_to;
+                            ui.add_enabled_ui(
+                                lobby.negotiated.has_capability(net::protocol::capability::SERIES),
+                                |ui| {
+                                    egui::ComboBox::new("start-series-combobox", "")
+                                        .width(150.0)
+                                        .selected_text(series_label(&config.language, first_to))
+                                        .show_ui(ui, |ui| {
+                                            for v in std::iter::once(None).chain((1..=MAX_FIRST_TO).map(Some)) {
+                                                ui.selectable_value(
+                                                    &mut first_to,
+                                                    v,
+                                                    series_label(&config.language, v),
+                                                );
+                                            }
+                                        });
+                                },
+                            );
+                            let _ = sync::block_on(lobby.set_first_to(first_to));
+                        });
+                        strip.cell(|ui| {
+                            ui.label(if lobby.remote_settings.game_info.is_some() {
+                                series_label(&config.language, lobby.remote_settings.first_to)
+                            } else {
+                                "".to_string()
+                            });
+                        });
+                    });
+            });
+
+            outer_strip.strip(|sb| {
+                sb.size(egui_extras::Size::remainder())
+                    .size(egui_extras::Size::exact(CELL_WIDTH))
+                    .size(egui_extras::Size::exact(CELL_WIDTH))
+                    .horizontal(|mut strip| {
+                        strip.cell(|ui| {
+                            ui.horizontal(|ui| {
+                                ui.strong(i18n::LOCALES.lookup(&config.language, "play-details-ruleset").unwrap());
+                                if lobby.local_selection.is_some()
+                                    && lobby.remote_settings.game_info.is_some()
+                                    && lobby.ruleset != lobby.remote_settings.ruleset
+                                {
+                                    gui::warning::show(
+                                        ui,
+                                        i18n::LOCALES
+                                            .lookup(&config.language, "lobby-issue-ruleset-mismatch")
+                                            .unwrap(),
+                                    );
+                                }
+                                if !lobby.local_violations.is_empty() {
+                                    gui::warning::show(
+                                        ui,
+                                        std::iter::once(
+                                            i18n::LOCALES
+                                                .lookup(&config.language, "lobby-issue-ruleset-violations")
+                                                .unwrap(),
+                                        )
+                                        .chain(lobby.local_violations.iter().map(|(violation, name)| {
+                                            format!(
+                                                "• {}",
+                                                describe_violation(&config.language, violation, name.as_deref())
+                                            )
+                                        }))
+                                        .collect::<Vec<_>>()
+                                        .join("\n"),
+                                    );
+                                }
+                            });
+                        });
+                        strip.cell(|ui| {
+                            let mut ruleset = lobby.ruleset.clone();
+                            ui.add_enabled_ui(
+                                lobby.negotiated.has_capability(net::protocol::capability::RULESETS),
+                                |ui| {
+                                    egui::ComboBox::new("start-ruleset-combobox", "")
+                                        .width(150.0)
+                                        .selected_text(ruleset_label(&config.language, ruleset.as_ref()))
+                                        .show_ui(ui, |ui| {
+                                            for v in std::iter::once(None)
+                                                .chain(lobby.available_rulesets.iter().cloned().map(Some))
+                                            {
+                                                let label = ruleset_label(&config.language, v.as_ref());
+                                                ui.selectable_value(&mut ruleset, v, label);
+                                            }
+                                        });
+                                },
+                            );
+                            let _ = sync::block_on(lobby.set_ruleset(ruleset));
+                        });
+                        strip.cell(|ui| {
+                            ui.label(if lobby.remote_settings.game_info.is_some() {
+                                ruleset_label(&config.language, lobby.remote_settings.ruleset.as_ref())
                             } else {
                                 "".to_string()
                             });
@@ -1192,6 +1697,48 @@ fn show_lobby_table(
                     });
             });
 
+            outer_strip.strip(|sb| {
+                sb.size(egui_extras::Size::remainder())
+                    .size(egui_extras::Size::exact(CELL_WIDTH))
+                    .size(egui_extras::Size::exact(CELL_WIDTH))
+                    .horizontal(|mut strip| {
+                        strip.cell(|ui| {
+                            ui.horizontal(|ui| {
+                                ui.strong(i18n::LOCALES.lookup(&config.language, "play-details-lockstep").unwrap())
+                                    .on_hover_text(
+                                        i18n::LOCALES
+                                            .lookup(&config.language, "play-details-lockstep.description")
+                                            .unwrap(),
+                                    );
+                                if lobby.local_selection.is_some()
+                                    && lobby.remote_settings.game_info.is_some()
+                                    && lobby.lockstep != lobby.remote_settings.lockstep
+                                {
+                                    gui::warning::show(
+                                        ui,
+                                        i18n::LOCALES
+                                            .lookup(&config.language, "lobby-issue-lockstep-mismatch")
+                                            .unwrap(),
+                                    );
+                                }
+                            });
+                        });
+                        strip.cell(|ui| {
+                            let mut checked = lobby.lockstep;
+                            ui.add_enabled_ui(
+                                lobby.negotiated.has_capability(net::protocol::capability::LOCKSTEP),
+                                |ui| {
+                                    ui.checkbox(&mut checked, "");
+                                },
+                            );
+                            let _ = sync::block_on(lobby.set_lockstep(checked));
+                        });
+                        strip.cell(|ui| {
+                            ui.checkbox(&mut lobby.remote_settings.lockstep.clone(), "");
+                        });
+                    });
+            });
+
             outer_strip.strip(|sb| {
                 sb.size(egui_extras::Size::remainder())
                     .size(egui_extras::Size::exact(CELL_WIDTH * 2.0 + spacing_x))
@@ -1268,11 +1815,15 @@ fn show_bottom_pane(
     connection_task_arc: std::sync::Arc<tokio::sync::Mutex<Option<ConnectionTask>>>,
     link_code: &mut String,
     show_link_code: &mut bool,
+    direct_addr: &mut String,
+    chat_input: &mut String,
     show_save_select: &mut Option<gui::save_select_view::State>,
+    lobby_list: &mut Option<gui::lobby_list_window::State>,
+    public_lobby_comment: &mut String,
     init_link_code: &mut Option<String>,
 ) {
     let error_window_open = {
-        if let Some(ConnectionTask::Failed(err)) = connection_task.as_ref() {
+        if let Some(ConnectionTask::Failed(err, report)) = connection_task.as_ref() {
             let mut open = true;
             let mut open2 = true;
             egui::Window::new(format!(
@@ -1289,9 +1840,46 @@ fn show_bottom_pane(
                         .unwrap(),
                     ConnectionError::Signaling(tango_signaling::Error::ServerAbort(
                         tango_signaling::AbortReason::ProtocolVersionTooOld,
+                        _,
                     )) => i18n::LOCALES
                         .lookup(&config.language, "connection-error-protocol-version-too-old")
                         .unwrap(),
+                    ConnectionError::Signaling(tango_signaling::Error::ServerAbort(
+                        tango_signaling::AbortReason::RateLimited | tango_signaling::AbortReason::TooManySessions,
+                        _,
+                    )) => i18n::LOCALES
+                        .lookup(&config.language, "connection-error-server-busy")
+                        .unwrap(),
+                    ConnectionError::Signaling(tango_signaling::Error::ServerAbort(
+                        tango_signaling::AbortReason::QueueTimeout,
+                        _,
+                    )) => i18n::LOCALES
+                        .lookup(&config.language, "connection-error-queue-timeout")
+                        .unwrap(),
+                    ConnectionError::Signaling(tango_signaling::Error::ServerAbort(
+                        tango_signaling::AbortReason::Unauthorized,
+                        _,
+                    )) => i18n::LOCALES
+                        .lookup(&config.language, "connection-error-unauthorized")
+                        .unwrap(),
+                    ConnectionError::Signaling(tango_signaling::Error::ServerAbort(
+                        tango_signaling::AbortReason::Forbidden,
+                        _,
+                    )) => i18n::LOCALES
+                        .lookup(&config.language, "connection-error-forbidden")
+                        .unwrap(),
+                    ConnectionError::Signaling(tango_signaling::Error::ServerAbort(
+                        tango_signaling::AbortReason::Kicked,
+                        _,
+                    )) => i18n::LOCALES
+                        .lookup(&config.language, "connection-error-kicked")
+                        .unwrap(),
+                    ConnectionError::Signaling(tango_signaling::Error::ServerAbort(
+                        tango_signaling::AbortReason::Banned,
+                        _,
+                    )) => i18n::LOCALES
+                        .lookup(&config.language, "connection-error-banned")
+                        .unwrap(),
                     ConnectionError::Negotiation(net::NegotiationError::RemoteProtocolVersionTooNew) => i18n::LOCALES
                         .lookup(&config.language, "connection-error-remote-protocol-version-too-new")
                         .unwrap(),
@@ -1308,6 +1896,31 @@ fn show_bottom_pane(
                         )
                         .unwrap(),
                 });
+                if let ConnectionError::Signaling(tango_signaling::Error::ServerAbort(_, message)) = err {
+                    if !message.is_empty() {
+                        ui.label(
+                            i18n::LOCALES
+                                .lookup_with_args(
+                                    &config.language,
+                                    "connection-error-server-message",
+                                    &std::collections::HashMap::from([("message", message.clone().into())]),
+                                )
+                                .unwrap(),
+                        );
+                    }
+                }
+                if let Some(report) = report.as_ref() {
+                    gui::server_notices_view::show(ui, &config.language, report);
+                    egui::CollapsingHeader::new(
+                        i18n::LOCALES
+                            .lookup(&config.language, "connection-error-details")
+                            .unwrap(),
+                    )
+                    .id_source("connection-failed-report")
+                    .show(ui, |ui| {
+                        gui::connection_report_view::show(ui, &config.language, report);
+                    });
+                }
                 if ui
                     .button(
                         i18n::LOCALES
@@ -1326,7 +1939,7 @@ fn show_bottom_pane(
     };
 
     if !error_window_open {
-        if let Some(ConnectionTask::Failed(_)) = connection_task.as_ref() {
+        if let Some(ConnectionTask::Failed(_, _)) = connection_task.as_ref() {
             *connection_task = None;
         }
     }
@@ -1343,7 +1956,11 @@ fn show_bottom_pane(
                 }) = connection_task.as_ref()
                 {
                     match connection_state {
-                        ConnectionState::Starting | ConnectionState::Signaling | ConnectionState::Waiting => {
+                        ConnectionState::Starting
+                        | ConnectionState::Queued
+                        | ConnectionState::Signaling
+                        | ConnectionState::Waiting(_)
+                        | ConnectionState::Relaying => {
                             ui.horizontal(|ui| {
                                 ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
                                     if ui
@@ -1363,18 +1980,27 @@ fn show_bottom_pane(
                                                 ConnectionState::Starting => i18n::LOCALES
                                                     .lookup(&config.language, "play-connection-task-starting")
                                                     .unwrap(),
+                                                ConnectionState::Queued => i18n::LOCALES
+                                                    .lookup(&config.language, "play-connection-task-queued")
+                                                    .unwrap(),
                                                 ConnectionState::Signaling => i18n::LOCALES
                                                     .lookup(&config.language, "play-connection-task-signaling")
                                                     .unwrap(),
-                                                ConnectionState::Waiting => i18n::LOCALES
+                                                ConnectionState::Waiting(_) => i18n::LOCALES
                                                     .lookup(&config.language, "play-connection-task-waiting")
                                                     .unwrap(),
+                                                ConnectionState::Relaying => i18n::LOCALES
+                                                    .lookup(&config.language, "play-connection-task-relaying")
+                                                    .unwrap(),
                                                 _ => unreachable!(),
                                             });
                                         });
                                     });
                                 });
                             });
+                            if let ConnectionState::Waiting(Some(report)) = connection_state {
+                                gui::server_notices_view::show(ui, &config.language, report);
+                            }
                             discord_client.set_current_activity(Some(discord::make_looking_activity(
                                 link_code,
                                 &config.language,
@@ -1390,6 +2016,98 @@ fn show_bottom_pane(
                                 }),
                             )));
                         }
+                        ConnectionState::Manual(manual) => {
+                            let mut manual = manual.lock();
+                            ui.horizontal(|ui| {
+                                ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
+                                    if ui
+                                        .button(format!(
+                                            "❎ {}",
+                                            i18n::LOCALES.lookup(&config.language, "play-cancel").unwrap()
+                                        ))
+                                        .clicked()
+                                    {
+                                        cancellation_token.cancel();
+                                    }
+
+                                    ui.horizontal_top(|ui| {
+                                        ui.with_layout(egui::Layout::left_to_right(egui::Align::Min), |ui| {
+                                            if manual.remote_blob_tx.is_none() {
+                                                ui.spinner();
+                                            }
+                                            ui.label(
+                                                i18n::LOCALES
+                                                    .lookup(
+                                                        &config.language,
+                                                        match (
+                                                            manual.is_offerer,
+                                                            manual.local_blob.is_some(),
+                                                            manual.remote_blob_tx.is_some(),
+                                                        ) {
+                                                            (_, false, false) => "play-manual-gathering",
+                                                            (true, _, true) => "play-manual-offer-instructions",
+                                                            (false, _, true) => "play-manual-answer-instructions",
+                                                            (_, true, false) => "play-manual-reply-instructions",
+                                                        },
+                                                    )
+                                                    .unwrap(),
+                                            );
+                                        });
+                                    });
+                                });
+                            });
+
+                            if let Some(local_blob) = manual.local_blob.as_ref() {
+                                ui.horizontal(|ui| {
+                                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
+                                        if ui
+                                            .button(format!(
+                                                "📋 {}",
+                                                i18n::LOCALES.lookup(&config.language, "play-manual-copy").unwrap()
+                                            ))
+                                            .clicked()
+                                        {
+                                            let _ = clipboard.set_text(local_blob.clone());
+                                        }
+                                        ui.add(
+                                            egui::TextEdit::singleline(&mut local_blob.as_str())
+                                                .desired_width(f32::INFINITY),
+                                        );
+                                    });
+                                });
+                            }
+
+                            if manual.remote_blob_tx.is_some() {
+                                ui.horizontal(|ui| {
+                                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
+                                        if ui
+                                            .add_enabled(
+                                                !manual.remote_blob.is_empty(),
+                                                egui::Button::new(format!(
+                                                    "🥊 {}",
+                                                    i18n::LOCALES.lookup(&config.language, "play-fight").unwrap()
+                                                )),
+                                            )
+                                            .clicked()
+                                        {
+                                            let remote_blob = manual.remote_blob.clone();
+                                            if let Some(remote_blob_tx) = manual.remote_blob_tx.take() {
+                                                let _ = remote_blob_tx.send(remote_blob);
+                                            }
+                                        }
+                                        ui.add(
+                                            egui::TextEdit::singleline(&mut manual.remote_blob)
+                                                .hint_text(
+                                                    i18n::LOCALES
+                                                        .lookup(&config.language, "play-manual-paste")
+                                                        .unwrap(),
+                                                )
+                                                .desired_width(f32::INFINITY),
+                                        );
+                                    });
+                                });
+                            }
+                        }
                         ConnectionState::InLobby(lobby) => {
                             let mut lobby = lobby.blocking_lock();
                             if !lobby.attention_requested {
@@ -1412,9 +2130,50 @@ fn show_bottom_pane(
                                 }),
                             )));
 
+                            if let Some(report) = lobby.connection_report.as_ref() {
+                                gui::server_notices_view::show(ui, &config.language, report);
+                            }
+
                             ui.add_enabled_ui(lobby.local_negotiated_state.is_none() && lobby.sender.is_some(), |ui| {
                                 show_lobby_table(ui, &cancellation_token, config, &mut lobby, &roms, &patches);
                             });
+
+                            if let Some(progress) = *lobby.transfer_progress.lock() {
+                                ui.add(
+                                    egui::ProgressBar::new(progress.fraction()).text(
+                                        i18n::LOCALES
+                                            .lookup_with_args(
+                                                &config.language,
+                                                "lobby-transfer-progress",
+                                                &std::collections::HashMap::from([
+                                                    ("sent", progress.sent.into()),
+                                                    ("to_send", progress.to_send.into()),
+                                                    ("received", progress.received.into()),
+                                                    ("to_receive", progress.to_receive.into()),
+                                                ]),
+                                            )
+                                            .unwrap(),
+                                    ),
+                                );
+                            }
+
+                            if lobby.negotiated.has_capability(net::protocol::capability::CHAT) {
+                                ui.separator();
+                                let action = ui
+                                    .add_enabled_ui(lobby.sender.is_some(), |ui| {
+                                        gui::chat_view::show(ui, &config.language, &lobby.chat.lock(), chat_input)
+                                    })
+                                    .inner;
+                                match action {
+                                    Some(gui::chat_view::Action::Message(text)) => {
+                                        let _ = sync::block_on(lobby.send_chat(text));
+                                    }
+                                    Some(gui::chat_view::Action::Emote(id)) => {
+                                        let _ = sync::block_on(lobby.send_emote(id));
+                                    }
+                                    None => {}
+                                }
+                            }
                         }
                     }
                 } else {
@@ -1437,13 +2196,14 @@ fn show_bottom_pane(
                                 },
                                 Some(cancellation_token.clone()),
                             ),
-                            ConnectionTask::Failed(_) => (None, None),
+                            ConnectionTask::Failed(_, _) => (None, None),
                         }
                     } else {
                         (None, None)
                     };
 
                     let mut submitted = false;
+                    let mut requested_signaling = None;
                     if cancellation_token.is_none() {
                         if ui
                             .add_enabled(
@@ -1468,6 +2228,191 @@ fn show_bottom_pane(
                             let _ = clipboard.set_text(link_code.clone());
                         }
 
+                        if ui
+                            .add_enabled(
+                                !error_window_open && selection.is_some(),
+                                egui::Button::new(egui::RichText::new("🎲")),
+                            )
+                            .on_hover_text(i18n::LOCALES.lookup(&config.language, "play-queue").unwrap())
+                            .clicked()
+                        {
+                            if let Some(selection) = selection.as_ref() {
+                                let (family, variant) = selection.game.gamedb_entry().family_and_variant;
+                                requested_signaling = Some(Signaling::Queue(
+                                    if !config.matchmaking_endpoint.is_empty() {
+                                        config.matchmaking_endpoint.clone()
+                                    } else {
+                                        config::DEFAULT_MATCHMAKING_ENDPOINT.to_string()
+                                    },
+                                    tango_signaling::queue::Request {
+                                        family: family.to_string(),
+                                        variant,
+                                        patch: selection
+                                            .patch
+                                            .as_ref()
+                                            .map(|(name, version, _)| (name.clone(), version.to_string())),
+                                        match_type: (config.default_match_type, 0),
+                                    },
+                                ));
+                            }
+                        }
+
+                        if ui
+                            .add_enabled(!error_window_open, egui::Button::new(egui::RichText::new("🌐")))
+                            .on_hover_text(i18n::LOCALES.lookup(&config.language, "play-lobby-list").unwrap())
+                            .clicked()
+                        {
+                            if lobby_list.is_some() {
+                                *lobby_list = None;
+                            } else {
+                                let state = gui::lobby_list_window::State::new(
+                                    if !config.matchmaking_endpoint.is_empty() {
+                                        config.matchmaking_endpoint.clone()
+                                    } else {
+                                        config::DEFAULT_MATCHMAKING_ENDPOINT.to_string()
+                                    },
+                                    selection.as_ref().map(|selection| {
+                                        selection.game.gamedb_entry().family_and_variant.0.to_string()
+                                    }),
+                                    tango_signaling::Auth {
+                                        token: if !config.matchmaking_token.is_empty() {
+                                            Some(config.matchmaking_token.clone())
+                                        } else {
+                                            None
+                                        },
+                                        family: None,
+                                    },
+                                );
+                                state.refresh(ui.ctx());
+                                *lobby_list = Some(state);
+                            }
+                        }
+
+                        ui.add_enabled_ui(!error_window_open && selection.is_some(), |ui| {
+                            ui.menu_button("📢", |ui| {
+                                ui.horizontal(|ui| {
+                                    let input_resp = ui.add(
+                                        egui::TextEdit::singleline(public_lobby_comment)
+                                            .hint_text(
+                                                i18n::LOCALES
+                                                    .lookup(&config.language, "play-public-lobby-comment")
+                                                    .unwrap(),
+                                            )
+                                            .desired_width(200.0),
+                                    );
+                                    if ui
+                                        .button(
+                                            i18n::LOCALES
+                                                .lookup(&config.language, "play-public-lobby-host")
+                                                .unwrap(),
+                                        )
+                                        .clicked()
+                                        || (input_resp.lost_focus()
+                                            && ui.ctx().input(|i| i.key_pressed(egui::Key::Enter)))
+                                    {
+                                        if let Some(selection) = selection.as_ref() {
+                                            let (family, variant) = selection.game.gamedb_entry().family_and_variant;
+                                            *link_code = randomcode::generate(&config.language);
+                                            requested_signaling = Some(Signaling::PublicLobby(
+                                                if !config.matchmaking_endpoint.is_empty() {
+                                                    config.matchmaking_endpoint.clone()
+                                                } else {
+                                                    config::DEFAULT_MATCHMAKING_ENDPOINT.to_string()
+                                                },
+                                                tango_signaling::lobbies::Listing {
+                                                    family: family.to_string(),
+                                                    variant,
+                                                    patch: selection
+                                                        .patch
+                                                        .as_ref()
+                                                        .map(|(name, version, _)| (name.clone(), version.to_string())),
+                                                    match_type: (config.default_match_type, 0),
+                                                    nickname: config.nickname.clone().unwrap_or_default(),
+                                                    comment: public_lobby_comment.trim().to_string(),
+                                                },
+                                            ));
+                                        }
+                                        ui.close_menu();
+                                    }
+                                });
+                            })
+                            .response
+                            .on_hover_text(i18n::LOCALES.lookup(&config.language, "play-public-lobby").unwrap());
+                        });
+
+                        if let Some(session_id) =
+                            gui::lobby_list_window::show(ui.ctx(), &config.language, lobby_list, !error_window_open)
+                        {
+                            *link_code = session_id;
+                            submitted = true;
+                        }
+
+                        if ui
+                            .add_enabled(!error_window_open, egui::Button::new(egui::RichText::new("📤")))
+                            .on_hover_text(i18n::LOCALES.lookup(&config.language, "play-manual-offer").unwrap())
+                            .clicked()
+                        {
+                            requested_signaling = Some(Signaling::Manual { is_offerer: true });
+                        }
+
+                        if ui
+                            .add_enabled(!error_window_open, egui::Button::new(egui::RichText::new("📥")))
+                            .on_hover_text(i18n::LOCALES.lookup(&config.language, "play-manual-answer").unwrap())
+                            .clicked()
+                        {
+                            requested_signaling = Some(Signaling::Manual { is_offerer: false });
+                        }
+
+                        ui.add_enabled_ui(!error_window_open, |ui| {
+                            ui.menu_button("🖧", |ui| {
+                                if ui
+                                    .button(
+                                        i18n::LOCALES
+                                            .lookup_with_args(
+                                                &config.language,
+                                                "play-direct-host",
+                                                &std::collections::HashMap::from([(
+                                                    "port",
+                                                    net::direct::DEFAULT_PORT.into(),
+                                                )]),
+                                            )
+                                            .unwrap(),
+                                    )
+                                    .clicked()
+                                {
+                                    requested_signaling = Some(Signaling::DirectHost(net::direct::DEFAULT_PORT));
+                                    ui.close_menu();
+                                }
+                                ui.horizontal(|ui| {
+                                    let input_resp = ui.add(
+                                        egui::TextEdit::singleline(direct_addr)
+                                            .hint_text(
+                                                i18n::LOCALES.lookup(&config.language, "play-direct-address").unwrap(),
+                                            )
+                                            .desired_width(150.0),
+                                    );
+                                    if ui
+                                        .add_enabled(
+                                            !direct_addr.is_empty(),
+                                            egui::Button::new(
+                                                i18n::LOCALES.lookup(&config.language, "play-direct-connect").unwrap(),
+                                            ),
+                                        )
+                                        .clicked()
+                                        || (!direct_addr.is_empty()
+                                            && input_resp.lost_focus()
+                                            && ui.ctx().input(|i| i.key_pressed(egui::Key::Enter)))
+                                    {
+                                        requested_signaling =
+                                            Some(Signaling::DirectConnect(direct_addr.trim().to_string()));
+                                        ui.close_menu();
+                                    }
+                                });
+                            })
+                            .response
+                            .on_hover_text(i18n::LOCALES.lookup(&config.language, "play-direct").unwrap());
+                        });
+
                         if config.streamer_mode {
                             if ui
                                 .selectable_label(*show_link_code, "👁️")
@@ -1490,6 +2435,7 @@ fn show_bottom_pane(
                                     &lobby.remote_settings,
                                     &patches,
                                 )
+                                && lobby.local_violations.is_empty()
                                 && lobby.sender.is_some(),
                             egui::Checkbox::new(
                                 &mut ready,
@@ -1559,13 +2505,25 @@ fn show_bottom_pane(
                         submitted = true;
                     }
 
-                    if submitted {
+                    if submitted || requested_signaling.is_some() {
                         let audio_binder = audio_binder.clone();
                         let egui_ctx = ui.ctx().clone();
                         let session = session.clone();
                         let emu_tps_counter = emu_tps_counter.clone();
 
-                        if !link_code.is_empty() {
+                        let signaling = if let Some(requested_signaling) = requested_signaling {
+                            Some(requested_signaling)
+                        } else if !link_code.is_empty() {
+                            Some(Signaling::Matchmaking(if !config.matchmaking_endpoint.is_empty() {
+                                config.matchmaking_endpoint.clone()
+                            } else {
+                                config::DEFAULT_MATCHMAKING_ENDPOINT.to_string()
+                            }))
+                        } else {
+                            None
+                        };
+
+                        if let Some(signaling) = signaling {
                             let cancellation_token = tokio_util::sync::CancellationToken::new();
                             *connection_task = Some(ConnectionTask::InProgress {
                                 state: ConnectionState::Starting,
@@ -1573,12 +2531,17 @@ fn show_bottom_pane(
                             });
 
                             tokio::task::spawn({
-                                let matchmaking_endpoint = if !config.matchmaking_endpoint.is_empty() {
-                                    config.matchmaking_endpoint.clone()
+                                let link_code = if let Signaling::Matchmaking(_)
+                                | Signaling::PublicLobby(_, _)
+                                | Signaling::Queue(_, _) = signaling
+                                {
+                                    link_code.to_owned()
                                 } else {
-                                    config::DEFAULT_MATCHMAKING_ENDPOINT.to_string()
+                                    MANUAL_LINK_CODE.to_owned()
                                 };
-                                let link_code = link_code.to_owned();
+                                let game_family = selection
+                                    .as_ref()
+                                    .map(|selection| selection.game.gamedb_entry().family_and_variant.0.to_string());
                                 let nickname = config.nickname.clone().unwrap_or_else(|| "".to_string());
                                 let patches_path = config.patches_path();
                                 let replays_path = config.replays_path();
@@ -1587,7 +2550,7 @@ fn show_bottom_pane(
                                 let roms_scanner = roms_scanner.clone();
                                 let patches_scanner = patches_scanner.clone();
                                 async move {
-                                    run_connection_task(
+                                    run_connection_task::<datachannel_wrapper::PeerConnection>(
                                         config_arc,
                                         egui_ctx.clone(),
                                         audio_binder,
@@ -1595,8 +2558,9 @@ fn show_bottom_pane(
                                         session,
                                         roms_scanner,
                                         patches_scanner,
-                                        matchmaking_endpoint,
+                                        signaling,
                                         link_code,
+                                        game_family,
                                         nickname,
                                         patches_path,
                                         replays_path,
@@ -1685,7 +2649,11 @@ pub fn show(
             connection_task_arc,
             &mut state.link_code,
             &mut state.show_link_code,
+            &mut state.direct_addr,
+            &mut state.chat_input,
             &mut state.show_save_select,
+            &mut state.lobby_list,
+            &mut state.public_lobby_comment,
             init_link_code,
         );
     }
@@ -1911,3 +2879,158 @@ pub fn show(
         let _ = sync::block_on(lobby.set_local_selection(&selection));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use prost::Message;
+
+    use super::*;
+
+    type WebSocketStream = tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>;
+
+    async fn send_packet(ws: &mut WebSocketStream, which: tango_signaling::proto::signaling::packet::Which) {
+        ws.send(tokio_tungstenite::tungstenite::Message::Binary(
+            tango_signaling::proto::signaling::Packet { which: Some(which) }.encode_to_vec(),
+        ))
+        .await
+        .unwrap();
+    }
+
+    async fn receive_packet(ws: &mut WebSocketStream) -> Option<tango_signaling::proto::signaling::packet::Which> {
+        loop {
+            if let tokio_tungstenite::tungstenite::Message::Binary(d) = ws.next().await.unwrap().unwrap() {
+                return tango_signaling::proto::signaling::Packet::decode(d.as_slice())
+                    .unwrap()
+                    .which;
+            }
+        }
+    }
+
+    async fn accept(
+        listener: &tokio::net::TcpListener,
+    ) -> (WebSocketStream, tango_signaling::proto::signaling::packet::Start) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        send_packet(
+            &mut ws,
+            tango_signaling::proto::signaling::packet::Which::Hello(tango_signaling::proto::signaling::packet::Hello {
+                supports_trickle_ice: false,
+                ..Default::default()
+            }),
+        )
+        .await;
+        match receive_packet(&mut ws).await {
+            Some(tango_signaling::proto::signaling::packet::Which::Start(start)) => (ws, start),
+            p => panic!("unexpected packet: {:?}", p),
+        }
+    }
+
+    /// Just enough of a signaling server for one session between two clients that don't trickle: whoever connects
+    /// first offers, and whoever connects second answers.
+    async fn run_signaling_server(listener: tokio::net::TcpListener) {
+        let (mut offerer_ws, offerer_start) = accept(&listener).await;
+        let (mut answerer_ws, _) = accept(&listener).await;
+
+        send_packet(
+            &mut answerer_ws,
+            tango_signaling::proto::signaling::packet::Which::Offer(tango_signaling::proto::signaling::packet::Offer {
+                sdp: offerer_start.offer_sdp,
+                ..Default::default()
+            }),
+        )
+        .await;
+        let answer = match receive_packet(&mut answerer_ws).await {
+            Some(tango_signaling::proto::signaling::packet::Which::Answer(answer)) => answer,
+            p => panic!("unexpected packet: {:?}", p),
+        };
+        send_packet(
+            &mut offerer_ws,
+            tango_signaling::proto::signaling::packet::Which::Answer(answer),
+        )
+        .await;
+    }
+
+    struct Client {
+        connection_task: std::sync::Arc<tokio::sync::Mutex<Option<ConnectionTask>>>,
+        cancellation_token: tokio_util::sync::CancellationToken,
+        handle: tokio::task::JoinHandle<()>,
+        _data_dir: tempfile::TempDir,
+    }
+
+    fn spawn_client(addr: &str, nickname: &str) -> Client {
+        let data_dir = tempfile::tempdir().unwrap();
+        let connection_task = std::sync::Arc::new(tokio::sync::Mutex::new(None));
+        let cancellation_token = tokio_util::sync::CancellationToken::new();
+        let handle = tokio::task::spawn(run_connection_task::<datachannel_wrapper::loopback::PeerConnection>(
+            std::sync::Arc::new(parking_lot::RwLock::new(config::Config {
+                data_path: data_dir.path().to_path_buf(),
+                ..Default::default()
+            })),
+            egui::Context::default(),
+            audio::LateBinder::new(),
+            std::sync::Arc::new(parking_lot::Mutex::new(stats::Counter::new(30))),
+            std::sync::Arc::new(parking_lot::Mutex::new(None)),
+            rom::Scanner::new(),
+            patch::Scanner::new(),
+            Signaling::Matchmaking(addr.to_string()),
+            "test".to_string(),
+            None,
+            nickname.to_string(),
+            data_dir.path().join("patches"),
+            data_dir.path().join("replays"),
+            connection_task.clone(),
+            cancellation_token.clone(),
+        ));
+        Client {
+            connection_task,
+            cancellation_token,
+            handle,
+            _data_dir: data_dir,
+        }
+    }
+
+    /// Waits for the client to get into the lobby and hear the remote's nickname.
+    async fn wait_for_remote_nickname(client: &Client) -> String {
+        loop {
+            let lobby = match &*client.connection_task.lock().await {
+                Some(ConnectionTask::InProgress {
+                    state: ConnectionState::InLobby(lobby),
+                    ..
+                }) => Some(lobby.clone()),
+                Some(ConnectionTask::Failed(e, _)) => panic!("connection failed: {:?}", e),
+                _ => None,
+            };
+            if let Some(lobby) = lobby {
+                let remote_nickname = lobby.lock().await.remote_settings.nickname.clone();
+                if !remote_nickname.is_empty() {
+                    return remote_nickname;
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_run_connection_task_loopback() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("ws://{}", listener.local_addr().unwrap());
+        let server = tokio::task::spawn(run_signaling_server(listener));
+
+        let alice = spawn_client(&addr, "alice");
+        let bob = spawn_client(&addr, "bob");
+
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            assert_eq!(wait_for_remote_nickname(&alice).await, "bob");
+            assert_eq!(wait_for_remote_nickname(&bob).await, "alice");
+        })
+        .await
+        .unwrap();
+        server.await.unwrap();
+
+        for client in [alice, bob] {
+            client.cancellation_token.cancel();
+            client.handle.await.unwrap();
+        }
+    }
+}
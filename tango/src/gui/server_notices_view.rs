@@ -0,0 +1,68 @@
+use fluent_templates::Loader;
+
+use crate::{gui, i18n, version};
+
+/// Picks the notice closest to the user's language: the exact language and region, then the same language in any
+/// region, then the fallback language, then whichever comes first.
+fn pick_notice<'a>(
+    notice: &'a std::collections::HashMap<String, String>,
+    language: &unic_langid::LanguageIdentifier,
+) -> Option<&'a str> {
+    let mut notices = notice
+        .iter()
+        .filter_map(|(tag, text)| {
+            tag.parse::<unic_langid::LanguageIdentifier>()
+                .ok()
+                .map(|tag| (tag, text.as_str()))
+        })
+        .collect::<Vec<_>>();
+    notices.sort_by_key(|(tag, _)| tag.to_string());
+
+    notices
+        .iter()
+        .find(|(tag, _)| tag == language)
+        .or_else(|| notices.iter().find(|(tag, _)| tag.language == language.language))
+        .or_else(|| {
+            notices
+                .iter()
+                .find(|(tag, _)| tag.language == i18n::FALLBACK_LANG.language)
+        })
+        .or_else(|| notices.first())
+        .map(|(_, text)| *text)
+}
+
+/// Shows what the matchmaking server's operator had to say when connecting, if anything.
+pub fn show(ui: &mut egui::Ui, language: &unic_langid::LanguageIdentifier, report: &tango_signaling::report::Report) {
+    if let Some(min_recommended_client_version) = report
+        .min_recommended_client_version
+        .as_ref()
+        .and_then(|v| semver::Version::parse(v).ok())
+        .filter(|v| version::current() < *v)
+    {
+        let mut layout_job = egui::text::LayoutJob::default();
+        gui::warning::append_to_layout_job(ui, &mut layout_job);
+        layout_job.append(
+            &i18n::LOCALES
+                .lookup_with_args(
+                    language,
+                    "server-notice-outdated",
+                    &std::collections::HashMap::from([("version", min_recommended_client_version.to_string().into())]),
+                )
+                .unwrap(),
+            0.0,
+            egui::TextFormat::simple(
+                ui.style().text_styles.get(&egui::TextStyle::Body).unwrap().clone(),
+                ui.visuals().text_color(),
+            ),
+        );
+        ui.label(layout_job);
+    }
+
+    if let Some(broadcast_message) = report.broadcast_message.as_ref() {
+        ui.label(format!("📢 {}", broadcast_message));
+    }
+
+    if let Some(notice) = pick_notice(&report.notice, language) {
+        ui.label(format!("ℹ {}", notice));
+    }
+}
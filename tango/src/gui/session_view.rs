@@ -1,14 +1,21 @@
 use fluent_templates::Loader;
 
-use crate::{discord, gui, i18n, input, session, stats, sync, video};
+use crate::{discord, gui, i18n, input, net, session, stats, sync, video};
 
+mod chat_window;
+mod diagnostics_window;
 mod replay_controls_window;
+mod series_score_window;
 
 pub struct State {
     vbuf: Option<VBuf>,
     opponent_save_view: gui::save_view::State,
     own_save_view: gui::save_view::State,
     debug_window: Option<gui::debug_window::State>,
+    show_diagnostics: bool,
+    show_chat: bool,
+    chat_input: String,
+    match_snapshot: MatchSnapshot,
 }
 
 impl State {
@@ -18,10 +25,76 @@ impl State {
             opponent_save_view: gui::save_view::State::new(),
             own_save_view: gui::save_view::State::new(),
             debug_window: None,
+            show_diagnostics: false,
+            show_chat: false,
+            chat_input: String::new(),
+            match_snapshot: MatchSnapshot::default(),
         }
     }
 }
 
+/// What the GUI last saw of the match.
+///
+/// In lockstep mode, the emulator thread holds the match for as long as the opponent's inputs take to arrive, so the GUI
+/// never waits on it: if it's busy, the last snapshot is shown again.
+#[derive(Clone, Default)]
+struct MatchSnapshot {
+    /// First to, with the score so far.
+    series: Option<(tango_pvp::battle::Score, u8)>,
+    round: Option<RoundSnapshot>,
+}
+
+#[derive(Clone)]
+struct RoundSnapshot {
+    tps_adjustment: f32,
+    local_queue_length: usize,
+    remote_queue_length: usize,
+    local_delay: u32,
+    current_tick: u32,
+    local_player_index: u8,
+    diagnostics: tango_pvp::diagnostics::RoundDiagnostics,
+}
+
+fn update_match_snapshot(session: &session::Session, snapshot: &mut MatchSnapshot) {
+    let pvp = if let session::Mode::PvP(pvp) = session.mode() {
+        pvp
+    } else {
+        return;
+    };
+
+    let match_ = if let Ok(match_) = pvp.match_.try_lock() {
+        match_
+    } else {
+        return;
+    };
+
+    let match_ = if let Some(match_) = &*match_ {
+        match_
+    } else {
+        *snapshot = MatchSnapshot::default();
+        return;
+    };
+
+    let round_state = if let Some(round_state) = match_.try_lock_round_state() {
+        round_state
+    } else {
+        return;
+    };
+
+    *snapshot = MatchSnapshot {
+        series: match_.first_to().map(|first_to| (round_state.score, first_to)),
+        round: round_state.round.as_ref().map(|round| RoundSnapshot {
+            tps_adjustment: round.tps_adjustment(),
+            local_queue_length: round.local_queue_length(),
+            remote_queue_length: round.remote_queue_length(),
+            local_delay: round.local_delay(),
+            current_tick: round.current_tick(),
+            local_player_index: round.local_player_index(),
+            diagnostics: round.diagnostics().clone(),
+        }),
+    };
+}
+
 struct VBuf {
     image: egui::ColorImage,
     texture: egui::TextureHandle,
@@ -128,6 +201,8 @@ pub fn show(
         };
     }
 
+    update_match_snapshot(session, &mut state.match_snapshot);
+
     let game_info = session.game_info();
     match session.mode() {
         session::Mode::SinglePlayer(_) => {
@@ -170,7 +245,9 @@ pub fn show(
         session::Mode::Replayer => {
             replay_controls_window::show(ctx, session, language, last_mouse_motion_time);
         }
-        _ => {}
+        session::Mode::PvP(_) => {
+            series_score_window::show(ctx, language, state.match_snapshot.series);
+        }
     }
 
     // If we've crashed, log the error and panic.
@@ -271,7 +348,10 @@ cpsr = {:08x}"#,
             language,
             session,
             show_debug,
+            &state.match_snapshot,
             &mut state.debug_window,
+            &mut state.show_diagnostics,
+            &mut state.show_chat,
             fps_counter.clone(),
             emu_tps_counter.clone(),
         );
@@ -300,12 +380,23 @@ cpsr = {:08x}"#,
             language,
             session,
             show_debug,
+            &state.match_snapshot,
             &mut state.debug_window,
+            &mut state.show_diagnostics,
+            &mut state.show_chat,
             fps_counter.clone(),
             emu_tps_counter.clone(),
         );
     }
     gui::debug_window::show(ctx, language, session, &mut state.debug_window);
+    diagnostics_window::show(
+        ctx,
+        language,
+        session,
+        state.match_snapshot.round.as_ref().map(|round| &round.diagnostics),
+        &mut state.show_diagnostics,
+    );
+    chat_window::show(ctx, language, session, &mut state.show_chat, &mut state.chat_input);
 }
 
 fn show_status_bar(
@@ -313,48 +404,40 @@ fn show_status_bar(
     language: &unic_langid::LanguageIdentifier,
     session: &session::Session,
     show_debug: bool,
+    match_snapshot: &MatchSnapshot,
     debug_window: &mut Option<gui::debug_window::State>,
+    show_diagnostics: &mut bool,
+    show_chat: &mut bool,
     fps_counter: std::sync::Arc<parking_lot::Mutex<stats::Counter>>,
     emu_tps_counter: std::sync::Arc<parking_lot::Mutex<stats::Counter>>,
 ) {
     egui::TopBottomPanel::bottom("session-status-bar").show(ctx, |ui| {
         ui.horizontal(|ui| {
             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                let (tps_adjustment, latency, round_info) = (|| {
-                    let pvp = if let session::Mode::PvP(pvp) = session.mode() {
-                        pvp
-                    } else {
-                        return (0.0, None, None);
-                    };
-
-                    let match_ = pvp.match_.blocking_lock();
-                    let match_ = if let Some(match_) = &*match_ {
-                        match_
-                    } else {
-                        return (0.0, None, None);
-                    };
+                let connection_stats = if let session::Mode::PvP(pvp) = session.mode() {
+                    Some(sync::block_on(pvp.connection_stats()))
+                } else {
+                    None
+                };
 
-                    let latency = sync::block_on(pvp.latency());
+                if let session::Mode::PvP(pvp) = session.mode() {
+                    if ui
+                        .selectable_label(*show_diagnostics, "📈")
+                        .on_hover_text(i18n::LOCALES.lookup(language, "session-diagnostics").unwrap())
+                        .clicked()
+                    {
+                        *show_diagnostics = !*show_diagnostics;
+                    }
 
-                    let round_state = match_.lock_round_state();
-                    let round = if let Some(round) = round_state.round.as_ref() {
-                        round
-                    } else {
-                        return (0.0, Some(latency), None);
-                    };
-
-                    (
-                        round.tps_adjustment(),
-                        Some(latency),
-                        Some((
-                            round.local_queue_length(),
-                            round.remote_queue_length(),
-                            round.local_delay(),
-                            round.current_tick(),
-                            round.local_player_index(),
-                        )),
-                    )
-                })();
+                    if pvp.chat().is_some()
+                        && ui
+                            .selectable_label(*show_chat, "💬")
+                            .on_hover_text(i18n::LOCALES.lookup(language, "session-chat").unwrap())
+                            .clicked()
+                    {
+                        *show_chat = !*show_chat;
+                    }
+                }
 
                 if show_debug {
                     let debug_window_open = debug_window.is_some();
@@ -382,34 +465,66 @@ fn show_status_bar(
                     ui.monospace(format!(
                         "tps {:7.2} ({:+5.2})",
                         1.0 / emu_tps_counter.lock().mean_duration().as_secs_f32(),
-                        tps_adjustment
+                        match_snapshot
+                            .round
+                            .as_ref()
+                            .map(|round| round.tps_adjustment)
+                            .unwrap_or(0.0)
                     ));
                 }
 
-                if let Some((local_qlen, remote_qlen, local_delay, current_tick, _)) = round_info {
+                if let Some(round) = match_snapshot.round.as_ref() {
                     if show_debug {
                         ui.add(egui::Separator::default().vertical());
                         ui.monospace(format!(
                             "qlen {:2} vs {:2} (delay = {:2})",
-                            local_qlen, remote_qlen, local_delay
+                            round.local_queue_length, round.remote_queue_length, round.local_delay
                         ));
 
                         ui.add(egui::Separator::default().vertical());
-                        ui.monospace(format!("tick {:5}", current_tick));
+                        ui.monospace(format!("tick {:5}", round.current_tick));
                     } else {
                         ui.add(egui::Separator::default().vertical());
-                        ui.monospace(format!("rollback ticks {:2}", local_qlen.saturating_sub(remote_qlen)));
+                        ui.monospace(format!(
+                            "rollback ticks {:2}",
+                            round.local_queue_length.saturating_sub(round.remote_queue_length)
+                        ));
+                    }
+                }
+
+                if let Some(connection_stats) = connection_stats {
+                    ui.add(egui::Separator::default().vertical());
+                    let resp = ui.monospace(if let Some(transport_rtt) = connection_stats.link.transport_rtt() {
+                        format!(
+                            "ping {:4}ms, rtt {:4}ms ({})",
+                            connection_stats.ping.as_millis(),
+                            transport_rtt.as_millis(),
+                            connection_stats.link.route_name()
+                        )
+                    } else {
+                        format!(
+                            "ping {:4}ms ({})",
+                            connection_stats.ping.as_millis(),
+                            connection_stats.link.route_name()
+                        )
+                    });
+                    if let net::LinkStats::PeerConnection(stats) = &connection_stats.link {
+                        resp.on_hover_text(format!(
+                            "{} ⇄ {}",
+                            stats.local_address.as_deref().unwrap_or("?"),
+                            stats.remote_address.as_deref().unwrap_or("?")
+                        ));
                     }
                 }
 
-                if let Some(latency) = latency {
+                if let Some((score, first_to)) = match_snapshot.series {
                     ui.add(egui::Separator::default().vertical());
-                    ui.monospace(format!("ping {:4}ms", latency.as_millis()));
+                    ui.monospace(format!("score {}-{} (ft{})", score.wins, score.losses, first_to));
                 }
 
-                if let Some((_, _, _, _, local_player_index)) = round_info {
+                if let Some(round) = match_snapshot.round.as_ref() {
                     ui.add(egui::Separator::default().vertical());
-                    ui.monospace(format!("P{}", local_player_index + 1));
+                    ui.monospace(format!("P{}", round.local_player_index + 1));
                 }
 
                 ui.add(egui::Separator::default().vertical());
@@ -0,0 +1,50 @@
+use fluent_templates::Loader;
+
+use crate::{gui, i18n, session, sync};
+
+pub fn show(
+    ctx: &egui::Context,
+    language: &unic_langid::LanguageIdentifier,
+    session: &session::Session,
+    open: &mut bool,
+    input: &mut String,
+) {
+    let pvp = if let session::Mode::PvP(pvp) = session.mode() {
+        pvp
+    } else {
+        return;
+    };
+
+    let chat = if let Some(chat) = pvp.chat() {
+        chat
+    } else {
+        return;
+    };
+
+    if !*open {
+        gui::chat_view::show_overlay(ctx, language, &chat.lock());
+        return;
+    }
+
+    let action = egui::Window::new(format!(
+        "💬 {}",
+        i18n::LOCALES.lookup(language, "session-chat").unwrap()
+    ))
+    .id(egui::Id::new("session-chat-window"))
+    .resizable(false)
+    .open(open)
+    .anchor(egui::Align2::RIGHT_TOP, egui::Vec2::new(-10.0, 10.0))
+    .show(ctx, |ui| gui::chat_view::show(ui, language, &chat.lock(), input))
+    .and_then(|response| response.inner)
+    .flatten();
+
+    match action {
+        Some(gui::chat_view::Action::Message(text)) => {
+            let _ = sync::block_on(pvp.send_chat(&text));
+        }
+        Some(gui::chat_view::Action::Emote(id)) => {
+            let _ = sync::block_on(pvp.send_emote(id));
+        }
+        None => {}
+    }
+}
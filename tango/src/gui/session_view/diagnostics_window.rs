@@ -0,0 +1,78 @@
+use fluent_templates::Loader;
+
+use crate::{i18n, session};
+
+pub fn show(
+    ctx: &egui::Context,
+    language: &unic_langid::LanguageIdentifier,
+    session: &session::Session,
+    diagnostics: Option<&tango_pvp::diagnostics::RoundDiagnostics>,
+    open: &mut bool,
+) {
+    if !matches!(session.mode(), session::Mode::PvP(_)) {
+        return;
+    }
+
+    egui::Window::new(format!(
+        "📈 {}",
+        i18n::LOCALES.lookup(language, "session-diagnostics").unwrap()
+    ))
+    .id(egui::Id::new("session-diagnostics-window"))
+    .resizable(false)
+    .open(open)
+    .anchor(egui::Align2::LEFT_TOP, egui::Vec2::new(10.0, 10.0))
+    .show(ctx, |ui| {
+        let diagnostics = if let Some(diagnostics) = diagnostics {
+            diagnostics
+        } else {
+            ui.label(i18n::LOCALES.lookup(language, "session-diagnostics.no-round").unwrap());
+            return;
+        };
+
+        egui::Grid::new("session-diagnostics-grid")
+            .num_columns(2)
+            .striped(true)
+            .show(ui, |ui| {
+                let mut row = |key: &str, value: String| {
+                    ui.strong(i18n::LOCALES.lookup(language, key).unwrap());
+                    ui.monospace(value);
+                    ui.end_row();
+                };
+
+                row("session-diagnostics.ticks", format!("{}", diagnostics.ticks));
+                row("session-diagnostics.rollbacks", format!("{}", diagnostics.rollbacks));
+                row(
+                    "session-diagnostics.rollback-depth",
+                    format!(
+                        "{:2} ({:.2} / {:2})",
+                        diagnostics.last_rollback_depth,
+                        diagnostics.mean_rollback_depth(),
+                        diagnostics.max_rollback_depth
+                    ),
+                );
+                row(
+                    "session-diagnostics.fastforward-time",
+                    format!(
+                        "{:.2}ms / {:.2}ms",
+                        diagnostics.mean_fastforward_time().as_secs_f32() * 1000.0,
+                        diagnostics.max_fastforward_time.as_secs_f32() * 1000.0
+                    ),
+                );
+                row(
+                    "session-diagnostics.dropped-frames",
+                    format!("{}", diagnostics.dropped_frames),
+                );
+                row(
+                    "session-diagnostics.stalled-frames",
+                    format!("{}", diagnostics.stalled_frames),
+                );
+                row(
+                    "session-diagnostics.remote-lead",
+                    format!(
+                        "{:+3} ({:+3}..={:+3})",
+                        diagnostics.remote_lead, diagnostics.min_remote_lead, diagnostics.max_remote_lead
+                    ),
+                );
+            });
+    });
+}
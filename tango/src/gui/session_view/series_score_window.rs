@@ -0,0 +1,36 @@
+use fluent_templates::Loader;
+
+use crate::i18n;
+
+pub fn show(
+    ctx: &egui::Context,
+    language: &unic_langid::LanguageIdentifier,
+    series: Option<(tango_pvp::battle::Score, u8)>,
+) {
+    let (score, first_to) = if let Some(series) = series {
+        series
+    } else {
+        return;
+    };
+
+    egui::Window::new("")
+        .id(egui::Id::new("series-score-window"))
+        .resizable(false)
+        .title_bar(false)
+        .anchor(egui::Align2::CENTER_TOP, egui::Vec2::new(0.0, 10.0))
+        .show(ctx, |ui| {
+            ui.label(
+                i18n::LOCALES
+                    .lookup_with_args(
+                        language,
+                        "session-series-score",
+                        &std::collections::HashMap::from([
+                            ("wins", score.wins.into()),
+                            ("losses", score.losses.into()),
+                            ("first_to", first_to.into()),
+                        ]),
+                    )
+                    .unwrap(),
+            );
+        });
+}
@@ -551,6 +551,23 @@ fn show_netplay_tab(ui: &mut egui::Ui, config: &mut config::Config) {
             );
             ui.end_row();
 
+            ui.strong(
+                i18n::LOCALES
+                    .lookup(&config.language, "settings-matchmaking-token")
+                    .unwrap(),
+            );
+            ui.add(
+                egui::TextEdit::singleline(&mut config.matchmaking_token)
+                    .desired_width(200.0)
+                    .password(true),
+            )
+            .on_hover_text(
+                i18n::LOCALES
+                    .lookup(&config.language, "settings-matchmaking-token.description")
+                    .unwrap(),
+            );
+            ui.end_row();
+
             {
                 ui.strong(i18n::LOCALES.lookup(&config.language, "settings-use-relay").unwrap());
 
@@ -579,6 +596,19 @@ fn show_netplay_tab(ui: &mut egui::Ui, config: &mut config::Config) {
                 ui.end_row();
             }
 
+            ui.strong(
+                i18n::LOCALES
+                    .lookup(&config.language, "settings-unreliable-inputs")
+                    .unwrap(),
+            )
+            .on_hover_text(
+                i18n::LOCALES
+                    .lookup(&config.language, "settings-unreliable-inputs.description")
+                    .unwrap(),
+            );
+            ui.checkbox(&mut config.unreliable_inputs, "");
+            ui.end_row();
+
             ui.strong(
                 i18n::LOCALES
                     .lookup(&config.language, "settings-replaycollector-endpoint")
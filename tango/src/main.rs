@@ -8,6 +8,8 @@ use clap::Parser;
 extern crate lazy_static;
 
 mod audio;
+mod bot;
+mod chat;
 mod config;
 mod discord;
 mod game;
@@ -19,6 +21,7 @@ mod net;
 mod patch;
 mod randomcode;
 mod rom;
+mod ruleset;
 mod save;
 mod scanner;
 mod session;
@@ -45,6 +48,24 @@ enum Command {
         /// Link code to join.
         link_code: String,
     },
+
+    /// Join a lobby headlessly as a sparring partner that accepts any match and plays back scripted inputs.
+    Spar {
+        /// Link code to join.
+        link_code: String,
+
+        /// Input script to play back. Each line is a frame count followed by the keys to hold, e.g. `30 a left`.
+        #[arg(long)]
+        script: Option<std::path::PathBuf>,
+
+        /// Save to use instead of the first one found for the selected game.
+        #[arg(long)]
+        save: Option<std::path::PathBuf>,
+
+        /// Game family to play, e.g. `bn6`. Defaults to the family of the save, if one was given.
+        #[arg(long)]
+        family: Option<String>,
+    },
 }
 
 enum UserEvent {
@@ -69,6 +90,16 @@ fn main() -> Result<(), anyhow::Error> {
         return child_main(config);
     }
 
+    if let Some(Command::Spar {
+        link_code,
+        script,
+        save,
+        family,
+    }) = Args::parse().command
+    {
+        return bot::main(config, link_code, script, save, family);
+    }
+
     let log_filename = format!(
         "{}.log",
         time::OffsetDateTime::from(std::time::SystemTime::now())
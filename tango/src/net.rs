@@ -1,7 +1,27 @@
+use sha3::digest::{ExtendableOutput, Update};
+
+pub mod direct;
+pub mod lobby;
 pub mod protocol;
+pub mod transfer;
 
 pub const PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
 
+/// How often unacknowledged inputs are resent if no new inputs are being sent.
+const RESEND_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// The most unacknowledged inputs to carry in a single bundle.
+const MAX_INPUTS_PER_BUNDLE: usize = 16;
+
+pub fn make_commitment(buf: &[u8]) -> [u8; 16] {
+    let mut shake128 = sha3::Shake128::default();
+    shake128.update(b"tango:lobby:");
+    shake128.update(buf);
+    let mut commitment = [0u8; 16];
+    shake128.finalize_xof_into(&mut commitment);
+    commitment
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum NegotiationError {
     #[error("expected hello")]
@@ -17,7 +37,20 @@ pub enum NegotiationError {
     Other(#[from] anyhow::Error),
 }
 
-pub async fn negotiate(sender: &mut Sender, receiver: &mut Receiver) -> Result<(), NegotiationError> {
+/// What both sides agreed on during the handshake.
+#[derive(Clone, Debug, Default)]
+pub struct Negotiated {
+    pub protocol_version: u8,
+    pub capabilities: std::collections::BTreeSet<String>,
+}
+
+impl Negotiated {
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+pub async fn negotiate(sender: &mut Sender, receiver: &mut Receiver) -> Result<Negotiated, NegotiationError> {
     sender
         .send_hello()
         .await
@@ -30,34 +63,183 @@ pub async fn negotiate(sender: &mut Sender, receiver: &mut Receiver) -> Result<(
         }
     };
 
-    if hello.protocol_version < protocol::VERSION {
+    if hello.max_protocol_version < protocol::MIN_VERSION {
         return Err(NegotiationError::RemoteProtocolVersionTooOld);
     }
 
-    if hello.protocol_version > protocol::VERSION {
+    if hello.min_protocol_version > protocol::VERSION {
         return Err(NegotiationError::RemoteProtocolVersionTooNew);
     }
 
-    Ok(())
+    let negotiated = Negotiated {
+        protocol_version: std::cmp::min(hello.max_protocol_version, protocol::VERSION),
+        capabilities: hello
+            .capabilities
+            .into_iter()
+            .filter(|c| protocol::capability::ALL.contains(&c.as_str()))
+            .collect(),
+    };
+    log::info!(
+        "negotiated protocol version {:x} with capabilities: {:?}",
+        negotiated.protocol_version,
+        negotiated.capabilities
+    );
+    sender.capabilities = negotiated.capabilities.clone();
+
+    Ok(negotiated)
+}
+
+/// What keeps the connection to the remote alive, besides the sender and receiver.
+pub enum Link {
+    PeerConnection(Box<dyn datachannel_wrapper::Peer>),
+    Direct(Option<tokio::net::UdpSocket>),
+    /// Everything goes through the signaling server's relay, which the sender and receiver hold on to themselves.
+    Relay,
+}
+
+/// What's known about the route the link is taking to the remote.
+#[derive(Clone, Debug)]
+pub enum LinkStats {
+    PeerConnection(datachannel_wrapper::Stats),
+    Direct,
+    Relay,
+}
+
+impl LinkStats {
+    /// A short name for the route, for showing next to the ping.
+    pub fn route_name(&self) -> &'static str {
+        match self {
+            LinkStats::PeerConnection(stats) => {
+                if stats.local_candidate_type == Some(datachannel_wrapper::CandidateType::Relay)
+                    || stats.remote_candidate_type == Some(datachannel_wrapper::CandidateType::Relay)
+                {
+                    "turn"
+                } else {
+                    "p2p"
+                }
+            }
+            LinkStats::Direct => "direct",
+            LinkStats::Relay => "relay",
+        }
+    }
+
+    /// The round-trip time measured by the transport itself, as opposed to the ping measured with our own packets.
+    pub fn transport_rtt(&self) -> Option<std::time::Duration> {
+        match self {
+            LinkStats::PeerConnection(stats) => stats.rtt,
+            LinkStats::Direct | LinkStats::Relay => None,
+        }
+    }
+}
+
+impl Link {
+    pub fn stats(&self) -> LinkStats {
+        match self {
+            Link::PeerConnection(peer_conn) => LinkStats::PeerConnection(peer_conn.stats()),
+            Link::Direct(_) => LinkStats::Direct,
+            Link::Relay => LinkStats::Relay,
+        }
+    }
+
+    /// Whether there's a separate unreliable channel to send inputs over.
+    pub fn supports_unreliable_inputs(&self) -> bool {
+        match self {
+            Link::PeerConnection(_) => true,
+            Link::Direct(udp) => udp.is_some(),
+            Link::Relay => false,
+        }
+    }
+
+    /// Opens the channel for unreliable inputs. Both sides must call this.
+    pub fn open_unreliable_inputs(&mut self) -> Result<UnreliableInputs, anyhow::Error> {
+        Ok(match self {
+            Link::PeerConnection(peer_conn) => {
+                UnreliableInputs::new(tango_signaling::create_unreliable_data_channel(peer_conn.as_mut())?)
+            }
+            Link::Direct(udp) => {
+                if let Some(udp) = udp.take() {
+                    UnreliableInputs::new_udp(udp)
+                } else {
+                    anyhow::bail!("unreliable inputs already opened");
+                }
+            }
+            Link::Relay => {
+                anyhow::bail!("unreliable inputs are not supported over the relay");
+            }
+        })
+    }
+}
+
+enum SenderTransport {
+    DataChannel(datachannel_wrapper::DataChannelSender),
+    Direct(tokio::net::tcp::OwnedWriteHalf),
+    Relay(tango_signaling::relay::Sender),
 }
 
 pub struct Sender {
-    dc_tx: datachannel_wrapper::DataChannelSender,
+    transport: SenderTransport,
+    /// What both sides agreed on in the handshake. Empty until then.
+    capabilities: std::collections::BTreeSet<String>,
 }
 
 impl Sender {
     pub fn new(dc_tx: datachannel_wrapper::DataChannelSender) -> Self {
-        Self { dc_tx }
+        Self {
+            transport: SenderTransport::DataChannel(dc_tx),
+            capabilities: std::collections::BTreeSet::new(),
+        }
+    }
+
+    pub fn new_direct(w: tokio::net::tcp::OwnedWriteHalf) -> Self {
+        Self {
+            transport: SenderTransport::Direct(w),
+            capabilities: std::collections::BTreeSet::new(),
+        }
+    }
+
+    pub fn new_relay(relay_tx: tango_signaling::relay::Sender) -> Self {
+        Self {
+            transport: SenderTransport::Relay(relay_tx),
+            capabilities: std::collections::BTreeSet::new(),
+        }
+    }
+
+    fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    /// How many bytes are waiting to go out, if the transport can tell.
+    pub fn buffered_amount(&self) -> Option<usize> {
+        match &self.transport {
+            SenderTransport::DataChannel(dc_tx) => Some(dc_tx.buffered_amount()),
+            SenderTransport::Direct(_) | SenderTransport::Relay(_) => None,
+        }
     }
 
     async fn send_packet(&mut self, p: &protocol::Packet) -> std::io::Result<()> {
-        self.dc_tx.send(p.serialize().unwrap().as_slice()).await?;
+        let buf = p.serialize().unwrap();
+        match &mut self.transport {
+            SenderTransport::DataChannel(dc_tx) => {
+                dc_tx.send(buf.as_slice()).await?;
+            }
+            SenderTransport::Direct(w) => {
+                direct::write_frame(w, buf.as_slice()).await?;
+            }
+            SenderTransport::Relay(relay_tx) => {
+                relay_tx
+                    .send(buf.as_slice())
+                    .await
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            }
+        }
         Ok(())
     }
 
     pub async fn send_hello(&mut self) -> std::io::Result<()> {
         self.send_packet(&protocol::Packet::Hello(protocol::Hello {
-            protocol_version: protocol::VERSION,
+            min_protocol_version: protocol::MIN_VERSION,
+            max_protocol_version: protocol::VERSION,
+            capabilities: protocol::capability::ALL.iter().map(|c| c.to_string()).collect(),
         }))
         .await
     }
@@ -70,7 +252,20 @@ impl Sender {
         self.send_packet(&protocol::Packet::Pong(protocol::Pong { ts })).await
     }
 
-    pub async fn send_settings(&mut self, settings: protocol::Settings) -> std::io::Result<()> {
+    /// Sends the settings, leaving out any for capabilities the remote doesn't have.
+    pub async fn send_settings(&mut self, mut settings: protocol::Settings) -> std::io::Result<()> {
+        if !self.has_capability(protocol::capability::LOCKSTEP) {
+            settings.lockstep = false;
+        }
+        if !self.has_capability(protocol::capability::SERIES) {
+            settings.first_to = None;
+        }
+        if !self.has_capability(protocol::capability::UNRELIABLE_INPUTS) {
+            settings.unreliable_inputs = false;
+        }
+        if !self.has_capability(protocol::capability::RULESETS) {
+            settings.ruleset = None;
+        }
         self.send_packet(&protocol::Packet::Settings(settings)).await
     }
 
@@ -84,29 +279,77 @@ impl Sender {
             .await
     }
 
-    pub async fn send_chunk(&mut self, chunk: Vec<u8>) -> std::io::Result<()> {
-        self.send_packet(&protocol::Packet::Chunk(protocol::Chunk { chunk }))
+    pub async fn send_transfer_offer(&mut self, offer: protocol::TransferOffer) -> std::io::Result<()> {
+        self.send_packet(&protocol::Packet::TransferOffer(offer)).await
+    }
+
+    pub async fn send_transfer_resume(&mut self, resume: protocol::TransferResume) -> std::io::Result<()> {
+        self.send_packet(&protocol::Packet::TransferResume(resume)).await
+    }
+
+    pub async fn send_chunk(&mut self, index: u32, chunk: Vec<u8>) -> std::io::Result<()> {
+        self.send_packet(&protocol::Packet::Chunk(protocol::Chunk { index, chunk }))
             .await
     }
 
+    fn require_capability(&self, capability: &str) -> std::io::Result<()> {
+        if !self.has_capability(capability) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("remote does not have capability: {}", capability),
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn send_chat(&mut self, text: String) -> std::io::Result<()> {
+        self.require_capability(protocol::capability::CHAT)?;
+        self.send_packet(&protocol::Packet::Chat(protocol::Chat { text })).await
+    }
+
+    pub async fn send_emote(&mut self, id: u8) -> std::io::Result<()> {
+        self.require_capability(protocol::capability::CHAT)?;
+        self.send_packet(&protocol::Packet::Emote(protocol::Emote { id })).await
+    }
+
     pub async fn send_start_match(&mut self) -> std::io::Result<()> {
         self.send_packet(&protocol::Packet::StartMatch(protocol::StartMatch {}))
             .await
     }
 }
 
+enum ReceiverTransport {
+    DataChannel(datachannel_wrapper::DataChannelReceiver),
+    Direct(tokio::net::tcp::OwnedReadHalf),
+    Relay(tango_signaling::relay::Receiver),
+}
+
 pub struct Receiver {
-    dc_rx: datachannel_wrapper::DataChannelReceiver,
+    transport: ReceiverTransport,
 }
 
 impl Receiver {
     pub fn new(dc_rx: datachannel_wrapper::DataChannelReceiver) -> Self {
-        Self { dc_rx }
+        Self {
+            transport: ReceiverTransport::DataChannel(dc_rx),
+        }
+    }
+
+    pub fn new_direct(r: tokio::net::tcp::OwnedReadHalf) -> Self {
+        Self {
+            transport: ReceiverTransport::Direct(r),
+        }
+    }
+
+    pub fn new_relay(relay_rx: tango_signaling::relay::Receiver) -> Self {
+        Self {
+            transport: ReceiverTransport::Relay(relay_rx),
+        }
     }
 
     pub async fn receive(&mut self) -> std::io::Result<protocol::Packet> {
-        match protocol::Packet::deserialize(
-            match self.dc_rx.receive().await {
+        let raw = match &mut self.transport {
+            ReceiverTransport::DataChannel(dc_rx) => match dc_rx.receive().await {
                 Some(d) => d,
                 None => {
                     return Err(std::io::Error::new(
@@ -114,9 +357,21 @@ impl Receiver {
                         "stream is empty",
                     ));
                 }
-            }
-            .as_slice(),
-        ) {
+            },
+            ReceiverTransport::Direct(r) => direct::read_frame(r).await?,
+            ReceiverTransport::Relay(relay_rx) => match relay_rx
+                .receive()
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+            {
+                Some(d) => d,
+                None => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "relay closed"));
+                }
+            },
+        };
+
+        match protocol::Packet::deserialize(raw.as_slice()) {
             Ok(p) => Ok(p),
             Err(e) => {
                 return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
@@ -125,19 +380,163 @@ impl Receiver {
     }
 }
 
+#[derive(Default)]
+struct UnreliableInputsState {
+    unacked: std::collections::VecDeque<tango_pvp::net::Input>,
+    last_received: Option<(u8, u32)>,
+}
+
+enum UnreliableTransport {
+    DataChannel {
+        dc_tx: tokio::sync::Mutex<datachannel_wrapper::DataChannelSender>,
+        dc_rx: tokio::sync::Mutex<datachannel_wrapper::DataChannelReceiver>,
+    },
+    Udp(tokio::net::UdpSocket),
+}
+
+/// Inputs sent over an unordered, unreliable data channel (or UDP, for direct connections).
+///
+/// Every bundle carries the oldest inputs the remote hasn't acknowledged yet, so a lost packet is covered by the next one
+/// instead of holding up everything behind it. As bundles always start from the oldest unacknowledged input, whatever
+/// arrives is contiguous with what was already received: the receiving end only needs to drop what it has seen.
+pub struct UnreliableInputs {
+    transport: UnreliableTransport,
+    state: parking_lot::Mutex<UnreliableInputsState>,
+}
+
+impl UnreliableInputs {
+    pub fn new(dc: datachannel_wrapper::DataChannel) -> Self {
+        let (dc_tx, dc_rx) = dc.split();
+        Self {
+            transport: UnreliableTransport::DataChannel {
+                dc_tx: tokio::sync::Mutex::new(dc_tx),
+                dc_rx: tokio::sync::Mutex::new(dc_rx),
+            },
+            state: parking_lot::Mutex::new(UnreliableInputsState::default()),
+        }
+    }
+
+    pub fn new_udp(udp: tokio::net::UdpSocket) -> Self {
+        Self {
+            transport: UnreliableTransport::Udp(udp),
+            state: parking_lot::Mutex::new(UnreliableInputsState::default()),
+        }
+    }
+
+    async fn send(&self, input: Option<&tango_pvp::net::Input>) -> std::io::Result<()> {
+        let bundle = {
+            let mut state = self.state.lock();
+            if let Some(input) = input {
+                state.unacked.push_back(input.clone());
+            }
+            if state.unacked.is_empty() {
+                return Ok(());
+            }
+            protocol::InputBundle {
+                ack: state.last_received,
+                inputs: state.unacked.iter().take(MAX_INPUTS_PER_BUNDLE).cloned().collect(),
+            }
+        };
+        let buf = protocol::Packet::InputBundle(bundle).serialize().unwrap();
+        match &self.transport {
+            UnreliableTransport::DataChannel { dc_tx, .. } => dc_tx.lock().await.send(buf.as_slice()).await,
+            UnreliableTransport::Udp(udp) => match udp.send(buf.as_slice()).await {
+                // Stray ICMP errors show up on connected UDP sockets, but it's fine to lose a bundle.
+                Err(e) if e.kind() != std::io::ErrorKind::ConnectionRefused => Err(e),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    async fn receive(&self) -> std::io::Result<Vec<tango_pvp::net::Input>> {
+        let raw = match &self.transport {
+            UnreliableTransport::DataChannel { dc_rx, .. } => {
+                if let Some(raw) = dc_rx.lock().await.receive().await {
+                    raw
+                } else {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "stream is empty",
+                    ));
+                }
+            }
+            UnreliableTransport::Udp(udp) => {
+                let mut buf = vec![0u8; 64 * 1024];
+                let n = loop {
+                    match udp.recv(&mut buf).await {
+                        Ok(n) if direct::is_probe(&buf[..n]) => {}
+                        Ok(n) => break n,
+                        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {}
+                        Err(e) => return Err(e),
+                    }
+                };
+                buf.truncate(n);
+                buf
+            }
+        };
+
+        let bundle = match protocol::Packet::deserialize(&raw) {
+            Ok(protocol::Packet::InputBundle(bundle)) => bundle,
+            Ok(p) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid packet: {:?}", p),
+                ));
+            }
+            Err(e) => {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+            }
+        };
+
+        let mut state = self.state.lock();
+        if let Some(ack) = bundle.ack {
+            while state
+                .unacked
+                .front()
+                .map(|input| (input.round_number, input.local_tick) <= ack)
+                .unwrap_or(false)
+            {
+                state.unacked.pop_front();
+            }
+        }
+        if let Some(last) = bundle.inputs.last() {
+            let key = (last.round_number, last.local_tick);
+            if state
+                .last_received
+                .map(|last_received| key > last_received)
+                .unwrap_or(true)
+            {
+                state.last_received = Some(key);
+            }
+        }
+        Ok(bundle.inputs)
+    }
+}
+
 pub struct PvpSender {
     sender: std::sync::Arc<tokio::sync::Mutex<Sender>>,
+    unreliable_inputs: Option<std::sync::Arc<UnreliableInputs>>,
 }
 
 impl PvpSender {
-    pub fn new(sender: std::sync::Arc<tokio::sync::Mutex<Sender>>) -> Self {
-        Self { sender }
+    pub fn new(
+        sender: std::sync::Arc<tokio::sync::Mutex<Sender>>,
+        unreliable_inputs: Option<std::sync::Arc<UnreliableInputs>>,
+    ) -> Self {
+        Self {
+            sender,
+            unreliable_inputs,
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl tango_pvp::net::Sender for PvpSender {
     async fn send(&mut self, input: &tango_pvp::net::Input) -> std::io::Result<()> {
+        if let Some(unreliable_inputs) = self.unreliable_inputs.as_ref() {
+            return unreliable_inputs.send(Some(input)).await;
+        }
+
         self.sender
             .lock()
             .await
@@ -150,7 +549,11 @@ pub struct PvpReceiver {
     receiver: Receiver,
     sender: std::sync::Arc<tokio::sync::Mutex<Sender>>,
     latency_counter: std::sync::Arc<tokio::sync::Mutex<crate::stats::LatencyCounter>>,
+    chat: std::sync::Arc<parking_lot::Mutex<crate::chat::Log>>,
     ping_timer: tokio::time::Interval,
+    unreliable_inputs: Option<std::sync::Arc<UnreliableInputs>>,
+    resend_timer: tokio::time::Interval,
+    pending_inputs: std::collections::VecDeque<tango_pvp::net::Input>,
 }
 
 impl PvpReceiver {
@@ -158,24 +561,53 @@ impl PvpReceiver {
         receiver: Receiver,
         sender: std::sync::Arc<tokio::sync::Mutex<Sender>>,
         latency_counter: std::sync::Arc<tokio::sync::Mutex<crate::stats::LatencyCounter>>,
+        chat: std::sync::Arc<parking_lot::Mutex<crate::chat::Log>>,
+        unreliable_inputs: Option<std::sync::Arc<UnreliableInputs>>,
     ) -> Self {
         Self {
             receiver,
             sender,
             latency_counter,
+            chat,
             ping_timer: tokio::time::interval(PING_INTERVAL),
+            unreliable_inputs,
+            resend_timer: tokio::time::interval(RESEND_INTERVAL),
+            pending_inputs: std::collections::VecDeque::new(),
         }
     }
 }
 
+async fn receive_unreliable_inputs(
+    unreliable_inputs: &Option<std::sync::Arc<UnreliableInputs>>,
+) -> std::io::Result<Vec<tango_pvp::net::Input>> {
+    if let Some(unreliable_inputs) = unreliable_inputs.as_ref() {
+        unreliable_inputs.receive().await
+    } else {
+        std::future::pending().await
+    }
+}
+
 #[async_trait::async_trait]
 impl tango_pvp::net::Receiver for PvpReceiver {
     async fn receive(&mut self) -> std::io::Result<tango_pvp::net::Input> {
         loop {
+            if let Some(input) = self.pending_inputs.pop_front() {
+                return Ok(input);
+            }
+
             tokio::select! {
                 _ = self.ping_timer.tick() => {
                     self.sender.lock().await.send_ping(std::time::SystemTime::now()).await?;
                 }
+                _ = self.resend_timer.tick() => {
+                    // Make sure the last few inputs of a round still get through even if we've stopped sending new ones.
+                    if let Some(unreliable_inputs) = self.unreliable_inputs.as_ref() {
+                        unreliable_inputs.send(None).await?;
+                    }
+                }
+                inputs = receive_unreliable_inputs(&self.unreliable_inputs) => {
+                    self.pending_inputs.extend(inputs?);
+                }
                 p = self.receiver.receive() => {
                     match p? {
                         protocol::Packet::Ping(ping) => {
@@ -189,6 +621,12 @@ impl tango_pvp::net::Receiver for PvpReceiver {
                         protocol::Packet::Input(input) => {
                             return Ok(input);
                         }
+                        protocol::Packet::Chat(chat) => {
+                            self.chat.lock().push_message(false, &chat.text);
+                        }
+                        protocol::Packet::Emote(emote) => {
+                            self.chat.lock().push_emote(false, emote.id);
+                        }
                         p => {
                             return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid packet: {:?}", p)))
                         },
@@ -198,3 +636,105 @@ impl tango_pvp::net::Receiver for PvpReceiver {
         }
     }
 }
+
+/// One end of an in-process connection, for tests.
+#[cfg(test)]
+pub(crate) struct LoopbackEnd {
+    pub(crate) sender: Sender,
+    pub(crate) receiver: Receiver,
+    _peer_conn: datachannel_wrapper::loopback::PeerConnection,
+}
+
+/// Connects two loopback peers to each other the way signaling would, without any signaling.
+#[cfg(test)]
+pub(crate) fn loopback_pair() -> (LoopbackEnd, LoopbackEnd) {
+    use datachannel_wrapper::Peer;
+
+    let (mut offerer, _) =
+        datachannel_wrapper::loopback::PeerConnection::new(datachannel_wrapper::RtcConfig::new::<&str>(&[])).unwrap();
+    let (mut answerer, _) =
+        datachannel_wrapper::loopback::PeerConnection::new(datachannel_wrapper::RtcConfig::new::<&str>(&[])).unwrap();
+
+    let (offerer_dc_tx, offerer_dc_rx) = offerer
+        .create_data_channel("tango", datachannel_wrapper::DataChannelInit::default())
+        .unwrap()
+        .split();
+    let (answerer_dc_tx, answerer_dc_rx) = answerer
+        .create_data_channel("tango", datachannel_wrapper::DataChannelInit::default())
+        .unwrap()
+        .split();
+
+    answerer
+        .set_local_description(datachannel_wrapper::SdpType::Rollback)
+        .unwrap();
+    answerer
+        .set_remote_description(offerer.local_description().unwrap())
+        .unwrap();
+    offerer
+        .set_remote_description(answerer.local_description().unwrap())
+        .unwrap();
+
+    (
+        LoopbackEnd {
+            sender: Sender::new(offerer_dc_tx),
+            receiver: Receiver::new(offerer_dc_rx),
+            _peer_conn: offerer,
+        },
+        LoopbackEnd {
+            sender: Sender::new(answerer_dc_tx),
+            receiver: Receiver::new(answerer_dc_rx),
+            _peer_conn: answerer,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_settings() -> protocol::Settings {
+        protocol::Settings {
+            nickname: "alice".to_string(),
+            lockstep: true,
+            first_to: Some(3),
+            unreliable_inputs: true,
+            ruleset: Some(Default::default()),
+            ..Default::default()
+        }
+    }
+
+    async fn send_and_receive_settings(capabilities: &[&str]) -> protocol::Settings {
+        let (mut local, mut remote) = loopback_pair();
+        local.sender.capabilities = capabilities.iter().map(|c| c.to_string()).collect();
+        local.sender.send_settings(make_settings()).await.unwrap();
+        match remote.receiver.receive().await.unwrap() {
+            protocol::Packet::Settings(settings) => settings,
+            p => panic!("unexpected packet: {:?}", p),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_settings_strips_unnegotiated() {
+        assert_eq!(
+            send_and_receive_settings(protocol::capability::ALL).await,
+            make_settings()
+        );
+
+        assert_eq!(
+            send_and_receive_settings(&[]).await,
+            protocol::Settings {
+                nickname: "alice".to_string(),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            send_and_receive_settings(&[protocol::capability::SERIES, protocol::capability::RULESETS]).await,
+            protocol::Settings {
+                lockstep: false,
+                unreliable_inputs: false,
+                ..make_settings()
+            }
+        );
+    }
+}
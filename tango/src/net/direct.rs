@@ -0,0 +1,210 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::net;
+
+pub const DEFAULT_PORT: u16 = 12121;
+
+/// Packets are bounded by the bincode limit anyway: anything bigger than this is garbage.
+const MAX_FRAME_SIZE: usize = 128 * 1024;
+
+const PROBE: &[u8] = b"tango-probe";
+const PROBE_ACK: &[u8] = b"tango-probe-ack";
+const PROBE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+const PROBE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// How long to keep answering the remote's probes after ours has been answered, in case it hasn't heard back yet.
+const PROBE_LINGER: std::time::Duration = std::time::Duration::from_millis(300);
+
+/// A direct connection to the remote, without signaling or ICE.
+///
+/// Packets go over TCP. Unreliable inputs go over a UDP socket bound to the same address and port as the TCP side, so
+/// both ends already know where to send them, but only if UDP actually gets through both ways: otherwise everything
+/// stays on TCP.
+pub struct Connection {
+    stream: tokio::net::TcpStream,
+    udp: Option<tokio::net::UdpSocket>,
+    is_host: bool,
+}
+
+impl Connection {
+    pub fn is_host(&self) -> bool {
+        self.is_host
+    }
+
+    pub fn split(self) -> (net::Sender, net::Receiver, net::Link) {
+        let (read_half, write_half) = self.stream.into_split();
+        (
+            net::Sender::new_direct(write_half),
+            net::Receiver::new_direct(read_half),
+            net::Link::Direct(self.udp),
+        )
+    }
+}
+
+/// Whether a datagram is left over from probing, rather than something the remote meant to send.
+pub(super) fn is_probe(buf: &[u8]) -> bool {
+    buf == PROBE || buf == PROBE_ACK
+}
+
+/// Checks that datagrams get through both ways, by sending probes until one is answered and answering the remote's.
+///
+/// Both sides probe at the same time, so this only tells us about our side: if the remote's probe doesn't get
+/// answered, it won't ask for unreliable inputs and they won't be used.
+async fn probe_udp(udp: &tokio::net::UdpSocket) -> bool {
+    let deadline = tokio::time::sleep(PROBE_TIMEOUT);
+    tokio::pin!(deadline);
+    let mut probe_timer = tokio::time::interval(PROBE_INTERVAL);
+    let mut answered = false;
+    let mut buf = [0u8; 64];
+    loop {
+        tokio::select! {
+            _ = &mut deadline => {
+                return answered;
+            }
+
+            _ = probe_timer.tick(), if !answered => {
+                // Until the remote has bound its socket, this may fail with a stray ICMP error.
+                let _ = udp.send(PROBE).await;
+            }
+
+            r = udp.recv(&mut buf) => {
+                let n = if let Ok(n) = r {
+                    n
+                } else {
+                    continue;
+                };
+                if &buf[..n] == PROBE {
+                    let _ = udp.send(PROBE_ACK).await;
+                } else if &buf[..n] == PROBE_ACK && !answered {
+                    answered = true;
+                    deadline.as_mut().reset(tokio::time::Instant::now() + PROBE_LINGER);
+                }
+            }
+        }
+    }
+}
+
+/// Binds a UDP socket to the same address as the TCP side and connects it to the remote, if UDP gets through.
+async fn open_udp(stream: &tokio::net::TcpStream) -> std::io::Result<Option<tokio::net::UdpSocket>> {
+    let udp = tokio::net::UdpSocket::bind(stream.local_addr()?).await?;
+    udp.connect(stream.peer_addr()?).await?;
+    if !probe_udp(&udp).await {
+        log::warn!("udp doesn't get through to remote, sending everything over tcp");
+        return Ok(None);
+    }
+    Ok(Some(udp))
+}
+
+/// Waits for a single remote to connect on the given port, over either IPv4 or IPv6.
+pub async fn host(port: u16) -> std::io::Result<Connection> {
+    // Depending on the OS, the IPv6 listener may take IPv4 connections too, in which case the IPv4 one can't bind to
+    // the same port: that's fine, as long as one of them is listening.
+    let v6_listener = tokio::net::TcpListener::bind((std::net::Ipv6Addr::UNSPECIFIED, port)).await;
+    let v4_listener = tokio::net::TcpListener::bind((std::net::Ipv4Addr::UNSPECIFIED, port)).await;
+    let (stream, peer_addr) = match (v6_listener, v4_listener) {
+        (Ok(v6_listener), Ok(v4_listener)) => {
+            log::info!(
+                "hosting direct connection on {} and {}",
+                v6_listener.local_addr()?,
+                v4_listener.local_addr()?
+            );
+            tokio::select! {
+                r = v6_listener.accept() => r?,
+                r = v4_listener.accept() => r?,
+            }
+        }
+        (Ok(listener), Err(_)) | (Err(_), Ok(listener)) => {
+            log::info!("hosting direct connection on {}", listener.local_addr()?);
+            listener.accept().await?
+        }
+        (Err(_), Err(e)) => {
+            return Err(e);
+        }
+    };
+    log::info!("accepted direct connection from {}", peer_addr);
+    stream.set_nodelay(true)?;
+
+    let udp = open_udp(&stream).await?;
+
+    Ok(Connection {
+        stream,
+        udp,
+        is_host: true,
+    })
+}
+
+/// Splits an address into host and port, using the default port if there isn't one. IPv6 addresses with a port must be
+/// in brackets, e.g. `[::1]:12121`.
+fn split_host_port(addr: &str) -> (&str, u16) {
+    if addr.parse::<std::net::Ipv6Addr>().is_ok() {
+        return (addr, DEFAULT_PORT);
+    }
+
+    if let Some(rest) = addr.strip_prefix('[') {
+        if let Some((host, port)) = rest.split_once("]:") {
+            if let Ok(port) = port.parse() {
+                return (host, port);
+            }
+        }
+        return (rest.trim_end_matches(']'), DEFAULT_PORT);
+    }
+
+    if let Some((host, port)) = addr.rsplit_once(':') {
+        if let Ok(port) = port.parse() {
+            return (host, port);
+        }
+    }
+
+    (addr, DEFAULT_PORT)
+}
+
+/// Connects to a remote that is hosting. If no port is given, the default port is used.
+pub async fn connect(addr: &str) -> std::io::Result<Connection> {
+    let stream = tokio::net::TcpStream::connect(split_host_port(addr)).await?;
+    let peer_addr = stream.peer_addr()?;
+    log::info!("direct connection established to {}", peer_addr);
+    stream.set_nodelay(true)?;
+
+    let udp = open_udp(&stream).await?;
+
+    Ok(Connection {
+        stream,
+        udp,
+        is_host: false,
+    })
+}
+
+pub(super) async fn write_frame(w: &mut tokio::net::tcp::OwnedWriteHalf, buf: &[u8]) -> std::io::Result<()> {
+    w.write_u32_le(buf.len() as u32).await?;
+    w.write_all(buf).await?;
+    Ok(())
+}
+
+pub(super) async fn read_frame(r: &mut tokio::net::tcp::OwnedReadHalf) -> std::io::Result<Vec<u8>> {
+    let len = r.read_u32_le().await? as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame too large: {} bytes", len),
+        ));
+    }
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("192.0.2.1"), ("192.0.2.1", DEFAULT_PORT));
+        assert_eq!(split_host_port("192.0.2.1:1234"), ("192.0.2.1", 1234));
+        assert_eq!(split_host_port("example.com"), ("example.com", DEFAULT_PORT));
+        assert_eq!(split_host_port("example.com:1234"), ("example.com", 1234));
+        assert_eq!(split_host_port("2001:db8::1"), ("2001:db8::1", DEFAULT_PORT));
+        assert_eq!(split_host_port("[2001:db8::1]"), ("2001:db8::1", DEFAULT_PORT));
+        assert_eq!(split_host_port("[2001:db8::1]:1234"), ("2001:db8::1", 1234));
+    }
+}
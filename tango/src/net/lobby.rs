@@ -0,0 +1,158 @@
+use crate::net::{self, protocol, transfer};
+
+/// What to do when the remote does something in the lobby, and where the lobby's state lives.
+///
+/// The lobby is over once both sides have committed, so a policy's reactions (changing its own settings, committing or
+/// uncommitting) are what decide when the match starts.
+#[async_trait::async_trait]
+pub trait Policy: Send {
+    async fn send_ping(&mut self) -> Result<(), anyhow::Error>;
+
+    async fn send_pong(&mut self, ts: std::time::SystemTime) -> Result<(), anyhow::Error>;
+
+    async fn mark_latency(&mut self, _latency: std::time::Duration) {}
+
+    async fn set_remote_settings(&mut self, settings: protocol::Settings) -> Result<(), anyhow::Error>;
+
+    async fn set_remote_commitment(&mut self, commitment: Option<[u8; 16]>);
+
+    async fn receive_chat(&mut self, _chat: protocol::Chat) {}
+
+    async fn receive_emote(&mut self, _emote: protocol::Emote) {}
+
+    /// Our negotiated state and the payload it was committed with, if we've committed.
+    async fn local_negotiated_state(&mut self) -> Option<(protocol::NegotiatedState, Vec<u8>)>;
+
+    async fn remote_commitment(&mut self) -> Option<[u8; 16]>;
+
+    /// Gives up the sender once the lobby is over.
+    async fn take_sender(&mut self) -> Option<net::Sender>;
+
+    /// Checks the remote's state once it's been verified against their commitment, before the match starts.
+    async fn check_remote_negotiated_state(
+        &mut self,
+        _remote_negotiated_state: &protocol::NegotiatedState,
+    ) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+pub struct Started {
+    pub sender: net::Sender,
+    pub local_negotiated_state: protocol::NegotiatedState,
+    pub remote_negotiated_state: protocol::NegotiatedState,
+    pub rng_seed: [u8; 16],
+}
+
+/// Runs the lobby until both sides have committed, then exchanges negotiated states and starts the match.
+pub async fn run(
+    policy: &mut impl Policy,
+    receiver: &mut net::Receiver,
+    on_progress: impl Fn(transfer::Progress) + Send + Sync,
+) -> Result<Started, anyhow::Error> {
+    let mut remote_offer = None;
+    let mut ping_timer = tokio::time::interval(net::PING_INTERVAL);
+    'l: loop {
+        tokio::select! {
+            _ = ping_timer.tick() => {
+                policy.send_ping().await?;
+            }
+            p = receiver.receive() => {
+                match p? {
+                    protocol::Packet::Ping(ping) => {
+                        policy.send_pong(ping.ts).await?;
+                    },
+                    protocol::Packet::Pong(pong) => {
+                        if let Ok(d) = std::time::SystemTime::now().duration_since(pong.ts) {
+                            policy.mark_latency(d).await;
+                        }
+                    },
+                    protocol::Packet::Settings(settings) => {
+                        policy.set_remote_settings(settings).await?;
+                    },
+                    protocol::Packet::Commit(commit) => {
+                        policy.set_remote_commitment(Some(commit.commitment)).await;
+                        if policy.local_negotiated_state().await.is_some() {
+                            break 'l;
+                        }
+                    },
+                    protocol::Packet::Uncommit(_) => {
+                        policy.set_remote_commitment(None).await;
+                    },
+                    protocol::Packet::TransferOffer(offer) => {
+                        remote_offer = Some(offer);
+                        break 'l;
+                    },
+                    protocol::Packet::Chat(chat) => {
+                        policy.receive_chat(chat).await;
+                    },
+                    protocol::Packet::Emote(emote) => {
+                        policy.receive_emote(emote).await;
+                    },
+                    p => {
+                        anyhow::bail!("unexpected packet: {:?}", p);
+                    }
+                }
+            }
+        }
+    }
+
+    log::info!("ending lobby");
+
+    let mut sender = if let Some(sender) = policy.take_sender().await {
+        sender
+    } else {
+        anyhow::bail!("no sender?");
+    };
+
+    let (local_negotiated_state, raw_local_state) =
+        if let Some(local_negotiated_state) = policy.local_negotiated_state().await {
+            local_negotiated_state
+        } else {
+            anyhow::bail!("missing local state?");
+        };
+
+    let received_remote_commitment = if let Some(commitment) = policy.remote_commitment().await {
+        commitment
+    } else {
+        anyhow::bail!("no remote commitment?");
+    };
+
+    let remote_negotiated_state = transfer::exchange(
+        &mut sender,
+        receiver,
+        &local_negotiated_state,
+        &raw_local_state,
+        received_remote_commitment,
+        remote_offer,
+        on_progress,
+    )
+    .await?;
+
+    policy.check_remote_negotiated_state(&remote_negotiated_state).await?;
+
+    let rng_seed = std::iter::zip(local_negotiated_state.nonce, remote_negotiated_state.nonce)
+        .map(|(x, y)| x ^ y)
+        .collect::<Vec<_>>()
+        .try_into()
+        .unwrap();
+    log::info!("session verified! rng seed = {:02x?}", rng_seed);
+
+    sender.send_start_match().await?;
+    loop {
+        match receiver.receive().await? {
+            protocol::Packet::StartMatch(_) => {
+                break;
+            }
+            protocol::Packet::Chat(_) | protocol::Packet::Emote(_) => {}
+            p => anyhow::bail!("unexpected packet when expecting start match: {:?}", p),
+        }
+    }
+
+    Ok(Started {
+        sender,
+        local_negotiated_state,
+        remote_negotiated_state,
+        rng_seed,
+    })
+}
@@ -1,6 +1,25 @@
 use bincode::Options;
 
-pub const VERSION: u8 = 0x3a;
+/// The newest protocol version we speak.
+pub const VERSION: u8 = 0x3d;
+
+/// The oldest protocol version we can still speak.
+pub const MIN_VERSION: u8 = 0x3d;
+
+/// Optional features, negotiated by name in the handshake.
+///
+/// Anything that introduces new packet types or settings a peer might not understand should be gated on one of these:
+/// new settings go in Settings' extensions under their capability, and new packets go at the end of Packet and are only
+/// sent if their capability was negotiated. Anything else that changes the wire format needs a new VERSION.
+pub mod capability {
+    pub const LOCKSTEP: &str = "lockstep";
+    pub const SERIES: &str = "series";
+    pub const UNRELIABLE_INPUTS: &str = "unreliable-inputs";
+    pub const RULESETS: &str = "rulesets";
+    pub const CHAT: &str = "chat";
+
+    pub const ALL: &[&str] = &[LOCKSTEP, SERIES, UNRELIABLE_INPUTS, RULESETS, CHAT];
+}
 
 lazy_static! {
     static ref BINCODE_OPTIONS: bincode::config::WithOtherLimit<
@@ -9,8 +28,6 @@ lazy_static! {
     > = bincode::DefaultOptions::new()
         .with_varint_encoding()
         .with_limit(64 * 1024);
-    static ref STATE_BINCODE_OPTIONS: bincode::config::WithOtherIntEncoding<bincode::config::DefaultOptions, bincode::config::VarintEncoding> =
-        bincode::DefaultOptions::new().with_varint_encoding();
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
@@ -31,6 +48,19 @@ pub enum Packet {
 
     // In match.
     Input(tango_pvp::net::Input),
+
+    // New packets go below here, so the ones above keep their tags.
+
+    // Lobby, before chunks are sent.
+    TransferOffer(TransferOffer),
+    TransferResume(TransferResume),
+
+    // In match, if both sides have the unreliable inputs capability.
+    InputBundle(InputBundle),
+
+    // Anytime, if both sides have the chat capability.
+    Chat(Chat),
+    Emote(Emote),
 }
 
 impl Packet {
@@ -45,7 +75,9 @@ impl Packet {
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Hello {
-    pub protocol_version: u8,
+    pub min_protocol_version: u8,
+    pub max_protocol_version: u8,
+    pub capabilities: Vec<String>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
@@ -56,11 +88,48 @@ pub struct Commit {
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Uncommit {}
 
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct TransferOffer {
+    /// The nonce the commitment was made with.
+    pub nonce: [u8; 16],
+
+    /// Identifies the payload, so that chunks from an interrupted transfer can be reused.
+    pub digest: [u8; 16],
+
+    pub total: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct TransferResume {
+    /// Indexes of chunks that don't need to be sent again.
+    pub received: Vec<u32>,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Chunk {
+    pub index: u32,
     pub chunk: Vec<u8>,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct InputBundle {
+    /// The round number and tick of the latest remote input received.
+    pub ack: Option<(u8, u32)>,
+
+    /// Inputs not yet acknowledged by the remote, oldest first.
+    pub inputs: Vec<tango_pvp::net::Input>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Chat {
+    pub text: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Emote {
+    pub id: u8,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Ping {
     pub ts: std::time::SystemTime,
@@ -71,19 +140,22 @@ pub struct Pong {
     pub ts: std::time::SystemTime,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
 pub struct PatchInfo {
     pub name: String,
     pub version: semver::Version,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
 pub struct GameInfo {
     pub family_and_variant: (String, u8),
     pub patch: Option<PatchInfo>,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+/// On the wire, everything gated on a capability goes in an extension keyed by that capability, so that settings can be
+/// added without changing the layout of the rest.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(into = "WireSettings", try_from = "WireSettings")]
 pub struct Settings {
     pub nickname: String,
     pub match_type: (u8, u8),
@@ -91,6 +163,87 @@ pub struct Settings {
     pub available_games: Vec<(String, u8)>,
     pub available_patches: Vec<(String, Vec<semver::Version>)>,
     pub reveal_setup: bool,
+    pub lockstep: bool,
+    pub first_to: Option<u8>,
+    pub unreliable_inputs: bool,
+    pub ruleset: Option<tango_dataview::rules::Ruleset>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct WireSettings {
+    nickname: String,
+    match_type: (u8, u8),
+    game_info: Option<GameInfo>,
+    available_games: Vec<(String, u8)>,
+    available_patches: Vec<(String, Vec<semver::Version>)>,
+    reveal_setup: bool,
+    /// Keyed by capability. Only non-default settings are sent, and unknown keys are ignored.
+    extensions: std::collections::BTreeMap<String, Vec<u8>>,
+}
+
+fn put_extension(
+    extensions: &mut std::collections::BTreeMap<String, Vec<u8>>,
+    capability: &str,
+    value: &impl serde::Serialize,
+) {
+    extensions.insert(capability.to_string(), BINCODE_OPTIONS.serialize(value).unwrap());
+}
+
+fn get_extension<T: serde::de::DeserializeOwned>(
+    extensions: &std::collections::BTreeMap<String, Vec<u8>>,
+    capability: &str,
+) -> bincode::Result<Option<T>> {
+    extensions
+        .get(capability)
+        .map(|buf| BINCODE_OPTIONS.deserialize(buf))
+        .transpose()
+}
+
+impl From<Settings> for WireSettings {
+    fn from(settings: Settings) -> Self {
+        let mut extensions = std::collections::BTreeMap::new();
+        if settings.lockstep {
+            put_extension(&mut extensions, capability::LOCKSTEP, &true);
+        }
+        if let Some(first_to) = settings.first_to {
+            put_extension(&mut extensions, capability::SERIES, &first_to);
+        }
+        if settings.unreliable_inputs {
+            put_extension(&mut extensions, capability::UNRELIABLE_INPUTS, &true);
+        }
+        if let Some(ruleset) = settings.ruleset.as_ref() {
+            put_extension(&mut extensions, capability::RULESETS, ruleset);
+        }
+
+        Self {
+            nickname: settings.nickname,
+            match_type: settings.match_type,
+            game_info: settings.game_info,
+            available_games: settings.available_games,
+            available_patches: settings.available_patches,
+            reveal_setup: settings.reveal_setup,
+            extensions,
+        }
+    }
+}
+
+impl TryFrom<WireSettings> for Settings {
+    type Error = bincode::Error;
+
+    fn try_from(wire: WireSettings) -> Result<Self, Self::Error> {
+        Ok(Self {
+            lockstep: get_extension(&wire.extensions, capability::LOCKSTEP)?.unwrap_or(false),
+            first_to: get_extension(&wire.extensions, capability::SERIES)?,
+            unreliable_inputs: get_extension(&wire.extensions, capability::UNRELIABLE_INPUTS)?.unwrap_or(false),
+            ruleset: get_extension(&wire.extensions, capability::RULESETS)?,
+            nickname: wire.nickname,
+            match_type: wire.match_type,
+            game_info: wire.game_info,
+            available_games: wire.available_games,
+            available_patches: wire.available_patches,
+            reveal_setup: wire.reveal_setup,
+        })
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
@@ -104,18 +257,79 @@ pub struct Input {
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct StartMatch {}
 
-#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct NegotiatedState {
     pub nonce: [u8; 16],
     pub save_data: Vec<u8>,
 }
 
-impl NegotiatedState {
-    pub fn serialize(&self) -> bincode::Result<Vec<u8>> {
-        STATE_BINCODE_OPTIONS.serialize(self)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_settings() -> Settings {
+        Settings {
+            nickname: "alice".to_string(),
+            match_type: (1, 2),
+            game_info: Some(GameInfo {
+                family_and_variant: ("bn6".to_string(), 1),
+                patch: Some(PatchInfo {
+                    name: "exe6_balance".to_string(),
+                    version: semver::Version::new(1, 2, 3),
+                }),
+            }),
+            available_games: vec![("bn6".to_string(), 0), ("bn6".to_string(), 1)],
+            available_patches: vec![("exe6_balance".to_string(), vec![semver::Version::new(1, 2, 3)])],
+            reveal_setup: true,
+            lockstep: true,
+            first_to: Some(3),
+            unreliable_inputs: true,
+            ruleset: Some(tango_dataview::rules::Ruleset {
+                name: "test".to_string(),
+                max_mega_chips: Some(5),
+                banned_chips: [1, 2].into_iter().collect(),
+                ..Default::default()
+            }),
+        }
     }
 
-    pub fn deserialize(d: &[u8]) -> bincode::Result<Self> {
-        STATE_BINCODE_OPTIONS.deserialize(d)
+    fn round_trip(settings: Settings) -> Settings {
+        match Packet::deserialize(&Packet::Settings(settings).serialize().unwrap()).unwrap() {
+            Packet::Settings(settings) => settings,
+            p => panic!("unexpected packet: {:?}", p),
+        }
+    }
+
+    #[test]
+    fn test_settings_round_trip() {
+        let settings = make_settings();
+        assert_eq!(round_trip(settings.clone()), settings);
+        assert_eq!(round_trip(Settings::default()), Settings::default());
+    }
+
+    #[test]
+    fn test_settings_extensions() {
+        let wire = WireSettings::from(make_settings());
+        assert_eq!(
+            wire.extensions.keys().map(|k| k.as_str()).collect::<Vec<_>>(),
+            vec![
+                capability::LOCKSTEP,
+                capability::RULESETS,
+                capability::SERIES,
+                capability::UNRELIABLE_INPUTS
+            ]
+        );
+        assert!(WireSettings::from(Settings::default()).extensions.is_empty());
+    }
+
+    #[test]
+    fn test_settings_ignores_unknown_extensions() {
+        let mut wire = WireSettings::from(make_settings());
+        wire.extensions
+            .insert("from-the-future".to_string(), vec![0xff, 0xff, 0xff]);
+        let settings: Settings = BINCODE_OPTIONS
+            .deserialize(&BINCODE_OPTIONS.serialize(&wire).unwrap())
+            .unwrap();
+        assert_eq!(settings, make_settings());
     }
 }
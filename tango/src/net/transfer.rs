@@ -0,0 +1,544 @@
+use std::io::Read;
+
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+
+use crate::net::{self, protocol};
+
+const CHUNK_SIZE: usize = 16 * 1024;
+const MAX_CHUNKS: u32 = 128;
+const COMPRESSION_LEVEL: i32 = 19;
+
+/// The size of the GBA's work RAM, which no save data can be bigger than.
+const MAX_SAVE_DATA_SIZE: usize = 256 * 1024;
+
+/// How many interrupted transfers to keep chunks for, and for how long.
+const MAX_INCOMING: usize = 4;
+const INCOMING_TTL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// Chunks received from interrupted transfers, keyed by the digest of the payload they belong to.
+///
+/// The sender's nonce changes every time it commits, but the save payload usually doesn't: if the connection drops
+/// mid-transfer and the same opponent reconnects, only the chunks we're missing need to be sent again. The digest is
+/// whatever the remote says it is, so only a few of these are kept, and not for long.
+struct Incoming {
+    total: u32,
+    chunks: std::collections::BTreeMap<u32, Vec<u8>>,
+    last_used: std::time::Instant,
+}
+
+lazy_static! {
+    static ref INCOMING: parking_lot::Mutex<std::collections::HashMap<[u8; 16], Incoming>> =
+        parking_lot::Mutex::new(std::collections::HashMap::new());
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Progress {
+    pub sent: u32,
+    pub to_send: u32,
+    pub received: u32,
+    pub to_receive: u32,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f32 {
+        let total = self.to_send + self.to_receive;
+        if total == 0 {
+            return 1.0;
+        }
+        (self.sent + self.received) as f32 / total as f32
+    }
+}
+
+fn digest(payload: &[u8]) -> [u8; 16] {
+    net::make_commitment(payload)
+}
+
+fn commitment(nonce: &[u8; 16], payload: &[u8]) -> [u8; 16] {
+    net::make_commitment(&[&nonce[..], payload].concat())
+}
+
+/// Generates a fresh nonce for the given save data, returning the state, its compressed payload and the commitment to
+/// send to the remote.
+pub fn prepare(save_data: &[u8]) -> Result<(protocol::NegotiatedState, Vec<u8>, [u8; 16]), anyhow::Error> {
+    let mut nonce = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let payload = zstd::stream::encode_all(save_data, COMPRESSION_LEVEL)?;
+    let commitment = commitment(&nonce, &payload);
+    log::info!("nonce = {:02x?}, commitment = {:02x?}", nonce, commitment);
+    Ok((
+        protocol::NegotiatedState {
+            nonce,
+            save_data: save_data.to_vec(),
+        },
+        payload,
+        commitment,
+    ))
+}
+
+/// Exchanges negotiated states with the remote and checks theirs against the commitment they sent.
+///
+/// `remote_offer` is the remote's offer, if it was already received while still in the lobby.
+pub async fn exchange(
+    sender: &mut net::Sender,
+    receiver: &mut net::Receiver,
+    local_state: &protocol::NegotiatedState,
+    local_payload: &[u8],
+    remote_commitment: [u8; 16],
+    remote_offer: Option<protocol::TransferOffer>,
+    on_progress: impl Fn(Progress) + Send + Sync,
+) -> Result<protocol::NegotiatedState, anyhow::Error> {
+    let local_chunks = local_payload.chunks(CHUNK_SIZE).collect::<Vec<_>>();
+    sender
+        .send_transfer_offer(protocol::TransferOffer {
+            nonce: local_state.nonce,
+            digest: digest(local_payload),
+            total: local_chunks.len() as u32,
+        })
+        .await?;
+
+    let remote_offer = if let Some(remote_offer) = remote_offer {
+        remote_offer
+    } else {
+        loop {
+            match receiver.receive().await? {
+                protocol::Packet::Ping(_)
+                | protocol::Packet::Pong(_)
+                | protocol::Packet::Chat(_)
+                | protocol::Packet::Emote(_) => {}
+                protocol::Packet::TransferOffer(offer) => {
+                    break offer;
+                }
+                p => {
+                    anyhow::bail!("unexpected packet when expecting transfer offer: {:?}", p);
+                }
+            }
+        }
+    };
+
+    if remote_offer.total == 0 || remote_offer.total > MAX_CHUNKS {
+        anyhow::bail!("remote offered {} chunks", remote_offer.total);
+    }
+
+    let received = {
+        let mut incoming = INCOMING.lock();
+        incoming.retain(|_, entry| entry.last_used.elapsed() < INCOMING_TTL);
+        if !incoming.contains_key(&remote_offer.digest) && incoming.len() >= MAX_INCOMING {
+            let oldest = incoming
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(digest, _)| *digest)
+                .unwrap();
+            incoming.remove(&oldest);
+        }
+        let entry = incoming.entry(remote_offer.digest).or_insert_with(|| Incoming {
+            total: remote_offer.total,
+            chunks: std::collections::BTreeMap::new(),
+            last_used: std::time::Instant::now(),
+        });
+        entry.last_used = std::time::Instant::now();
+        if entry.total != remote_offer.total {
+            entry.total = remote_offer.total;
+            entry.chunks.clear();
+        }
+        entry.chunks.keys().cloned().collect::<Vec<_>>()
+    };
+    if !received.is_empty() {
+        log::info!(
+            "resuming transfer with {}/{} chunks already received",
+            received.len(),
+            remote_offer.total
+        );
+    }
+    sender
+        .send_transfer_resume(protocol::TransferResume {
+            received: received.clone(),
+        })
+        .await?;
+
+    let remote_resume = loop {
+        match receiver.receive().await? {
+            protocol::Packet::Ping(_)
+            | protocol::Packet::Pong(_)
+            | protocol::Packet::Chat(_)
+            | protocol::Packet::Emote(_) => {}
+            protocol::Packet::TransferResume(resume) => {
+                break resume;
+            }
+            p => {
+                anyhow::bail!("unexpected packet when expecting transfer resume: {:?}", p);
+            }
+        }
+    };
+
+    let to_send = (0..local_chunks.len() as u32)
+        .filter(|i| !remote_resume.received.contains(i))
+        .collect::<Vec<_>>();
+
+    let progress = parking_lot::Mutex::new(Progress {
+        sent: 0,
+        to_send: to_send.len() as u32,
+        received: 0,
+        to_receive: remote_offer.total - received.len() as u32,
+    });
+    on_progress(*progress.lock());
+
+    tokio::try_join!(
+        async {
+            for index in to_send {
+                sender.send_chunk(index, local_chunks[index as usize].to_vec()).await?;
+                let mut progress = progress.lock();
+                progress.sent += 1;
+                on_progress(*progress);
+            }
+            Ok::<_, anyhow::Error>(())
+        },
+        async {
+            loop {
+                {
+                    let progress = progress.lock();
+                    if progress.received >= progress.to_receive {
+                        break;
+                    }
+                }
+
+                let chunk = match receiver.receive().await? {
+                    protocol::Packet::Ping(_)
+                    | protocol::Packet::Pong(_)
+                    | protocol::Packet::Chat(_)
+                    | protocol::Packet::Emote(_) => {
+                        continue;
+                    }
+                    protocol::Packet::Chunk(chunk) => chunk,
+                    p => {
+                        anyhow::bail!("unexpected packet when expecting chunk: {:?}", p);
+                    }
+                };
+
+                if chunk.index >= remote_offer.total {
+                    anyhow::bail!("chunk {} out of range", chunk.index);
+                }
+
+                if chunk.chunk.len() > CHUNK_SIZE {
+                    anyhow::bail!("chunk {} too long: {} bytes", chunk.index, chunk.chunk.len());
+                }
+
+                let mut incoming = INCOMING.lock();
+                let entry = if let Some(entry) = incoming.get_mut(&remote_offer.digest) {
+                    entry
+                } else {
+                    anyhow::bail!("transfer state went away");
+                };
+                entry.last_used = std::time::Instant::now();
+                if entry.chunks.insert(chunk.index, chunk.chunk).is_none() {
+                    let mut progress = progress.lock();
+                    progress.received += 1;
+                    on_progress(*progress);
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        },
+    )?;
+
+    let remote_payload = if let Some(entry) = INCOMING.lock().remove(&remote_offer.digest) {
+        entry.chunks.into_values().flatten().collect::<Vec<_>>()
+    } else {
+        anyhow::bail!("transfer state went away");
+    };
+
+    log::info!("remote commitment = {:02x?}", remote_commitment);
+    if !bool::from(commitment(&remote_offer.nonce, &remote_payload).ct_eq(&remote_commitment)) {
+        anyhow::bail!("commitment mismatch?");
+    }
+
+    // The payload is only as trustworthy as the remote, so don't let it decompress to more than any save could be.
+    let mut save_data = vec![];
+    zstd::stream::read::Decoder::new(&remote_payload[..])?
+        .take(MAX_SAVE_DATA_SIZE as u64 + 1)
+        .read_to_end(&mut save_data)?;
+    if save_data.len() > MAX_SAVE_DATA_SIZE {
+        anyhow::bail!("remote save data is more than {} bytes", MAX_SAVE_DATA_SIZE);
+    }
+
+    Ok(protocol::NegotiatedState {
+        nonce: remote_offer.nonce,
+        save_data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    lazy_static! {
+        /// Every exchange in the process shares `INCOMING`, so only one test can use it at a time.
+        static ref INCOMING_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    }
+
+    fn random_save_data(len: usize) -> Vec<u8> {
+        let mut save_data = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut save_data);
+        save_data
+    }
+
+    fn make_offer(nonce: [u8; 16], payload: &[u8]) -> protocol::TransferOffer {
+        protocol::TransferOffer {
+            nonce,
+            digest: digest(payload),
+            total: payload.chunks(CHUNK_SIZE).count() as u32,
+        }
+    }
+
+    fn make_chunks(payload: &[u8]) -> Vec<protocol::Chunk> {
+        payload
+            .chunks(CHUNK_SIZE)
+            .enumerate()
+            .map(|(index, chunk)| protocol::Chunk {
+                index: index as u32,
+                chunk: chunk.to_vec(),
+            })
+            .collect()
+    }
+
+    /// Plays the remote's side of a transfer by hand: makes the given offer, says it has nothing of ours, then sends the
+    /// given chunks whatever we asked for. Returns what we said we already had.
+    async fn run_remote(
+        end: &mut net::LoopbackEnd,
+        offer: protocol::TransferOffer,
+        chunks: Vec<protocol::Chunk>,
+    ) -> Result<protocol::TransferResume, anyhow::Error> {
+        end.sender.send_transfer_offer(offer).await?;
+        match end.receiver.receive().await? {
+            protocol::Packet::TransferOffer(_) => {}
+            p => anyhow::bail!("unexpected packet: {:?}", p),
+        }
+        end.sender
+            .send_transfer_resume(protocol::TransferResume { received: vec![] })
+            .await?;
+        let resume = match end.receiver.receive().await? {
+            protocol::Packet::TransferResume(resume) => resume,
+            p => anyhow::bail!("unexpected packet: {:?}", p),
+        };
+        for chunk in chunks {
+            end.sender.send_chunk(chunk.index, chunk.chunk).await?;
+        }
+        Ok(resume)
+    }
+
+    /// Runs our side of the exchange against a hand-played remote. Our own save is small enough to go in one chunk, so
+    /// sending it never waits on the remote reading it.
+    async fn exchange_with_remote(
+        remote_commitment: [u8; 16],
+        offer: protocol::TransferOffer,
+        chunks: Vec<protocol::Chunk>,
+    ) -> (
+        Result<protocol::NegotiatedState, anyhow::Error>,
+        protocol::TransferResume,
+        Progress,
+    ) {
+        let (mut local, mut remote) = net::loopback_pair();
+        let (local_state, local_payload, _) = prepare(b"local").unwrap();
+        let progress = parking_lot::Mutex::new(Progress::default());
+        let (r, resume) = tokio::join!(
+            exchange(
+                &mut local.sender,
+                &mut local.receiver,
+                &local_state,
+                &local_payload,
+                remote_commitment,
+                None,
+                |p| *progress.lock() = p,
+            ),
+            run_remote(&mut remote, offer, chunks),
+        );
+        (r, resume.unwrap(), progress.into_inner())
+    }
+
+    #[tokio::test]
+    async fn test_exchange() {
+        let _guard = INCOMING_LOCK.lock().await;
+        INCOMING.lock().clear();
+
+        let (mut alice, mut bob) = net::loopback_pair();
+        let alice_save_data = random_save_data(3 * CHUNK_SIZE);
+        let bob_save_data = random_save_data(CHUNK_SIZE / 2);
+        let (alice_state, alice_payload, alice_commitment) = prepare(&alice_save_data).unwrap();
+        let (bob_state, bob_payload, bob_commitment) = prepare(&bob_save_data).unwrap();
+
+        let (alice_r, bob_r) = tokio::join!(
+            exchange(
+                &mut alice.sender,
+                &mut alice.receiver,
+                &alice_state,
+                &alice_payload,
+                bob_commitment,
+                None,
+                |_| {},
+            ),
+            exchange(
+                &mut bob.sender,
+                &mut bob.receiver,
+                &bob_state,
+                &bob_payload,
+                alice_commitment,
+                None,
+                |_| {},
+            ),
+        );
+
+        let remote_state = alice_r.unwrap();
+        assert_eq!(remote_state.nonce, bob_state.nonce);
+        assert_eq!(remote_state.save_data, bob_save_data);
+        let remote_state = bob_r.unwrap();
+        assert_eq!(remote_state.nonce, alice_state.nonce);
+        assert_eq!(remote_state.save_data, alice_save_data);
+
+        assert!(INCOMING.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_exchange_resumes_by_digest() {
+        let _guard = INCOMING_LOCK.lock().await;
+        INCOMING.lock().clear();
+
+        let save_data = random_save_data(3 * CHUNK_SIZE + 1);
+        let (state, payload, commitment) = prepare(&save_data).unwrap();
+        let mut chunks = make_chunks(&payload);
+        let total = chunks.len() as u32;
+        assert_eq!(total, 4);
+
+        // What's left over from a transfer of the same payload under another nonce.
+        INCOMING.lock().insert(
+            digest(&payload),
+            Incoming {
+                total,
+                chunks: chunks.drain(..2).map(|chunk| (chunk.index, chunk.chunk)).collect(),
+                last_used: std::time::Instant::now(),
+            },
+        );
+
+        let (r, resume, progress) = exchange_with_remote(commitment, make_offer(state.nonce, &payload), chunks).await;
+        assert_eq!(r.unwrap().save_data, save_data);
+        assert_eq!(resume.received, vec![0, 1]);
+        assert_eq!(progress.to_receive, total - 2);
+        assert_eq!(progress.received, total - 2);
+        assert!(INCOMING.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_exchange_clears_on_total_mismatch() {
+        let _guard = INCOMING_LOCK.lock().await;
+        INCOMING.lock().clear();
+
+        let save_data = random_save_data(CHUNK_SIZE + 1);
+        let (state, payload, commitment) = prepare(&save_data).unwrap();
+        let chunks = make_chunks(&payload);
+        let total = chunks.len() as u32;
+
+        INCOMING.lock().insert(
+            digest(&payload),
+            Incoming {
+                total: total + 1,
+                chunks: [(0, b"garbage".to_vec())].into_iter().collect(),
+                last_used: std::time::Instant::now(),
+            },
+        );
+
+        let (r, resume, progress) = exchange_with_remote(commitment, make_offer(state.nonce, &payload), chunks).await;
+        assert_eq!(r.unwrap().save_data, save_data);
+        assert!(resume.received.is_empty());
+        assert_eq!(progress.to_receive, total);
+    }
+
+    #[tokio::test]
+    async fn test_exchange_evicts_oldest() {
+        let _guard = INCOMING_LOCK.lock().await;
+        INCOMING.lock().clear();
+
+        let now = std::time::Instant::now();
+        for i in 0..MAX_INCOMING {
+            INCOMING.lock().insert(
+                [i as u8; 16],
+                Incoming {
+                    total: 1,
+                    chunks: std::collections::BTreeMap::new(),
+                    last_used: now - std::time::Duration::from_secs(((MAX_INCOMING - i) * 60) as u64),
+                },
+            );
+        }
+
+        let (state, payload, commitment) = prepare(b"remote").unwrap();
+        let (r, _, _) =
+            exchange_with_remote(commitment, make_offer(state.nonce, &payload), make_chunks(&payload)).await;
+        r.unwrap();
+
+        let incoming = INCOMING.lock();
+        assert_eq!(incoming.len(), MAX_INCOMING - 1);
+        assert!(!incoming.contains_key(&[0u8; 16]));
+        for i in 1..MAX_INCOMING {
+            assert!(incoming.contains_key(&[i as u8; 16]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exchange_rejects_out_of_range_chunk() {
+        let _guard = INCOMING_LOCK.lock().await;
+        INCOMING.lock().clear();
+
+        let (state, payload, commitment) = prepare(b"remote").unwrap();
+        let offer = make_offer(state.nonce, &payload);
+        let chunks = vec![protocol::Chunk {
+            index: offer.total,
+            chunk: payload.clone(),
+        }];
+        let (r, _, _) = exchange_with_remote(commitment, offer, chunks).await;
+        assert!(r.unwrap_err().to_string().contains("out of range"));
+    }
+
+    #[tokio::test]
+    async fn test_exchange_rejects_oversized_chunk() {
+        let _guard = INCOMING_LOCK.lock().await;
+        INCOMING.lock().clear();
+
+        let (state, payload, commitment) = prepare(b"remote").unwrap();
+        let chunks = vec![protocol::Chunk {
+            index: 0,
+            chunk: vec![0u8; CHUNK_SIZE + 1],
+        }];
+        let (r, _, _) = exchange_with_remote(commitment, make_offer(state.nonce, &payload), chunks).await;
+        assert!(r.unwrap_err().to_string().contains("too long"));
+    }
+
+    #[tokio::test]
+    async fn test_exchange_rejects_commitment_mismatch() {
+        let _guard = INCOMING_LOCK.lock().await;
+        INCOMING.lock().clear();
+
+        let (state, payload, _) = prepare(b"remote").unwrap();
+        let (r, _, _) = exchange_with_remote(
+            commitment(&[0u8; 16], &payload),
+            make_offer(state.nonce, &payload),
+            make_chunks(&payload),
+        )
+        .await;
+        assert!(r.unwrap_err().to_string().contains("commitment mismatch"));
+    }
+
+    #[tokio::test]
+    async fn test_exchange_caps_decompressed_size() {
+        let _guard = INCOMING_LOCK.lock().await;
+        INCOMING.lock().clear();
+
+        // Compresses down to almost nothing, but is a byte bigger than any save could be.
+        let (state, payload, commitment) = prepare(&vec![0u8; MAX_SAVE_DATA_SIZE + 1]).unwrap();
+        assert_eq!(make_chunks(&payload).len(), 1);
+        let (r, _, _) =
+            exchange_with_remote(commitment, make_offer(state.nonce, &payload), make_chunks(&payload)).await;
+        assert!(r.unwrap_err().to_string().contains("more than"));
+
+        let (state, payload, commitment) = prepare(&vec![0u8; MAX_SAVE_DATA_SIZE]).unwrap();
+        let (r, _, _) =
+            exchange_with_remote(commitment, make_offer(state.nonce, &payload), make_chunks(&payload)).await;
+        assert_eq!(r.unwrap().save_data.len(), MAX_SAVE_DATA_SIZE);
+    }
+}
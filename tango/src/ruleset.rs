@@ -0,0 +1,72 @@
+use crate::{game, rom};
+
+pub use tango_dataview::rules::{Ruleset, Violation};
+
+/// Loads every ruleset in the rulesets directory, sorted by name.
+///
+/// Rulesets are JSON files: if a ruleset doesn't specify a name, its file stem is used instead.
+pub fn scan(path: &std::path::Path) -> Vec<Ruleset> {
+    let read_dir = match std::fs::read_dir(path) {
+        Ok(read_dir) => read_dir,
+        Err(e) => {
+            log::error!("failed to read rulesets from {}: {:?}", path.display(), e);
+            return vec![];
+        }
+    };
+
+    let mut rulesets = read_dir
+        .flat_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map(|ext| ext == "json").unwrap_or(false))
+        .flat_map(|path| match load(&path) {
+            Ok(ruleset) => Some(ruleset),
+            Err(e) => {
+                log::error!("failed to load ruleset {}: {:?}", path.display(), e);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    rulesets.sort_by(|a, b| a.name.cmp(&b.name));
+    rulesets
+}
+
+pub fn load(path: &std::path::Path) -> Result<Ruleset, anyhow::Error> {
+    let mut ruleset: Ruleset = serde_json::from_reader(std::fs::File::open(path)?)?;
+    if ruleset.name.is_empty() {
+        ruleset.name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+    }
+    Ok(ruleset)
+}
+
+/// Checks a save against a ruleset, returning each violation alongside the name of the chip, part or patch card
+/// involved, if any.
+pub fn check(
+    ruleset: &Ruleset,
+    game: &'static (dyn game::Game + Send + Sync),
+    rom: &[u8],
+    overrides: &rom::Overrides,
+    save: &(dyn tango_dataview::save::Save + Send + Sync),
+) -> Result<Vec<(Violation, Option<String>)>, anyhow::Error> {
+    let assets = game.load_rom_assets(rom, &save.as_raw_wram(), overrides)?;
+    Ok(ruleset
+        .check(save, assets.as_ref())
+        .into_iter()
+        .map(|violation| {
+            let name = match &violation {
+                Violation::TooManyChipCopies { id, .. } | Violation::BannedChip { id } => {
+                    assets.chip(*id).and_then(|chip| chip.name())
+                }
+                Violation::BannedNavicustPart { id } => assets.navicust_part(*id).and_then(|ncp| ncp.name()),
+                Violation::BannedPatchCard { id } | Violation::TooManyPatchCardCopies { id, .. } => assets
+                    .patch_card56(*id)
+                    .and_then(|card| card.name())
+                    .or_else(|| assets.patch_card4(*id).and_then(|card| card.name())),
+                _ => None,
+            };
+            (violation, name)
+        })
+        .collect())
+}
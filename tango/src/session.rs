@@ -1,4 +1,4 @@
-use crate::{audio, config, game, net, rom, stats, video};
+use crate::{audio, chat, config, game, net, rom, stats, video};
 use parking_lot::Mutex;
 use rand::SeedableRng;
 use std::sync::Arc;
@@ -34,13 +34,74 @@ pub struct PvP {
     pub match_: std::sync::Arc<tokio::sync::Mutex<Option<std::sync::Arc<tango_pvp::battle::Match>>>>,
     cancellation_token: tokio_util::sync::CancellationToken,
     latency_counter: std::sync::Arc<tokio::sync::Mutex<crate::stats::LatencyCounter>>,
-    _peer_conn: datachannel_wrapper::PeerConnection,
+    sender: std::sync::Arc<tokio::sync::Mutex<net::Sender>>,
+    /// The last buffered amount read off the sender, for when it's busy sending.
+    last_buffered_amount: Mutex<Option<usize>>,
+    chat: Option<std::sync::Arc<Mutex<chat::Log>>>,
+    link: net::Link,
+}
+
+pub struct ConnectionStats {
+    pub link: net::LinkStats,
+    /// The median round-trip time of our own ping packets.
+    pub ping: std::time::Duration,
+    /// Only known when going over a data channel.
+    pub buffered_amount: Option<usize>,
 }
 
 impl PvP {
     pub async fn latency(&self) -> std::time::Duration {
         self.latency_counter.lock().await.median()
     }
+
+    pub async fn connection_stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            link: self.link.stats(),
+            ping: self.latency().await,
+            buffered_amount: self.buffered_amount(),
+        }
+    }
+
+    /// The sender is held for as long as it takes to write to the network, so don't wait for it: this is read every
+    /// frame.
+    fn buffered_amount(&self) -> Option<usize> {
+        let mut last_buffered_amount = self.last_buffered_amount.lock();
+        if let Ok(sender) = self.sender.try_lock() {
+            *last_buffered_amount = sender.buffered_amount();
+        }
+        *last_buffered_amount
+    }
+
+    /// The chat log shared with the lobby, if the remote supports chat.
+    pub fn chat(&self) -> Option<&std::sync::Arc<Mutex<chat::Log>>> {
+        self.chat.as_ref()
+    }
+
+    pub async fn send_chat(&self, text: &str) -> Result<(), anyhow::Error> {
+        let chat = if let Some(chat) = self.chat.as_ref() {
+            chat
+        } else {
+            return Ok(());
+        };
+        let text = text.trim().chars().take(chat::MAX_MESSAGE_LENGTH).collect::<String>();
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.sender.lock().await.send_chat(text.clone()).await?;
+        chat.lock().push_message(true, &text);
+        Ok(())
+    }
+
+    pub async fn send_emote(&self, id: u8) -> Result<(), anyhow::Error> {
+        let chat = if let Some(chat) = self.chat.as_ref() {
+            chat
+        } else {
+            return Ok(());
+        };
+        self.sender.lock().await.send_emote(id).await?;
+        chat.lock().push_emote(true, id);
+        Ok(())
+    }
 }
 
 pub struct SinglePlayer {}
@@ -51,6 +112,71 @@ pub enum Mode {
     Replayer,
 }
 
+#[derive(serde::Serialize)]
+struct SeriesResult<'a> {
+    ts: u64,
+    link_code: &'a str,
+    local_nickname: &'a str,
+    remote_nickname: &'a str,
+    first_to: u8,
+    wins: u8,
+    losses: u8,
+    outcome: &'static str,
+}
+
+fn write_series_result(
+    replays_path: &std::path::Path,
+    link_code: &str,
+    netplay_compatibility: &str,
+    local_nickname: &str,
+    remote_nickname: &str,
+    first_to: u8,
+    score: &tango_pvp::battle::Score,
+    outcome: tango_pvp::battle::BattleOutcome,
+) -> Result<(), anyhow::Error> {
+    const TIME_DESCRIPTION: &[time::format_description::FormatItem<'_>] = time::macros::format_description!(
+        "[year padding:zero][month padding:zero repr:numerical][day padding:zero][hour padding:zero][minute padding:zero][second padding:zero]"
+    );
+    let now = std::time::SystemTime::now();
+    let filename = replays_path.join(format!(
+        "{}.json",
+        format!(
+            "{}-{}-{}-vs-{}-series",
+            time::OffsetDateTime::from(now).format(TIME_DESCRIPTION)?,
+            link_code,
+            netplay_compatibility,
+            remote_nickname,
+        )
+        .chars()
+        .filter(|c| "/\\?%*:|\"<>. ".chars().all(|c2| c2 != *c))
+        .collect::<String>()
+    ));
+    log::info!(
+        "series ended {}-{}, writing result: {}",
+        score.wins,
+        score.losses,
+        filename.display()
+    );
+    let f = std::fs::File::create(&filename)?;
+    serde_json::to_writer_pretty(
+        f,
+        &SeriesResult {
+            ts: now.duration_since(std::time::UNIX_EPOCH)?.as_millis() as u64,
+            link_code,
+            local_nickname,
+            remote_nickname,
+            first_to,
+            wins: score.wins,
+            losses: score.losses,
+            outcome: match outcome {
+                tango_pvp::battle::BattleOutcome::Win => "win",
+                tango_pvp::battle::BattleOutcome::Loss => "loss",
+            },
+        },
+    )?;
+    Ok(())
+}
+
 impl Session {
     pub fn new_pvp(
         config: std::sync::Arc<parking_lot::RwLock<config::Config>>,
@@ -71,7 +197,8 @@ impl Session {
         emu_tps_counter: Arc<Mutex<stats::Counter>>,
         sender: net::Sender,
         receiver: net::Receiver,
-        peer_conn: datachannel_wrapper::PeerConnection,
+        mut link: net::Link,
+        chat: Option<std::sync::Arc<Mutex<chat::Log>>>,
         is_offerer: bool,
         replays_path: std::path::PathBuf,
         match_type: (u8, u8),
@@ -118,6 +245,12 @@ impl Session {
         let thread = mgba::thread::Thread::new(core);
 
         let sender = std::sync::Arc::new(tokio::sync::Mutex::new(sender));
+        let unreliable_inputs = if local_settings.unreliable_inputs && remote_settings.unreliable_inputs {
+            log::info!("sending inputs over unreliable channel");
+            Some(std::sync::Arc::new(link.open_unreliable_inputs()?))
+        } else {
+            None
+        };
         let latency_counter = std::sync::Arc::new(tokio::sync::Mutex::new(crate::stats::LatencyCounter::new(5)));
 
         let cancellation_token = tokio_util::sync::CancellationToken::new();
@@ -135,14 +268,16 @@ impl Session {
                 local_hooks,
                 tango_pvp::hooks::hooks_for_gamedb_entry(remote_game.gamedb_entry()).unwrap(),
                 cancellation_token.clone(),
-                Box::new(crate::net::PvpSender::new(sender.clone())),
+                Box::new(crate::net::PvpSender::new(sender.clone(), unreliable_inputs.clone())),
                 rand_pcg::Mcg128Xsl64::from_seed(rng_seed),
                 is_offerer,
                 thread.handle(),
                 remote_rom,
                 remote_save.as_ref(),
                 match_type,
+                local_settings.first_to,
                 config.input_delay,
+                local_settings.lockstep,
                 move |round_number, local_player_index| {
                     const TIME_DESCRIPTION: &[time::format_description::FormatItem<'_>] = time::macros::format_description!(
                         "[year padding:zero][month padding:zero repr:numerical][day padding:zero][hour padding:zero][minute padding:zero][second padding:zero]"
@@ -258,6 +393,9 @@ impl Session {
                     receiver,
                     sender.clone(),
                     latency_counter.clone(),
+                    chat.clone()
+                        .unwrap_or_else(|| std::sync::Arc::new(Mutex::new(chat::Log::new()))),
+                    unreliable_inputs.clone(),
                 ));
                 tokio::task::spawn(async move {
                     tokio::select! {
@@ -293,6 +431,12 @@ impl Session {
             let joyflags = joyflags.clone();
             let vbuf = vbuf.clone();
             let emu_tps_counter = emu_tps_counter.clone();
+            let match_ = match_.clone();
+            let replays_path = config.read().replays_path();
+            let link_code = link_code.clone();
+            let netplay_compatibility = netplay_compatibility.clone();
+            let local_nickname = local_settings.nickname.clone();
+            let remote_nickname = remote_settings.nickname.clone();
             move |mut core, video_buffer, mut thread_handle| {
                 let mut vbuf = vbuf.lock();
                 vbuf.copy_from_slice(video_buffer);
@@ -300,6 +444,31 @@ impl Session {
                 core.set_keys(joyflags.load(std::sync::atomic::Ordering::Relaxed));
                 emu_tps_counter.lock().mark();
 
+                if !completion_token.is_complete() {
+                    if let Some(match_) = match_.blocking_lock().as_ref() {
+                        if let Some(first_to) = match_.first_to() {
+                            let round_state = match_.lock_round_state();
+                            if round_state.round.is_none() {
+                                if let Some(outcome) = round_state.score.series_outcome(first_to) {
+                                    if let Err(e) = write_series_result(
+                                        &replays_path,
+                                        &link_code,
+                                        &netplay_compatibility,
+                                        &local_nickname,
+                                        &remote_nickname,
+                                        first_to,
+                                        &round_state.score,
+                                        outcome,
+                                    ) {
+                                        log::error!("failed to write series result: {:?}", e);
+                                    }
+                                    completion_token.complete();
+                                }
+                            }
+                        }
+                    }
+                }
+
                 if completion_token.is_complete() {
                     thread_handle.pause();
                 }
@@ -319,8 +488,11 @@ impl Session {
             mode: Mode::PvP(PvP {
                 match_,
                 cancellation_token,
-                _peer_conn: peer_conn,
+                link,
                 latency_counter,
+                sender,
+                last_buffered_amount: Mutex::new(None),
+                chat,
             }),
             completion_token,
             pause_on_next_frame: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)),